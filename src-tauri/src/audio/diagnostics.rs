@@ -0,0 +1,345 @@
+//! Static checks that point at code the engine can't play as written.
+//!
+//! The parser skips anything it doesn't recognise so a run never fails on a
+//! single bad line. That keeps the music going but hides mistakes, so
+//! `check_code` walks the same logical lines and reports what was ignored,
+//! approximated or left unbalanced, with a position the editor can mark.
+
+use serde::Serialize;
+use std::collections::HashSet;
+
+use super::parser::{
+    apply_assignment, extract_symbol, find_trailing_if, find_trailing_unless, is_block_opener,
+    is_note_command, node_ref, numbered_logical_lines, parse_line, strip_inline_comment,
+    synth_from_name, try_parse_assignment, ParseContext,
+};
+use super::sc_engine::fx_synthdef_name;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The code is structurally broken; playback may differ a lot from intent
+    Error,
+    /// Part of the code is ignored or replaced by a fallback
+    Warning,
+    /// Not supported by this app, but harmless
+    Info,
+}
+
+/// One problem found in the source
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// 1-based source line
+    pub line: usize,
+    /// 1-based column (in characters) of the offending text
+    pub column: usize,
+    /// The offending text
+    pub text: String,
+    /// What happens to it and how to fix it
+    pub hint: String,
+}
+
+/// Commands `parse_line` understands, used to tell "bad arguments" apart
+/// from "unknown statement"
+const KNOWN_COMMANDS: &[&str] = &[
+    "play", "play_pattern", "play_pattern_timed", "sample", "sleep", "wait", "use_bpm",
+    "set_volume", "set_volume!", "use_synth", "synth", "stop", "puts", "print", "log", "cue",
+    "sync", "control", "use_random_seed", "use_random_source", "rand_reset", "rand_skip", "rand_back",
+];
+
+/// Check `code` and return its diagnostics in source order
+pub fn check_code(code: &str) -> Vec<Diagnostic> {
+    let source: Vec<&str> = code.lines().collect();
+    let lines: Vec<(usize, String)> = numbered_logical_lines(code)
+        .into_iter()
+        .map(|(line_no, line)| (line_no, strip_inline_comment(line.trim())))
+        .collect();
+    let mut checker = Checker {
+        source,
+        diagnostics: Vec::new(),
+        ctx: ParseContext::new(),
+        functions: collect_function_names(&lines),
+    };
+
+    // Open blocks: (line, header)
+    let mut open: Vec<(usize, String)> = Vec::new();
+
+    for (line_no, line) in &lines {
+        let line_no = *line_no;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line == "end" {
+            if open.pop().is_none() {
+                checker.report(Severity::Error, line_no, "end", "unmatched `end` — there is no open block for it to close");
+            }
+            continue;
+        }
+
+        if line == "else" || line.starts_with("elsif ") {
+            continue;
+        }
+
+        if is_block_opener(line) {
+            checker.check_block_header(line_no, line);
+            open.push((line_no, line.clone()));
+            continue;
+        }
+
+        if line.contains("Time.now") {
+            continue;
+        }
+
+        if let Some((var_name, var_value)) = try_parse_assignment(line) {
+            if is_note_command(&var_value) {
+                checker.check_statement(line_no, &var_value);
+                checker.ctx.variables.insert(var_name, node_ref(0));
+            } else {
+                apply_assignment(&var_name, &var_value, &mut checker.ctx);
+            }
+            continue;
+        }
+
+        checker.check_statement(line_no, line);
+    }
+
+    for (line_no, header) in open {
+        let keyword = header.split_whitespace().next().unwrap_or("do").to_string();
+        checker.report(
+            Severity::Error,
+            line_no,
+            &keyword,
+            &format!("`{}` block is never closed — add a matching `end`", keyword),
+        );
+    }
+
+    checker.diagnostics.sort_by_key(|d| (d.line, d.column));
+    checker.diagnostics
+}
+
+/// Names of every `define :name` / `def name` in the program
+fn collect_function_names(lines: &[(usize, String)]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for (_, line) in lines {
+        if line.starts_with("define") {
+            if let Some(name) = extract_symbol(line) {
+                names.insert(name);
+            }
+        } else if let Some(rest) = line.strip_prefix("def ") {
+            let rest = rest.trim();
+            let end = rest.find('(').or_else(|| rest.find(' ')).unwrap_or(rest.len());
+            names.insert(rest[..end].to_string());
+        }
+    }
+    names
+}
+
+struct Checker<'a> {
+    source: Vec<&'a str>,
+    diagnostics: Vec<Diagnostic>,
+    ctx: ParseContext,
+    functions: HashSet<String>,
+}
+
+impl Checker<'_> {
+    fn report(&mut self, severity: Severity, line: usize, text: &str, hint: &str) {
+        let source = self.source.get(line.saturating_sub(1)).copied().unwrap_or("");
+        let byte_pos = source
+            .find(text)
+            .unwrap_or_else(|| source.len() - source.trim_start().len());
+        self.diagnostics.push(Diagnostic {
+            severity,
+            line,
+            column: source[..byte_pos].chars().count() + 1,
+            text: text.to_string(),
+            hint: hint.to_string(),
+        });
+    }
+
+    fn check_block_header(&mut self, line_no: usize, header: &str) {
+        let keyword = header.split_whitespace().next().unwrap_or("");
+
+        // Bind block and function parameters so the body's lines resolve
+        let params = header
+            .find('|')
+            .map(|start| &header[start + 1..])
+            .and_then(|after| after.find('|').map(|end| &after[..end]))
+            .or_else(|| {
+                let open = header.find('(')?;
+                let close = header.rfind(')')?;
+                (keyword == "def" && close > open).then(|| &header[open + 1..close])
+            });
+        for param in params.unwrap_or("").split(',') {
+            let name = param.split(['=', ':']).next().unwrap_or("").trim();
+            if !name.is_empty() {
+                self.ctx.variables.insert(name.to_string(), "0".to_string());
+            }
+        }
+
+        match keyword {
+            "with_fx" => {
+                if let Some(fx) = extract_symbol(header) {
+                    if fx_synthdef_name(&fx).is_none() {
+                        self.report(
+                            Severity::Warning,
+                            line_no,
+                            &format!(":{}", fx),
+                            &format!("unknown FX :{} — SuperCollider falls back to reverb, the built-in engine ignores it", fx),
+                        );
+                    }
+                }
+            }
+            "with_synth" => self.check_synth(line_no, header),
+            "with_swing" => {
+                self.report(
+                    Severity::Warning,
+                    line_no,
+                    keyword,
+                    "`with_swing` timing parsed but ignored — the block runs once, immediately",
+                );
+            }
+            _ => {}
+        }
+    }
+
+    fn check_statement(&mut self, line_no: usize, line: &str) {
+        // The trailing condition only decides whether the statement runs
+        let stmt = match (find_trailing_if(line), find_trailing_unless(line)) {
+            (Some(pos), _) | (None, Some(pos)) => line[..pos].trim(),
+            (None, None) => line,
+        };
+        let first = stmt.split_whitespace().next().unwrap_or("");
+        let word = first.split('(').next().unwrap_or(first);
+
+        match word {
+            "at" | "time_warp" => {
+                self.report(
+                    Severity::Warning,
+                    line_no,
+                    word,
+                    &format!("`{}` needs a `do ... end` block to run; line ignored", word),
+                );
+                return;
+            }
+            "with_swing" => {
+                self.report(Severity::Warning, line_no, word, "`with_swing` parsed but ignored");
+                return;
+            }
+            "if" | "unless" => {
+                self.report(
+                    Severity::Warning,
+                    line_no,
+                    word,
+                    &format!("`{}` block without `do` or `then` is not supported — add `then` to the end of the line", word),
+                );
+                return;
+            }
+            _ if word.starts_with("midi") => {
+                self.report(Severity::Info, line_no, word, "MIDI output is not supported; ignored");
+                return;
+            }
+            "use_synth" | "synth" => self.check_synth(line_no, stmt),
+            _ => {}
+        }
+
+        if self.functions.contains(word) {
+            return;
+        }
+
+        if parse_line(stmt, &mut self.ctx).is_none() {
+            if KNOWN_COMMANDS.contains(&word) {
+                self.report(
+                    Severity::Warning,
+                    line_no,
+                    stmt,
+                    &format!("could not evaluate the arguments of `{}`; line ignored", word),
+                );
+            } else {
+                self.report(
+                    Severity::Warning,
+                    line_no,
+                    stmt,
+                    &format!("unrecognised statement `{}`; line ignored", word),
+                );
+            }
+        }
+    }
+
+    /// Warn when `use_synth` / `synth` / `with_synth` names an unknown synth
+    fn check_synth(&mut self, line_no: usize, line: &str) {
+        let Some(name) = line.split_whitespace().nth(1) else {
+            return;
+        };
+        let name = name.trim_start_matches(':').trim_end_matches(',');
+        if name == "do" || name.is_empty() || synth_from_name(name).is_some() {
+            return;
+        }
+        self.report(
+            Severity::Warning,
+            line_no,
+            &format!(":{}", name),
+            &format!("unknown synth :{}, falling back to beep", name),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean_code_has_no_diagnostics() {
+        let code = r#"
+use_bpm 120
+define :hit do |amp|
+  sample :bd_haus, amp: amp
+end
+live_loop :beat do
+  with_fx :reverb, mix: 0.3 do
+    hit 1
+    play :c4, release: 0.2 if one_in(2)
+  end
+  use_random_source :pink
+  at [0.25, 0.75], [:e4, :g4] do |n|
+    play n
+  end
+  time_warp -0.1 do
+    sample :drum_cymbal_closed
+  end
+  sleep 0.5
+end
+"#;
+        let diags = check_code(code);
+        assert!(diags.is_empty(), "{:?}", diags);
+    }
+
+    #[test]
+    fn test_unknown_synth_and_ignored_commands() {
+        let code = "use_synth :foo\nlive_loop :a, sync: :go do\n  sync\n  sync :tick\n  play 60\n  sleep 1\nend\n";
+        let diags = check_code(code);
+        assert_eq!(diags.len(), 2, "{:?}", diags);
+
+        assert_eq!(diags[0].line, 1);
+        assert_eq!(diags[0].column, 11);
+        assert_eq!(diags[0].text, ":foo");
+        assert!(diags[0].hint.contains("falling back to beep"));
+
+        assert_eq!(diags[1].severity, Severity::Warning);
+        assert_eq!((diags[1].line, diags[1].column), (3, 3));
+        assert!(diags[1].hint.contains("arguments of `sync`"));
+    }
+
+    #[test]
+    fn test_unrecognised_lines_and_unbalanced_blocks() {
+        let code = "live_loop :a do\n  wobble_it 3\n  sleep 1\n\nend\nend\nin_thread do\n  play 60\n";
+        let diags = check_code(code);
+        assert_eq!(diags.len(), 3, "{:?}", diags);
+        assert_eq!((diags[0].line, diags[0].text.as_str()), (2, "wobble_it 3"));
+        assert!(diags[0].hint.contains("unrecognised"));
+        assert_eq!((diags[1].line, diags[1].severity), (6, Severity::Error));
+        assert_eq!((diags[2].line, diags[2].severity), (7, Severity::Error));
+        assert!(diags[2].hint.contains("never closed"));
+    }
+}
@@ -1,4 +1,6 @@
-use std::f32::consts::PI;
+use std::f32::consts::{FRAC_PI_2, PI};
+
+use super::synth::midi_to_freq_f32;
 
 // ────────────────── Biquad Filter (12 dB/octave) ──────────────────
 
@@ -15,10 +17,15 @@ struct BiquadFilter {
 impl BiquadFilter {
     /// Create a low-pass biquad at the given cutoff frequency with Q = 0.707 (Butterworth).
     fn low_pass(cutoff: f32, sample_rate: f32) -> Self {
+        Self::resonant_low_pass(cutoff, 0.7071, sample_rate)
+    }
+
+    /// Low-pass biquad with resonance `q` (SC's `RLPF` with `rq = 1 / q`)
+    fn resonant_low_pass(cutoff: f32, q: f32, sample_rate: f32) -> Self {
         let omega = 2.0 * PI * cutoff / sample_rate;
         let sin_w = omega.sin();
         let cos_w = omega.cos();
-        let alpha = sin_w / (2.0 * 0.7071);
+        let alpha = sin_w / (2.0 * q);
         let a0 = 1.0 + alpha;
         Self {
             b0: ((1.0 - cos_w) / 2.0) / a0,
@@ -32,10 +39,15 @@ impl BiquadFilter {
 
     /// Create a high-pass biquad at the given cutoff frequency with Q = 0.707.
     fn high_pass(cutoff: f32, sample_rate: f32) -> Self {
+        Self::resonant_high_pass(cutoff, 0.7071, sample_rate)
+    }
+
+    /// High-pass biquad with resonance `q` (SC's `RHPF` with `rq = 1 / q`)
+    fn resonant_high_pass(cutoff: f32, q: f32, sample_rate: f32) -> Self {
         let omega = 2.0 * PI * cutoff / sample_rate;
         let sin_w = omega.sin();
         let cos_w = omega.cos();
-        let alpha = sin_w / (2.0 * 0.7071);
+        let alpha = sin_w / (2.0 * q);
         let a0 = 1.0 + alpha;
         Self {
             b0: ((1.0 + cos_w) / 2.0) / a0,
@@ -55,6 +67,16 @@ impl BiquadFilter {
         *self = Self::high_pass(cutoff, sample_rate);
     }
 
+    /// Take `other`'s coefficients but keep this filter's state, so a
+    /// moving cutoff does not click
+    fn retune(&mut self, other: Self) {
+        self.b0 = other.b0;
+        self.b1 = other.b1;
+        self.b2 = other.b2;
+        self.a1 = other.a1;
+        self.a2 = other.a2;
+    }
+
     fn process(&mut self, input: f32) -> f32 {
         let y = self.b0 * input + self.b1 * self.x1 + self.b2 * self.x2
               - self.a1 * self.y1 - self.a2 * self.y2;
@@ -347,3 +369,549 @@ impl EffectChain {
         (l, r)
     }
 }
+
+// ────────────────── with_fx blocks ──────────────────
+//
+// One DSP per FX synthdef in `sc_synthdefs.rs`, with the same param names
+// and defaults, so a `with_fx` block sounds the same without SuperCollider.
+
+/// `.lag` / `Lag.kr` coefficient for a 60 dB settle in `time` seconds
+fn lag_coefficient(time: f32, sample_rate: f32) -> f32 {
+    if time <= 0.0 {
+        0.0
+    } else {
+        (0.001f32.ln() / (time * sample_rate)).exp()
+    }
+}
+
+/// `CombL` feedback for a 60 dB decay in `decay` seconds; negative decay
+/// gives negative feedback
+fn comb_feedback(delay: f32, decay: f32) -> f32 {
+    if decay == 0.0 {
+        0.0
+    } else {
+        0.001f32.powf(delay / decay.abs()).copysign(decay)
+    }
+}
+
+/// LFO waveforms, as `Select.kr(wave, [...])` indexes them
+#[derive(Clone, Copy)]
+enum Wave {
+    Saw,
+    Pulse,
+    Sine,
+    Tri,
+}
+
+impl Wave {
+    /// Value in -1..1 at `phase` (0..1) through the cycle
+    fn at(self, phase: f32) -> f32 {
+        match self {
+            Wave::Saw => phase * 2.0 - 1.0,
+            Wave::Pulse => if phase < 0.5 { 1.0 } else { -1.0 },
+            Wave::Sine => (phase * 2.0 * PI).sin(),
+            Wave::Tri => {
+                if phase < 0.25 {
+                    phase * 4.0
+                } else if phase < 0.75 {
+                    2.0 - phase * 4.0
+                } else {
+                    phase * 4.0 - 4.0
+                }
+            }
+        }
+    }
+}
+
+/// Free-running LFO phase, one cycle every `period` seconds
+#[derive(Default)]
+struct Lfo {
+    phase: f32,
+}
+
+impl Lfo {
+    fn advance(&mut self, period: f32, sample_rate: f32) {
+        if period > 0.0 {
+            self.phase = (self.phase + 1.0 / (period * sample_rate)).fract();
+        }
+    }
+}
+
+/// `.range(lo, hi)` of an LFO value in -1..1
+fn lfo_range(value: f32, lo: f32, hi: f32) -> f32 {
+    lo + (hi - lo) * (value + 1.0) * 0.5
+}
+
+/// Stereo delay line read at a fractional delay (`CombL`)
+struct CombL {
+    left: Vec<f32>,
+    right: Vec<f32>,
+    write_pos: usize,
+}
+
+impl CombL {
+    fn new(max_delay: f32, sample_rate: f32) -> Self {
+        let len = (max_delay * sample_rate) as usize + 2;
+        Self { left: vec![0.0; len], right: vec![0.0; len], write_pos: 0 }
+    }
+
+    fn process(&mut self, left: f32, right: f32, delay_samples: f32, feedback: f32) -> (f32, f32) {
+        let len = self.left.len();
+        let delay = delay_samples.clamp(1.0, (len - 2) as f32);
+        let whole = delay as usize;
+        let frac = delay - whole as f32;
+        let a = (self.write_pos + len - whole) % len;
+        let b = (a + len - 1) % len;
+        let out_l = self.left[a] * (1.0 - frac) + self.left[b] * frac;
+        let out_r = self.right[a] * (1.0 - frac) + self.right[b] * frac;
+        self.left[self.write_pos] = left + out_l * feedback;
+        self.right[self.write_pos] = right + out_r * feedback;
+        self.write_pos = (self.write_pos + 1) % len;
+        (out_l, out_r)
+    }
+}
+
+/// Freeverb comb with a damping low-pass in its feedback path
+struct DampedComb {
+    buffer: Vec<f32>,
+    write_pos: usize,
+    filter_store: f32,
+}
+
+impl DampedComb {
+    fn new(delay_samples: usize) -> Self {
+        Self { buffer: vec![0.0; delay_samples.max(1)], write_pos: 0, filter_store: 0.0 }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damp: f32) -> f32 {
+        let output = self.buffer[self.write_pos];
+        self.filter_store = output * (1.0 - damp) + self.filter_store * damp;
+        self.buffer[self.write_pos] = input + self.filter_store * feedback;
+        self.write_pos = (self.write_pos + 1) % self.buffer.len();
+        output
+    }
+}
+
+/// `FreeVerb2`: Freeverb's comb and allpass banks, one per channel
+struct FreeVerb {
+    combs: [Vec<DampedComb>; 2],
+    allpasses: [Vec<AllpassFilter>; 2],
+    mix: f32,
+    room: f32,
+    damp: f32,
+}
+
+impl FreeVerb {
+    /// Freeverb's tunings at 44.1 kHz; the right channel is spread by 23
+    const COMBS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+    const ALLPASSES: [usize; 4] = [556, 441, 341, 225];
+    const SPREAD: usize = 23;
+
+    fn new(sample_rate: f32) -> Self {
+        let scale = |n: usize| (n as f32 * sample_rate / 44100.0) as usize;
+        let bank = |spread: usize| -> (Vec<DampedComb>, Vec<AllpassFilter>) {
+            (
+                Self::COMBS.iter().map(|&n| DampedComb::new(scale(n + spread))).collect(),
+                Self::ALLPASSES.iter().map(|&n| AllpassFilter::new(scale(n + spread).max(1), 0.5)).collect(),
+            )
+        };
+        let (combs_l, allpasses_l) = bank(0);
+        let (combs_r, allpasses_r) = bank(Self::SPREAD);
+        Self {
+            combs: [combs_l, combs_r],
+            allpasses: [allpasses_l, allpasses_r],
+            mix: 0.4,
+            room: 0.6,
+            damp: 0.5,
+        }
+    }
+
+    fn set(&mut self, name: &str, value: f32) {
+        match name {
+            "mix" => self.mix = value.clamp(0.0, 1.0),
+            "room" => self.room = value.clamp(0.0, 1.0),
+            "damp" => self.damp = value.clamp(0.0, 1.0),
+            _ => {}
+        }
+    }
+
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let input = (left + right) * 0.015;
+        let feedback = self.room * 0.28 + 0.7;
+        let damp = self.damp * 0.4;
+        let mut wet = [0.0f32; 2];
+        for (ch, out) in wet.iter_mut().enumerate() {
+            let mut sum: f32 = self.combs[ch].iter_mut().map(|c| c.process(input, feedback, damp)).sum();
+            for allpass in self.allpasses[ch].iter_mut() {
+                sum = allpass.process(sum);
+            }
+            *out = sum * 3.0;
+        }
+        (
+            left * (1.0 - self.mix) + wet[0] * self.mix,
+            right * (1.0 - self.mix) + wet[1] * self.mix,
+        )
+    }
+}
+
+/// `Compander` with `slope_below = 1`: gain reduction above `threshold`
+struct Compressor {
+    threshold: f32,
+    slope_above: f32,
+    clamp_time: f32,
+    relax_time: f32,
+    envelope: [f32; 2],
+}
+
+impl Compressor {
+    fn process(&mut self, left: f32, right: f32, sample_rate: f32) -> (f32, f32) {
+        let clamp = lag_coefficient(self.clamp_time, sample_rate);
+        let relax = lag_coefficient(self.relax_time, sample_rate);
+        let mut out = [left, right];
+        for (ch, sample) in out.iter_mut().enumerate() {
+            let level = sample.abs();
+            let env = &mut self.envelope[ch];
+            let coef = if level < *env { relax } else { clamp };
+            *env = level + (*env - level) * coef;
+            if *env > self.threshold && self.threshold > 0.0 {
+                *sample *= (*env / self.threshold).powf(self.slope_above - 1.0);
+            }
+        }
+        (out[0], out[1])
+    }
+}
+
+/// Resonant filter with its cutoff as a MIDI note, like the `lpf` / `hpf`
+/// synthdefs
+struct NoteFilter {
+    high_pass: bool,
+    cutoff: f32,
+    filters: [BiquadFilter; 2],
+}
+
+impl NoteFilter {
+    /// `rq = 0.5` in both synthdefs
+    const Q: f32 = 2.0;
+
+    fn new(high_pass: bool, cutoff: f32, sample_rate: f32) -> Self {
+        let mut filter = Self {
+            high_pass,
+            cutoff,
+            filters: [BiquadFilter::low_pass(1000.0, sample_rate), BiquadFilter::low_pass(1000.0, sample_rate)],
+        };
+        filter.tune(sample_rate);
+        filter
+    }
+
+    fn tune(&mut self, sample_rate: f32) {
+        let hz = midi_to_freq_f32(self.cutoff);
+        let design = if self.high_pass {
+            BiquadFilter::resonant_high_pass(hz.max(20.0), Self::Q, sample_rate)
+        } else {
+            BiquadFilter::resonant_low_pass(hz.min(sample_rate * 0.45), Self::Q, sample_rate)
+        };
+        for filter in self.filters.iter_mut() {
+            filter.retune(design.clone());
+        }
+    }
+
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        (self.filters[0].process(left), self.filters[1].process(right))
+    }
+}
+
+/// The DSP behind one FX synthdef, with its params
+enum FxKind {
+    Reverb(FreeVerb),
+    Slicer { phase: f32, wave: f32, smooth: f32, amp: f32, lfo: Lfo, gain: f32 },
+    Distortion { distort: f32 },
+    Echo { phase: f32, decay: f32, mix: f32, comb: CombL },
+    Filter(NoteFilter),
+    Flanger { phase: f32, depth: f32, feedback: f32, decay: f32, lfo: Lfo, comb: CombL },
+    Compressor(Compressor),
+    Bitcrusher { bits: f32, rate: f32, clock: f32, held: (f32, f32) },
+    Pan { pan: f32 },
+    Wobble { phase: f32, cutoff_min: f32, cutoff_max: f32, res: f32, lfo: Lfo, filters: [BiquadFilter; 2], counter: usize },
+    Tremolo { phase: f32, depth: f32, wave: f32, lfo: Lfo },
+}
+
+/// `Select.kr(wave, waves)`: the index is clipped to the list
+fn select_wave(wave: f32, waves: [Wave; 4]) -> Wave {
+    waves[(wave.max(0.0) as usize).min(3)]
+}
+
+/// The effect of one `with_fx` block in the built-in engine, matching the
+/// SuperCollider FX synthdef of the same name
+pub struct Fx {
+    kind: FxKind,
+    sample_rate: f32,
+}
+
+impl Fx {
+    /// Control-rate block size, as `.kr` UGens update in SuperCollider
+    const CONTROL_BLOCK: usize = 64;
+
+    /// The FX for `fx_type` with the synthdef's defaults, then `params`.
+    /// Unknown FX use reverb, as the SuperCollider engine does.
+    pub fn new(fx_type: &str, params: &[(String, f32)], sample_rate: f32) -> Self {
+        let kind = match fx_type {
+            "slicer" => FxKind::Slicer { phase: 0.25, wave: 0.0, smooth: 0.0, amp: 1.0, lfo: Lfo::default(), gain: 0.0 },
+            "distortion" | "tanh" => FxKind::Distortion { distort: 0.5 },
+            "echo" | "delay" => FxKind::Echo { phase: 0.25, decay: 2.0, mix: 1.0, comb: CombL::new(2.0, sample_rate) },
+            "lpf" | "rlpf" | "nrlpf" => FxKind::Filter(NoteFilter::new(false, 100.0, sample_rate)),
+            "hpf" | "rhpf" | "nrhpf" => FxKind::Filter(NoteFilter::new(true, 0.0, sample_rate)),
+            "flanger" => FxKind::Flanger {
+                phase: 4.0,
+                depth: 5.0,
+                feedback: 0.0,
+                decay: 2.0,
+                lfo: Lfo::default(),
+                comb: CombL::new(0.02, sample_rate),
+            },
+            "compressor" => FxKind::Compressor(Compressor {
+                threshold: 0.2,
+                slope_above: 0.5,
+                clamp_time: 0.01,
+                relax_time: 0.01,
+                envelope: [0.0; 2],
+            }),
+            "bitcrusher" => FxKind::Bitcrusher { bits: 8.0, rate: 8000.0, clock: 1.0, held: (0.0, 0.0) },
+            "pan" => FxKind::Pan { pan: 0.0 },
+            "wobble" => FxKind::Wobble {
+                phase: 0.5,
+                cutoff_min: 60.0,
+                cutoff_max: 120.0,
+                res: 0.3,
+                lfo: Lfo::default(),
+                filters: [BiquadFilter::low_pass(1000.0, sample_rate), BiquadFilter::low_pass(1000.0, sample_rate)],
+                counter: 0,
+            },
+            "tremolo" => FxKind::Tremolo { phase: 4.0, depth: 0.5, wave: 2.0, lfo: Lfo::default() },
+            _ => FxKind::Reverb(FreeVerb::new(sample_rate)),
+        };
+        let mut fx = Self { kind, sample_rate };
+        fx.set_params(params);
+        fx
+    }
+
+    /// Change params while running (the block entered again); params the FX
+    /// does not have are ignored
+    pub fn set_params(&mut self, params: &[(String, f32)]) {
+        let sample_rate = self.sample_rate;
+        for (name, value) in params {
+            let value = *value;
+            match (&mut self.kind, name.as_str()) {
+                (FxKind::Reverb(reverb), name) => reverb.set(name, value),
+                (FxKind::Slicer { phase, .. }, "phase")
+                | (FxKind::Echo { phase, .. }, "phase")
+                | (FxKind::Flanger { phase, .. }, "phase")
+                | (FxKind::Wobble { phase, .. }, "phase")
+                | (FxKind::Tremolo { phase, .. }, "phase") => *phase = value,
+                (FxKind::Slicer { wave, .. }, "wave") | (FxKind::Tremolo { wave, .. }, "wave") => *wave = value,
+                (FxKind::Slicer { smooth, .. }, "smooth") => *smooth = value.max(0.0),
+                (FxKind::Slicer { amp, .. }, "amp") => *amp = value,
+                (FxKind::Distortion { distort }, "distort") => *distort = value,
+                (FxKind::Echo { decay, .. }, "decay") | (FxKind::Flanger { decay, .. }, "decay") => *decay = value,
+                (FxKind::Echo { mix, .. }, "mix") => *mix = value,
+                (FxKind::Filter(filter), "cutoff") => {
+                    filter.cutoff = value;
+                    filter.tune(sample_rate);
+                }
+                (FxKind::Flanger { depth, .. }, "depth") | (FxKind::Tremolo { depth, .. }, "depth") => *depth = value,
+                (FxKind::Flanger { feedback, .. }, "feedback") => *feedback = value,
+                (FxKind::Compressor(c), "threshold") => c.threshold = value,
+                (FxKind::Compressor(c), "clamp_time") => c.clamp_time = value,
+                (FxKind::Compressor(c), "slope_above") => c.slope_above = value,
+                (FxKind::Compressor(c), "relax_time") => c.relax_time = value,
+                (FxKind::Bitcrusher { bits, .. }, "bits") => *bits = value,
+                (FxKind::Bitcrusher { rate, .. }, "sample_rate") => *rate = value.max(0.0),
+                (FxKind::Pan { pan }, "pan") => *pan = value.clamp(-1.0, 1.0),
+                (FxKind::Wobble { cutoff_min, .. }, "cutoff_min") => *cutoff_min = value,
+                (FxKind::Wobble { cutoff_max, .. }, "cutoff_max") => *cutoff_max = value,
+                (FxKind::Wobble { res, .. }, "res") => *res = value,
+                _ => {}
+            }
+        }
+    }
+
+    pub fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let sample_rate = self.sample_rate;
+        match &mut self.kind {
+            FxKind::Reverb(reverb) => reverb.process(left, right),
+            FxKind::Slicer { phase, wave, smooth, amp, lfo, gain } => {
+                let waves = [Wave::Saw, Wave::Pulse, Wave::Sine, Wave::Tri];
+                let shape = select_wave(*wave, waves);
+                // LFSaw starts half way up its ramp (iphase 1)
+                let at = if matches!(shape, Wave::Saw) { (lfo.phase + 0.5).fract() } else { lfo.phase };
+                let target = lfo_range(shape.at(at), 0.0, 1.0);
+                lfo.advance(*phase, sample_rate);
+                let coef = lag_coefficient(*smooth, sample_rate);
+                *gain = target + (*gain - target) * coef;
+                (left * *gain * *amp, right * *gain * *amp)
+            }
+            FxKind::Distortion { distort } => {
+                let drive = 1.0 + *distort * 50.0;
+                let level = 1.0 / (1.0 + *distort);
+                ((left * drive).tanh() * level, (right * drive).tanh() * level)
+            }
+            FxKind::Echo { phase, decay, mix, comb } => {
+                let delay = phase.clamp(0.0, 2.0);
+                let (dl, dr) = comb.process(left, right, delay * sample_rate, comb_feedback(delay, *decay));
+                (left * (1.0 - *mix) + dl * *mix, right * (1.0 - *mix) + dr * *mix)
+            }
+            FxKind::Filter(filter) => filter.process(left, right),
+            FxKind::Flanger { phase, depth, feedback, decay, lfo, comb } => {
+                let delay = lfo_range(Wave::Sine.at(lfo.phase), 0.001, *depth * 0.001).clamp(0.0, 0.02);
+                lfo.advance(*phase, sample_rate);
+                let (dl, dr) = comb.process(left, right, delay * sample_rate, comb_feedback(delay, *decay * *feedback));
+                (left + dl, right + dr)
+            }
+            FxKind::Compressor(compressor) => compressor.process(left, right, sample_rate),
+            FxKind::Bitcrusher { bits, rate, clock, held } => {
+                let step = 2.0f32.powf(1.0 - *bits);
+                let crush = |x: f32| (x / step + 0.5).floor() * step;
+                // Impulse fires on its first sample, then every 1 / rate seconds
+                if *clock >= 1.0 {
+                    *clock -= clock.floor();
+                    *held = (crush(left), crush(right));
+                }
+                *clock += *rate / sample_rate;
+                *held
+            }
+            FxKind::Pan { pan } => {
+                let mono = left + right;
+                let angle = (*pan + 1.0) * 0.5 * FRAC_PI_2;
+                (mono * angle.cos(), mono * angle.sin())
+            }
+            FxKind::Wobble { phase, cutoff_min, cutoff_max, res, lfo, filters, counter } => {
+                if *counter == 0 {
+                    let lo = midi_to_freq_f32(*cutoff_min);
+                    let hi = midi_to_freq_f32(*cutoff_max);
+                    let hz = lfo_range(Wave::Sine.at(lfo.phase), lo, hi).min(sample_rate * 0.45);
+                    let design = BiquadFilter::resonant_low_pass(hz, 1.0 / res.clamp(0.01, 0.99), sample_rate);
+                    for filter in filters.iter_mut() {
+                        filter.retune(design.clone());
+                    }
+                }
+                *counter = (*counter + 1) % Self::CONTROL_BLOCK;
+                lfo.advance(*phase, sample_rate);
+                (filters[0].process(left), filters[1].process(right))
+            }
+            FxKind::Tremolo { phase, depth, wave, lfo } => {
+                let waves = [Wave::Sine, Wave::Saw, Wave::Tri, Wave::Pulse];
+                let gain = lfo_range(select_wave(*wave, waves).at(lfo.phase), 1.0 - *depth, 1.0);
+                lfo.advance(*phase, sample_rate);
+                (left * gain, right * gain)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fx(fx_type: &str, params: &[(&str, f32)], sample_rate: f32) -> Fx {
+        let params: Vec<(String, f32)> = params.iter().map(|&(name, value)| (name.to_string(), value)).collect();
+        Fx::new(fx_type, &params, sample_rate)
+    }
+
+    /// Left channel out for `input` fed to both channels
+    fn run(fx: &mut Fx, input: impl IntoIterator<Item = f32>) -> Vec<f32> {
+        input.into_iter().map(|x| fx.process(x, x).0).collect()
+    }
+
+    fn sine(hz: f32, frames: usize, sample_rate: f32) -> impl Iterator<Item = f32> {
+        (0..frames).map(move |i| (2.0 * PI * hz * i as f32 / sample_rate).sin())
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |peak, x| peak.max(x.abs()))
+    }
+
+    #[test]
+    fn test_silence_stays_silent() {
+        let kinds = [
+            "reverb", "slicer", "distortion", "echo", "lpf", "hpf", "flanger", "compressor", "bitcrusher", "pan",
+            "wobble", "tremolo",
+        ];
+        for kind in kinds {
+            let out = run(&mut fx(kind, &[], 8000.0), std::iter::repeat_n(0.0, 8000));
+            assert_eq!(peak(&out), 0.0, "{}", kind);
+        }
+    }
+
+    #[test]
+    fn test_echo_repeats_after_its_phase() {
+        let mut echo = fx("echo", &[("phase", 0.25)], 1000.0);
+        let out = run(&mut echo, std::iter::once(1.0).chain(std::iter::repeat_n(0.0, 600)));
+        assert_eq!(out[0], 0.0);
+        assert!((out[250] - 1.0).abs() < 1e-6);
+        // Each repeat loses 60 dB over `decay` seconds
+        assert!((out[500] - comb_feedback(0.25, 2.0)).abs() < 1e-6);
+        assert_eq!(peak(&out[251..500]), 0.0);
+    }
+
+    #[test]
+    fn test_distortion_is_bounded() {
+        let mut distortion = fx("distortion", &[("distort", 0.9)], 1000.0);
+        let out = run(&mut distortion, (-100..=100).map(|i| i as f32 * 0.1));
+        assert!(peak(&out) <= 1.0 / 1.9 + 1e-6);
+    }
+
+    #[test]
+    fn test_filters_follow_their_cutoff_note() {
+        let sr = 44100.0;
+        // Cutoff at note 60, about 262 Hz
+        let low = |hz| peak(&run(&mut fx("lpf", &[("cutoff", 60.0)], sr), sine(hz, 8820, sr))[4410..]);
+        let high = |hz| peak(&run(&mut fx("hpf", &[("cutoff", 60.0)], sr), sine(hz, 8820, sr))[4410..]);
+        assert!(low(50.0) > 0.9);
+        assert!(low(5000.0) < 0.01);
+        assert!(high(50.0) < 0.05);
+        assert!(high(5000.0) > 0.9);
+    }
+
+    #[test]
+    fn test_pan_is_equal_power() {
+        let mut left = fx("pan", &[("pan", -1.0)], 1000.0);
+        assert_eq!(left.process(0.25, 0.25), (0.5, 0.0));
+        let mut centre = fx("pan", &[], 1000.0);
+        let (l, r) = centre.process(0.25, 0.25);
+        assert!((l - 0.5 * std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6 && (r - l).abs() < 1e-6);
+        let mut right = fx("pan", &[("pan", 2.0)], 1000.0);
+        let (l, r) = right.process(0.25, 0.25);
+        assert!(l.abs() < 1e-6 && (r - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_bitcrusher_holds_and_quantises() {
+        let mut crusher = fx("bitcrusher", &[("bits", 3.0), ("sample_rate", 250.0)], 1000.0);
+        let out = run(&mut crusher, (0..8).map(|i| i as f32 * 0.1));
+        // 3 bits: steps of 0.25, a new value every 4 samples
+        assert_eq!(out, vec![0.0, 0.0, 0.0, 0.0, 0.5, 0.5, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_slicer_pulse_gates_half_of_each_phase() {
+        let mut slicer = fx("slicer", &[("phase", 0.25), ("wave", 1.0)], 1000.0);
+        let out = run(&mut slicer, std::iter::repeat_n(1.0, 500));
+        assert!(out[..125].iter().all(|&x| x == 1.0));
+        assert!(out[125..250].iter().all(|&x| x == 0.0));
+        assert_eq!(out[250], 1.0);
+    }
+
+    #[test]
+    fn test_tremolo_without_depth_is_unity() {
+        let mut tremolo = fx("tremolo", &[("depth", 0.0)], 1000.0);
+        let out = run(&mut tremolo, std::iter::repeat_n(0.5, 1000));
+        assert!(out.iter().all(|&x| x == 0.5));
+    }
+
+    #[test]
+    fn test_unknown_params_are_ignored() {
+        let mut plain = fx("echo", &[], 1000.0);
+        let mut other = fx("echo", &[("cutoff", 10.0), ("room", 1.0)], 1000.0);
+        let input: Vec<f32> = sine(30.0, 1000, 1000.0).collect();
+        assert_eq!(run(&mut plain, input.clone()), run(&mut other, input));
+    }
+}
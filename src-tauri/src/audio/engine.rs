@@ -2,11 +2,17 @@ use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{SampleFormat, StreamConfig};
 use crossbeam_channel::{bounded, Receiver, Sender};
 use parking_lot::Mutex;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::f32::consts::FRAC_PI_4;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use super::effects::EffectChain;
+use super::effects::{EffectChain, Fx};
+use super::master::{Limiter, Meter, MeterReading};
 use super::recorder::Recorder;
-use super::synth::{Envelope, OscillatorType, SynthVoice};
+use super::synth::{Envelope, OscillatorType, Slide, SynthVoice};
 
 /// Messages sent from the main thread to the audio thread
 #[derive(Debug, Clone)]
@@ -15,22 +21,41 @@ pub enum AudioCommand {
         synth_type: OscillatorType,
         frequency: f32,
         amplitude: f32,
-        duration_secs: f32,
+        /// The note's envelope, which also sets how long it lasts
         envelope: Envelope,
         pan: f32,
         /// Synth-specific parameters (cutoff, res, detune, depth, etc.)
         /// forwarded to SuperCollider as named OSC args.
         params: Vec<(String, f32)>,
+        /// Handle for later `ControlNote`s, when the code keeps a reference
+        /// (`s = play ...`)
+        node: Option<u32>,
+        /// `with_fx` block the note plays into (`None` = straight to the output)
+        fx: Option<u32>,
+    },
+    /// Change parameters of a sounding note (`control s, note: 64`).
+    /// `*_slide` entries set how long each change glides.
+    ControlNote {
+        node: u32,
+        params: Vec<(String, f32)>,
     },
     PlaySample {
+        /// Interleaved when stereo
         samples: Vec<f32>,
+        /// 1 or 2. `pan` places mono samples and balances stereo ones.
+        channels: u16,
         sample_rate: u32,
         amplitude: f32,
         rate: f32,
         pan: f32,
+        /// `with_fx` block the sample plays into (`None` = straight to the output)
+        fx: Option<u32>,
     },
     SetBpm(f32),
     SetMasterVolume(f32),
+    /// Most notes and samples that sound at once; past it the quietest
+    /// (then oldest) is faded out to make room
+    SetMaxVoices(usize),
     Stop,
     SetEffect {
         reverb_mix: f32,
@@ -40,15 +65,18 @@ pub enum AudioCommand {
         lpf_cutoff: f32,
         hpf_cutoff: f32,
     },
-    /// Start an FX block — allocates an audio bus and creates the FX synth.
-    /// All subsequent PlayNote/PlaySample commands route through this FX
-    /// until the matching FxEnd.
+    /// Enter `with_fx` block `id`, nested inside block `parent`. The first
+    /// start of an id allocates its audio bus and creates the FX synth; later
+    /// ones (the same block on the next loop iteration) update its params.
+    /// Notes and samples naming the id play through it.
     FxStart {
+        id: u32,
+        parent: Option<u32>,
         fx_type: String,
         params: Vec<(String, f32)>,
     },
-    /// End the current FX block — frees the FX synth, restores output bus.
-    FxEnd,
+    /// Block `id` will not run again: free its FX once its tail has rung out
+    FxFree { id: u32 },
 }
 
 /// Shared audio state for waveform visualization
@@ -57,7 +85,14 @@ pub struct AudioState {
     pub is_playing: bool,
     pub master_volume: f32,
     pub bpm: f32,
-    pub sample_rate: u32,
+    /// Notes and samples sounding at the end of the last audio buffer
+    pub voice_count: usize,
+    /// Notes and samples cut short to stay under the voice limit since start
+    pub stolen_voices: u64,
+    /// Commands dropped since start because the timed queue was full
+    pub dropped_commands: u64,
+    /// Master bus levels at the end of the last audio buffer
+    pub meter: MeterReading,
 }
 
 impl Default for AudioState {
@@ -67,14 +102,72 @@ impl Default for AudioState {
             is_playing: false,
             master_volume: 1.0,
             bpm: 120.0,
-            sample_rate: 44100,
+            voice_count: 0,
+            stolen_voices: 0,
+            dropped_commands: 0,
+            meter: MeterReading::default(),
         }
     }
 }
 
+/// Beat count shared by every run, so code run while something is playing
+/// can start on the beat
+#[derive(Debug, Clone, Copy)]
+pub struct BeatClock {
+    anchor: Instant,
+    /// Beat count at `anchor`
+    anchor_beat: f64,
+    bpm: f32,
+}
+
+impl BeatClock {
+    pub fn new(bpm: f32) -> Self {
+        Self { anchor: Instant::now(), anchor_beat: 0.0, bpm }
+    }
+
+    /// Count from beat 0 at `origin`
+    pub fn restart(&mut self, origin: Instant, bpm: f32) {
+        *self = Self { anchor: origin, anchor_beat: 0.0, bpm };
+    }
+
+    /// Change tempo from `at` on, keeping the beat count continuous
+    pub fn set_bpm(&mut self, bpm: f32, at: Instant) {
+        if bpm > 0.0 {
+            *self = Self { anchor: at, anchor_beat: self.beat_at(at), bpm };
+        }
+    }
+
+    pub fn beat_at(&self, at: Instant) -> f64 {
+        let secs = if at >= self.anchor {
+            at.duration_since(self.anchor).as_secs_f64()
+        } else {
+            -self.anchor.duration_since(at).as_secs_f64()
+        };
+        self.anchor_beat + secs * self.bpm as f64 / 60.0
+    }
+
+    /// First instant at or after `after` on a multiple of `quantum` beats
+    /// (`after` itself when `quantum` is zero)
+    pub fn next_boundary(&self, after: Instant, quantum: f64) -> Instant {
+        if quantum <= 0.0 {
+            return after;
+        }
+        let beat = self.beat_at(after);
+        let target = (beat / quantum).ceil() * quantum;
+        after + Duration::from_secs_f64((target - beat).max(0.0) * 60.0 / self.bpm as f64)
+    }
+}
+
 pub struct AudioEngine {
     pub state: Arc<Mutex<AudioState>>,
-    command_tx: Sender<AudioCommand>,
+    /// Beat clock for launch quantisation, on both this engine and SuperCollider
+    pub clock: Mutex<BeatClock>,
+    /// Maps wall-clock time to output frames for timed commands
+    frame_clock: Arc<FrameClock>,
+    sample_rate: u32,
+    command_tx: Sender<TimedCommand>,
+    /// Effects the audio thread is done with, dropped here on the next send
+    freed_fx: Receiver<Box<Fx>>,
     _stream: Mutex<Option<cpal::Stream>>,
 }
 
@@ -83,21 +176,670 @@ pub struct AudioEngine {
 unsafe impl Send for AudioEngine {}
 unsafe impl Sync for AudioEngine {}
 
+/// What the mixer applies: an `AudioCommand`, with a `with_fx` block's
+/// effect already built, since that allocates its delay lines and the audio
+/// thread must not
+enum MixerCommand {
+    Audio(AudioCommand),
+    FxStart {
+        id: u32,
+        parent: Option<u32>,
+        fx: Box<Fx>,
+        params: Vec<(String, f32)>,
+    },
+}
+
+impl MixerCommand {
+    fn new(cmd: AudioCommand, sample_rate: u32) -> Self {
+        match cmd {
+            AudioCommand::FxStart { id, parent, fx_type, params } => MixerCommand::FxStart {
+                id,
+                parent,
+                fx: Box::new(Fx::new(&fx_type, &params, sample_rate as f32)),
+                params,
+            },
+            cmd => MixerCommand::Audio(cmd),
+        }
+    }
+}
+
+/// Command stamped with the output frame it takes effect on (0 = at once)
+struct TimedCommand {
+    frame: u64,
+    command: MixerCommand,
+}
+
+/// Where the output stream is in time. The audio callback reports how many
+/// frames it has rendered; a smoothed offset against the wall clock turns an
+/// `Instant` into a frame number without callback jitter leaking in.
+struct FrameClock {
+    origin: Instant,
+    sample_rate: f64,
+    /// Rendered frames minus frames elapsed on the wall clock (f64 bits)
+    offset: AtomicU64,
+    /// Set once the first callback has reported in
+    started: AtomicBool,
+}
+
+impl FrameClock {
+    /// Weight of each new observation in the smoothed offset
+    const SMOOTHING: f64 = 0.05;
+
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            origin: Instant::now(),
+            sample_rate: sample_rate as f64,
+            offset: AtomicU64::new(0f64.to_bits()),
+            started: AtomicBool::new(false),
+        }
+    }
+
+    /// Called from the audio callback with the frames rendered so far
+    fn observe(&self, rendered: u64) {
+        let measured = rendered as f64 - self.origin.elapsed().as_secs_f64() * self.sample_rate;
+        let offset = if self.started.swap(true, Ordering::Relaxed) {
+            let previous = f64::from_bits(self.offset.load(Ordering::Relaxed));
+            previous + (measured - previous) * Self::SMOOTHING
+        } else {
+            measured
+        };
+        self.offset.store(offset.to_bits(), Ordering::Relaxed);
+    }
+
+    fn frame_at(&self, at: Instant) -> u64 {
+        let secs = at.saturating_duration_since(self.origin).as_secs_f64();
+        let offset = f64::from_bits(self.offset.load(Ordering::Relaxed));
+        // Frame 0 means "now", so a timed command never uses it
+        (secs * self.sample_rate + offset).max(1.0) as u64
+    }
+}
+
+/// Queued command, ordered by frame and then by arrival
+struct Queued {
+    frame: u64,
+    seq: u64,
+    command: MixerCommand,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        (self.frame, self.seq) == (other.frame, other.seq)
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    /// Reversed, so the `BinaryHeap` pops the earliest command first
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (other.frame, other.seq).cmp(&(self.frame, self.seq))
+    }
+}
+
+/// How long a finished `with_fx` block keeps processing so reverb and echo
+/// tails ring out before it is dropped
+const FX_TAIL_SECS: f32 = 4.0;
+/// Most `with_fx` blocks running at once; the buses are allocated up front,
+/// and blocks past this play straight to their parent
+const FX_BUS_POOL_SIZE: usize = 64;
+/// Stereo frames the recording is handed over in. Callback buffers are
+/// usually smaller; a bigger one is handed over a block at a time.
+const RECORD_BLOCK_FRAMES: usize = 4096;
+/// Room for effects on their way back from the audio thread to be dropped
+const FREED_FX_CAPACITY: usize = 4096;
+
+/// Voice limit until `SetMaxVoices` changes it
+const DEFAULT_MAX_VOICES: usize = 64;
+/// Highest voice limit; the pools are allocated for this many up front
+const VOICE_POOL_SIZE: usize = 256;
+/// Most commands waiting for their frame; the queue is allocated for this
+/// many up front and drops commands past it
+const COMMAND_QUEUE_SIZE: usize = VOICE_POOL_SIZE * 16;
+/// How long a stolen voice takes to fade out
+const STEAL_FADE_SECS: f32 = 0.005;
+
 struct Voice {
     synth: SynthVoice,
     samples_elapsed: u64,
     duration_samples: u64,
-    pan: f32,
+    pan: Slide,
+    node: Option<u32>,
+    fx: Option<u32>,
+    /// Envelope times amplitude on the last frame, for voice stealing
+    level: f32,
+    /// Frames left of the fade-out once the voice has been stolen
+    fade: Option<u32>,
 }
 
 struct SamplePlayback {
+    /// Interleaved when stereo
     data: Vec<f32>,
+    channels: usize,
+    /// In frames
     position: f64,
     /// Effective playback rate combining user rate and sample-rate-conversion ratio
     rate: f64,
     amplitude: f32,
     pan: f32,
+    fx: Option<u32>,
     done: bool,
+    /// Frame the sample started on, for voice stealing
+    started: u64,
+    /// Frames left of the fade-out once the sample has been stolen
+    fade: Option<u32>,
+}
+
+/// Gain of a fading voice with `fade` frames left, counting the frame down
+fn fade_gain(fade: &mut Option<u32>, fade_frames: u32) -> f32 {
+    match fade {
+        Some(left) => {
+            *left = left.saturating_sub(1);
+            *left as f32 / fade_frames as f32
+        }
+        None => 1.0,
+    }
+}
+
+/// Left and right gains of a stereo sample panned to `pan` (-1 to 1), as
+/// `Balance2` sets them on SuperCollider: equal power, so both channels are
+/// 3 dB down in the centre
+fn balance_gains(pan: f32) -> (f32, f32) {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * FRAC_PI_4;
+    (angle.cos(), angle.sin())
+}
+
+/// Channel `channel` of interleaved `data` at frame `idx + frac`, with cubic
+/// Hermite interpolation for smooth playback. `idx + 1` must be a frame.
+fn interpolate(data: &[f32], channels: usize, channel: usize, idx: usize, frac: f32) -> f32 {
+    let at = |frame: usize| data[frame * channels + channel];
+    let frames = data.len() / channels;
+    if idx >= 1 && idx + 2 < frames {
+        let y0 = at(idx - 1);
+        let y1 = at(idx);
+        let y2 = at(idx + 1);
+        let y3 = at(idx + 2);
+        let c0 = y1;
+        let c1 = 0.5 * (y2 - y0);
+        let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+        let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+        ((c3 * frac + c2) * frac + c1) * frac + c0
+    } else {
+        // Fall back to linear at boundaries
+        at(idx) * (1.0 - frac) + at(idx + 1) * frac
+    }
+}
+
+/// A `with_fx` block's bus: the sound played into it this frame, and the
+/// effect it goes through on its way to the parent block or the output
+struct FxBus {
+    id: u32,
+    parent: Option<u32>,
+    fx: Box<Fx>,
+    left: f32,
+    right: f32,
+    /// Frame after which the block is dropped, once it has been freed
+    free_at: Option<u64>,
+}
+
+/// Everything the audio callback renders: voices, samples, effects and the
+/// queue of timed commands, applied on the exact frame they are stamped with
+struct Mixer {
+    sample_rate: u32,
+    state: Arc<Mutex<AudioState>>,
+    recorder: Recorder,
+    /// Interleaved stereo of the current buffer, while recording. Allocated
+    /// up front and never grown on the audio thread.
+    record_block: Vec<f32>,
+    voices: Vec<Voice>,
+    sample_playbacks: Vec<SamplePlayback>,
+    /// Most voices and samples sounding at once, not counting fading ones
+    max_voices: usize,
+    stolen_voices: u64,
+    master_volume: f32,
+    effect_chain: EffectChain,
+    limiter: Limiter,
+    meter: Meter,
+    /// Running `with_fx` blocks, by id. A nested block always has a higher
+    /// id than its parent, so rendering from the back runs children first.
+    fx_buses: Vec<FxBus>,
+    /// Where effects go once no bus uses them, so their buffers are freed
+    /// off the audio thread
+    freed_fx: Sender<Box<Fx>>,
+    waveform_write_pos: usize,
+    queue: BinaryHeap<Queued>,
+    dropped_commands: u64,
+    next_seq: u64,
+    /// Frames rendered so far
+    position: u64,
+}
+
+impl Mixer {
+    fn new(
+        sample_rate: u32,
+        state: Arc<Mutex<AudioState>>,
+        recorder: Recorder,
+        freed_fx: Sender<Box<Fx>>,
+    ) -> Self {
+        Self {
+            sample_rate,
+            state,
+            recorder,
+            record_block: Vec::with_capacity(RECORD_BLOCK_FRAMES * 2),
+            voices: Vec::with_capacity(VOICE_POOL_SIZE * 2),
+            sample_playbacks: Vec::with_capacity(VOICE_POOL_SIZE * 2),
+            max_voices: DEFAULT_MAX_VOICES,
+            stolen_voices: 0,
+            master_volume: 1.0,
+            effect_chain: EffectChain::new(sample_rate as f32),
+            limiter: Limiter::new(sample_rate as f32),
+            meter: Meter::new(sample_rate),
+            fx_buses: Vec::with_capacity(FX_BUS_POOL_SIZE),
+            freed_fx,
+            waveform_write_pos: 0,
+            queue: BinaryHeap::with_capacity(COMMAND_QUEUE_SIZE),
+            dropped_commands: 0,
+            next_seq: 0,
+            position: 0,
+        }
+    }
+
+    fn queue(&mut self, cmd: TimedCommand) {
+        // Stop also drops everything scheduled after it
+        if matches!(cmd.command, MixerCommand::Audio(AudioCommand::Stop)) {
+            self.queue.clear();
+        }
+        if self.queue.len() >= COMMAND_QUEUE_SIZE {
+            // Growing the heap would allocate on the audio thread
+            self.dropped_commands += 1;
+            return;
+        }
+        self.queue.push(Queued { frame: cmd.frame, seq: self.next_seq, command: cmd.command });
+        self.next_seq += 1;
+    }
+
+    fn apply(&mut self, cmd: AudioCommand) {
+        let sample_rate = self.sample_rate;
+        match cmd {
+            AudioCommand::PlayNote {
+                synth_type,
+                frequency,
+                amplitude,
+                envelope,
+                pan,
+                params,
+                node,
+                fx,
+            } => {
+                let mut voice = SynthVoice::new(
+                    synth_type,
+                    frequency,
+                    amplitude,
+                    sample_rate as f32,
+                    envelope,
+                );
+                voice.set_params(&params);
+                let slides: Vec<(String, f32)> =
+                    params.into_iter().filter(|(n, _)| n.ends_with("_slide")).collect();
+                voice.control(&slides);
+                let mut pan = Slide::new(pan);
+                pan.apply("pan", &slides, sample_rate as f32);
+                self.make_room();
+                if self.voices.len() == self.voices.capacity() {
+                    Self::drop_faded(&mut self.voices, |v| v.fade);
+                }
+                self.voices.push(Voice {
+                    synth: voice,
+                    samples_elapsed: 0,
+                    duration_samples: (envelope.duration() * sample_rate as f32) as u64,
+                    pan,
+                    node,
+                    fx,
+                    level: amplitude,
+                    fade: None,
+                });
+            }
+            AudioCommand::ControlNote { node, params } => {
+                for voice in self.voices.iter_mut().filter(|v| v.node == Some(node)) {
+                    voice.synth.control(&params);
+                    voice.pan.apply("pan", &params, sample_rate as f32);
+                }
+            }
+            AudioCommand::PlaySample {
+                samples,
+                channels,
+                sample_rate: file_sr,
+                amplitude,
+                rate,
+                pan,
+                fx,
+            } => {
+                // Combine user rate with sample-rate-conversion ratio
+                // so samples recorded at any SR play at correct pitch/speed
+                let sr_ratio = file_sr as f64 / sample_rate as f64;
+                let effective_rate = rate as f64 * sr_ratio;
+                self.make_room();
+                if self.sample_playbacks.len() == self.sample_playbacks.capacity() {
+                    Self::drop_faded(&mut self.sample_playbacks, |sp| sp.fade);
+                }
+                self.sample_playbacks.push(SamplePlayback {
+                    data: samples,
+                    channels: channels.clamp(1, 2) as usize,
+                    position: 0.0_f64,
+                    rate: effective_rate,
+                    amplitude,
+                    pan,
+                    fx,
+                    done: false,
+                    started: self.position,
+                    fade: None,
+                });
+            }
+            AudioCommand::SetBpm(bpm) => {
+                let mut s = self.state.lock();
+                s.bpm = bpm;
+            }
+            AudioCommand::SetMasterVolume(vol) => {
+                self.master_volume = vol;
+                let mut s = self.state.lock();
+                s.master_volume = vol;
+            }
+            AudioCommand::SetMaxVoices(max) => {
+                self.max_voices = max.clamp(1, VOICE_POOL_SIZE);
+            }
+            AudioCommand::Stop => {
+                self.voices.clear();
+                self.sample_playbacks.clear();
+                for bus in self.fx_buses.drain(..) {
+                    let _ = self.freed_fx.try_send(bus.fx);
+                }
+                let mut s = self.state.lock();
+                s.is_playing = false;
+            }
+            AudioCommand::SetEffect {
+                reverb_mix,
+                delay_time,
+                delay_feedback,
+                distortion,
+                lpf_cutoff,
+                hpf_cutoff,
+            } => {
+                self.effect_chain.set_reverb_mix(reverb_mix);
+                self.effect_chain.set_delay(delay_time, delay_feedback);
+                self.effect_chain.set_distortion(distortion);
+                self.effect_chain.set_lpf(lpf_cutoff);
+                self.effect_chain.set_hpf(hpf_cutoff);
+            }
+            // Arrives as `MixerCommand::FxStart`, with its effect built
+            AudioCommand::FxStart { .. } => {}
+            AudioCommand::FxFree { id } => {
+                if let Some(bus) = self.fx_buses.iter_mut().find(|bus| bus.id == id) {
+                    bus.free_at = Some(self.position + (FX_TAIL_SECS * sample_rate as f32) as u64);
+                }
+            }
+        }
+    }
+
+    /// Enter `with_fx` block `id`: the first start takes `fx` as its bus's
+    /// effect, later ones only update the params of the running one
+    fn start_fx(&mut self, id: u32, parent: Option<u32>, fx: Box<Fx>, params: &[(String, f32)]) {
+        match self.fx_buses.binary_search_by_key(&id, |bus| bus.id) {
+            Ok(index) => {
+                self.fx_buses[index].fx.set_params(params);
+                self.free_fx(fx);
+            }
+            Err(_) if self.fx_buses.len() >= FX_BUS_POOL_SIZE => self.free_fx(fx),
+            Err(index) => self.fx_buses.insert(index, FxBus {
+                id,
+                parent,
+                fx,
+                left: 0.0,
+                right: 0.0,
+                free_at: None,
+            }),
+        }
+    }
+
+    /// Hand `fx` back to the sending side to drop. Only if the channel is
+    /// full (or gone) is it dropped here.
+    fn free_fx(&self, fx: Box<Fx>) {
+        let _ = self.freed_fx.try_send(fx);
+    }
+
+    /// Start fading out voices until a new one fits under the limit. The
+    /// quietest voice goes first, and of equally quiet ones the oldest.
+    fn make_room(&mut self) {
+        let sounding = self.voices.iter().filter(|v| v.fade.is_none()).count()
+            + self.sample_playbacks.iter().filter(|sp| sp.fade.is_none() && !sp.done).count();
+        let fade_frames = self.fade_frames();
+        for _ in self.max_voices..=sounding {
+            let position = self.position;
+            let voice = self
+                .voices
+                .iter_mut()
+                .enumerate()
+                .filter(|(_, v)| v.fade.is_none())
+                .map(|(i, v)| (false, i, v.level, v.samples_elapsed))
+                .min_by(|a, b| a.2.total_cmp(&b.2).then(b.3.cmp(&a.3)));
+            let sample = self
+                .sample_playbacks
+                .iter()
+                .enumerate()
+                .filter(|(_, sp)| sp.fade.is_none() && !sp.done)
+                .map(|(i, sp)| (true, i, sp.amplitude, position - sp.started))
+                .min_by(|a, b| a.2.total_cmp(&b.2).then(b.3.cmp(&a.3)));
+            let victim = match (voice, sample) {
+                (Some(v), Some(s)) => Some(if s.2.total_cmp(&v.2).then(v.3.cmp(&s.3)).is_lt() { s } else { v }),
+                (v, s) => v.or(s),
+            };
+            match victim {
+                Some((false, index, ..)) => self.voices[index].fade = Some(fade_frames),
+                Some((true, index, ..)) => self.sample_playbacks[index].fade = Some(fade_frames),
+                None => break,
+            }
+            self.stolen_voices += 1;
+        }
+    }
+
+    fn fade_frames(&self) -> u32 {
+        ((STEAL_FADE_SECS * self.sample_rate as f32) as u32).max(1)
+    }
+
+    /// Make space in a full pool by dropping the fading entry closest to
+    /// silence, so the pool never grows past what it was allocated with
+    fn drop_faded<T>(pool: &mut Vec<T>, fade: impl Fn(&T) -> Option<u32>) {
+        let nearest = pool
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entry)| fade(entry).map(|left| (i, left)))
+            .min_by_key(|&(_, left)| left);
+        let index = nearest.map_or(0, |(i, _)| i);
+        pool.swap_remove(index);
+    }
+
+    /// Add a stereo frame to block `fx`'s bus, or to `master` if there is no
+    /// such block (any more)
+    fn send(buses: &mut [FxBus], fx: Option<u32>, left: f32, right: f32, master: &mut (f32, f32)) {
+        let bus = fx.and_then(|id| buses.binary_search_by_key(&id, |bus| bus.id).ok());
+        match bus {
+            Some(index) => {
+                buses[index].left += left;
+                buses[index].right += right;
+            }
+            None => {
+                master.0 += left;
+                master.1 += right;
+            }
+        }
+    }
+
+    /// Fill an interleaved output buffer, applying each queued command on its frame
+    fn render(&mut self, data: &mut [f32], channels: usize) {
+        let frames = data.len() / channels;
+        let fade_frames = self.fade_frames();
+        let recording = self.recorder.is_recording();
+        for frame in 0..frames {
+            while self.queue.peek().is_some_and(|q| q.frame <= self.position) {
+                if let Some(queued) = self.queue.pop() {
+                    match queued.command {
+                        MixerCommand::Audio(cmd) => self.apply(cmd),
+                        MixerCommand::FxStart { id, parent, fx, params } => {
+                            self.start_fx(id, parent, fx, &params)
+                        }
+                    }
+                }
+            }
+            self.position += 1;
+
+            let mut master = (0.0f32, 0.0f32);
+
+            // Mix synth voices
+            for voice in self.voices.iter_mut() {
+                if voice.samples_elapsed < voice.duration_samples {
+                    let sample = voice.synth.next_sample();
+                    let env = voice.synth.envelope_value(voice.samples_elapsed);
+                    voice.level = env.abs() * voice.synth.amplitude();
+                    let s = sample * env * fade_gain(&mut voice.fade, fade_frames);
+                    if voice.fade == Some(0) {
+                        voice.samples_elapsed = voice.duration_samples;
+                    }
+                    let pan = voice.pan.next();
+                    let l_gain = ((1.0 - pan) * 0.5 + 0.5).min(1.0);
+                    let r_gain = ((1.0 + pan) * 0.5 + 0.5).min(1.0);
+                    Self::send(&mut self.fx_buses, voice.fx, s * l_gain, s * r_gain, &mut master);
+                    voice.samples_elapsed += 1;
+                }
+            }
+
+            // Mix sample playbacks (with cubic Hermite interpolation)
+            for sp in self.sample_playbacks.iter_mut() {
+                if !sp.done {
+                    let idx = sp.position as usize;
+                    let frames = sp.data.len() / sp.channels;
+                    if idx + 1 < frames {
+                        let frac = (sp.position - idx as f64) as f32;
+                        let gain = sp.amplitude * fade_gain(&mut sp.fade, fade_frames);
+                        if sp.fade == Some(0) {
+                            sp.done = true;
+                        }
+                        let (l, r) = if sp.channels == 2 {
+                            let (l_gain, r_gain) = balance_gains(sp.pan);
+                            let l = interpolate(&sp.data, 2, 0, idx, frac) * gain;
+                            let r = interpolate(&sp.data, 2, 1, idx, frac) * gain;
+                            (l * l_gain, r * r_gain)
+                        } else {
+                            let s = interpolate(&sp.data, 1, 0, idx, frac) * gain;
+                            let l_gain = ((1.0 - sp.pan) * 0.5 + 0.5).min(1.0);
+                            let r_gain = ((1.0 + sp.pan) * 0.5 + 0.5).min(1.0);
+                            (s * l_gain, s * r_gain)
+                        };
+                        Self::send(&mut self.fx_buses, sp.fx, l, r, &mut master);
+                        sp.position += sp.rate;
+                    } else {
+                        sp.done = true;
+                    }
+                }
+            }
+
+            // Run each with_fx block, innermost first, into its parent
+            for index in (0..self.fx_buses.len()).rev() {
+                let bus = &mut self.fx_buses[index];
+                let (l, r) = bus.fx.process(bus.left, bus.right);
+                bus.left = 0.0;
+                bus.right = 0.0;
+                let parent = bus.parent;
+                Self::send(&mut self.fx_buses[..index], parent, l, r, &mut master);
+            }
+
+            // Apply effects
+            let (proc_l, proc_r) = self.effect_chain.process(master.0, master.1);
+            let (left, right) =
+                self.limiter.process(proc_l * self.master_volume, proc_r * self.master_volume);
+            self.meter.process(left, right, self.limiter.gain());
+
+            // Write to output
+            for ch in 0..channels {
+                data[frame * channels + ch] = if ch % 2 == 0 { left } else { right };
+            }
+
+            if recording {
+                if self.record_block.len() + 2 > self.record_block.capacity() {
+                    self.recorder.push_samples(&self.record_block);
+                    self.record_block.clear();
+                }
+                self.record_block.extend_from_slice(&[left, right]);
+            }
+
+            // Write to waveform buffer
+            {
+                let mono_sample = (left + right) * 0.5;
+                let mut s = self.state.lock();
+                let len = s.waveform_buffer.len();
+                s.waveform_buffer[self.waveform_write_pos % len] = mono_sample;
+                self.waveform_write_pos += 1;
+                s.is_playing = !self.voices.is_empty() || self.sample_playbacks.iter().any(|sp| !sp.done);
+            }
+        }
+
+        if recording {
+            self.recorder.push_samples(&self.record_block);
+            self.record_block.clear();
+        }
+
+        // Remove finished voices and samples
+        self.voices.retain(|v| v.samples_elapsed < v.duration_samples);
+        self.sample_playbacks.retain(|sp| !sp.done);
+        {
+            let mut s = self.state.lock();
+            s.voice_count = self.voices.len() + self.sample_playbacks.len();
+            s.stolen_voices = self.stolen_voices;
+            s.dropped_commands = self.dropped_commands;
+            s.meter = self.meter.reading();
+        }
+        let position = self.position;
+        let done = |bus: &mut FxBus| bus.free_at.is_some_and(|frame| frame <= position);
+        for bus in self.fx_buses.extract_if(.., done) {
+            let _ = self.freed_fx.try_send(bus.fx);
+        }
+    }
+}
+
+/// The built-in engine's mixing without an audio device: commands are
+/// stamped with output frames and rendered as fast as the CPU allows
+pub struct OfflineEngine {
+    mixer: Mixer,
+    freed_fx: Receiver<Box<Fx>>,
+}
+
+impl OfflineEngine {
+    pub fn new(sample_rate: u32) -> Self {
+        let state = Arc::new(Mutex::new(AudioState::default()));
+        let (freed_tx, freed_fx) = bounded(FREED_FX_CAPACITY);
+        let mixer = Mixer::new(sample_rate, state, Recorder::new(sample_rate), freed_tx);
+        Self { mixer, freed_fx }
+    }
+
+    /// Apply `cmd` on output frame `frame`
+    pub fn queue(&mut self, cmd: AudioCommand, frame: u64) {
+        while self.freed_fx.try_recv().is_ok() {}
+        let command = MixerCommand::new(cmd, self.mixer.sample_rate);
+        self.mixer.queue(TimedCommand { frame, command });
+    }
+
+    /// Frames rendered so far
+    pub fn position(&self) -> u64 {
+        self.mixer.position
+    }
+
+    /// Render the next `data.len() / 2` frames as interleaved stereo
+    pub fn render(&mut self, data: &mut [f32]) {
+        self.mixer.render(data, 2);
+    }
 }
 
 impl AudioEngine {
@@ -120,191 +862,25 @@ impl AudioEngine {
             buffer_size: cpal::BufferSize::Default,
         };
 
-        let state = Arc::new(Mutex::new(AudioState {
-            sample_rate,
-            ..Default::default()
-        }));
-
-        let (cmd_tx, cmd_rx): (Sender<AudioCommand>, Receiver<AudioCommand>) = bounded(4096);
+        let state = Arc::new(Mutex::new(AudioState::default()));
+        recorder.set_sample_rate(sample_rate);
 
-        let state_clone = state.clone();
-        let recorder_clone = recorder.clone();
+        let (cmd_tx, cmd_rx): (Sender<TimedCommand>, Receiver<TimedCommand>) = bounded(COMMAND_QUEUE_SIZE);
+        let (freed_tx, freed_fx) = bounded(FREED_FX_CAPACITY);
 
-        let mut voices: Vec<Voice> = Vec::new();
-        let mut sample_playbacks: Vec<SamplePlayback> = Vec::new();
-        let mut master_volume: f32 = 1.0;
-        let mut effect_chain = EffectChain::new(sample_rate as f32);
-        let mut waveform_write_pos: usize = 0;
+        let frame_clock = Arc::new(FrameClock::new(sample_rate));
+        let frame_clock_cb = frame_clock.clone();
+        let mut mixer = Mixer::new(sample_rate, state.clone(), recorder.clone(), freed_tx);
 
         let stream = match supported.sample_format() {
             SampleFormat::F32 => device.build_output_stream(
                 &config,
                 move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                    // Process commands
+                    frame_clock_cb.observe(mixer.position);
                     while let Ok(cmd) = cmd_rx.try_recv() {
-                        match cmd {
-                            AudioCommand::PlayNote {
-                                synth_type,
-                                frequency,
-                                amplitude,
-                                duration_secs,
-                                envelope,
-                                pan,
-                                params: _, // Only used by SC engine
-                            } => {
-                                let voice = SynthVoice::new(
-                                    synth_type,
-                                    frequency,
-                                    amplitude,
-                                    sample_rate as f32,
-                                    envelope,
-                                );
-                                voices.push(Voice {
-                                    synth: voice,
-                                    samples_elapsed: 0,
-                                    duration_samples: (duration_secs * sample_rate as f32) as u64,
-                                    pan,
-                                });
-                            }
-                            AudioCommand::PlaySample {
-                                samples,
-                                sample_rate: file_sr,
-                                amplitude,
-                                rate,
-                                pan,
-                            } => {
-                                // Combine user rate with sample-rate-conversion ratio
-                                // so samples recorded at any SR play at correct pitch/speed
-                                let sr_ratio = file_sr as f64 / sample_rate as f64;
-                                let effective_rate = rate as f64 * sr_ratio;
-                                sample_playbacks.push(SamplePlayback {
-                                    data: samples,
-                                    position: 0.0_f64,
-                                    rate: effective_rate,
-                                    amplitude,
-                                    pan,
-                                    done: false,
-                                });
-                            }
-                            AudioCommand::SetBpm(bpm) => {
-                                let mut s = state_clone.lock();
-                                s.bpm = bpm;
-                            }
-                            AudioCommand::SetMasterVolume(vol) => {
-                                master_volume = vol;
-                                let mut s = state_clone.lock();
-                                s.master_volume = vol;
-                            }
-                            AudioCommand::Stop => {
-                                voices.clear();
-                                sample_playbacks.clear();
-                                let mut s = state_clone.lock();
-                                s.is_playing = false;
-                            }
-                            AudioCommand::SetEffect {
-                                reverb_mix,
-                                delay_time,
-                                delay_feedback,
-                                distortion,
-                                lpf_cutoff,
-                                hpf_cutoff,
-                            } => {
-                                effect_chain.set_reverb_mix(reverb_mix);
-                                effect_chain.set_delay(delay_time, delay_feedback);
-                                effect_chain.set_distortion(distortion);
-                                effect_chain.set_lpf(lpf_cutoff);
-                                effect_chain.set_hpf(hpf_cutoff);
-                            }
-                            // FxStart/FxEnd only used by SC engine; cpal ignores them
-                            AudioCommand::FxStart { .. } | AudioCommand::FxEnd => {}
-                        }
-                    }
-
-                    // Generate audio
-                    let frames = data.len() / channels;
-                    for frame in 0..frames {
-                        let mut left = 0.0f32;
-                        let mut right = 0.0f32;
-
-                        // Mix synth voices
-                        for voice in voices.iter_mut() {
-                            if voice.samples_elapsed < voice.duration_samples {
-                                let sample = voice.synth.next_sample();
-                                let env = voice.synth.envelope_value(voice.samples_elapsed, voice.duration_samples);
-                                let s = sample * env;
-                                let l_gain = ((1.0 - voice.pan) * 0.5 + 0.5).min(1.0);
-                                let r_gain = ((1.0 + voice.pan) * 0.5 + 0.5).min(1.0);
-                                left += s * l_gain;
-                                right += s * r_gain;
-                                voice.samples_elapsed += 1;
-                            }
-                        }
-
-                        // Mix sample playbacks (with cubic Hermite interpolation)
-                        for sp in sample_playbacks.iter_mut() {
-                            if !sp.done {
-                                let idx = sp.position as usize;
-                                let len = sp.data.len();
-                                if idx + 1 < len {
-                                    let frac = (sp.position - idx as f64) as f32;
-                                    // Cubic Hermite interpolation for smooth playback
-                                    let s = if idx >= 1 && idx + 2 < len {
-                                        let y0 = sp.data[idx - 1];
-                                        let y1 = sp.data[idx];
-                                        let y2 = sp.data[idx + 1];
-                                        let y3 = sp.data[idx + 2];
-                                        let c0 = y1;
-                                        let c1 = 0.5 * (y2 - y0);
-                                        let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
-                                        let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
-                                        ((c3 * frac + c2) * frac + c1) * frac + c0
-                                    } else {
-                                        // Fall back to linear at boundaries
-                                        sp.data[idx] * (1.0 - frac) + sp.data[idx + 1] * frac
-                                    };
-                                    let s = s * sp.amplitude;
-                                    let l_gain = ((1.0 - sp.pan) * 0.5 + 0.5).min(1.0);
-                                    let r_gain = ((1.0 + sp.pan) * 0.5 + 0.5).min(1.0);
-                                    left += s * l_gain;
-                                    right += s * r_gain;
-                                    sp.position += sp.rate;
-                                } else {
-                                    sp.done = true;
-                                }
-                            }
-                        }
-
-                        // Apply effects
-                        let (proc_l, proc_r) = effect_chain.process(left, right);
-                        left = proc_l * master_volume;
-                        right = proc_r * master_volume;
-
-                        // Clip
-                        left = left.clamp(-1.0, 1.0);
-                        right = right.clamp(-1.0, 1.0);
-
-                        // Write to output
-                        for ch in 0..channels {
-                            data[frame * channels + ch] = if ch % 2 == 0 { left } else { right };
-                        }
-
-                        // Record the mixed audio (mono mix of left and right)
-                        let mono_sample = (left + right) * 0.5;
-                        recorder_clone.push_samples(&[mono_sample]);
-
-                        // Write to waveform buffer
-                        {
-                            let mut s = state_clone.lock();
-                            let len = s.waveform_buffer.len();
-                            s.waveform_buffer[waveform_write_pos % len] = mono_sample;
-                            waveform_write_pos += 1;
-                            s.is_playing = !voices.is_empty() || sample_playbacks.iter().any(|sp| !sp.done);
-                        }
+                        mixer.queue(cmd);
                     }
-
-                    // Remove finished voices and samples
-                    voices.retain(|v| v.samples_elapsed < v.duration_samples);
-                    sample_playbacks.retain(|sp| !sp.done);
+                    mixer.render(data, channels);
                 },
                 |err| eprintln!("Audio stream error: {}", err),
                 None,
@@ -324,19 +900,32 @@ impl AudioEngine {
 
         Ok(Self {
             state,
+            clock: Mutex::new(BeatClock::new(120.0)),
+            frame_clock,
+            sample_rate,
             command_tx: cmd_tx,
+            freed_fx,
             _stream: Mutex::new(Some(stream)),
         })
     }
 
+    /// Apply `cmd` as soon as the audio thread picks it up
     pub fn send_command(&self, cmd: AudioCommand) -> Result<(), String> {
-        self.command_tx
-            .try_send(cmd)
-            .map_err(|e| format!("Failed to send command: {}", e))
+        self.send(cmd, 0)
     }
 
-    pub fn command_tx_clone(&self) -> Sender<AudioCommand> {
-        self.command_tx.clone()
+    /// Apply `cmd` at the output frame that plays at `at`. Send it at least
+    /// one audio buffer early for it to land exactly.
+    pub fn send_command_at(&self, cmd: AudioCommand, at: Instant) -> Result<(), String> {
+        self.send(cmd, self.frame_clock.frame_at(at))
+    }
+
+    fn send(&self, cmd: AudioCommand, frame: u64) -> Result<(), String> {
+        while self.freed_fx.try_recv().is_ok() {}
+        let command = MixerCommand::new(cmd, self.sample_rate);
+        self.command_tx
+            .try_send(TimedCommand { frame, command })
+            .map_err(|e| format!("Failed to send command: {}", e))
     }
 
     pub fn get_waveform(&self) -> Vec<f32> {
@@ -348,4 +937,353 @@ impl AudioEngine {
         let s = self.state.lock();
         (s.is_playing, s.master_volume, s.bpm)
     }
+
+    pub fn get_meter(&self) -> MeterReading {
+        self.state.lock().meter
+    }
+
+    /// Notes and samples sounding now, and how many have been stolen
+    pub fn get_voice_stats(&self) -> (usize, u64) {
+        let s = self.state.lock();
+        (s.voice_count, s.stolen_voices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::recorder::RecordFormat;
+
+    fn secs(from: Instant, to: Instant) -> f64 {
+        to.duration_since(from).as_secs_f64()
+    }
+
+    fn mixer(sample_rate: u32) -> Mixer {
+        let (freed_fx, _) = bounded(0);
+        let state = Arc::new(Mutex::new(AudioState::default()));
+        Mixer::new(sample_rate, state, Recorder::new(sample_rate), freed_fx)
+    }
+
+    /// Render `frames` stereo frames and return them interleaved
+    fn render(mixer: &mut Mixer, frames: usize) -> Vec<f32> {
+        let mut data = vec![0.0; frames * 2];
+        mixer.render(&mut data, 2);
+        data
+    }
+
+    /// A mono sample of `frames` frames at a constant level, at `sample_rate`
+    fn sample(frames: usize, amplitude: f32, sample_rate: u32) -> AudioCommand {
+        AudioCommand::PlaySample {
+            samples: vec![0.5; frames],
+            channels: 1,
+            sample_rate,
+            amplitude,
+            rate: 1.0,
+            pan: 0.0,
+            fx: None,
+        }
+    }
+
+    fn queue(mixer: &mut Mixer, frame: u64, command: AudioCommand) {
+        let command = MixerCommand::new(command, mixer.sample_rate);
+        mixer.queue(TimedCommand { frame, command });
+    }
+
+    #[test]
+    fn test_beat_clock_counts_across_tempo_changes() {
+        let origin = Instant::now();
+        let mut clock = BeatClock::new(120.0);
+        clock.restart(origin, 120.0);
+        assert_eq!(clock.beat_at(origin), 0.0);
+        assert!((clock.beat_at(origin + Duration::from_secs(2)) - 4.0).abs() < 1e-9);
+        // Halving the tempo at beat 4 keeps the count continuous
+        clock.set_bpm(60.0, origin + Duration::from_secs(2));
+        assert!((clock.beat_at(origin + Duration::from_secs(2)) - 4.0).abs() < 1e-9);
+        assert!((clock.beat_at(origin + Duration::from_secs(4)) - 6.0).abs() < 1e-9);
+        // A zero tempo is ignored
+        clock.set_bpm(0.0, origin);
+        assert!((clock.beat_at(origin + Duration::from_secs(4)) - 6.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_beat_clock_next_boundary() {
+        let origin = Instant::now();
+        let mut clock = BeatClock::new(100.0);
+        clock.restart(origin, 100.0);
+        let at = origin + Duration::from_secs_f64(1.01);
+        // 100 BPM: a beat every 0.6s, a bar every 2.4s
+        assert!((secs(origin, clock.next_boundary(at, 1.0)) - 1.2).abs() < 1e-6);
+        assert!((secs(origin, clock.next_boundary(at, 4.0)) - 2.4).abs() < 1e-6);
+        assert_eq!(clock.next_boundary(at, 0.0), at);
+        // Already on a boundary: stays there
+        let on_beat = origin + Duration::from_secs_f64(1.8);
+        assert!((secs(origin, clock.next_boundary(on_beat, 1.0)) - 1.8).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_frame_clock_follows_the_callback() {
+        let clock = FrameClock::new(48_000);
+        let one_second = clock.origin + Duration::from_secs(1);
+        // Before any callback, frames count from the clock's creation
+        assert_eq!(clock.frame_at(one_second), 48_000);
+        // Frame 0 means "now", so a timed command never gets it
+        assert_eq!(clock.frame_at(clock.origin), 1);
+
+        // The stream has rendered a second more than the wall clock shows
+        clock.observe(48_000);
+        let frame = clock.frame_at(one_second) as f64;
+        assert!((frame - 96_000.0).abs() < 2_400.0, "frame {}", frame);
+
+        // Later reports only nudge the offset, so jitter does not leak in
+        clock.observe(0);
+        let frame = clock.frame_at(one_second) as f64;
+        assert!((frame - (48_000.0 + 48_000.0 * 0.95)).abs() < 2_400.0, "frame {}", frame);
+    }
+
+    #[test]
+    fn test_timed_command_starts_on_its_frame() {
+        let mut m = mixer(1000);
+        queue(&mut m, 100, sample(1000, 1.0, 1000));
+        render(&mut m, 99);
+        assert!(m.sample_playbacks.is_empty());
+        render(&mut m, 51);
+        let playback = &m.sample_playbacks[0];
+        assert_eq!(playback.started, 100);
+        assert_eq!(playback.position, 50.0);
+    }
+
+    #[test]
+    fn test_queue_applies_in_frame_then_arrival_order() {
+        let mut m = mixer(1000);
+        queue(&mut m, 30, AudioCommand::SetMasterVolume(0.5));
+        queue(&mut m, 20, AudioCommand::SetMasterVolume(0.2));
+        queue(&mut m, 20, AudioCommand::SetMasterVolume(0.3));
+        render(&mut m, 25);
+        // Equal frames keep the order they were sent in
+        assert_eq!(m.master_volume, 0.3);
+        render(&mut m, 10);
+        assert_eq!(m.master_volume, 0.5);
+    }
+
+    #[test]
+    fn test_full_queue_drops_commands_without_growing() {
+        let mut m = mixer(1000);
+        for _ in 0..COMMAND_QUEUE_SIZE + 10 {
+            queue(&mut m, 100, AudioCommand::SetMasterVolume(0.5));
+        }
+        assert_eq!(m.queue.len(), COMMAND_QUEUE_SIZE);
+        assert_eq!(m.queue.capacity(), COMMAND_QUEUE_SIZE);
+        render(&mut m, 1);
+        assert_eq!(m.state.lock().dropped_commands, 10);
+        // Stop still gets in, clearing the way
+        queue(&mut m, 0, AudioCommand::Stop);
+        assert_eq!(m.queue.len(), 1);
+    }
+
+    #[test]
+    fn test_stop_drops_commands_queued_before_it() {
+        let mut m = mixer(1000);
+        queue(&mut m, 50, sample(1000, 1.0, 1000));
+        queue(&mut m, 0, AudioCommand::Stop);
+        render(&mut m, 100);
+        assert!(m.sample_playbacks.is_empty());
+    }
+
+    #[test]
+    fn test_recording_a_big_buffer_does_not_grow_the_block() {
+        let path = std::env::temp_dir().join(format!("sonic-engine-{}-big.wav", std::process::id()));
+        let mut m = mixer(1000);
+        m.recorder.start(&path, RecordFormat::Float32).unwrap();
+        render(&mut m, RECORD_BLOCK_FRAMES * 2 + 100);
+        assert_eq!(m.record_block.capacity(), RECORD_BLOCK_FRAMES * 2);
+        let message = m.recorder.stop().unwrap();
+        assert!(message.starts_with(&format!("Saved {} frames", RECORD_BLOCK_FRAMES * 2 + 100)), "{}", message);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn fx_start(id: u32, parent: Option<u32>) -> AudioCommand {
+        AudioCommand::FxStart { id, parent, fx_type: "lpf".to_string(), params: Vec::new() }
+    }
+
+    #[test]
+    fn test_fx_bus_lives_until_its_tail_has_rung_out() {
+        let mut m = mixer(1000);
+        queue(&mut m, 0, fx_start(1, None));
+        // Every pass of the block reuses its bus
+        queue(&mut m, 0, fx_start(1, None));
+        queue(&mut m, 0, fx_start(2, Some(1)));
+        render(&mut m, 10);
+        assert_eq!(m.fx_buses.iter().map(|bus| bus.id).collect::<Vec<_>>(), vec![1, 2]);
+
+        queue(&mut m, 0, AudioCommand::FxFree { id: 2 });
+        render(&mut m, 10);
+        let free_at = 10 + (FX_TAIL_SECS * 1000.0) as u64;
+        assert_eq!(m.fx_buses[1].free_at, Some(free_at));
+        render(&mut m, free_at as usize - 21);
+        assert_eq!(m.fx_buses.len(), 2);
+        render(&mut m, 1);
+        assert_eq!(m.fx_buses.iter().map(|bus| bus.id).collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn test_effects_are_handed_back_to_be_dropped() {
+        let mut m = mixer(1000);
+        let (freed_tx, freed_fx) = bounded(FREED_FX_CAPACITY);
+        m.freed_fx = freed_tx;
+        queue(&mut m, 0, fx_start(1, None));
+        render(&mut m, 1);
+        assert_eq!(freed_fx.len(), 0);
+        // A later pass only updates the params, so its effect goes back unused
+        queue(&mut m, 0, fx_start(1, None));
+        render(&mut m, 1);
+        assert_eq!(freed_fx.len(), 1);
+        // So does a freed bus once its tail has rung out
+        queue(&mut m, 0, AudioCommand::FxFree { id: 1 });
+        render(&mut m, 1 + (FX_TAIL_SECS * 1000.0) as usize);
+        assert!(m.fx_buses.is_empty());
+        assert_eq!(freed_fx.len(), 2);
+    }
+
+    #[test]
+    fn test_fx_buses_stay_within_the_pool() {
+        let mut m = mixer(1000);
+        for id in 0..FX_BUS_POOL_SIZE as u32 + 1 {
+            queue(&mut m, 0, fx_start(id, None));
+        }
+        render(&mut m, 1);
+        assert_eq!(m.fx_buses.len(), FX_BUS_POOL_SIZE);
+        assert_eq!(m.fx_buses.capacity(), FX_BUS_POOL_SIZE);
+    }
+
+    #[test]
+    fn test_send_to_a_freed_bus_reaches_the_master() {
+        let mut buses = vec![FxBus {
+            id: 1,
+            parent: None,
+            fx: Box::new(Fx::new("lpf", &[], 1000.0)),
+            left: 0.0,
+            right: 0.0,
+            free_at: None,
+        }];
+        let mut master = (0.0, 0.0);
+        Mixer::send(&mut buses, Some(1), 0.25, 0.5, &mut master);
+        assert_eq!((buses[0].left, buses[0].right), (0.25, 0.5));
+        Mixer::send(&mut buses, Some(2), 0.25, 0.5, &mut master);
+        Mixer::send(&mut buses, None, 0.25, 0.5, &mut master);
+        assert_eq!(master, (0.5, 1.0));
+    }
+
+    fn note(amplitude: f32) -> AudioCommand {
+        AudioCommand::PlayNote {
+            synth_type: OscillatorType::Sine,
+            frequency: 440.0,
+            amplitude,
+            envelope: Envelope::default(),
+            pan: 0.0,
+            params: Vec::new(),
+            node: None,
+            fx: None,
+        }
+    }
+
+    /// Amplitudes of the samples still sounding, in the order they started
+    fn sounding(mixer: &Mixer) -> Vec<f32> {
+        mixer.sample_playbacks.iter().filter(|sp| sp.fade.is_none()).map(|sp| sp.amplitude).collect()
+    }
+
+    #[test]
+    fn test_quietest_voice_is_stolen_first() {
+        let mut m = mixer(1000);
+        m.apply(AudioCommand::SetMaxVoices(2));
+        m.apply(sample(1000, 0.5, 1000));
+        m.apply(sample(1000, 0.2, 1000));
+        m.apply(sample(1000, 0.8, 1000));
+        assert_eq!(sounding(&m), vec![0.5, 0.8]);
+        assert_eq!(m.stolen_voices, 1);
+    }
+
+    #[test]
+    fn test_oldest_of_equally_quiet_voices_is_stolen() {
+        let mut m = mixer(1000);
+        m.apply(AudioCommand::SetMaxVoices(2));
+        for started in [0, 10] {
+            m.apply(sample(1000, 0.5, 1000));
+            render(&mut m, 10);
+            assert_eq!(m.sample_playbacks.last().unwrap().started, started);
+        }
+        m.apply(sample(1000, 0.5, 1000));
+        let stolen: Vec<u64> = m.sample_playbacks.iter().filter(|sp| sp.fade.is_some()).map(|sp| sp.started).collect();
+        assert_eq!(stolen, vec![0]);
+    }
+
+    #[test]
+    fn test_notes_and_samples_share_the_limit() {
+        let mut m = mixer(1000);
+        m.apply(AudioCommand::SetMaxVoices(1));
+        m.apply(note(0.3));
+        m.apply(sample(1000, 0.5, 1000));
+        assert!(m.voices[0].fade.is_some());
+        assert_eq!(sounding(&m), vec![0.5]);
+
+        // The stolen note fades out, then is dropped and counted in the status
+        let fade_frames = m.fade_frames() as usize;
+        render(&mut m, fade_frames + 1);
+        assert!(m.voices.is_empty());
+        let state = m.state.lock();
+        assert_eq!((state.voice_count, state.stolen_voices), (1, 1));
+    }
+
+    #[test]
+    fn test_full_pool_never_grows() {
+        let mut m = mixer(1000);
+        m.apply(AudioCommand::SetMaxVoices(1000));
+        assert_eq!(m.max_voices, VOICE_POOL_SIZE);
+        for _ in 0..600 {
+            m.apply(note(0.5));
+        }
+        assert_eq!(m.voices.capacity(), VOICE_POOL_SIZE * 2);
+        assert_eq!(m.voices.len(), VOICE_POOL_SIZE * 2);
+        assert_eq!(m.voices.iter().filter(|v| v.fade.is_none()).count(), VOICE_POOL_SIZE);
+        assert_eq!(m.stolen_voices, 600 - VOICE_POOL_SIZE as u64);
+    }
+
+    #[test]
+    fn test_stereo_balance_is_equal_power() {
+        let close = |(l, r): (f32, f32), (el, er): (f32, f32)| (l - el).abs() < 1e-6 && (r - er).abs() < 1e-6;
+        let centre = std::f32::consts::FRAC_1_SQRT_2;
+        assert!(close(balance_gains(-1.0), (1.0, 0.0)));
+        assert!(close(balance_gains(0.0), (centre, centre)));
+        assert!(close(balance_gains(1.0), (0.0, 1.0)));
+        assert!(close(balance_gains(2.0), (0.0, 1.0)));
+        for pan in [-0.8, -0.3, 0.4, 0.9] {
+            let (l, r) = balance_gains(pan);
+            assert!((l * l + r * r - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_stereo_sample_pans_with_balance() {
+        let output = |pan: f32| {
+            let mut m = mixer(1000);
+            m.apply(AudioCommand::PlaySample {
+                samples: vec![0.5; 2000],
+                channels: 2,
+                sample_rate: 1000,
+                amplitude: 1.0,
+                rate: 1.0,
+                pan,
+                fx: None,
+            });
+            // A frame out of the limiter's look-ahead
+            let out = render(&mut m, 100);
+            (out[100], out[101])
+        };
+        let (full, silent) = output(-1.0);
+        assert!(full > 0.1 && silent.abs() < 1e-6);
+        let (l, r) = output(1.0);
+        assert!(l.abs() < 1e-6 && (r - full).abs() < 1e-6);
+        // The same power in the centre
+        let (l, r) = output(0.0);
+        assert!((l - r).abs() < 1e-6 && (l * l + r * r - full * full).abs() < 1e-5, "{} {} {}", l, r, full);
+    }
 }
@@ -0,0 +1,2281 @@
+//! Runtime evaluator for Sonic Pi-style code.
+//!
+//! `parse_code` resolves `rrand`, `.choose`, `.tick` and `if` once, while it
+//! parses, so every pass through a loop body is identical. Here only the block
+//! structure is decided up front: the program becomes a tree of `Stmt`s whose
+//! expressions stay as source text and are evaluated each time they run.
+//!
+//! Every `live_loop` and `in_thread` runs as its own logical thread with its
+//! own clock. `Interpreter::run_until` advances the threads in time order and
+//! hands back the events they produced, so callers can stream them to an
+//! engine instead of expanding the whole piece in advance.
+//!
+//! `sync` parks a thread until another thread fires a matching `cue`; the
+//! woken thread carries on from the cue's logical time. Every live_loop cues
+//! `/live_loop/<name>` at the start of each iteration.
+//!
+//! Random values come from a seeded stream per thread (seed 0 unless the
+//! code calls `use_random_seed`), so a piece plays the same on every run.
+//!
+//! `at` starts a thread per offset; `time_warp` shifts the thread's clock for
+//! its body and puts it back afterwards, so neither moves the enclosing
+//! thread forward.
+//!
+//! `Interpreter::hot_swap` runs edited code into a program that is already
+//! playing: live_loops it still defines pick up their new body at their next
+//! iteration, keeping their phase, and live_loops it no longer defines stop
+//! at theirs.
+//!
+//! Each live_loop can also be stopped, restarted, muted or soloed on its own
+//! (`Interpreter::control_loop`); threads it starts go with it.
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use super::engine::AudioCommand;
+use super::parser::{
+    apply_assignment, cue_path, evaluate_condition, extract_fx_params, extract_symbol, find_trailing_if,
+    find_trailing_unless, is_block_opener, is_note_command, node_ref, note_to_audio, numbered_logical_lines,
+    parse_line, parse_synth_name, strip_inline_comment, try_parse_assignment, Binding, ParseContext,
+    ParsedCommand,
+};
+use super::random::RandomStream;
+use super::synth::OscillatorType;
+
+/// Statements a thread may run without sleeping before it is considered hung
+const MAX_STEPS_WITHOUT_SLEEP: usize = 100_000;
+
+/// Variable holding the value of the last expression run in a function body
+const RETURN_VAR: &str = "__return";
+
+/// Shared, immutable list of statements (a block body)
+pub type Block = Arc<Vec<Stmt>>;
+
+/// One statement of the program tree
+#[derive(Debug, Clone)]
+pub struct Stmt {
+    /// 1-based source line the statement starts on
+    pub line: usize,
+    pub kind: StmtKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum StmtKind {
+    /// Single-line statement: play, sample, sleep, assignment, function call...
+    Line(String),
+    LiveLoop {
+        name: String,
+        /// Cue path from the `sync:` option, waited on before the first iteration
+        sync: Option<String>,
+        body: Block,
+    },
+    /// `loop do`
+    Loop {
+        body: Block,
+    },
+    /// `N.times do |i|` — the count is an expression
+    Times {
+        count: String,
+        var: Option<String>,
+        body: Block,
+    },
+    /// `list.each do |x|` / `list.each_with_index do |x, i|`
+    Each {
+        list: String,
+        vars: Vec<String>,
+        with_index: bool,
+        body: Block,
+    },
+    InThread {
+        body: Block,
+    },
+    /// The whole `with_fx` line is kept so its params are re-evaluated on entry
+    WithFx {
+        header: String,
+        body: Block,
+    },
+    /// `if` / `elsif` / `else` branches in order; `None` is the `else` branch
+    If {
+        branches: Vec<(Option<String>, Block)>,
+    },
+    Unless {
+        condition: String,
+        body: Block,
+    },
+    /// `define :name do |args|` or `def name(args)`
+    Define {
+        name: String,
+        params: Vec<Param>,
+        /// Raw body text, kept for `evaluate_condition`'s function heuristics
+        source: String,
+        body: Block,
+    },
+    WithSynth {
+        synth: String,
+        body: Block,
+    },
+    WithBpm {
+        bpm: String,
+        body: Block,
+    },
+    WithBpmMul {
+        factor: String,
+        body: Block,
+    },
+    /// `with_random_seed N do` — the body draws from a fresh stream for N
+    WithRandomSeed {
+        seed: String,
+        body: Block,
+    },
+    /// `at times, values do |args|` — the body runs on its own thread at
+    /// each offset (in beats), without holding up this one
+    At {
+        times: String,
+        values: Option<String>,
+        vars: Vec<String>,
+        body: Block,
+    },
+    /// `time_warp times, values do |args|` — the body runs in place once per
+    /// offset (in beats) with the clock shifted, then the clock is put back
+    TimeWarp {
+        times: String,
+        values: Option<String>,
+        vars: Vec<String>,
+        body: Block,
+    },
+    /// `density N do` — runs the body N times, N times as fast
+    Density {
+        factor: String,
+        body: Block,
+    },
+    /// Body that runs once in place (`uncomment do`, blocks we don't model)
+    Block(Block),
+}
+
+impl StmtKind {
+    /// Blocks nested directly in this statement
+    fn blocks(&self) -> Vec<&Block> {
+        match self {
+            StmtKind::Line(_) => Vec::new(),
+            StmtKind::If { branches } => branches.iter().map(|(_, body)| body).collect(),
+            StmtKind::LiveLoop { body, .. }
+            | StmtKind::Loop { body }
+            | StmtKind::Times { body, .. }
+            | StmtKind::Each { body, .. }
+            | StmtKind::InThread { body }
+            | StmtKind::WithFx { body, .. }
+            | StmtKind::Unless { body, .. }
+            | StmtKind::Define { body, .. }
+            | StmtKind::WithSynth { body, .. }
+            | StmtKind::WithBpm { body, .. }
+            | StmtKind::WithBpmMul { body, .. }
+            | StmtKind::WithRandomSeed { body, .. }
+            | StmtKind::At { body, .. }
+            | StmtKind::TimeWarp { body, .. }
+            | StmtKind::Density { body, .. }
+            | StmtKind::Block(body) => vec![body],
+        }
+    }
+}
+
+/// Names of every live_loop defined anywhere in `stmts`
+fn live_loop_names(stmts: &[Stmt], names: &mut Vec<String>) {
+    for stmt in stmts {
+        if let StmtKind::LiveLoop { name, .. } = &stmt.kind {
+            names.push(name.clone());
+        }
+        for body in stmt.kind.blocks() {
+            live_loop_names(body, names);
+        }
+    }
+}
+
+/// One parameter of a `define` / `def` function
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: String,
+    /// Expression for the value when the caller leaves it out
+    pub default: Option<String>,
+    /// `name:` keyword parameter, only passed by name
+    pub keyword: bool,
+}
+
+/// Transport action on a single live_loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopControl {
+    Stop,
+    /// Start again from the top of the body, as when it was first defined
+    Restart,
+    Mute(bool),
+    Solo(bool),
+}
+
+/// A live_loop as the transport shows it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoopStatus {
+    pub name: String,
+    /// Iterations completed since it (re)started
+    pub iterations: usize,
+    pub running: bool,
+    pub muted: bool,
+    pub soloed: bool,
+}
+
+/// Something a thread produced at a point in time
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// Command for the audio engine (notes, FX, BPM, volume)
+    Audio(AudioCommand),
+    /// Sample trigger by name; the caller resolves and loads the sample data
+    Sample {
+        name: String,
+        rate: f32,
+        amplitude: f32,
+        pan: f32,
+        /// `with_fx` block the sample plays into
+        fx: Option<u32>,
+    },
+    /// Output of `puts` / `print` / `log`
+    Log(String),
+    /// Runtime problem, e.g. a loop that never sleeps
+    Error(String),
+}
+
+// ================================================================
+// PROGRAM TREE
+// ================================================================
+
+/// Build the statement tree for `code`
+pub fn parse_program(code: &str) -> Result<Vec<Stmt>, String> {
+    let lines: Vec<(usize, String)> = numbered_logical_lines(code)
+        .into_iter()
+        .map(|(line_no, line)| (line_no, strip_inline_comment(line.trim())))
+        .collect();
+    parse_range(&lines, 0, lines.len())
+}
+
+/// Parse the lines in `start..end` into statements
+fn parse_range(lines: &[(usize, String)], start: usize, end: usize) -> Result<Vec<Stmt>, String> {
+    let mut stmts = Vec::new();
+    let mut i = start;
+
+    while i < end {
+        let (line_no, line) = (&lines[i].0, lines[i].1.as_str());
+
+        // Blank lines, comments and stray `end`s
+        if line.is_empty() || line.starts_with('#') || line == "end" {
+            i += 1;
+            continue;
+        }
+
+        if is_block_opener(line) {
+            let (close, markers) = find_block_end(lines, i, end);
+            if let Some(kind) = parse_block_stmt(line, lines, i, close, &markers)? {
+                stmts.push(Stmt { line: *line_no, kind });
+            }
+            i = close + 1;
+            continue;
+        }
+
+        stmts.push(Stmt {
+            line: *line_no,
+            kind: StmtKind::Line(line.to_string()),
+        });
+        i += 1;
+    }
+
+    Ok(stmts)
+}
+
+/// Find the `end` closing the block opened at `start`, plus the indices of
+/// any `elsif` / `else` lines at the block's own depth. An unterminated block
+/// runs to `limit`.
+fn find_block_end(lines: &[(usize, String)], start: usize, limit: usize) -> (usize, Vec<usize>) {
+    let mut depth = 1;
+    let mut markers = Vec::new();
+
+    for (j, (_, l)) in lines.iter().enumerate().take(limit).skip(start + 1) {
+        if l == "end" {
+            depth -= 1;
+            if depth == 0 {
+                return (j, markers);
+            }
+            continue;
+        }
+        if depth == 1 && (l.starts_with("elsif ") || l == "else") {
+            markers.push(j);
+            continue;
+        }
+        if is_block_opener(l) {
+            depth += 1;
+        }
+    }
+
+    (limit, markers)
+}
+
+/// Text between the `|...|` of a block opener
+fn pipe_contents(header: &str) -> Option<&str> {
+    let start = header.find('|')?;
+    let after = &header[start + 1..];
+    after.find('|').map(|end| &after[..end])
+}
+
+/// Names between the `|...|` of a block opener: `do |x, i|` → ["x", "i"]
+fn block_vars(header: &str) -> Vec<String> {
+    pipe_contents(header)
+        .map(|vars| {
+            vars.split(',')
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Function parameters: `a, b = 1, amp: 0.5`
+fn parse_params(list: &str) -> Vec<Param> {
+    split_args(list)
+        .into_iter()
+        .filter_map(|param| {
+            let param = param.trim_start_matches(['*', '&']);
+            if let Some((name, default)) = keyword_arg(param) {
+                let default = Some(default.to_string()).filter(|d| !d.is_empty());
+                return Some(Param { name: name.to_string(), default, keyword: true });
+            }
+            let (name, default) = match param.split_once('=') {
+                Some((name, default)) => (name.trim(), Some(default.trim().to_string())),
+                None => (param, None),
+            };
+            (!name.is_empty()).then(|| Param { name: name.to_string(), default, keyword: false })
+        })
+        .collect()
+}
+
+/// Split `amp: 0.5` into name and value; `None` for positional args
+/// (including symbols such as `:e2`)
+fn keyword_arg(arg: &str) -> Option<(&str, &str)> {
+    let (name, value) = arg.split_once(':')?;
+    let is_name = !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    (is_name && (value.is_empty() || value.starts_with(char::is_whitespace))).then(|| (name, value.trim()))
+}
+
+/// The arguments of an `at` / `time_warp` header, without `do |...|`
+fn timing_args<'a>(header: &'a str, keyword: &str) -> &'a str {
+    let call = header[keyword.len()..].starts_with('(');
+    let rest = header[keyword.len()..].trim();
+    let rest = match rest.rfind(" do") {
+        Some(pos) if rest[pos + 3..].trim().is_empty() || rest[pos + 3..].trim().starts_with('|') => &rest[..pos],
+        _ => rest.trim_end_matches("do"),
+    };
+    let rest = rest.trim();
+    // at(1, [:c4]) do
+    match rest.strip_prefix('(').and_then(|r| r.strip_suffix(')')) {
+        Some(inner) if call => inner.trim(),
+        _ => rest,
+    }
+}
+
+/// Split on the commas that are not inside brackets or parentheses
+fn split_args(args: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0i32;
+    let mut start = 0;
+    for (i, c) in args.char_indices() {
+        match c {
+            '[' | '(' => depth += 1,
+            ']' | ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(args[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    let last = args[start..].trim();
+    if !last.is_empty() {
+        parts.push(last);
+    }
+    parts
+}
+
+/// Strip a leading keyword and the trailing `do` / `then` from a block header
+fn header_arg<'a>(header: &'a str, keyword: &str) -> &'a str {
+    header
+        .strip_prefix(keyword)
+        .unwrap_or(header)
+        .trim()
+        .trim_end_matches("do")
+        .trim_end_matches("then")
+        .trim()
+}
+
+/// Classify the block opened by `header` at `start` and closed at `close`.
+/// Returns `None` for blocks that produce nothing (`comment do`).
+fn parse_block_stmt(
+    header: &str,
+    lines: &[(usize, String)],
+    start: usize,
+    close: usize,
+    markers: &[usize],
+) -> Result<Option<StmtKind>, String> {
+    let body = || -> Result<Block, String> { Ok(Arc::new(parse_range(lines, start + 1, close)?)) };
+
+    // live_loop :name do
+    if header.starts_with("live_loop") {
+        let name = extract_symbol(header).unwrap_or_else(|| "loop".to_string());
+        let sync = header.find("sync:").and_then(|pos| cue_path(header_arg(&header[pos + 5..], "")));
+        return Ok(Some(StmtKind::LiveLoop { name, sync, body: body()? }));
+    }
+
+    // loop do
+    if header.starts_with("loop do") {
+        return Ok(Some(StmtKind::Loop { body: body()? }));
+    }
+
+    // N.times do / N.times do |i|
+    if let Some(dot_pos) = header.find(".times") {
+        let count = header[..dot_pos].trim();
+        if !count.is_empty() {
+            return Ok(Some(StmtKind::Times {
+                count: count.to_string(),
+                var: block_vars(header).into_iter().next(),
+                body: body()?,
+            }));
+        }
+    }
+
+    // with_fx :effect, params do
+    if header.starts_with("with_fx") {
+        return Ok(Some(StmtKind::WithFx {
+            header: header.to_string(),
+            body: body()?,
+        }));
+    }
+
+    // in_thread do
+    if header.starts_with("in_thread") {
+        return Ok(Some(StmtKind::InThread { body: body()? }));
+    }
+
+    // define :name do |a, b = 1, amp: 0.5|
+    if header.starts_with("define") {
+        let name = extract_symbol(header).unwrap_or_else(|| "unnamed".to_string());
+        let params = parse_params(pipe_contents(header).unwrap_or(""));
+        return Ok(Some(define_stmt(name, params, lines, start, close)?));
+    }
+
+    // def name(a, b = 1, amp: 0.5) / def name a, b
+    if let Some(rest) = header.strip_prefix("def ") {
+        let rest = rest.trim();
+        let name_end = rest.find('(').or_else(|| rest.find(' ')).unwrap_or(rest.len());
+        let name = rest[..name_end].trim().to_string();
+        let args = rest[name_end..].trim();
+        let args = match (args.strip_prefix('('), args.rfind(')')) {
+            (Some(_), Some(close)) => &args[1..close],
+            _ => args,
+        };
+        return Ok(Some(define_stmt(name, parse_params(args), lines, start, close)?));
+    }
+
+    // if ... do / elsif ... / else / end
+    if header.starts_with("if ") {
+        let mut branches = Vec::new();
+        let mut condition = Some(header_arg(header, "if").to_string());
+        let mut branch_start = start + 1;
+        for &marker in markers.iter().chain(std::iter::once(&close)) {
+            let block = Arc::new(parse_range(lines, branch_start, marker)?);
+            branches.push((condition.take(), block));
+            if marker < close {
+                let marker_line = lines[marker].1.as_str();
+                condition = if marker_line == "else" {
+                    None
+                } else {
+                    Some(header_arg(marker_line, "elsif").to_string())
+                };
+                branch_start = marker + 1;
+            }
+        }
+        return Ok(Some(StmtKind::If { branches }));
+    }
+
+    // unless ... do
+    if header.starts_with("unless ") {
+        return Ok(Some(StmtKind::Unless {
+            condition: header_arg(header, "unless").to_string(),
+            body: body()?,
+        }));
+    }
+
+    // with_synth :name do
+    if header.starts_with("with_synth") {
+        let synth = extract_symbol(header).unwrap_or_else(|| "sine".to_string());
+        return Ok(Some(StmtKind::WithSynth { synth, body: body()? }));
+    }
+
+    // with_random_seed N do
+    if header.starts_with("with_random_seed") {
+        return Ok(Some(StmtKind::WithRandomSeed {
+            seed: header_arg(header, "with_random_seed").to_string(),
+            body: body()?,
+        }));
+    }
+
+    // with_bpm_mul N do (checked before with_bpm, which is its prefix)
+    if header.starts_with("with_bpm_mul") {
+        return Ok(Some(StmtKind::WithBpmMul {
+            factor: header_arg(header, "with_bpm_mul").to_string(),
+            body: body()?,
+        }));
+    }
+
+    // with_bpm N do
+    if header.starts_with("with_bpm") {
+        return Ok(Some(StmtKind::WithBpm {
+            bpm: header_arg(header, "with_bpm").to_string(),
+            body: body()?,
+        }));
+    }
+
+    // list.each_with_index do |x, i| (checked before .each, which is its prefix)
+    if let Some(dot_pos) = header.find(".each_with_index") {
+        let mut vars = block_vars(header);
+        if vars.is_empty() {
+            vars.push("x".to_string());
+        }
+        return Ok(Some(StmtKind::Each {
+            list: header[..dot_pos].to_string(),
+            vars,
+            with_index: true,
+            body: body()?,
+        }));
+    }
+
+    // list.each do |x|
+    if let Some(dot_pos) = header.find(".each") {
+        let mut vars = block_vars(header);
+        if vars.is_empty() {
+            vars.push("x".to_string());
+        }
+        return Ok(Some(StmtKind::Each {
+            list: header[..dot_pos].to_string(),
+            vars,
+            with_index: false,
+            body: body()?,
+        }));
+    }
+
+    // comment do ... end (ignore contents)
+    if header.starts_with("comment do") {
+        return Ok(None);
+    }
+
+    // at [1, 2], [:c4, :e4] do |t, n| / time_warp 0.5 do
+    let keyword = header.split(|c: char| c.is_whitespace() || c == '(').next().unwrap_or("");
+    if keyword == "at" || keyword == "time_warp" {
+        let args = split_args(timing_args(header, keyword));
+        let times = args.first().map(|t| t.to_string()).unwrap_or_else(|| "0".to_string());
+        let values = args.get(1).map(|v| v.to_string());
+        let vars = block_vars(header);
+        let body = body()?;
+        return Ok(Some(if keyword == "at" {
+            StmtKind::At { times, values, vars, body }
+        } else {
+            StmtKind::TimeWarp { times, values, vars, body }
+        }));
+    }
+
+    // density N do
+    if header.starts_with("density") {
+        return Ok(Some(StmtKind::Density {
+            factor: header_arg(header, "density").to_string(),
+            body: body()?,
+        }));
+    }
+
+    // uncomment do, and any other block: run the body in place
+    Ok(Some(StmtKind::Block(body()?)))
+}
+
+fn define_stmt(
+    name: String,
+    params: Vec<Param>,
+    lines: &[(usize, String)],
+    start: usize,
+    close: usize,
+) -> Result<StmtKind, String> {
+    let source = lines[start + 1..close.min(lines.len())]
+        .iter()
+        .map(|(_, l)| l.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    Ok(StmtKind::Define {
+        name,
+        params,
+        source,
+        body: Arc::new(parse_range(lines, start + 1, close)?),
+    })
+}
+
+// ================================================================
+// RUNTIME
+// ================================================================
+
+/// What a frame is executing
+#[derive(Debug, Clone)]
+enum Body {
+    Stmts(Block),
+    /// Pre-built commands from single-line helpers like `play_pattern_timed`
+    Commands(Arc<Vec<ParsedCommand>>),
+}
+
+impl Body {
+    fn len(&self) -> usize {
+        match self {
+            Body::Stmts(stmts) => stmts.len(),
+            Body::Commands(cmds) => cmds.len(),
+        }
+    }
+}
+
+/// How far a thread has got: its logical time and how many times `sync` has
+/// woken it. A loop iteration that ends where it began never yielded.
+type Progress = (f64, usize);
+
+/// How a frame behaves when it reaches the end of its body
+#[derive(Debug, Clone)]
+enum FrameKind {
+    Once,
+    /// Root of a live_loop thread: repeats until stopped
+    LiveLoop { iteration_start: Progress },
+    /// `loop do` (count `None`) or `N.times do`
+    Repeat {
+        count: Option<usize>,
+        index: usize,
+        var: Option<String>,
+        saved: Option<String>,
+        iteration_start: Progress,
+    },
+    Each {
+        items: Vec<String>,
+        index: usize,
+        vars: Vec<String>,
+        with_index: bool,
+        saved: Vec<Option<String>>,
+    },
+    Density { count: usize, index: usize, saved_bpm: f32 },
+    /// `time_warp`: one run per offset (seconds from `origin`) with its
+    /// block args; the clock goes back to `origin` at the end
+    TimeWarp {
+        runs: Vec<(f64, Vec<String>)>,
+        index: usize,
+        origin: f64,
+        vars: Vec<String>,
+        saved: Vec<Option<String>>,
+    },
+    /// Function body; `saved` holds what its params shadowed, and `result`
+    /// the caller's variable that receives the return value
+    Call { saved: Vec<(String, Option<Binding>)>, result: Option<String> },
+    Fx,
+    Synth { saved: OscillatorType },
+    Bpm { saved: f32 },
+    Random { saved: RandomStream },
+}
+
+#[derive(Debug, Clone)]
+struct Frame {
+    body: Body,
+    pc: usize,
+    kind: FrameKind,
+}
+
+impl Frame {
+    fn new(body: Body, kind: FrameKind) -> Self {
+        Self { body, pc: 0, kind }
+    }
+}
+
+/// What running one statement did to the thread
+enum Flow {
+    Next,
+    Sleep,
+    Stop,
+}
+
+/// A `define` / `def` function
+#[derive(Debug, Clone)]
+struct Function {
+    params: Vec<Param>,
+    body: Block,
+}
+
+/// State shared by all threads of a run
+#[derive(Debug, Clone, Default)]
+struct Env {
+    /// `define` / `def` functions (global, as in Sonic Pi)
+    functions: HashMap<String, Function>,
+    /// Threads started during the current step, adopted by the interpreter
+    spawned: Vec<Thread>,
+    /// Cues fired during the current step, delivered to syncing threads
+    cues: Vec<(f64, String)>,
+    /// Events with the live_loop whose thread produced them
+    events: Vec<(f64, Option<String>, Event)>,
+    next_id: usize,
+    /// Next handle for a note kept in a variable (`s = play ...`)
+    next_node: u32,
+    /// live_loops whose notes and samples are dropped
+    muted: HashSet<String>,
+    /// When not empty, only these live_loops are heard
+    soloed: HashSet<String>,
+    /// Last `with_fx` block id handed out
+    next_fx: u32,
+    /// Threads holding each running `with_fx` block; it is freed when the
+    /// last one ends
+    fx_users: HashMap<u32, usize>,
+}
+
+impl Env {
+    /// Whether sound from a thread belonging to `live_loop` gets through
+    fn audible(&self, live_loop: Option<&str>) -> bool {
+        match live_loop {
+            Some(name) => !self.muted.contains(name) && (self.soloed.is_empty() || self.soloed.contains(name)),
+            None => self.soloed.is_empty(),
+        }
+    }
+}
+
+/// One logical thread: the main program, a live_loop or an in_thread block
+#[derive(Debug, Clone)]
+struct Thread {
+    id: usize,
+    /// live_loop name, if this thread runs one
+    name: Option<String>,
+    /// live_loop this thread belongs to: its own, or the one that started it
+    live_loop: Option<String>,
+    /// Logical time in seconds since the run started
+    time: f64,
+    bpm: f32,
+    ctx: ParseContext,
+    /// `with_fx` blocks this thread is inside, innermost last
+    fx_stack: Vec<u32>,
+    /// Block id for each `with_fx` this thread has entered, by (line,
+    /// enclosing block, FX name), so every iteration reuses the same block
+    fx_blocks: HashMap<(usize, Option<u32>, String), u32>,
+    /// Blocks this thread keeps running: its own and those it started in
+    fx_held: Vec<u32>,
+    frames: Vec<Frame>,
+    /// New body for a redefined live_loop, and the variables of the code
+    /// that redefined it, swapped in at the next iteration
+    pending_body: Option<(Block, HashMap<String, String>)>,
+    /// live_loop dropped from the code by a hot swap: stops at its next iteration
+    retired: bool,
+    /// Times a live_loop has gone round since it started
+    iterations: usize,
+    /// Cue path this thread is blocked on in `sync`
+    waiting: Option<String>,
+    /// Times `sync` has returned, so a loop that only syncs is not seen as hung
+    wakeups: usize,
+    /// Source line of the statement being run, for error messages
+    line: usize,
+}
+
+impl Thread {
+    /// Run until the thread sleeps (returns true) or finishes (returns false)
+    fn run(&mut self, env: &mut Env) -> bool {
+        for _ in 0..MAX_STEPS_WITHOUT_SLEEP {
+            let Some(frame) = self.frames.last_mut() else {
+                return false;
+            };
+            if frame.pc >= frame.body.len() {
+                if !self.end_of_body(env) {
+                    return false;
+                }
+                continue;
+            }
+            if frame.pc == 0 && matches!(frame.kind, FrameKind::LiveLoop { .. }) {
+                if let Some(name) = &self.name {
+                    env.cues.push((self.time, format!("/live_loop/{}", name)));
+                }
+            }
+
+            let body = frame.body.clone();
+            let pc = frame.pc;
+            frame.pc += 1;
+
+            let flow = match &body {
+                Body::Stmts(stmts) => self.exec_stmt(&stmts[pc], env),
+                Body::Commands(cmds) => self.exec_command(&cmds[pc], env),
+            };
+            match flow {
+                Flow::Next => {}
+                Flow::Sleep => return true,
+                Flow::Stop => {
+                    self.frames.clear();
+                    return false;
+                }
+            }
+        }
+
+        self.error(env, format!("{} ran {} steps without sleeping, stopping it", self.label(), MAX_STEPS_WITHOUT_SLEEP));
+        false
+    }
+
+    /// The top frame ran off the end of its body: repeat it or pop it.
+    /// Returns false if the thread must stop.
+    fn end_of_body(&mut self, env: &mut Env) -> bool {
+        let now = self.progress();
+        let mut hung = false;
+        let frame = self.frames.last_mut().expect("end_of_body without a frame");
+
+        match &mut frame.kind {
+            FrameKind::LiveLoop { iteration_start } => {
+                if self.retired {
+                    return false;
+                }
+                if now == *iteration_start {
+                    hung = true;
+                } else {
+                    *iteration_start = now;
+                    frame.pc = 0;
+                    self.iterations += 1;
+                    if let Some((body, variables)) = self.pending_body.take() {
+                        frame.body = Body::Stmts(body);
+                        self.ctx.variables.extend(variables);
+                    }
+                    return true;
+                }
+            }
+            FrameKind::Repeat { count, index, var, iteration_start, .. } => {
+                *index += 1;
+                if count.is_none_or(|n| *index < n) {
+                    if count.is_none() && now == *iteration_start {
+                        hung = true;
+                    } else {
+                        *iteration_start = now;
+                        frame.pc = 0;
+                        if let Some(var) = var {
+                            self.ctx.variables.insert(var.clone(), index.to_string());
+                        }
+                        return true;
+                    }
+                }
+            }
+            FrameKind::Each { items, index, vars, with_index, .. } => {
+                *index += 1;
+                if *index < items.len() {
+                    bind_each(&mut self.ctx, items, *index, vars, *with_index);
+                    frame.pc = 0;
+                    return true;
+                }
+            }
+            FrameKind::Density { count, index, .. } => {
+                *index += 1;
+                if *index < *count {
+                    frame.pc = 0;
+                    return true;
+                }
+            }
+            FrameKind::TimeWarp { runs, index, origin, vars, .. } => {
+                *index += 1;
+                if let Some((offset, args)) = runs.get(*index) {
+                    self.time = *origin + offset;
+                    bind_vars(&mut self.ctx, vars, args);
+                    frame.pc = 0;
+                    return true;
+                }
+            }
+            _ => {}
+        }
+
+        if hung {
+            self.error(env, format!("{} did not sleep, stopping it", self.label()));
+            return false;
+        }
+
+        // Block finished: pop it and undo its scoped state
+        let frame = self.frames.pop().expect("end_of_body without a frame");
+        match frame.kind {
+            FrameKind::Repeat { var: Some(var), saved, .. } => {
+                restore_var(&mut self.ctx, &var, saved);
+            }
+            FrameKind::Each { vars, saved, .. } => {
+                for (var, old) in vars.iter().zip(saved) {
+                    restore_var(&mut self.ctx, var, old);
+                }
+            }
+            FrameKind::TimeWarp { origin, vars, saved, .. } => {
+                self.time = origin;
+                for (var, old) in vars.iter().zip(saved) {
+                    restore_var(&mut self.ctx, var, old);
+                }
+            }
+            FrameKind::Density { saved_bpm, .. } | FrameKind::Bpm { saved: saved_bpm } => {
+                self.set_bpm(saved_bpm, env);
+            }
+            FrameKind::Fx => {
+                // The block keeps running for the next iteration
+                self.fx_stack.pop();
+            }
+            FrameKind::Synth { saved } => {
+                self.ctx.current_synth = saved;
+            }
+            FrameKind::Random { saved } => {
+                self.ctx.set_random(saved);
+            }
+            FrameKind::Call { saved, result } => {
+                let value = self.ctx.binding(RETURN_VAR);
+                for (name, old) in saved {
+                    self.ctx.bind(&name, old);
+                }
+                match result {
+                    Some(var) => self.ctx.bind(&var, value),
+                    // A call as the last statement of another function
+                    None if self.in_call() => self.ctx.bind(RETURN_VAR, value),
+                    None => {}
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn exec_stmt(&mut self, stmt: &Stmt, env: &mut Env) -> Flow {
+        self.line = stmt.line;
+        match &stmt.kind {
+            StmtKind::Line(line) => return self.exec_line(line, env),
+            StmtKind::LiveLoop { name, sync, body } => {
+                let frame = Frame::new(
+                    Body::Stmts(body.clone()),
+                    FrameKind::LiveLoop { iteration_start: self.progress() },
+                );
+                self.spawn(env, Some(name.clone()), frame, sync.clone());
+            }
+            StmtKind::InThread { body } => {
+                self.spawn(env, None, Frame::new(Body::Stmts(body.clone()), FrameKind::Once), None);
+            }
+            StmtKind::Loop { body } => {
+                self.push(
+                    Body::Stmts(body.clone()),
+                    FrameKind::Repeat { count: None, index: 0, var: None, saved: None, iteration_start: self.progress() },
+                );
+            }
+            StmtKind::Times { count, var, body } => {
+                let n = self.eval_number(count).map(|n| n.max(0.0) as usize).unwrap_or(0);
+                if n > 0 {
+                    let saved = var.as_ref().and_then(|v| self.ctx.variables.get(v).cloned());
+                    if let Some(var) = var {
+                        self.ctx.variables.insert(var.clone(), "0".to_string());
+                    }
+                    self.push(
+                        Body::Stmts(body.clone()),
+                        FrameKind::Repeat {
+                            count: Some(n),
+                            index: 0,
+                            var: var.clone(),
+                            saved,
+                            iteration_start: self.progress(),
+                        },
+                    );
+                }
+            }
+            StmtKind::Each { list, vars, with_index, body } => {
+                let items = self.ctx.resolve_to_list(list).unwrap_or_default();
+                if !items.is_empty() {
+                    let saved = vars.iter().map(|v| self.ctx.variables.get(v).cloned()).collect();
+                    bind_each(&mut self.ctx, &items, 0, vars, *with_index);
+                    self.push(
+                        Body::Stmts(body.clone()),
+                        FrameKind::Each { items, index: 0, vars: vars.clone(), with_index: *with_index, saved },
+                    );
+                }
+            }
+            StmtKind::WithFx { header, body } => {
+                let fx_type = extract_symbol(header).unwrap_or_else(|| "reverb".to_string());
+                let params = extract_fx_params(header, &self.ctx);
+                self.enter_fx(fx_type, params, Body::Stmts(body.clone()), env);
+            }
+            StmtKind::If { branches } => {
+                let taken = branches.iter().find(|(condition, _)| {
+                    condition.as_ref().is_none_or(|c| evaluate_condition(c, &self.ctx))
+                });
+                if let Some((_, body)) = taken {
+                    self.push(Body::Stmts(body.clone()), FrameKind::Once);
+                }
+            }
+            StmtKind::Unless { condition, body } => {
+                if !evaluate_condition(condition, &self.ctx) {
+                    self.push(Body::Stmts(body.clone()), FrameKind::Once);
+                }
+            }
+            StmtKind::Define { name, params, source, body } => {
+                env.functions.insert(name.clone(), Function { params: params.clone(), body: body.clone() });
+                self.ctx.functions.insert(name.clone(), source.clone());
+            }
+            StmtKind::WithSynth { synth, body } => {
+                let saved = self.ctx.current_synth;
+                self.ctx.current_synth = parse_synth_name(synth);
+                self.push(Body::Stmts(body.clone()), FrameKind::Synth { saved });
+            }
+            StmtKind::WithBpm { bpm, body } => {
+                let saved = self.bpm;
+                if let Some(bpm) = self.eval_number(bpm) {
+                    self.set_bpm(bpm, env);
+                }
+                self.push(Body::Stmts(body.clone()), FrameKind::Bpm { saved });
+            }
+            StmtKind::WithBpmMul { factor, body } => {
+                let saved = self.bpm;
+                if let Some(factor) = self.eval_number(factor) {
+                    self.set_bpm(saved * factor, env);
+                }
+                self.push(Body::Stmts(body.clone()), FrameKind::Bpm { saved });
+            }
+            StmtKind::At { times, values, vars, body } => {
+                for (offset, args) in self.timing_runs(times, values.as_deref(), vars.len()) {
+                    let frame = Frame::new(Body::Stmts(body.clone()), FrameKind::Once);
+                    self.spawn(env, None, frame, None);
+                    if let Some(thread) = env.spawned.last_mut() {
+                        thread.time += offset;
+                        bind_vars(&mut thread.ctx, vars, &args);
+                    }
+                }
+            }
+            StmtKind::TimeWarp { times, values, vars, body } => {
+                let runs = self.timing_runs(times, values.as_deref(), vars.len());
+                if let Some((offset, args)) = runs.first() {
+                    let saved = vars.iter().map(|v| self.ctx.variables.get(v).cloned()).collect();
+                    let origin = self.time;
+                    self.time = origin + offset;
+                    bind_vars(&mut self.ctx, vars, args);
+                    self.frames.push(Frame::new(
+                        Body::Stmts(body.clone()),
+                        FrameKind::TimeWarp { runs, index: 0, origin, vars: vars.clone(), saved },
+                    ));
+                }
+            }
+            StmtKind::WithRandomSeed { seed, body } => {
+                let saved = self.ctx.random();
+                let mut stream = saved;
+                stream.reseed(self.eval_number(seed).unwrap_or(0.0) as i64 as u64);
+                self.ctx.set_random(stream);
+                self.frames.push(Frame::new(Body::Stmts(body.clone()), FrameKind::Random { saved }));
+            }
+            StmtKind::Density { factor, body } => {
+                let count = self.eval_number(factor).map(|d| d.max(1.0) as usize).unwrap_or(1);
+                let saved_bpm = self.bpm;
+                self.set_bpm(saved_bpm * count as f32, env);
+                self.push(Body::Stmts(body.clone()), FrameKind::Density { count, index: 0, saved_bpm });
+            }
+            StmtKind::Block(body) => {
+                self.push(Body::Stmts(body.clone()), FrameKind::Once);
+            }
+        }
+        Flow::Next
+    }
+
+    fn exec_line(&mut self, line: &str, env: &mut Env) -> Flow {
+        // Time.now.to_f → 0.0 (logical time starts at zero)
+        if line.contains("Time.now") {
+            if let Some((var_name, _)) = try_parse_assignment(line) {
+                self.ctx.variables.insert(var_name, "0.0".to_string());
+            }
+            return Flow::Next;
+        }
+
+        if let Some((var_name, var_value)) = try_parse_assignment(line) {
+            if is_note_command(&var_value) {
+                self.play_node(&var_name, &var_value, env);
+            } else if let Some((func, args)) = function_call(&var_value, env) {
+                self.call(func, args, Some(var_name), env);
+            } else {
+                apply_assignment(&var_name, &var_value, &mut self.ctx);
+                if self.in_call() {
+                    let value = self.ctx.binding(&var_name);
+                    self.ctx.bind(RETURN_VAR, value);
+                }
+            }
+            return Flow::Next;
+        }
+
+        if let Some(cmd) = parse_line(line, &mut self.ctx) {
+            return self.exec_command(&cmd, env);
+        }
+
+        // Call to a defined function: "riff", "bassline(:e2, 0.5)", "hit amp: 2 if one_in(2)"
+        let (call, condition) = match (find_trailing_if(line), find_trailing_unless(line)) {
+            (Some(pos), _) => (line[..pos].trim(), Some((&line[pos + 3..], true))),
+            (None, Some(pos)) => (line[..pos].trim(), Some((&line[pos + 7..], false))),
+            (None, None) => (line, None),
+        };
+        if let Some((func, args)) = function_call(call, env) {
+            if condition.is_none_or(|(c, wanted)| evaluate_condition(c, &self.ctx) == wanted) {
+                self.call(func, args, None, env);
+            }
+        } else if self.in_call() {
+            // A bare expression: the function's value if it is the last one
+            let value = self.ctx.evaluate(line);
+            self.ctx.bind(RETURN_VAR, Some(value));
+        }
+        Flow::Next
+    }
+
+    /// Run `func` with `args` bound to its parameters, shadowing any
+    /// variables of the same name until it returns. With `result` set the
+    /// function's value is assigned to that variable.
+    fn call(&mut self, func: Function, args: &str, result: Option<String>, env: &mut Env) {
+        let mut positional = Vec::new();
+        let mut keywords = Vec::new();
+        for arg in split_args(args) {
+            match keyword_arg(arg) {
+                Some((name, value)) => keywords.push((name.to_string(), self.ctx.evaluate(value))),
+                None => positional.push(self.ctx.evaluate(arg)),
+            }
+        }
+
+        let expected = func.params.iter().filter(|p| !p.keyword).count();
+        if positional.len() > expected {
+            self.error(env, format!("wrong number of arguments (given {}, expected {})", positional.len(), expected));
+            return;
+        }
+        if let Some((name, _)) = keywords.iter().find(|(name, _)| func.params.iter().all(|p| p.name != *name)) {
+            self.error(env, format!("unknown keyword argument `{}`", name));
+            return;
+        }
+
+        // Match args to params before binding, so a missing one binds nothing
+        let mut positional = positional.into_iter();
+        let mut values = Vec::new();
+        for param in &func.params {
+            let value = match keywords.iter().position(|(name, _)| *name == param.name) {
+                Some(i) => Some(keywords.swap_remove(i).1),
+                None if !param.keyword => positional.next(),
+                None => None,
+            };
+            if value.is_none() && param.default.is_none() {
+                self.error(env, format!("missing argument `{}`", param.name));
+                return;
+            }
+            values.push(value);
+        }
+
+        let saved = func
+            .params
+            .iter()
+            .map(|p| p.name.as_str())
+            .chain(std::iter::once(RETURN_VAR))
+            .map(|name| (name.to_string(), self.ctx.binding(name)))
+            .collect();
+        self.ctx.bind(RETURN_VAR, None);
+        // Defaults are evaluated in order, so they can use earlier params
+        for (param, value) in func.params.iter().zip(values) {
+            let value = value.unwrap_or_else(|| self.ctx.evaluate(param.default.as_deref().unwrap_or("nil")));
+            self.ctx.bind(&param.name, Some(value));
+        }
+        self.frames.push(Frame::new(Body::Stmts(func.body), FrameKind::Call { saved, result }));
+    }
+
+    /// True while running inside a function body
+    fn in_call(&self) -> bool {
+        self.frames.iter().any(|f| matches!(f.kind, FrameKind::Call { .. }))
+    }
+
+    fn exec_command(&mut self, cmd: &ParsedCommand, env: &mut Env) -> Flow {
+        match cmd {
+            ParsedCommand::PlayNote { .. } => {
+                if let Some(note) = note_to_audio(cmd) {
+                    self.emit(env, Event::Audio(note));
+                }
+            }
+            ParsedCommand::PlaySample { name, rate, amplitude, pan } => {
+                self.emit(env, Event::Sample {
+                    name: name.clone(),
+                    rate: *rate,
+                    amplitude: *amplitude,
+                    pan: *pan,
+                    fx: None,
+                });
+            }
+            ParsedCommand::Sleep(beats) => {
+                if self.bpm > 0.0 {
+                    self.time += beats.max(0.0) as f64 * 60.0 / self.bpm as f64;
+                }
+                return Flow::Sleep;
+            }
+            ParsedCommand::SetBpm(bpm) => self.set_bpm(*bpm, env),
+            ParsedCommand::SetVolume(vol) => {
+                self.emit(env, Event::Audio(AudioCommand::SetMasterVolume(*vol)));
+            }
+            ParsedCommand::SetSynth(synth) => self.ctx.current_synth = *synth,
+            ParsedCommand::WithFx { fx_type, params, commands } => {
+                self.enter_fx(fx_type.clone(), params.clone(), Body::Commands(Arc::new(commands.clone())), env);
+            }
+            ParsedCommand::Loop { name, commands, parallel } => {
+                let body = Body::Commands(Arc::new(commands.clone()));
+                if *parallel {
+                    let frame = Frame::new(body, FrameKind::LiveLoop { iteration_start: self.progress() });
+                    self.spawn(env, Some(name.clone()), frame, None);
+                } else {
+                    self.push(
+                        body,
+                        FrameKind::Repeat { count: None, index: 0, var: None, saved: None, iteration_start: self.progress() },
+                    );
+                }
+            }
+            ParsedCommand::TimesLoop { count, commands } => {
+                if *count > 0 {
+                    self.push(
+                        Body::Commands(Arc::new(commands.clone())),
+                        FrameKind::Repeat {
+                            count: Some(*count),
+                            index: 0,
+                            var: None,
+                            saved: None,
+                            iteration_start: self.progress(),
+                        },
+                    );
+                }
+            }
+            ParsedCommand::Stop => return Flow::Stop,
+            ParsedCommand::Cue(path) => env.cues.push((self.time, path.clone())),
+            ParsedCommand::Sync(path) => {
+                self.waiting = Some(path.clone());
+                return Flow::Sleep;
+            }
+            ParsedCommand::Control { node, params } => {
+                self.emit(env, Event::Audio(AudioCommand::ControlNote { node: *node, params: params.clone() }));
+            }
+            ParsedCommand::Log(msg) => self.emit(env, Event::Log(msg.clone())),
+            ParsedCommand::Comment(_) => {}
+        }
+        Flow::Next
+    }
+
+    /// `s = play ...`: play the note with a fresh node handle and keep the
+    /// handle in `var_name` for `control`
+    fn play_node(&mut self, var_name: &str, expr: &str, env: &mut Env) {
+        let Some(cmd) = parse_line(expr, &mut self.ctx) else {
+            return;
+        };
+        match note_to_audio(&cmd) {
+            Some(mut note) => {
+                env.next_node += 1;
+                if let AudioCommand::PlayNote { node, .. } = &mut note {
+                    *node = Some(env.next_node);
+                }
+                self.emit(env, Event::Audio(note));
+                self.ctx.variables.insert(var_name.to_string(), node_ref(env.next_node));
+            }
+            // Rests and chords: nothing to control later
+            None => {
+                self.exec_command(&cmd, env);
+            }
+        }
+    }
+
+    /// Start a `with_fx` block: FxStart with the block's id and its parent's.
+    /// The same `with_fx` gets the same id each time this thread enters it,
+    /// so the engine creates its FX once and frees it when the thread ends.
+    fn enter_fx(&mut self, fx_type: String, params: Vec<(String, f32)>, body: Body, env: &mut Env) {
+        let parent = self.fx_stack.last().copied();
+        let key = (self.line, parent, fx_type.clone());
+        let id = match self.fx_blocks.get(&key) {
+            Some(&id) => id,
+            None => {
+                env.next_fx += 1;
+                let id = env.next_fx;
+                self.fx_blocks.insert(key, id);
+                self.hold_fx(id, env);
+                id
+            }
+        };
+        self.emit(env, Event::Audio(AudioCommand::FxStart { id, parent, fx_type, params }));
+        self.fx_stack.push(id);
+        self.push(body, FrameKind::Fx);
+    }
+
+    /// Keep `with_fx` block `id` running until this thread ends
+    fn hold_fx(&mut self, id: u32, env: &mut Env) {
+        *env.fx_users.entry(id).or_insert(0) += 1;
+        self.fx_held.push(id);
+    }
+
+    /// Let go of every `with_fx` block this thread holds, freeing those no
+    /// other thread is using
+    fn release_fx(&mut self, env: &mut Env) {
+        for id in std::mem::take(&mut self.fx_held) {
+            let Some(users) = env.fx_users.get_mut(&id) else {
+                continue;
+            };
+            *users -= 1;
+            if *users == 0 {
+                env.fx_users.remove(&id);
+                self.emit(env, Event::Audio(AudioCommand::FxFree { id }));
+            }
+        }
+    }
+
+    fn push(&mut self, body: Body, kind: FrameKind) {
+        if body.len() > 0 || matches!(kind, FrameKind::Fx | FrameKind::Synth { .. } | FrameKind::Bpm { .. } | FrameKind::Density { .. }) {
+            self.frames.push(Frame::new(body, kind));
+        }
+    }
+
+    /// Start a new thread at this thread's current time, inheriting its
+    /// variables, synth, BPM and tick state. Its random stream is derived
+    /// from this thread's seed and the live_loop name (or thread id). With
+    /// `sync` set it waits for that cue before running.
+    fn spawn(&self, env: &mut Env, name: Option<String>, frame: Frame, sync: Option<String>) {
+        let id = env.next_id;
+        env.next_id += 1;
+        let mut ctx = self.ctx.clone();
+        let stream_name = name.clone().unwrap_or_else(|| format!("thread {}", id));
+        ctx.set_random(self.ctx.random().derive(&stream_name));
+        let mut thread = Thread {
+            id,
+            live_loop: name.clone().or_else(|| self.live_loop.clone()),
+            name,
+            time: self.time,
+            bpm: self.bpm,
+            ctx,
+            fx_stack: self.fx_stack.clone(),
+            fx_blocks: HashMap::new(),
+            fx_held: Vec::new(),
+            frames: vec![frame],
+            pending_body: None,
+            retired: false,
+            iterations: 0,
+            waiting: sync,
+            wakeups: self.wakeups,
+            line: self.line,
+        };
+        // Blocks it was started in keep running while it plays into them
+        for &id in &self.fx_stack {
+            thread.hold_fx(id, env);
+        }
+        env.spawned.push(thread);
+    }
+
+    fn progress(&self) -> Progress {
+        (self.time, self.wakeups)
+    }
+
+    fn set_bpm(&mut self, bpm: f32, env: &mut Env) {
+        if bpm > 0.0 {
+            self.bpm = bpm;
+            self.emit(env, Event::Audio(AudioCommand::SetBpm(bpm)));
+        }
+    }
+
+    /// Queue `event` at this thread's time. Notes and samples are sent into
+    /// the innermost `with_fx` block, or dropped if the thread is muted.
+    fn emit(&self, env: &mut Env, mut event: Event) {
+        match &mut event {
+            Event::Sample { fx, .. } | Event::Audio(AudioCommand::PlayNote { fx, .. }) => {
+                if !env.audible(self.live_loop.as_deref()) {
+                    return;
+                }
+                *fx = self.fx_stack.last().copied();
+            }
+            _ => {}
+        }
+        env.events.push((self.time, self.live_loop.clone(), event));
+    }
+
+    fn error(&self, env: &mut Env, message: String) {
+        let message = format!("line {}: {}", self.line, message);
+        eprintln!("[interpreter] {}", message);
+        self.emit(env, Event::Error(message));
+    }
+
+    fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("live_loop :{}", name),
+            None if self.id == 0 => "main thread".to_string(),
+            None => format!("thread {}", self.id),
+        }
+    }
+
+    /// Offsets (seconds) and block args for each run of an `at` / `time_warp`
+    /// block. As in Sonic Pi, a block taking one arg gets the value when
+    /// values are given and the offset otherwise; more args get the offset,
+    /// the value and the index, in that order.
+    fn timing_runs(&self, times: &str, values: Option<&str>, arity: usize) -> Vec<(f64, Vec<String>)> {
+        let offsets: Vec<f32> = match self.ctx.resolve_to_list(times) {
+            Some(items) => items.iter().filter_map(|t| self.eval_number(t)).collect(),
+            None => self.eval_number(times).into_iter().collect(),
+        };
+        let values: Option<Vec<String>> = values.map(|v| {
+            self.ctx
+                .resolve_to_list(v)
+                .unwrap_or_else(|| vec![v.to_string()])
+        });
+        let beat = 60.0 / self.bpm.max(f32::EPSILON) as f64;
+
+        offsets
+            .iter()
+            .enumerate()
+            .map(|(index, offset)| {
+                let value = values.as_ref().filter(|v| !v.is_empty()).map(|v| v[index % v.len()].clone());
+                let mut args = vec![offset.to_string()];
+                match value {
+                    Some(value) if arity == 1 => args = vec![value],
+                    Some(value) => args.push(value),
+                    None => {}
+                }
+                args.push(index.to_string());
+                args.truncate(arity);
+                (*offset as f64 * beat, args)
+            })
+            .collect()
+    }
+
+    /// Evaluate a count / factor expression: number, rrand(..), or variable
+    fn eval_number(&self, expr: &str) -> Option<f32> {
+        let trimmed = expr.trim();
+        self.ctx.resolve_numeric(trimmed).or_else(|| {
+            self.ctx.variables.get(trimmed).and_then(|v| v.trim().parse().ok())
+        })
+    }
+}
+
+/// Bind `.each` block variables for item `index`
+fn bind_each(ctx: &mut ParseContext, items: &[String], index: usize, vars: &[String], with_index: bool) {
+    if let Some(var) = vars.first() {
+        ctx.variables.insert(var.clone(), items[index].clone());
+    }
+    if with_index {
+        if let Some(var) = vars.get(1) {
+            ctx.variables.insert(var.clone(), index.to_string());
+        }
+    }
+}
+
+/// A call to a defined function: the function and its argument text.
+/// Handles `name`, `name(args)` and `name args`.
+fn function_call<'a>(expr: &'a str, env: &Env) -> Option<(Function, &'a str)> {
+    let expr = expr.trim();
+    let name_end = expr.find(|c: char| c == '(' || c.is_whitespace()).unwrap_or(expr.len());
+    let func = env.functions.get(&expr[..name_end])?.clone();
+    let rest = expr[name_end..].trim();
+    let args = match rest.strip_prefix('(') {
+        Some(inner) => inner.rfind(')').map_or(inner, |close| &inner[..close]),
+        None => rest,
+    };
+    Some((func, args))
+}
+
+/// Bind block variables to `values`, in order
+fn bind_vars(ctx: &mut ParseContext, vars: &[String], values: &[String]) {
+    for (var, value) in vars.iter().zip(values) {
+        ctx.variables.insert(var.clone(), value.clone());
+    }
+}
+
+/// Add `name` to `set` or take it out
+fn toggle(set: &mut HashSet<String>, name: &str, on: bool) {
+    if on {
+        set.insert(name.to_string());
+    } else {
+        set.remove(name);
+    }
+}
+
+/// Put back a block variable's value from before the block
+fn restore_var(ctx: &mut ParseContext, var: &str, old: Option<String>) {
+    match old {
+        Some(value) => {
+            ctx.variables.insert(var.to_string(), value);
+        }
+        None => {
+            ctx.variables.remove(var);
+        }
+    }
+}
+
+/// Runs a program as a set of logical threads and produces timed events
+#[derive(Debug, Clone)]
+pub struct Interpreter {
+    threads: Vec<Thread>,
+    env: Env,
+    /// Every live_loop as it was when it started (with its latest body), in
+    /// start order, so it can be restarted
+    launched: Vec<Thread>,
+}
+
+impl Interpreter {
+    /// Set up `program` to run from time zero on the main thread
+    pub fn new(program: Vec<Stmt>, bpm: f32) -> Self {
+        Self {
+            threads: vec![Self::main_thread(program, bpm, 0.0)],
+            env: Env {
+                next_id: 1,
+                ..Default::default()
+            },
+            launched: Vec::new(),
+        }
+    }
+
+    fn main_thread(program: Vec<Stmt>, bpm: f32, time: f64) -> Thread {
+        Thread {
+            id: 0,
+            name: None,
+            live_loop: None,
+            time,
+            bpm,
+            ctx: ParseContext::new(),
+            fx_stack: Vec::new(),
+            fx_blocks: HashMap::new(),
+            fx_held: Vec::new(),
+            frames: vec![Frame::new(Body::Stmts(Arc::new(program)), FrameKind::Once)],
+            pending_body: None,
+            retired: false,
+            iterations: 0,
+            waiting: None,
+            wakeups: 0,
+            line: 0,
+        }
+    }
+
+    /// Parse `code` and set it up to run from time zero
+    #[cfg(test)]
+    pub fn from_code(code: &str, bpm: f32) -> Result<Self, String> {
+        Ok(Self::new(parse_program(code)?, bpm))
+    }
+
+    /// Replace the running code with `program`, starting it on a new main
+    /// thread at `time` (seconds, no earlier than the last `run_until`).
+    /// Running live_loops it defines again swap in their new body at their
+    /// next iteration and keep their clock; live_loops it no longer defines
+    /// stop at their next iteration (at once if parked in `sync`). The old
+    /// main thread stops; `in_thread` and `at` threads run out on their own.
+    pub fn hot_swap(&mut self, program: Vec<Stmt>, bpm: f32, time: f64) {
+        let mut names = Vec::new();
+        live_loop_names(&program, &mut names);
+
+        let mut kept = Vec::with_capacity(self.threads.len());
+        for mut thread in std::mem::take(&mut self.threads) {
+            match &thread.name {
+                Some(name) if names.contains(name) => thread.retired = false,
+                Some(_) if thread.waiting.is_none() => thread.retired = true,
+                Some(_) => {
+                    thread.release_fx(&mut self.env);
+                    continue;
+                }
+                None if thread.id == 0 => {
+                    thread.release_fx(&mut self.env);
+                    continue;
+                }
+                None => {}
+            }
+            kept.push(thread);
+        }
+        self.threads = kept;
+        self.launched.retain(|t| t.name.as_ref().is_some_and(|name| names.contains(name)));
+
+        // The new main thread takes over id 0 from the one just stopped
+        self.threads.push(Self::main_thread(program, bpm, time));
+    }
+
+    /// Apply a transport action to the live_loop `name` at `time` (seconds,
+    /// no earlier than the last `run_until`). Stopping or restarting a loop
+    /// also ends the threads it started.
+    pub fn control_loop(&mut self, name: &str, control: LoopControl, time: f64) -> Result<(), String> {
+        let Some(template) = self.launched.iter().find(|t| t.name.as_deref() == Some(name)).cloned() else {
+            return Err(format!("No live_loop named :{}", name));
+        };
+        match control {
+            LoopControl::Mute(on) => toggle(&mut self.env.muted, name, on),
+            LoopControl::Solo(on) => toggle(&mut self.env.soloed, name, on),
+            LoopControl::Stop | LoopControl::Restart => {
+                let (stopped, kept) = std::mem::take(&mut self.threads)
+                    .into_iter()
+                    .partition(|t| t.live_loop.as_deref() == Some(name));
+                self.threads = kept;
+                for mut thread in stopped {
+                    thread.release_fx(&mut self.env);
+                }
+                if control == LoopControl::Restart {
+                    let mut thread = template;
+                    thread.time = time;
+                    thread.id = self.env.next_id;
+                    self.env.next_id += 1;
+                    // Rejoin the blocks it was started in, if still running
+                    thread.fx_stack.retain(|id| self.env.fx_users.contains_key(id));
+                    for id in thread.fx_stack.clone() {
+                        thread.hold_fx(id, &mut self.env);
+                    }
+                    self.threads.push(thread);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Every live_loop started so far, in start order
+    pub fn loops(&self) -> Vec<LoopStatus> {
+        self.launched
+            .iter()
+            .filter_map(|t| t.name.as_ref())
+            .map(|name| {
+                let running = self.threads.iter().find(|t| t.name.as_ref() == Some(name));
+                LoopStatus {
+                    name: name.clone(),
+                    iterations: running.map_or(0, |t| t.iterations),
+                    running: running.is_some(),
+                    muted: self.env.muted.contains(name),
+                    soloed: self.env.soloed.contains(name),
+                }
+            })
+            .collect()
+    }
+
+    /// True once every thread has finished, or all that are left are
+    /// waiting in `sync` for a cue nothing can fire any more, and every
+    /// event has been handed out
+    pub fn is_finished(&self) -> bool {
+        self.threads.iter().all(|t| t.waiting.is_some()) && self.env.events.is_empty()
+    }
+
+    /// Run every thread whose logical time is before `until` (seconds),
+    /// always advancing the one furthest behind, and return the events
+    /// produced, sorted by time. Events a `time_warp` put at or after
+    /// `until` are held back for a later call.
+    pub fn run_until(&mut self, until: f64) -> Vec<(f64, Event)> {
+        self.run_until_by_loop(until)
+            .into_iter()
+            .map(|(time, _, event)| (time, event))
+            .collect()
+    }
+
+    /// `run_until`, with the live_loop each event came from (`None` for the
+    /// main thread and the threads it started)
+    pub fn run_until_by_loop(&mut self, until: f64) -> Vec<(f64, Option<String>, Event)> {
+        loop {
+            let next = self
+                .threads
+                .iter()
+                .enumerate()
+                .filter(|(_, t)| t.waiting.is_none() && t.time < until)
+                .min_by(|(_, a), (_, b)| {
+                    a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal).then(a.id.cmp(&b.id))
+                })
+                .map(|(i, _)| i);
+            let Some(idx) = next else {
+                break;
+            };
+
+            if !self.threads[idx].run(&mut self.env) {
+                self.threads.remove(idx).release_fx(&mut self.env);
+            }
+            self.adopt_spawned();
+            self.deliver_cues();
+        }
+
+        let (mut events, later) = std::mem::take(&mut self.env.events)
+            .into_iter()
+            .partition(|(time, _, _)| *time < until);
+        self.env.events = later;
+        events.sort_by(|a: &(f64, Option<String>, Event), b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        events
+    }
+
+    /// Wake the threads syncing on each cue fired in the last step. A woken
+    /// thread takes on the cue's logical time; cues nobody waits for are lost.
+    fn deliver_cues(&mut self) {
+        for (time, path) in std::mem::take(&mut self.env.cues) {
+            for thread in &mut self.threads {
+                if thread.waiting.as_deref() == Some(path.as_str()) {
+                    thread.waiting = None;
+                    thread.time = time;
+                    thread.wakeups += 1;
+                }
+            }
+        }
+    }
+
+    fn adopt_spawned(&mut self) {
+        for thread in std::mem::take(&mut self.env.spawned) {
+            // Redefining a running live_loop swaps its body at the next
+            // iteration, and the body sees the variables of the new code
+            if let Some(name) = &thread.name {
+                let template = self.launched.iter_mut().find(|t| t.name.as_ref() == Some(name));
+                match template {
+                    Some(template) => {
+                        template.frames = thread.frames.clone();
+                        template.ctx.variables.extend(thread.ctx.variables.clone());
+                    }
+                    None => self.launched.push(thread.clone()),
+                }
+                if let Some(existing) = self.threads.iter_mut().find(|t| t.name.as_ref() == Some(name)) {
+                    if let Some(Frame { body: Body::Stmts(body), .. }) = thread.frames.first() {
+                        existing.pending_body = Some((body.clone(), thread.ctx.variables.clone()));
+                    }
+                    let mut unused = thread;
+                    unused.release_fx(&mut self.env);
+                    continue;
+                }
+            }
+            self.threads.push(thread);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notes(events: &[(f64, Event)]) -> Vec<(f64, f32)> {
+        events
+            .iter()
+            .filter_map(|(t, e)| match e {
+                Event::Audio(AudioCommand::PlayNote { frequency, .. }) => Some((*t, *frequency)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn samples(events: &[(f64, Event)]) -> Vec<(f64, String)> {
+        events
+            .iter()
+            .filter_map(|(t, e)| match e {
+                Event::Sample { name, .. } => Some((*t, name.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_live_loop_reevaluates_body_each_iteration() {
+        let code = r#"
+notes = (ring :c4, :e4, :g4)
+live_loop :arp do
+  play notes.tick
+  sleep 1
+end
+"#;
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        let freqs: Vec<f32> = notes(&interp.run_until(6.0)).into_iter().map(|(_, f)| f).collect();
+        assert_eq!(freqs.len(), 6);
+        assert_ne!(freqs[0], freqs[1], "tick should advance across iterations: {:?}", freqs);
+        assert_eq!(freqs[0], freqs[3], "ring should wrap: {:?}", freqs);
+    }
+
+    #[test]
+    fn test_if_is_decided_per_iteration() {
+        let code = r#"
+live_loop :alt do
+  if (ring true, false).tick then
+    sample :bd_haus
+  else
+    sample :sn_dub
+  end
+  sleep 1
+end
+"#;
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        let names: Vec<String> = samples(&interp.run_until(4.0)).into_iter().map(|(_, n)| n).collect();
+        assert_eq!(names.len(), 4);
+        assert!(names.iter().any(|n| n.contains("bd_haus")), "{:?}", names);
+        assert!(names.iter().any(|n| n.contains("sn_dub")), "{:?}", names);
+    }
+
+    #[test]
+    fn test_live_loops_run_in_parallel() {
+        let code = r#"
+use_bpm 120
+live_loop :a do
+  play :c4
+  sleep 1
+end
+live_loop :b do
+  play :c5
+  sleep 2
+end
+"#;
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        let played = notes(&interp.run_until(2.0));
+        // 120 BPM: :a every 0.5s, :b every 1s, both starting at zero
+        let a: Vec<f64> = played.iter().filter(|(_, f)| (*f - 261.6).abs() < 1.0).map(|(t, _)| *t).collect();
+        let b: Vec<f64> = played.iter().filter(|(_, f)| (*f - 523.3).abs() < 1.0).map(|(t, _)| *t).collect();
+        assert_eq!(a, vec![0.0, 0.5, 1.0, 1.5]);
+        assert_eq!(b, vec![0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_run_until_streams_in_chunks() {
+        let code = "live_loop :a do\n  play 60\n  sleep 1\nend\n";
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        assert_eq!(notes(&interp.run_until(2.0)).len(), 2);
+        let next = notes(&interp.run_until(4.0));
+        assert_eq!(next.iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![2.0, 3.0]);
+        assert!(!interp.is_finished());
+    }
+
+    #[test]
+    fn test_stop_and_finite_programs_finish() {
+        let code = r#"
+define :hit do
+  sample :bd_haus
+end
+4.times do
+  hit
+  sleep 0.5
+end
+live_loop :once do
+  play 60
+  sleep 1
+  stop
+end
+"#;
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        let events = interp.run_until(100.0);
+        assert_eq!(samples(&events).len(), 4);
+        assert_eq!(notes(&events).len(), 1);
+        assert!(interp.is_finished());
+    }
+
+    #[test]
+    fn test_loop_without_sleep_reports_error() {
+        let code = "live_loop :busy do\n  play 60\nend\n";
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        let events = interp.run_until(10.0);
+        assert!(events.iter().any(|(_, e)| matches!(e, Event::Error(msg) if msg.contains("did not sleep"))));
+        assert!(interp.is_finished());
+    }
+
+    #[test]
+    fn test_sync_waits_for_cue_and_takes_its_time() {
+        let code = r#"
+live_loop :follower do
+  sync :bar
+  sample :bd_haus
+end
+live_loop :metro do
+  sleep 1
+  cue :bar
+  sleep 3
+end
+"#;
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        let times: Vec<f64> = samples(&interp.run_until(10.0)).into_iter().map(|(t, _)| t).collect();
+        assert_eq!(times, vec![1.0, 5.0, 9.0]);
+    }
+
+    #[test]
+    fn test_live_loop_sync_option_and_auto_cue() {
+        let code = r#"
+live_loop :drums do
+  sample :bd_haus
+  sleep 2
+end
+live_loop :bass, sync: "/live_loop/drums" do
+  play :c2
+  sleep 1
+end
+live_loop :late, sync: :never do
+  play :c5
+  sleep 1
+end
+"#;
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        let played = notes(&interp.run_until(4.0));
+        // :bass waits from the moment it is defined and catches :drums' first cue;
+        // :late never hears its cue and plays nothing
+        assert_eq!(played.iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![0.0, 1.0, 2.0, 3.0]);
+        assert!(played.iter().all(|(_, f)| *f < 100.0));
+    }
+
+    #[test]
+    fn test_program_waiting_on_missing_cue_finishes() {
+        let code = "play 60
+sync :nothing
+play 62
+";
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        assert_eq!(notes(&interp.run_until(10.0)).len(), 1);
+        assert!(interp.is_finished());
+    }
+
+    #[test]
+    fn test_control_targets_the_stored_note() {
+        let code = "s = play :c4, sustain: 8
+sleep 1
+control s, note: :e4, note_slide: 0.5, cutoff: 80
+play :g4
+";
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        let events = interp.run_until(10.0);
+        let handles: Vec<Option<u32>> = events
+            .iter()
+            .filter_map(|(_, e)| match e {
+                Event::Audio(AudioCommand::PlayNote { node, .. }) => Some(*node),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(handles, vec![Some(1), None]);
+
+        let (t, node, params) = events
+            .iter()
+            .find_map(|(t, e)| match e {
+                Event::Audio(AudioCommand::ControlNote { node, params }) => Some((*t, *node, params.clone())),
+                _ => None,
+            })
+            .expect("control emitted");
+        assert_eq!((t, node), (1.0, 1));
+        let get = |name: &str| params.iter().find(|(n, _)| n == name).map(|(_, v)| *v);
+        assert!((get("freq").unwrap() - 329.63).abs() < 0.1);
+        assert_eq!(get("freq_slide"), Some(0.5));
+        assert_eq!(get("cutoff"), Some(80.0));
+    }
+
+    #[test]
+    fn test_at_runs_body_at_each_offset() {
+        let code = "use_bpm 120
+at [0, 1, 2], [:c4, :e4, :g4] do |n|
+  play n
+end
+at [1, 3] do |t|
+  sample :bd_haus if t > 2
+end
+play :a4
+";
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        let events = interp.run_until(10.0);
+        let played: Vec<(f64, u32)> = notes(&events).iter().map(|(t, f)| (*t, f.round() as u32)).collect();
+        assert_eq!(played, vec![(0.0, 440), (0.0, 262), (0.5, 330), (1.0, 392)]);
+
+        // `t` is bound to each offset in beats
+        assert_eq!(samples(&events).iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![1.5]);
+        assert!(interp.is_finished());
+    }
+
+    #[test]
+    fn test_time_warp_shifts_without_advancing() {
+        let code = "time_warp 0.5 do
+  play :c4
+end
+time_warp [0, 2] do
+  sample :bd_haus
+end
+play :e4
+sleep 1
+time_warp -0.5 do
+  play :g4
+end
+play :a4
+";
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        let first = interp.run_until(1.0);
+        assert_eq!(notes(&first).iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![0.0, 0.5]);
+        assert_eq!(samples(&first).iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![0.0]);
+
+        // The sample warped past the horizon comes with the next call
+        let rest = interp.run_until(10.0);
+        assert_eq!(samples(&rest).iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![2.0]);
+        assert_eq!(notes(&rest).iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![0.5, 1.0]);
+        assert!(interp.is_finished());
+    }
+
+    #[test]
+    fn test_function_params_defaults_and_keywords() {
+        let code = "define :bassline do |note, dur = 1, amp: 0.5|
+  play note, amp: amp
+  sleep dur
+end
+note = :c2
+bassline :e2, 0.5
+bassline(:g2, amp: 2)
+play note
+";
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        let events = interp.run_until(10.0);
+        let played: Vec<(f64, u32, f32)> = events
+            .iter()
+            .filter_map(|(t, e)| match e {
+                Event::Audio(AudioCommand::PlayNote { frequency, amplitude, .. }) => {
+                    Some((*t, frequency.round() as u32, *amplitude))
+                }
+                _ => None,
+            })
+            .collect();
+        // The param shadows the global `note` only during the call
+        assert_eq!(played, vec![(0.0, 82, 0.5), (0.5, 98, 2.0), (1.5, 65, 0.5)]);
+    }
+
+    #[test]
+    fn test_function_return_value_and_bad_calls() {
+        let code = "def pick_note(notes, offset = 0)
+  offset
+  notes.choose
+end
+scale_notes = [:a4]
+n = pick_note(scale_notes)
+play n
+pick_note
+pick_note scale_notes, 1, 2
+";
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        let events = interp.run_until(10.0);
+        let played: Vec<u32> = notes(&events).iter().map(|(_, f)| f.round() as u32).collect();
+        assert_eq!(played, vec![440]);
+
+        let errors: Vec<&String> = events
+            .iter()
+            .filter_map(|(_, e)| match e {
+                Event::Error(msg) => Some(msg),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].contains("missing argument `notes`"));
+        assert!(errors[1].contains("given 3, expected 2"));
+    }
+
+    fn sample_amps(events: &[(f64, Event)], name: &str) -> Vec<f32> {
+        events
+            .iter()
+            .filter_map(|(_, e)| match e {
+                Event::Sample { name: n, amplitude, .. } if n.contains(name) => Some(*amplitude),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_random_streams_repeat_per_run_and_differ_per_loop() {
+        let code = "live_loop :a do
+  sample :bd_haus, amp: rrand(0, 1)
+  sleep 1
+end
+live_loop :b do
+  sample :sn_dub, amp: rrand(0, 1)
+  sleep 1
+end
+";
+        let first = Interpreter::from_code(code, 60.0).unwrap().run_until(4.0);
+        let second = Interpreter::from_code(code, 60.0).unwrap().run_until(4.0);
+        let a = sample_amps(&first, "bd_haus");
+        assert_eq!(a.len(), 4);
+        assert_eq!(a, sample_amps(&second, "bd_haus"));
+        assert_ne!(a, sample_amps(&first, "sn_dub"));
+
+        let reseeded = Interpreter::from_code(&format!("use_random_seed 3\n{}", code), 60.0)
+            .unwrap()
+            .run_until(4.0);
+        assert_ne!(a, sample_amps(&reseeded, "bd_haus"));
+    }
+
+    #[test]
+    fn test_random_seed_reset_skip_and_scoped_seed() {
+        let code = "use_random_seed 7
+sample :bd_haus, amp: rand()
+rand_reset
+sample :bd_haus, amp: rand()
+with_random_seed 1 do
+  sample :bd_haus, amp: rand()
+end
+sample :bd_haus, amp: rand()
+rand_back
+sample :bd_haus, amp: rand()
+rand_skip 2
+sample :bd_haus, amp: rand()
+use_random_seed 7
+rand_skip 4
+sample :bd_haus, amp: rand()
+";
+        let amps = sample_amps(&Interpreter::from_code(code, 60.0).unwrap().run_until(1.0), "bd_haus");
+        assert_eq!(amps.len(), 7);
+        assert_eq!(amps[0], amps[1]);
+        assert_ne!(amps[2], amps[3]);
+        // with_random_seed leaves the outer stream where it was
+        assert_ne!(amps[1], amps[3]);
+        assert_eq!(amps[3], amps[4]);
+        assert_eq!(amps[5], amps[6]);
+    }
+
+    #[test]
+    fn test_hot_swap_keeps_phase_and_stops_removed_loops() {
+        let before = r#"
+live_loop :lead do
+  play :c4
+  sleep 1
+end
+live_loop :gone do
+  play :a4
+  sleep 1
+end
+"#;
+        let after = r#"
+live_loop :lead do
+  play :d4
+  sleep 1
+end
+live_loop :added do
+  play :a5
+  sleep 1
+end
+"#;
+        let mut interp = Interpreter::from_code(before, 60.0).unwrap();
+        interp.run_until(2.5);
+        interp.hot_swap(parse_program(after).unwrap(), 60.0, 2.5);
+        let played = notes(&interp.run_until(5.0));
+
+        let at = |freq: f32| -> Vec<f64> {
+            played.iter().filter(|(_, f)| (*f - freq).abs() < 0.5).map(|(t, _)| *t).collect()
+        };
+        // :lead keeps its beat and plays the new body from its next iteration
+        assert_eq!(at(293.66), vec![3.0, 4.0]);
+        assert!(at(261.63).is_empty());
+        // :gone stops at its next iteration; :added starts when the swap lands
+        assert!(at(440.0).is_empty());
+        assert_eq!(at(880.0), vec![2.5, 3.5, 4.5]);
+    }
+
+    #[test]
+    fn test_hot_swap_brings_new_variables_into_running_loops() {
+        let code = |note: &str| format!("n = {}\nlive_loop :a do\n  play n\n  sleep 1\nend\n", note);
+        let mut interp = Interpreter::from_code(&code(":c4"), 60.0).unwrap();
+        interp.run_until(1.5);
+        interp.hot_swap(parse_program(&code(":e4")).unwrap(), 60.0, 1.5);
+        let played = notes(&interp.run_until(3.5));
+        let freqs: Vec<f32> = played.iter().map(|(_, f)| (*f * 100.0).round() / 100.0).collect();
+        assert_eq!(freqs, vec![329.63, 329.63]);
+
+        // Restarting the loop keeps the new value too
+        interp.control_loop("a", LoopControl::Restart, 3.5).unwrap();
+        let played = notes(&interp.run_until(4.0));
+        assert_eq!(played.len(), 1);
+        assert!((played[0].1 - 329.63).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_loop_mute_solo_stop_and_restart() {
+        let code = r#"
+live_loop :drums do
+  sample :bd_haus
+  sleep 1
+end
+live_loop :bass do
+  play :c2
+  sleep 1
+end
+"#;
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        interp.run_until(2.0);
+
+        interp.control_loop("drums", LoopControl::Mute(true), 2.0).unwrap();
+        let events = interp.run_until(3.0);
+        assert!(samples(&events).is_empty());
+        assert_eq!(notes(&events).len(), 1);
+
+        // Solo wins over everything else; unmuting does not undo it
+        interp.control_loop("drums", LoopControl::Mute(false), 3.0).unwrap();
+        interp.control_loop("drums", LoopControl::Solo(true), 3.0).unwrap();
+        let events = interp.run_until(4.0);
+        assert_eq!(samples(&events).len(), 1);
+        assert!(notes(&events).is_empty());
+        interp.control_loop("drums", LoopControl::Solo(false), 4.0).unwrap();
+
+        interp.control_loop("bass", LoopControl::Stop, 4.0).unwrap();
+        assert!(notes(&interp.run_until(5.5)).is_empty());
+        let status = interp.loops();
+        assert_eq!(status.iter().map(|l| (l.name.as_str(), l.running)).collect::<Vec<_>>(),
+            vec![("drums", true), ("bass", false)]);
+        assert_eq!(status[0].iterations, 5);
+
+        // A restart plays from the top of the body straight away
+        interp.control_loop("bass", LoopControl::Restart, 5.5).unwrap();
+        let times: Vec<f64> = notes(&interp.run_until(7.0)).iter().map(|(t, _)| *t).collect();
+        assert_eq!(times, vec![5.5, 6.5]);
+        assert!(interp.control_loop("lead", LoopControl::Stop, 7.0).is_err());
+    }
+
+    #[test]
+    fn test_events_carry_their_live_loop() {
+        let code = r#"
+play 60
+live_loop :drums do
+  in_thread do
+    sample :bd_haus
+  end
+  sleep 1
+end
+"#;
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        let sources: Vec<(f64, Option<String>)> = interp
+            .run_until_by_loop(2.0)
+            .into_iter()
+            .filter(|(_, _, e)| matches!(e, Event::Sample { .. } | Event::Audio(AudioCommand::PlayNote { .. })))
+            .map(|(t, live_loop, _)| (t, live_loop))
+            .collect();
+        let drums = Some("drums".to_string());
+        assert_eq!(sources, vec![(0.0, None), (0.0, drums.clone()), (1.0, drums)]);
+    }
+
+    #[test]
+    fn test_overlapping_loops_keep_their_own_fx_blocks() {
+        let code = r#"
+live_loop :pad do
+  with_fx :reverb do
+    with_fx :echo do
+      play :c4
+    end
+    sleep 1
+  end
+end
+live_loop :lead do
+  with_fx :distortion do
+    play :a4
+    sleep 0.5
+  end
+end
+"#;
+        let mut interp = Interpreter::from_code(code, 60.0).unwrap();
+        let events = interp.run_until(3.0);
+
+        // One block per with_fx, kept across iterations; echo nests in reverb
+        let starts: Vec<(u32, Option<u32>)> = events
+            .iter()
+            .filter_map(|(_, e)| match e {
+                Event::Audio(AudioCommand::FxStart { id, parent, .. }) => Some((*id, *parent)),
+                _ => None,
+            })
+            .collect();
+        let mut blocks = starts.clone();
+        blocks.sort();
+        blocks.dedup();
+        assert_eq!(blocks.len(), 3);
+        let reverb = starts[0].0;
+        assert!(starts.contains(&(reverb + 1, Some(reverb))));
+
+        let note_fx = |freq: f32| -> Vec<Option<u32>> {
+            events
+                .iter()
+                .filter_map(|(_, e)| match e {
+                    Event::Audio(AudioCommand::PlayNote { frequency, fx, .. })
+                        if (*frequency - freq).abs() < 1.0 => Some(*fx),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(note_fx(261.63), vec![Some(reverb + 1); 3]);
+        let lead = note_fx(440.0);
+        assert_eq!(lead.len(), 6);
+        assert!(lead.iter().all(|fx| *fx == lead[0] && fx.is_some() && *fx != Some(reverb + 1)));
+
+        // Stopping a loop frees only its own blocks
+        interp.control_loop("lead", LoopControl::Stop, 3.0).unwrap();
+        let freed: Vec<u32> = interp
+            .run_until(4.0)
+            .iter()
+            .filter_map(|(_, e)| match e {
+                Event::Audio(AudioCommand::FxFree { id }) => Some(*id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(freed, vec![lead[0].unwrap()]);
+    }
+}
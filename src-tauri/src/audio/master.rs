@@ -0,0 +1,381 @@
+//! Master bus processing: the brickwall limiter that keeps the output
+//! under full scale, and the meters that report its level.
+//!
+//! The meters work on blocks: the built-in engine feeds them frame by frame
+//! and they close a block every 100 ms, while SuperCollider's `sonic_meter`
+//! synth sends one ready-made block per reply. Peak and RMS cover the last
+//! 300 ms, short-term loudness the last 3 s (ITU-R BS.1770, K-weighted).
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// Level the limiter holds the output under: -1 dBFS
+pub const LIMITER_CEILING: f32 = 0.891;
+/// How far ahead the limiter looks for peaks
+pub const LIMITER_LOOKAHEAD_SECS: f32 = 0.005;
+/// How long the limiter takes to let go after a peak
+const LIMITER_RELEASE_SECS: f32 = 0.1;
+
+/// Floor the meters report for silence, in dB
+const MIN_DB: f32 = -100.0;
+/// Length of the blocks the built-in engine's meter closes
+const BLOCK_SECS: f64 = 0.1;
+/// Window of the peak, RMS and gain reduction readings
+const RMS_WINDOW_SECS: f64 = 0.3;
+/// Window of the short-term loudness reading
+const SHORT_TERM_SECS: f64 = 3.0;
+/// Blocks kept: 3 s of SuperCollider's 30 replies a second, with room over
+const MAX_BLOCKS: usize = 128;
+
+/// Look-ahead brickwall limiter, stereo-linked. The audio is delayed by the
+/// look-ahead so the gain is already down when a peak comes out.
+pub struct Limiter {
+    ceiling: f32,
+    /// Look-ahead in frames
+    lookahead: usize,
+    delay: Vec<(f32, f32)>,
+    /// Gain each recent frame needs, as a rising sequence whose front is
+    /// the lowest over the look-ahead window (frame number, gain)
+    minima: VecDeque<(u64, f32)>,
+    /// Held gain after the release, and the last `lookahead` of it
+    released: f32,
+    smoothing: Vec<f32>,
+    smoothing_sum: f64,
+    release: f32,
+    pos: usize,
+    frame: u64,
+    gain: f32,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: f32) -> Self {
+        let lookahead = ((LIMITER_LOOKAHEAD_SECS * sample_rate) as usize).max(1);
+        Self {
+            ceiling: LIMITER_CEILING,
+            lookahead,
+            delay: vec![(0.0, 0.0); lookahead],
+            minima: VecDeque::with_capacity(lookahead + 1),
+            released: 1.0,
+            smoothing: vec![1.0; lookahead],
+            smoothing_sum: lookahead as f64,
+            release: 1.0 - (-1.0 / (LIMITER_RELEASE_SECS * sample_rate)).exp(),
+            pos: 0,
+            frame: 0,
+            gain: 1.0,
+        }
+    }
+
+    /// Gain applied to the last frame
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    pub fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let peak = left.abs().max(right.abs());
+        let needed = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+
+        // Lowest gain needed over the look-ahead
+        while self.minima.back().is_some_and(|&(_, gain)| gain >= needed) {
+            self.minima.pop_back();
+        }
+        self.minima.push_back((self.frame, needed));
+        while self.minima.front().is_some_and(|&(frame, _)| frame + self.lookahead as u64 <= self.frame) {
+            self.minima.pop_front();
+        }
+        let held = self.minima.front().map_or(1.0, |&(_, gain)| gain);
+
+        // Drop at once, recover slowly, then average over the look-ahead so
+        // the gain ramps down; every averaged value is at most the gain the
+        // peak needs by the time the peak leaves the delay
+        self.released = if held < self.released {
+            held
+        } else {
+            self.released + (held - self.released) * self.release
+        };
+        self.smoothing_sum += (self.released - self.smoothing[self.pos]) as f64;
+        self.smoothing[self.pos] = self.released;
+        self.gain = (self.smoothing_sum / self.lookahead as f64).min(1.0) as f32;
+
+        self.delay[self.pos] = (left, right);
+        self.pos = (self.pos + 1) % self.lookahead;
+        self.frame += 1;
+        // The oldest frame in the delay went in `lookahead - 1` frames ago
+        let (l, r) = self.delay[self.pos];
+        (l * self.gain, r * self.gain)
+    }
+}
+
+/// What the master meters read, in dB
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MeterReading {
+    /// Highest sample over the last 300 ms, in dBFS
+    pub peak_db: f32,
+    /// RMS over the last 300 ms, in dBFS
+    pub rms_db: f32,
+    /// Short-term loudness over the last 3 s, in LUFS
+    pub short_term_lufs: f32,
+    /// Most the limiter turned the output down over the last 300 ms
+    pub gain_reduction_db: f32,
+}
+
+impl Default for MeterReading {
+    fn default() -> Self {
+        Self { peak_db: MIN_DB, rms_db: MIN_DB, short_term_lufs: MIN_DB, gain_reduction_db: 0.0 }
+    }
+}
+
+/// One stretch of metered audio
+#[derive(Debug, Clone, Copy, Default)]
+struct Block {
+    secs: f64,
+    peak: f32,
+    /// Mean square of the two channels added together
+    square: f64,
+    /// The same, K-weighted
+    k_square: f64,
+    /// Lowest limiter gain
+    gain: f32,
+}
+
+/// Second-order section in direct form I
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self { b, a, x: [0.0; 2], y: [0.0; 2] }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let y = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// BS.1770 K-weighting at `sample_rate`: a +4 dB high shelf for the head's
+/// effect, then a high-pass near 38 Hz
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / sample_rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / sample_rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+    [shelf, high_pass]
+}
+
+fn to_db(value: f64) -> f32 {
+    if value > 0.0 {
+        ((20.0 * value.log10()) as f32).max(MIN_DB)
+    } else {
+        MIN_DB
+    }
+}
+
+/// Peak, RMS, short-term loudness and limiter gain reduction of the master
+/// bus
+pub struct Meter {
+    sample_rate: f64,
+    /// Left and right K-weighting filters
+    k_filters: [[Biquad; 2]; 2],
+    /// The block being filled, frame by frame
+    current: Block,
+    frames: usize,
+    block_frames: usize,
+    blocks: VecDeque<Block>,
+    reading: MeterReading,
+}
+
+impl Meter {
+    pub fn new(sample_rate: u32) -> Self {
+        let sample_rate = sample_rate as f64;
+        Self {
+            sample_rate,
+            k_filters: [k_weighting(sample_rate), k_weighting(sample_rate)],
+            current: Block { gain: 1.0, ..Block::default() },
+            frames: 0,
+            block_frames: ((BLOCK_SECS * sample_rate) as usize).max(1),
+            blocks: VecDeque::with_capacity(MAX_BLOCKS),
+            reading: MeterReading::default(),
+        }
+    }
+
+    pub fn reading(&self) -> MeterReading {
+        self.reading
+    }
+
+    /// Meter one output frame, and the limiter gain it went out with
+    pub fn process(&mut self, left: f32, right: f32, limiter_gain: f32) {
+        let k_left = self.k_filters[0].iter_mut().fold(left as f64, |x, f| f.process(x));
+        let k_right = self.k_filters[1].iter_mut().fold(right as f64, |x, f| f.process(x));
+        let block = &mut self.current;
+        block.peak = block.peak.max(left.abs()).max(right.abs());
+        block.square += (left as f64).powi(2) + (right as f64).powi(2);
+        block.k_square += k_left * k_left + k_right * k_right;
+        block.gain = block.gain.min(limiter_gain);
+        self.frames += 1;
+        if self.frames == self.block_frames {
+            let frames = self.frames as f64;
+            let block = Block {
+                secs: frames / self.sample_rate,
+                square: block.square / frames,
+                k_square: block.k_square / frames,
+                ..*block
+            };
+            self.current = Block { gain: 1.0, ..Block::default() };
+            self.frames = 0;
+            self.push_block(block);
+        }
+    }
+
+    /// Add a block metered elsewhere: `secs` long, with its peak, and the
+    /// mean squares (plain and K-weighted) of both channels added together
+    pub fn push_measured(&mut self, secs: f64, peak: f32, square: f64, k_square: f64) {
+        self.push_block(Block { secs, peak, square, k_square, gain: 1.0 });
+    }
+
+    fn push_block(&mut self, block: Block) {
+        if self.blocks.len() == MAX_BLOCKS {
+            self.blocks.pop_back();
+        }
+        self.blocks.push_front(block);
+
+        let mut recent = Block { gain: 1.0, ..Block::default() };
+        let mut short_term = (0.0, 0.0);
+        for block in &self.blocks {
+            if recent.secs < RMS_WINDOW_SECS {
+                recent.secs += block.secs;
+                recent.peak = recent.peak.max(block.peak);
+                recent.square += block.square * block.secs;
+                recent.gain = recent.gain.min(block.gain);
+            }
+            if short_term.0 >= SHORT_TERM_SECS {
+                break;
+            }
+            short_term.0 += block.secs;
+            short_term.1 += block.k_square * block.secs;
+        }
+        // Each channel's mean square is halved for RMS, but summed for loudness
+        let mean_square = recent.square / recent.secs.max(f64::EPSILON) / 2.0;
+        let loudness = short_term.1 / short_term.0.max(f64::EPSILON);
+        self.reading = MeterReading {
+            peak_db: to_db(recent.peak as f64),
+            rms_db: to_db(mean_square.sqrt()),
+            short_term_lufs: if loudness > 0.0 {
+                ((-0.691 + 10.0 * loudness.log10()) as f32).max(MIN_DB)
+            } else {
+                MIN_DB
+            },
+            gain_reduction_db: (0.0 - to_db(recent.gain as f64)).max(0.0),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db(value: f32) -> f32 {
+        20.0 * value.log10()
+    }
+
+    #[test]
+    fn test_limiter_holds_the_ceiling() {
+        let mut limiter = Limiter::new(44100.0);
+        for i in 0..44100 {
+            // A loud sine with louder spikes on top
+            let mut x = 4.0 * (i as f32 * 0.05).sin();
+            if i % 1000 == 500 {
+                x = 20.0;
+            }
+            let (l, r) = limiter.process(x, -x * 0.5);
+            assert!(l.abs() <= LIMITER_CEILING + 1e-6 && r.abs() <= LIMITER_CEILING + 1e-6, "frame {}: {}", i, l);
+        }
+    }
+
+    #[test]
+    fn test_limiter_passes_quiet_audio_late() {
+        let mut limiter = Limiter::new(44100.0);
+        let lookahead = (LIMITER_LOOKAHEAD_SECS * 44100.0) as usize;
+        let input: Vec<f32> = (0..1000).map(|i| i as f32 * 1e-4).collect();
+        let out: Vec<f32> = input.iter().map(|&x| limiter.process(x, 0.0).0).collect();
+        assert_eq!(out[lookahead - 1..], input[..input.len() - lookahead + 1]);
+        assert_eq!(limiter.gain(), 1.0);
+    }
+
+    #[test]
+    fn test_limiter_lets_go_after_a_peak() {
+        let mut limiter = Limiter::new(44100.0);
+        let lookahead = (LIMITER_LOOKAHEAD_SECS * 44100.0) as usize;
+        let mut out = limiter.process(2.0, 2.0);
+        for _ in 1..lookahead {
+            out = limiter.process(0.0, 0.0);
+        }
+        // The peak comes out of the delay right at the ceiling
+        assert!((out.0 - LIMITER_CEILING).abs() < 1e-4, "{}", out.0);
+        for _ in 0..44100 {
+            limiter.process(0.0, 0.0);
+        }
+        assert!(limiter.gain() > 0.99);
+    }
+
+    #[test]
+    fn test_peak_holds_for_its_window_then_falls() {
+        let mut meter = Meter::new(44100);
+        meter.push_measured(0.1, 0.5, 0.0, 0.0);
+        assert!((meter.reading().peak_db - db(0.5)).abs() < 1e-4);
+        for _ in 0..2 {
+            meter.push_measured(0.1, 0.0, 0.0, 0.0);
+            assert!((meter.reading().peak_db - db(0.5)).abs() < 1e-4);
+        }
+        meter.push_measured(0.1, 0.0, 0.0, 0.0);
+        assert_eq!(meter.reading().peak_db, MIN_DB);
+    }
+
+    #[test]
+    fn test_meter_reads_a_full_scale_sine() {
+        let sample_rate = 48000;
+        let mut meter = Meter::new(sample_rate);
+        for i in 0..sample_rate {
+            let x = (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / sample_rate as f32).sin();
+            meter.process(x, x, 0.5);
+        }
+        let reading = meter.reading();
+        assert!(reading.peak_db.abs() < 0.01, "peak {}", reading.peak_db);
+        assert!((reading.rms_db - db(std::f32::consts::FRAC_1_SQRT_2)).abs() < 0.05, "rms {}", reading.rms_db);
+        assert!((reading.gain_reduction_db + db(0.5)).abs() < 1e-3);
+        assert!(reading.short_term_lufs > -5.0 && reading.short_term_lufs < 3.0, "{}", reading.short_term_lufs);
+    }
+
+    #[test]
+    fn test_silence_reads_the_floor() {
+        let mut meter = Meter::new(44100);
+        for _ in 0..44100 {
+            meter.process(0.0, 0.0, 1.0);
+        }
+        let reading = meter.reading();
+        assert_eq!((reading.peak_db, reading.rms_db, reading.short_term_lufs), (MIN_DB, MIN_DB, MIN_DB));
+        assert_eq!(reading.gain_reduction_db, 0.0);
+    }
+}
@@ -1,8 +1,13 @@
 pub mod engine;
 pub mod synth;
 pub mod effects;
+pub mod master;
 pub mod sample;
 pub mod recorder;
+pub mod render;
 pub mod parser;
+pub mod random;
+pub mod interpreter;
+pub mod diagnostics;
 pub mod sc_engine;
 pub mod sc_synthdefs;
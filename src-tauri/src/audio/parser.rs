@@ -1,7 +1,8 @@
+use std::cell::Cell;
 use std::collections::HashMap;
-use rand::Rng;
 use super::engine::AudioCommand;
-use super::synth::{midi_to_freq, note_name_to_midi, Envelope, OscillatorType};
+use super::random::{RandomSource, RandomStream};
+use super::synth::{midi_to_freq, note_name_to_midi, EnvCurve, Envelope, OscillatorType};
 
 /// Represents a parsed command from user code
 #[derive(Debug, Clone)]
@@ -10,8 +11,8 @@ pub enum ParsedCommand {
         synth_type: OscillatorType,
         frequency: f32,
         amplitude: f32,
-        duration: f32,
         pan: f32,
+        /// Envelope opts; `sustain` is the hold time in seconds
         envelope: Envelope,
         /// Synth-specific parameters (cutoff, res, detune, depth, etc.)
         params: Vec<(String, f32)>,
@@ -44,16 +45,36 @@ pub enum ParsedCommand {
         commands: Vec<ParsedCommand>,
     },
     Stop,
+    /// `cue` — wakes threads waiting in `sync` on the same cue path
+    Cue(String),
+    /// `sync` — blocks the thread until the next matching cue
+    Sync(String),
+    /// `control node, param: value` — change a sounding note
+    Control {
+        node: u32,
+        params: Vec<(String, f32)>,
+    },
     Comment(String),
     Log(String),
 }
 
+/// Scratch variable `ParseContext::evaluate` assigns through
+const SCRATCH_VAR: &str = "__value";
+
+/// What a variable holds: plain text, or a list / ring with its tick position
+#[derive(Debug, Clone, PartialEq)]
+pub enum Binding {
+    Value(String),
+    List(Vec<String>, usize),
+}
+
 /// Parser context that tracks variables, functions, and synth state
-struct ParseContext {
-    variables: HashMap<String, String>,
-    current_synth: OscillatorType,
+#[derive(Debug, Clone)]
+pub struct ParseContext {
+    pub variables: HashMap<String, String>,
+    pub current_synth: OscillatorType,
     /// Stored function definitions from `define :name do ... end`
-    functions: HashMap<String, String>,
+    pub functions: HashMap<String, String>,
     /// Ring buffer values: variable name -> list of values
     ring_values: HashMap<String, Vec<String>>,
     /// Ring tick counters: variable name -> current index
@@ -64,10 +85,13 @@ struct ParseContext {
     sample_defaults: HashMap<String, f32>,
     /// Global tick counter (used by standalone `tick` / `look`)
     global_tick: usize,
+    /// This thread's random stream; a `Cell` so lookups through `&self`
+    /// can draw from it
+    random: Cell<RandomStream>,
 }
 
 impl ParseContext {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             variables: HashMap::new(),
             current_synth: OscillatorType::Sine,
@@ -77,9 +101,31 @@ impl ParseContext {
             synth_defaults: HashMap::new(),
             sample_defaults: HashMap::new(),
             global_tick: 0,
+            random: Cell::new(RandomStream::default()),
         }
     }
 
+    pub fn random(&self) -> RandomStream {
+        self.random.get()
+    }
+
+    pub fn set_random(&mut self, stream: RandomStream) {
+        self.random.set(stream);
+    }
+
+    /// Next value in `0.0..1.0` from this thread's random stream
+    fn rand_f32(&self) -> f32 {
+        let mut stream = self.random.get();
+        let value = stream.next_f32();
+        self.random.set(stream);
+        value
+    }
+
+    /// Random index in `0..n` (0 when `n` is 0)
+    fn rand_below(&self, n: usize) -> usize {
+        ((self.rand_f32() * n as f32) as usize).min(n.saturating_sub(1))
+    }
+
     /// Resolve a value that may reference a variable or use string concatenation
     fn resolve_string(&self, raw: &str) -> String {
         let trimmed = raw.trim();
@@ -119,9 +165,8 @@ impl ParseContext {
     }
 
     /// Resolve a numeric expression that may contain rrand(), rand(), dice(), etc.
-    fn resolve_numeric(&self, expr: &str) -> Option<f32> {
+    pub fn resolve_numeric(&self, expr: &str) -> Option<f32> {
         let trimmed = expr.trim();
-        let mut rng = rand::thread_rng();
 
         // rrand(min, max)
         if let Some(inner) = extract_func_args(trimmed, "rrand") {
@@ -129,7 +174,7 @@ impl ParseContext {
             if args.len() == 2 {
                 let min: f32 = args[0].trim().parse().ok()?;
                 let max: f32 = args[1].trim().parse().ok()?;
-                return Some(rng.gen_range(min..=max));
+                return Some(min + (max - min) * self.rand_f32());
             }
         }
 
@@ -139,7 +184,7 @@ impl ParseContext {
             if args.len() == 2 {
                 let min: i32 = args[0].trim().parse().ok()?;
                 let max: i32 = args[1].trim().parse().ok()?;
-                return Some(rng.gen_range(min..=max) as f32);
+                return Some((min + self.rand_below((max - min + 1).max(1) as usize) as i32) as f32);
             }
         }
 
@@ -150,19 +195,19 @@ impl ParseContext {
             } else {
                 inner.trim().parse().unwrap_or(1.0)
             };
-            return Some(rng.gen_range(0.0..max));
+            return Some(self.rand_f32() * max);
         }
 
         // rand_i(max)
         if let Some(inner) = extract_func_args(trimmed, "rand_i") {
             let max: i32 = inner.trim().parse().unwrap_or(2);
-            return Some(rng.gen_range(0..max) as f32);
+            return Some(self.rand_below(max.max(1) as usize) as f32);
         }
 
         // dice(n) - random integer 1..n
         if let Some(inner) = extract_func_args(trimmed, "dice") {
             let n: i32 = inner.trim().parse().unwrap_or(6);
-            return Some(rng.gen_range(1..=n) as f32);
+            return Some((1 + self.rand_below(n.max(1) as usize)) as f32);
         }
 
         // Expression with arithmetic: e.g. "1 + rrand(-0.02, 0.03)"
@@ -240,8 +285,7 @@ impl ParseContext {
         if let Some(inner) = extract_func_args(expr, "one_in") {
             let n: u32 = inner.trim().parse().ok()?;
             if n == 0 { return Some(false); }
-            let mut rng = rand::thread_rng();
-            return Some(rng.gen_ratio(1, n));
+            return Some(self.rand_below(n as usize) == 0);
         }
         None
     }
@@ -283,7 +327,6 @@ impl ParseContext {
     ///   `var_name.tick`
     fn resolve_list_value(&mut self, expr: &str) -> Option<String> {
         let trimmed = expr.trim();
-        let mut rng = rand::thread_rng();
 
         // Check for method calls: .choose, .pick, .shuffle, .reverse, .tick, .look, .first, .last
         for method in &[".choose", ".pick(", ".pick", ".shuffle", ".reverse",
@@ -299,7 +342,7 @@ impl ParseContext {
 
                 // Apply the method
                 if method_name.starts_with("choose") {
-                    let idx = rng.gen_range(0..values.len());
+                    let idx = self.rand_below(values.len());
                     return Some(values[idx].clone());
                 }
                 if method_name.starts_with("pick(") {
@@ -307,16 +350,16 @@ impl ParseContext {
                     if let Some(inner) = extract_func_args(method_name, "pick") {
                         let n: usize = inner.trim().parse().unwrap_or(1);
                         let picked: Vec<String> = (0..n)
-                            .map(|_| values[rng.gen_range(0..values.len())].clone())
+                            .map(|_| values[self.rand_below(values.len())].clone())
                             .collect();
                         // Return as first element for single note context
                         return picked.first().cloned();
                     }
-                    let idx = rng.gen_range(0..values.len());
+                    let idx = self.rand_below(values.len());
                     return Some(values[idx].clone());
                 }
                 if method_name == "pick" {
-                    let idx = rng.gen_range(0..values.len());
+                    let idx = self.rand_below(values.len());
                     return Some(values[idx].clone());
                 }
                 if method_name.starts_with("tick") {
@@ -345,7 +388,7 @@ impl ParseContext {
                 }
                 if method_name == "shuffle" {
                     // Shuffle and return first
-                    let idx = rng.gen_range(0..values.len());
+                    let idx = self.rand_below(values.len());
                     return Some(values[idx].clone());
                 }
                 if method_name == "min" {
@@ -373,8 +416,53 @@ impl ParseContext {
         None
     }
 
+    /// What `name` is bound to, if anything
+    pub fn binding(&self, name: &str) -> Option<Binding> {
+        if let Some(items) = self.ring_values.get(name) {
+            let tick = self.ring_counters.get(name).copied().unwrap_or(0);
+            return Some(Binding::List(items.clone(), tick));
+        }
+        self.variables.get(name).map(|v| Binding::Value(v.clone()))
+    }
+
+    /// Bind `name`, replacing whatever it held; `None` unbinds it
+    pub fn bind(&mut self, name: &str, binding: Option<Binding>) {
+        self.variables.remove(name);
+        self.ring_values.remove(name);
+        self.ring_counters.remove(name);
+        match binding {
+            Some(Binding::Value(value)) => {
+                self.variables.insert(name.to_string(), value);
+            }
+            Some(Binding::List(items, tick)) => {
+                self.ring_values.insert(name.to_string(), items);
+                self.ring_counters.insert(name.to_string(), tick);
+            }
+            None => {}
+        }
+    }
+
+    /// Evaluate `expr` like the right-hand side of an assignment, e.g. for a
+    /// function argument or return value
+    pub fn evaluate(&mut self, expr: &str) -> Binding {
+        let trimmed = expr.trim();
+        if let Some(binding) = self.binding(trimmed) {
+            return binding;
+        }
+        if let Some(value) = try_resolve_list_method(trimmed, self) {
+            return Binding::Value(value);
+        }
+        if let Some(value) = self.resolve_numeric(trimmed) {
+            return Binding::Value(value.to_string());
+        }
+        apply_assignment(SCRATCH_VAR, trimmed, self);
+        let binding = self.binding(SCRATCH_VAR).unwrap_or_else(|| Binding::Value(trimmed.to_string()));
+        self.bind(SCRATCH_VAR, None);
+        binding
+    }
+
     /// Resolve an expression to a list of string values
-    fn resolve_to_list(&self, expr: &str) -> Option<Vec<String>> {
+    pub fn resolve_to_list(&self, expr: &str) -> Option<Vec<String>> {
         let trimmed = expr.trim();
 
         // Inline array: [:c4, :e4, :g4]
@@ -703,10 +791,21 @@ pub fn parse_code(code: &str) -> Result<Vec<ParsedCommand>, String> {
 
 /// Pre-process code to join continuation lines (lines ending with `,` or `\`)
 fn join_continuation_lines(code: &str) -> String {
+    numbered_logical_lines(code)
+        .into_iter()
+        .map(|(_, line)| line)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Join continuation lines like `join_continuation_lines`, keeping the
+/// 1-based source line each logical line starts on.
+pub fn numbered_logical_lines(code: &str) -> Vec<(usize, String)> {
     let raw_lines: Vec<&str> = code.lines().collect();
     let mut joined = Vec::new();
     let mut i = 0;
     while i < raw_lines.len() {
+        let line_no = i + 1;
         let mut current = raw_lines[i].to_string();
         // Keep joining while the trimmed line ends with ',' or '\'
         while i + 1 < raw_lines.len() {
@@ -725,10 +824,10 @@ fn join_continuation_lines(code: &str) -> String {
                 break;
             }
         }
-        joined.push(current);
+        joined.push((line_no, current));
         i += 1;
     }
-    joined.join("\n")
+    joined
 }
 
 fn parse_code_with_context(
@@ -769,125 +868,8 @@ fn parse_code_with_context(
         }
 
         // Variable assignment: var_name = "value" or var_name = expression
-        if let Some(caps) = try_parse_assignment(&line) {
-            let (var_name, var_value) = caps;
-
-            // Check if value is a ring() call
-            if let Some(ring_args) = extract_func_args(&var_value, "ring") {
-                let items: Vec<String> = ring_args.split(',')
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                    .collect();
-                eprintln!("[parse] ring '{}' = {:?}", var_name, items);
-                ctx.ring_values.insert(var_name.clone(), items);
-                ctx.ring_counters.insert(var_name, 0);
-                i += 1;
-                continue;
-            }
-
-            // Check if value is a spread() call
-            if let Some(spread_args) = extract_func_args(&var_value, "spread") {
-                let args: Vec<&str> = spread_args.split(',').collect();
-                if args.len() >= 2 {
-                    let pulses: usize = args[0].trim().parse().unwrap_or(0);
-                    let steps: usize = args[1].trim().parse().unwrap_or(0);
-                    let pattern = euclidean_rhythm(pulses, steps);
-                    let items: Vec<String> = pattern.iter()
-                        .map(|b| if *b { "true".to_string() } else { "false".to_string() })
-                        .collect();
-                    eprintln!("[parse] spread({}, {}) '{}' = {:?}", pulses, steps, var_name, items);
-                    ctx.ring_values.insert(var_name.clone(), items);
-                    ctx.ring_counters.insert(var_name, 0);
-                }
-                i += 1;
-                continue;
-            }
-
-            // Check if value is (ring ...) (Sonic Pi alternate syntax)
-            if var_value.starts_with("(ring") || var_value.starts_with("( ring") {
-                let inner = var_value.trim_start_matches('(').trim_end_matches(')').trim();
-                let inner = inner.strip_prefix("ring").unwrap_or(inner).trim();
-                let items: Vec<String> = inner.split(',')
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                    .collect();
-                ctx.ring_values.insert(var_name.clone(), items);
-                ctx.ring_counters.insert(var_name, 0);
-                i += 1;
-                continue;
-            }
-
-            // Check if value is a scale() call → store as ring
-            if var_value.starts_with("scale(") || var_value.starts_with("scale ") {
-                if let Some(items) = ctx.resolve_to_list(&var_value) {
-                    eprintln!("[parse] scale '{}' = {:?}", var_name, items);
-                    ctx.ring_values.insert(var_name.clone(), items);
-                    ctx.ring_counters.insert(var_name, 0);
-                }
-                i += 1;
-                continue;
-            }
-
-            // Check if value is a chord() call → store as ring
-            if var_value.starts_with("chord(") || var_value.starts_with("chord ") {
-                if let Some(items) = ctx.resolve_to_list(&var_value) {
-                    eprintln!("[parse] chord '{}' = {:?}", var_name, items);
-                    ctx.ring_values.insert(var_name.clone(), items);
-                    ctx.ring_counters.insert(var_name, 0);
-                }
-                i += 1;
-                continue;
-            }
-
-            // Check if value is a knit() call → store as ring
-            if var_value.starts_with("knit(") {
-                if let Some(items) = ctx.resolve_to_list(&var_value) {
-                    eprintln!("[parse] knit '{}' = {:?}", var_name, items);
-                    ctx.ring_values.insert(var_name.clone(), items);
-                    ctx.ring_counters.insert(var_name, 0);
-                }
-                i += 1;
-                continue;
-            }
-
-            // Check if value is a range() call → store as ring
-            if var_value.starts_with("range(") {
-                if let Some(items) = ctx.resolve_to_list(&var_value) {
-                    eprintln!("[parse] range '{}' = {:?}", var_name, items);
-                    ctx.ring_values.insert(var_name.clone(), items);
-                    ctx.ring_counters.insert(var_name, 0);
-                }
-                i += 1;
-                continue;
-            }
-
-            // Check if value is a line() call → store as ring
-            if var_value.starts_with("line(") {
-                if let Some(items) = ctx.resolve_to_list(&var_value) {
-                    eprintln!("[parse] line '{}' = {:?}", var_name, items);
-                    ctx.ring_values.insert(var_name.clone(), items);
-                    ctx.ring_counters.insert(var_name, 0);
-                }
-                i += 1;
-                continue;
-            }
-
-            // Check if value is an inline array: [:c4, :e4, :g4]
-            if var_value.starts_with('[') && var_value.ends_with(']') {
-                let inner = &var_value[1..var_value.len()-1];
-                let items: Vec<String> = inner.split(',')
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                    .collect();
-                ctx.ring_values.insert(var_name.clone(), items);
-                ctx.ring_counters.insert(var_name, 0);
-                i += 1;
-                continue;
-            }
-
-            // Resolve the value (could reference other vars)
-            let resolved = ctx.resolve_string(&var_value);
-            ctx.variables.insert(var_name, resolved);
+        if let Some((var_name, var_value)) = try_parse_assignment(&line) {
+            apply_assignment(&var_name, &var_value, ctx);
             i += 1;
             continue;
         }
@@ -932,8 +914,120 @@ fn parse_code_with_context(
     Ok(commands)
 }
 
+/// Store the value of `var_name = var_value` in the context. Lists (ring,
+/// spread, scale, chord, knit, range, line, inline arrays) become rings.
+pub fn apply_assignment(var_name: &str, var_value: &str, ctx: &mut ParseContext) {
+    // Check if value is a ring() call
+    if let Some(ring_args) = extract_func_args(var_value, "ring") {
+        let items: Vec<String> = ring_args.split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+        eprintln!("[parse] ring '{}' = {:?}", var_name, items);
+        ctx.ring_values.insert(var_name.to_string(), items);
+        ctx.ring_counters.insert(var_name.to_string(), 0);
+        return;
+    }
+
+    // Check if value is a spread() call
+    if let Some(spread_args) = extract_func_args(var_value, "spread") {
+        let args: Vec<&str> = spread_args.split(',').collect();
+        if args.len() >= 2 {
+            let pulses: usize = args[0].trim().parse().unwrap_or(0);
+            let steps: usize = args[1].trim().parse().unwrap_or(0);
+            let pattern = euclidean_rhythm(pulses, steps);
+            let items: Vec<String> = pattern.iter()
+                .map(|b| if *b { "true".to_string() } else { "false".to_string() })
+                .collect();
+            eprintln!("[parse] spread({}, {}) '{}' = {:?}", pulses, steps, var_name, items);
+            ctx.ring_values.insert(var_name.to_string(), items);
+            ctx.ring_counters.insert(var_name.to_string(), 0);
+        }
+        return;
+    }
+
+    // Check if value is (ring ...) (Sonic Pi alternate syntax)
+    if var_value.starts_with("(ring") || var_value.starts_with("( ring") {
+        let inner = var_value.trim_start_matches('(').trim_end_matches(')').trim();
+        let inner = inner.strip_prefix("ring").unwrap_or(inner).trim();
+        let items: Vec<String> = inner.split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+        ctx.ring_values.insert(var_name.to_string(), items);
+        ctx.ring_counters.insert(var_name.to_string(), 0);
+        return;
+    }
+
+    // Check if value is a scale() call → store as ring
+    if var_value.starts_with("scale(") || var_value.starts_with("scale ") {
+        if let Some(items) = ctx.resolve_to_list(var_value) {
+            eprintln!("[parse] scale '{}' = {:?}", var_name, items);
+            ctx.ring_values.insert(var_name.to_string(), items);
+            ctx.ring_counters.insert(var_name.to_string(), 0);
+        }
+        return;
+    }
+
+    // Check if value is a chord() call → store as ring
+    if var_value.starts_with("chord(") || var_value.starts_with("chord ") {
+        if let Some(items) = ctx.resolve_to_list(var_value) {
+            eprintln!("[parse] chord '{}' = {:?}", var_name, items);
+            ctx.ring_values.insert(var_name.to_string(), items);
+            ctx.ring_counters.insert(var_name.to_string(), 0);
+        }
+        return;
+    }
+
+    // Check if value is a knit() call → store as ring
+    if var_value.starts_with("knit(") {
+        if let Some(items) = ctx.resolve_to_list(var_value) {
+            eprintln!("[parse] knit '{}' = {:?}", var_name, items);
+            ctx.ring_values.insert(var_name.to_string(), items);
+            ctx.ring_counters.insert(var_name.to_string(), 0);
+        }
+        return;
+    }
+
+    // Check if value is a range() call → store as ring
+    if var_value.starts_with("range(") {
+        if let Some(items) = ctx.resolve_to_list(var_value) {
+            eprintln!("[parse] range '{}' = {:?}", var_name, items);
+            ctx.ring_values.insert(var_name.to_string(), items);
+            ctx.ring_counters.insert(var_name.to_string(), 0);
+        }
+        return;
+    }
+
+    // Check if value is a line() call → store as ring
+    if var_value.starts_with("line(") {
+        if let Some(items) = ctx.resolve_to_list(var_value) {
+            eprintln!("[parse] line '{}' = {:?}", var_name, items);
+            ctx.ring_values.insert(var_name.to_string(), items);
+            ctx.ring_counters.insert(var_name.to_string(), 0);
+        }
+        return;
+    }
+
+    // Check if value is an inline array: [:c4, :e4, :g4]
+    if var_value.starts_with('[') && var_value.ends_with(']') {
+        let inner = &var_value[1..var_value.len()-1];
+        let items: Vec<String> = inner.split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+        ctx.ring_values.insert(var_name.to_string(), items);
+        ctx.ring_counters.insert(var_name.to_string(), 0);
+        return;
+    }
+
+    // Resolve the value (could reference other vars)
+    let resolved = ctx.resolve_string(var_value);
+    ctx.variables.insert(var_name.to_string(), resolved);
+}
+
 /// Try to parse a variable assignment like `sample_path = "..."`
-fn try_parse_assignment(line: &str) -> Option<(String, String)> {
+pub fn try_parse_assignment(line: &str) -> Option<(String, String)> {
     // Match: identifier = value (but NOT ==)
     // Must not start with a keyword
     let keywords = [
@@ -1028,7 +1122,7 @@ fn try_parse_block(
     // with_fx :effect, params do
     if line.starts_with("with_fx") {
         let fx_type = extract_symbol(line).unwrap_or_else(|| "reverb".to_string());
-        let params = extract_fx_params(line);
+        let params = extract_fx_params(line, ctx);
         let (body, end_i) = collect_block_body(lines, start_i)?;
         let sub = parse_code_with_context(&body, ctx)?;
         return Ok(Some((
@@ -1339,7 +1433,7 @@ fn try_parse_block(
 }
 
 /// Evaluate a condition expression (for if blocks)
-fn evaluate_condition(condition: &str, ctx: &ParseContext) -> bool {
+pub fn evaluate_condition(condition: &str, ctx: &ParseContext) -> bool {
     let trimmed = condition.trim();
 
     // one_in(n)
@@ -1353,11 +1447,14 @@ fn evaluate_condition(condition: &str, ctx: &ParseContext) -> bool {
             let left_str = trimmed[..op_pos].trim();
             let right_str = trimmed[op_pos + op.len()..].trim();
 
-            // Try to resolve both sides as numbers
-            let left = ctx.resolve_numeric(left_str)
-                .or_else(|| left_str.parse::<f32>().ok());
-            let right = ctx.resolve_numeric(right_str)
-                .or_else(|| right_str.parse::<f32>().ok());
+            // Try to resolve both sides as numbers, literal or variable
+            let number = |s: &str| {
+                ctx.resolve_numeric(s)
+                    .or_else(|| s.parse::<f32>().ok())
+                    .or_else(|| ctx.variables.get(s).and_then(|v| v.trim().parse().ok()))
+            };
+            let left = number(left_str);
+            let right = number(right_str);
 
             if let (Some(l), Some(r)) = (left, right) {
                 return match *op {
@@ -1392,14 +1489,12 @@ fn evaluate_condition(condition: &str, ctx: &ParseContext) -> bool {
                     let match_count = values.iter().filter(|v| v.trim() == expected).count();
                     if values.is_empty() { return false; }
                     let probability = match_count as f64 / values.len() as f64;
-                    let mut rng = rand::thread_rng();
-                    return rng.gen_bool(probability.min(1.0));
+                    return (ctx.rand_f32() as f64) < probability;
                 }
                 return true;
             }
         }
-        let mut rng = rand::thread_rng();
-        return rng.gen_bool(0.5);
+        return ctx.rand_f32() < 0.5;
     }
 
     // true/false literals
@@ -1610,7 +1705,7 @@ fn split_if_branches(body: &str) -> IfBranches {
 }
 
 /// Check if a line opens a new block (ends with 'do' or 'do |...|' or 'then')
-fn is_block_opener(line: &str) -> bool {
+pub fn is_block_opener(line: &str) -> bool {
     let trimmed = strip_inline_comment(line.trim());
     // Ends with "do" or "do |var|" or "do |var, var|"
     if trimmed.ends_with("do") {
@@ -1639,7 +1734,7 @@ fn is_block_opener(line: &str) -> bool {
 }
 
 /// Strip inline comment from a line (outside of strings)
-fn strip_inline_comment(line: &str) -> String {
+pub fn strip_inline_comment(line: &str) -> String {
     let mut in_string = false;
     let mut string_char = ' ';
     let chars: Vec<char> = line.chars().collect();
@@ -1661,7 +1756,7 @@ fn strip_inline_comment(line: &str) -> String {
 /// Find a trailing `if` condition in a line (outside of strings).
 /// Returns the byte position of the ` if ` keyword, or None.
 /// Example: "sample :bd, amp: 2 if one_in(3)" -> Some(19)
-fn find_trailing_if(line: &str) -> Option<usize> {
+pub fn find_trailing_if(line: &str) -> Option<usize> {
     let mut in_string = false;
     let mut string_char = ' ';
     let chars: Vec<char> = line.chars().collect();
@@ -1692,7 +1787,7 @@ fn find_trailing_if(line: &str) -> Option<usize> {
 }
 
 /// Find a trailing `unless` condition in a line (outside of strings).
-fn find_trailing_unless(line: &str) -> Option<usize> {
+pub fn find_trailing_unless(line: &str) -> Option<usize> {
     let mut in_string = false;
     let mut string_char = ' ';
     let chars: Vec<char> = line.chars().collect();
@@ -1742,6 +1837,50 @@ fn try_resolve_list_method(expr: &str, ctx: &mut ParseContext) -> Option<String>
     None
 }
 
+/// Replace variables holding a number or symbol with their value, so
+/// `sleep dur` and `play n, amp: amp` see literals. The command word, `name:`
+/// keys, method and function names and string contents are left alone.
+fn substitute_vars(line: &str, ctx: &ParseContext) -> String {
+    if ctx.variables.is_empty() {
+        return line.to_string();
+    }
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    let mut out = String::with_capacity(line.len());
+    let mut in_string = false;
+    let mut first_word = true;
+    let mut rest = line;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '"' {
+            in_string = !in_string;
+        }
+        if in_string || !(c.is_alphabetic() || c == '_') {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+
+        let end = rest.find(|c: char| !is_ident(c)).unwrap_or(rest.len());
+        let word = &rest[..end];
+        let before = out.chars().last();
+        let after = rest[end..].chars().next();
+        let value = ctx.variables.get(word).filter(|v| {
+            let v = v.trim();
+            v.parse::<f32>().is_ok() || (v.starts_with(':') && v.len() > 1 && v[1..].chars().all(is_ident))
+        });
+        let replace = !first_word
+            && !before.is_some_and(|b| is_ident(b) || matches!(b, ':' | '.' | '@' | '$'))
+            && !matches!(after, Some(':' | '(' | '.' | '?' | '!'));
+        match value {
+            Some(v) if replace => out.push_str(v.trim()),
+            _ => out.push_str(word),
+        }
+        first_word = false;
+        rest = &rest[end..];
+    }
+    out
+}
+
 /// Find where a method call expression ends (before params)
 fn find_method_end(expr: &str) -> Option<usize> {
     let mut paren_depth = 0;
@@ -1759,12 +1898,33 @@ fn find_method_end(expr: &str) -> Option<usize> {
 }
 
 /// Extract param with defaults fallback
-fn extract_param_with_defaults(line: &str, param: &str, defaults: &HashMap<String, f32>, fallback: f32) -> f32 {
-    extract_param(line, param)
+fn extract_param_with_defaults(line: &str, param: &str, defaults: &HashMap<String, f32>, fallback: f32, ctx: &ParseContext) -> f32 {
+    extract_param(line, param, ctx)
         .or_else(|| defaults.get(param).copied())
         .unwrap_or(fallback)
 }
 
+/// Envelope opts of a play line, then `use_synth_defaults`, then `fallback`.
+/// `decay_level` follows `sustain_level` unless it is set itself.
+fn extract_envelope(line: &str, ctx: &ParseContext, fallback: Envelope) -> Envelope {
+    let opt = |name: &str, default: f32| extract_param_with_defaults(line, name, &ctx.synth_defaults, default, ctx);
+    let sustain = extract_param(line, "sustain", ctx)
+        .or_else(|| extract_param(line, "duration", ctx))
+        .or_else(|| ctx.synth_defaults.get("sustain").copied())
+        .unwrap_or(fallback.sustain);
+    let sustain_level = opt("sustain_level", fallback.sustain_level);
+    Envelope {
+        attack: opt("attack", fallback.attack),
+        decay: opt("decay", fallback.decay),
+        sustain,
+        release: opt("release", fallback.release),
+        attack_level: opt("attack_level", fallback.attack_level),
+        decay_level: opt("decay_level", sustain_level),
+        sustain_level,
+        curve: EnvCurve::from_number(opt("env_curve", fallback.curve.number())),
+    }
+}
+
 /// Parse a defaults line like "use_synth_defaults attack: 0.1, release: 0.5"
 fn parse_defaults_line(line: &str, prefix: &str, defaults: &mut HashMap<String, f32>) {
     let rest = line.strip_prefix(prefix).unwrap_or("").trim();
@@ -1781,7 +1941,10 @@ fn parse_defaults_line(line: &str, prefix: &str, defaults: &mut HashMap<String,
     }
 }
 
-fn parse_line(line: &str, ctx: &mut ParseContext) -> Option<ParsedCommand> {
+pub fn parse_line(line: &str, ctx: &mut ParseContext) -> Option<ParsedCommand> {
+    let substituted = substitute_vars(line, ctx);
+    let line = substituted.as_str();
+
     // Handle trailing `if one_in(n)` or `if condition`
     // e.g., "sample :drum_cymbal_hard, sustain: 0.2, amp: 2 if one_in(3)"
     if let Some(if_pos) = find_trailing_if(line) {
@@ -1824,56 +1987,36 @@ fn parse_line(line: &str, ctx: &mut ParseContext) -> Option<ParsedCommand> {
             let note_expr = &line["play".len()..].trim();
             if let Some(note_str) = try_resolve_list_method(note_expr, ctx) {
                 let note = parse_note_value(&note_str)?;
-                let amplitude = extract_param_with_defaults(line, "amp", &ctx.synth_defaults, 0.5);
-                let duration = extract_param_with_defaults(line, "sustain", &ctx.synth_defaults, 0.5);
-                let pan = extract_param_with_defaults(line, "pan", &ctx.synth_defaults, 0.0);
-                let attack = extract_param_with_defaults(line, "attack", &ctx.synth_defaults, 0.01);
-                let decay = extract_param_with_defaults(line, "decay", &ctx.synth_defaults, 0.1);
-                let sustain_level = extract_param_with_defaults(line, "sustain_level", &ctx.synth_defaults, 0.7);
-                let release = extract_param_with_defaults(line, "release", &ctx.synth_defaults, 0.3);
+                let amplitude = extract_param_with_defaults(line, "amp", &ctx.synth_defaults, 0.5, ctx);
+                let pan = extract_param_with_defaults(line, "pan", &ctx.synth_defaults, 0.0, ctx);
 
                 return Some(ParsedCommand::PlayNote {
                     synth_type: ctx.current_synth,
                     frequency: note,
                     amplitude,
-                    duration,
                     pan,
-                    envelope: Envelope {
-                        attack,
-                        decay,
-                        sustain: sustain_level,
-                        release,
-                    },
-                    params: extract_synth_params(line),
+                    envelope: extract_envelope(line, ctx, Envelope::default()),
+                    params: extract_synth_params(line, ctx),
                 });
             }
 
             let note_str = parts.get(1)?;
-            let note = parse_note_value(note_str)?;
-            let amplitude = extract_param_with_defaults(line, "amp", &ctx.synth_defaults, 0.5);
-            let duration = extract_param(line, "sustain")
-                .or_else(|| extract_param(line, "duration"))
-                .or_else(|| ctx.synth_defaults.get("sustain").copied())
-                .unwrap_or(0.5);
-            let pan = extract_param_with_defaults(line, "pan", &ctx.synth_defaults, 0.0);
-            let attack = extract_param_with_defaults(line, "attack", &ctx.synth_defaults, 0.01);
-            let decay = extract_param_with_defaults(line, "decay", &ctx.synth_defaults, 0.1);
-            let sustain_level = extract_param_with_defaults(line, "sustain_level", &ctx.synth_defaults, 0.7);
-            let release = extract_param_with_defaults(line, "release", &ctx.synth_defaults, 0.3);
+            // play n — a note kept in a variable or block argument
+            let note = parse_note_value(note_str).or_else(|| {
+                ctx.variables
+                    .get(note_str.trim_end_matches(','))
+                    .and_then(|v| parse_note_value(v))
+            })?;
+            let amplitude = extract_param_with_defaults(line, "amp", &ctx.synth_defaults, 0.5, ctx);
+            let pan = extract_param_with_defaults(line, "pan", &ctx.synth_defaults, 0.0, ctx);
 
             Some(ParsedCommand::PlayNote {
                 synth_type: ctx.current_synth,
                 frequency: note,
                 amplitude,
-                duration,
                 pan,
-                envelope: Envelope {
-                    attack,
-                    decay,
-                    sustain: sustain_level,
-                    release,
-                },
-                params: extract_synth_params(line),
+                envelope: extract_envelope(line, ctx, Envelope::default()),
+                params: extract_synth_params(line, ctx),
             })
         }
         "play_pattern_timed" => parse_play_pattern_timed(line, ctx),
@@ -1885,16 +2028,16 @@ fn parse_line(line: &str, ctx: &mut ParseContext) -> Option<ParsedCommand> {
             let resolved = resolve_sample_name(sample_expr, ctx);
             eprintln!("[parse] sample expr='{}' -> resolved='{}'", sample_expr, resolved);
 
-            let rate = extract_param_with_defaults(params_str, "rate", &ctx.sample_defaults, 1.0);
-            let amplitude = extract_param_with_defaults(params_str, "amp", &ctx.sample_defaults, 1.0);
-            let pan = extract_param_with_defaults(params_str, "pan", &ctx.sample_defaults, 0.0);
+            let rate = extract_param_with_defaults(params_str, "rate", &ctx.sample_defaults, 1.0, ctx);
+            let amplitude = extract_param_with_defaults(params_str, "amp", &ctx.sample_defaults, 1.0, ctx);
+            let pan = extract_param_with_defaults(params_str, "pan", &ctx.sample_defaults, 0.0, ctx);
 
             // These are parsed but applied as rate modifiers where possible
-            let rpitch = extract_param(params_str, "rpitch");
-            let beat_stretch = extract_param(params_str, "beat_stretch");
-            let _start = extract_param(params_str, "start"); // 0.0-1.0 range
-            let _finish = extract_param(params_str, "finish"); // 0.0-1.0 range
-            let _pitch_stretch = extract_param(params_str, "pitch_stretch");
+            let rpitch = extract_param(params_str, "rpitch", ctx);
+            let beat_stretch = extract_param(params_str, "beat_stretch", ctx);
+            let _start = extract_param(params_str, "start", ctx); // 0.0-1.0 range
+            let _finish = extract_param(params_str, "finish", ctx); // 0.0-1.0 range
+            let _pitch_stretch = extract_param(params_str, "pitch_stretch", ctx);
 
             // Apply rpitch as rate modifier (semitone shift)
             let mut final_rate = rate;
@@ -1943,7 +2086,7 @@ fn parse_line(line: &str, ctx: &mut ParseContext) -> Option<ParsedCommand> {
             let synth_type = parse_synth_name(synth_name);
 
             // Try to resolve note as a list method expression
-            let note = extract_param(line, "note")
+            let note = extract_param(line, "note", ctx)
                 .or_else(|| extract_note_param(line, "note"))
                 .or_else(|| {
                     // Check if note param uses a list method: note: scale(:c4, :minor).choose
@@ -1964,26 +2107,15 @@ fn parse_line(line: &str, ctx: &mut ParseContext) -> Option<ParsedCommand> {
                 })
                 .unwrap_or(261.63);
 
-            let amplitude = extract_param_with_defaults(line, "amp", &ctx.synth_defaults, 0.5);
-            let duration = extract_param_with_defaults(line, "sustain", &ctx.synth_defaults, 0.5);
-            let pan = extract_param_with_defaults(line, "pan", &ctx.synth_defaults, 0.0);
-            let attack = extract_param_with_defaults(line, "attack", &ctx.synth_defaults, 0.01);
-            let decay = extract_param_with_defaults(line, "decay", &ctx.synth_defaults, 0.1);
-            let sustain_level = extract_param_with_defaults(line, "sustain_level", &ctx.synth_defaults, 0.7);
-            let release = extract_param_with_defaults(line, "release", &ctx.synth_defaults, 0.3);
+            let amplitude = extract_param_with_defaults(line, "amp", &ctx.synth_defaults, 0.5, ctx);
+            let pan = extract_param_with_defaults(line, "pan", &ctx.synth_defaults, 0.0, ctx);
             Some(ParsedCommand::PlayNote {
                 synth_type,
                 frequency: note,
                 amplitude,
-                duration,
                 pan,
-                envelope: Envelope {
-                    attack,
-                    decay,
-                    sustain: sustain_level,
-                    release,
-                },
-                params: extract_synth_params(line),
+                envelope: extract_envelope(line, ctx, Envelope::default()),
+                params: extract_synth_params(line, ctx),
             })
         }
         "stop" => Some(ParsedCommand::Stop),
@@ -1991,13 +2123,24 @@ fn parse_line(line: &str, ctx: &mut ParseContext) -> Option<ParsedCommand> {
             let msg = parts[1..].join(" ").trim_matches('"').to_string();
             Some(ParsedCommand::Log(msg))
         }
-        "cue" | "sync" => {
-            Some(ParsedCommand::Comment(format!("# {}", line)))
-        }
+        "cue" => cue_path(&line["cue".len()..]).map(ParsedCommand::Cue),
+        "sync" => cue_path(&line["sync".len()..]).map(ParsedCommand::Sync),
         "at" => {
             Some(ParsedCommand::Comment(format!("# {}", line)))
         }
-        "use_random_seed" | "use_random_source" => {
+        "use_random_seed" | "use_random_source" | "rand_reset" | "rand_skip" | "rand_back" => {
+            let arg = line[parts[0].len()..].trim();
+            let mut stream = ctx.random();
+            match parts[0] {
+                "use_random_seed" => stream.reseed(ctx.resolve_numeric(arg)? as i64 as u64),
+                "use_random_source" => stream.set_source(RandomSource::from_name(arg)?),
+                "rand_reset" => stream.reset(),
+                _ => {
+                    let steps = if arg.is_empty() { 1 } else { ctx.resolve_numeric(arg)? as i64 };
+                    stream.skip(if parts[0] == "rand_back" { -steps } else { steps });
+                }
+            }
+            ctx.set_random(stream);
             Some(ParsedCommand::Comment(format!("# {}", line)))
         }
         "use_synth_defaults" => {
@@ -2038,8 +2181,19 @@ fn parse_line(line: &str, ctx: &mut ParseContext) -> Option<ParsedCommand> {
             Some(ParsedCommand::Comment(format!("# {}", line)))
         }
         "control" => {
-            // control — modifying running synths, not directly supported but don't error
-            Some(ParsedCommand::Comment(format!("# {}", line)))
+            // control s, cutoff: 80, note: :e4, note_slide: 1
+            let rest = line["control".len()..].trim();
+            let (target, params_str) = rest.split_once(',').unwrap_or((rest, ""));
+            let target = target.trim();
+            let node = ctx
+                .variables
+                .get(target)
+                .and_then(|v| parse_node_ref(v))
+                .or_else(|| parse_node_ref(target))?;
+            Some(ParsedCommand::Control {
+                node,
+                params: extract_control_params(params_str, ctx),
+            })
         }
         "midi" | "midi_note_on" | "midi_note_off" | "midi_cc" | "midi_raw" | "midi_pitch_bend"
         | "midi_channel_pressure" | "midi_poly_pressure" | "midi_clock_tick"
@@ -2065,9 +2219,14 @@ fn parse_line(line: &str, ctx: &mut ParseContext) -> Option<ParsedCommand> {
     }
 }
 
-/// Parse synth name to type – maps every Sonic Pi synth name
-fn parse_synth_name(name: &str) -> OscillatorType {
-    match name {
+/// Parse synth name to type, falling back to a sine (`:beep`) for unknown names
+pub fn parse_synth_name(name: &str) -> OscillatorType {
+    synth_from_name(name).unwrap_or(OscillatorType::Sine)
+}
+
+/// Look up a synth name – maps every Sonic Pi synth name
+pub fn synth_from_name(name: &str) -> Option<OscillatorType> {
+    let synth = match name {
         // ── Basic oscillators ──
         "sine" | "beep" => OscillatorType::Sine,
         "saw" => OscillatorType::Saw,
@@ -2134,15 +2293,14 @@ fn parse_synth_name(name: &str) -> OscillatorType {
         "pad" => OscillatorType::Hollow,
         "winwood_lead" => OscillatorType::SuperSaw,
 
-        _ => OscillatorType::Sine,
-    }
+        _ => return None,
+    };
+    Some(synth)
 }
 
 /// Parse "play chord(:e3, :minor7), release: 1, amp: 1"
 fn parse_play_chord(line: &str, ctx: &ParseContext) -> Option<ParsedCommand> {
-    let amplitude = extract_param(line, "amp").unwrap_or(0.5);
-    let release = extract_param(line, "release").unwrap_or(0.3);
-    let attack = extract_param(line, "attack").unwrap_or(0.01);
+    let amplitude = extract_param(line, "amp", ctx).unwrap_or(0.5);
 
     // Extract chord(...) content
     let chord_start = line.find("chord(")?;
@@ -2173,15 +2331,9 @@ fn parse_play_chord(line: &str, ctx: &ParseContext) -> Option<ParsedCommand> {
         synth_type: ctx.current_synth,
         frequency: freq,
         amplitude,
-        duration: 0.5,
         pan: 0.0,
-        envelope: Envelope {
-            attack,
-            decay: 0.1,
-            sustain: 0.7,
-            release,
-        },
-        params: extract_synth_params(line),
+        envelope: extract_envelope(line, ctx, Envelope::default()),
+        params: extract_synth_params(line, ctx),
     })
 }
 
@@ -2212,10 +2364,10 @@ fn chord_intervals(chord_type: &str) -> Vec<i32> {
 
 /// Parse play_pattern_timed: play_pattern_timed [:e2, :g2, :b2, :d3], [0.5, 0.5, 1, 0.5], release: 0.3
 fn parse_play_pattern_timed(line: &str, ctx: &ParseContext) -> Option<ParsedCommand> {
-    let amplitude = extract_param(line, "amp").unwrap_or(0.5);
-    let release = extract_param(line, "release").unwrap_or(0.3);
-    let attack = extract_param(line, "attack").unwrap_or(0.01);
-    let synth_params = extract_synth_params(line);
+    let amplitude = extract_param(line, "amp", ctx).unwrap_or(0.5);
+    let release = extract_param(line, "release", ctx).unwrap_or(0.3);
+    let envelope = extract_envelope(line, ctx, Envelope { decay: 0.05, sustain: release, ..Envelope::default() });
+    let synth_params = extract_synth_params(line, ctx);
 
     // Extract the notes array and timing array
     let notes = extract_array(line, 0)?;
@@ -2246,14 +2398,8 @@ fn parse_play_pattern_timed(line: &str, ctx: &ParseContext) -> Option<ParsedComm
                 synth_type: ctx.current_synth,
                 frequency: *freq,
                 amplitude,
-                duration: release,
                 pan: 0.0,
-                envelope: Envelope {
-                    attack,
-                    decay: 0.05,
-                    sustain: 0.7,
-                    release,
-                },
+                envelope,
                 params: synth_params.clone(),
             });
         }
@@ -2272,9 +2418,10 @@ fn parse_play_pattern_timed(line: &str, ctx: &ParseContext) -> Option<ParsedComm
 
 /// Parse play_pattern: play_pattern [:c4, :e4, :g4]
 fn parse_play_pattern(line: &str, ctx: &ParseContext) -> Option<ParsedCommand> {
-    let amplitude = extract_param(line, "amp").unwrap_or(0.5);
-    let release = extract_param(line, "release").unwrap_or(0.3);
-    let synth_params = extract_synth_params(line);
+    let amplitude = extract_param(line, "amp", ctx).unwrap_or(0.5);
+    let release = extract_param(line, "release", ctx).unwrap_or(0.3);
+    let envelope = extract_envelope(line, ctx, Envelope { sustain: release, ..Envelope::default() });
+    let synth_params = extract_synth_params(line, ctx);
 
     let notes = extract_array(line, 0)?;
     let frequencies: Vec<f32> = notes
@@ -2293,9 +2440,8 @@ fn parse_play_pattern(line: &str, ctx: &ParseContext) -> Option<ParsedCommand> {
                 synth_type: ctx.current_synth,
                 frequency: *freq,
                 amplitude,
-                duration: release,
                 pan: 0.0,
-                envelope: Envelope::default(),
+                envelope,
                 params: synth_params.clone(),
             });
         }
@@ -2428,7 +2574,7 @@ fn parse_note_value(value: &str) -> Option<f32> {
     None
 }
 
-fn extract_param(line: &str, param: &str) -> Option<f32> {
+fn extract_param(line: &str, param: &str, ctx: &ParseContext) -> Option<f32> {
     let patterns = [
         format!("{}: ", param),
         format!("{}:", param),
@@ -2445,7 +2591,6 @@ fn extract_param(line: &str, param: &str) -> Option<f32> {
                     // Extract the full function call including parens
                     if let Some(inner) = extract_func_args(after_trimmed, func_name) {
                         let func_call = &after_trimmed[..func_name.len() + 1 + inner.len() + 1];
-                        let ctx = ParseContext::new();
                         if let Some(val) = ctx.resolve_numeric(func_call) {
                             return Some(val);
                         }
@@ -2459,7 +2604,6 @@ fn extract_param(line: &str, param: &str) -> Option<f32> {
                 let expr_end = after_trimmed.find(|c: char| c == ',' && !after_trimmed[..after_trimmed.find(c).unwrap_or(0)].contains('('))
                     .unwrap_or(after_trimmed.len());
                 let expr = &after_trimmed[..expr_end];
-                let ctx = ParseContext::new();
                 if let Some(val) = ctx.resolve_numeric(expr) {
                     return Some(val);
                 }
@@ -2500,7 +2644,28 @@ fn extract_note_param(line: &str, param: &str) -> Option<f32> {
     None
 }
 
-fn extract_symbol(line: &str) -> Option<String> {
+/// Cue path named by the first argument of `cue` / `sync`. Symbols and bare
+/// names live under `/cue/` (`:beat` → "/cue/beat"), paths are kept as written
+/// (`"/live_loop/drums"`).
+pub fn cue_path(args: &str) -> Option<String> {
+    let first = args
+        .trim()
+        .trim_start_matches('(')
+        .split([',', ')'])
+        .next()?
+        .trim()
+        .trim_start_matches(':')
+        .trim_matches(|c| c == '"' || c == '\'');
+    if first.is_empty() {
+        None
+    } else if first.starts_with('/') {
+        Some(first.to_string())
+    } else {
+        Some(format!("/cue/{}", first))
+    }
+}
+
+pub fn extract_symbol(line: &str) -> Option<String> {
     if let Some(pos) = line.find(':') {
         let after = &line[pos + 1..];
         let name: String = after
@@ -2514,7 +2679,7 @@ fn extract_symbol(line: &str) -> Option<String> {
     None
 }
 
-fn extract_fx_params(line: &str) -> Vec<(String, f32)> {
+pub fn extract_fx_params(line: &str, ctx: &ParseContext) -> Vec<(String, f32)> {
     let mut params = Vec::new();
     let param_names = [
         "mix", "room", "time", "feedback", "phase", "decay", "cutoff", "res",
@@ -2522,7 +2687,7 @@ fn extract_fx_params(line: &str) -> Vec<(String, f32)> {
         "release", "attack", "sustain", "reps",
     ];
     for name in &param_names {
-        if let Some(val) = extract_param(line, name) {
+        if let Some(val) = extract_param(line, name, ctx) {
             params.push((name.to_string(), val));
         }
     }
@@ -2532,8 +2697,8 @@ fn extract_fx_params(line: &str) -> Vec<(String, f32)> {
 /// Extract synth-specific parameters from a play/synth line.
 /// These are forwarded to SuperCollider as named OSC args so the
 /// SynthDef can use them (cutoff, res, detune, wave, depth, divisor, etc.)
-fn extract_synth_params(line: &str) -> Vec<(String, f32)> {
-    let mut params = Vec::new();
+fn extract_synth_params(line: &str, ctx: &ParseContext) -> Vec<(String, f32)> {
+    let mut params = extract_slide_params(line, ctx);
     let synth_param_names = [
         "cutoff", "res", "detune", "depth", "divisor", "wave",
         "pulse_width", "width", "sub_amp", "noise", "coef",
@@ -2541,275 +2706,125 @@ fn extract_synth_params(line: &str) -> Vec<(String, f32)> {
         "mod_wave", "mod_invert_wave", "vel",
     ];
     for name in &synth_param_names {
-        if let Some(val) = extract_param(line, name) {
+        if let Some(val) = extract_param(line, name, ctx) {
             params.push((name.to_string(), val));
         }
     }
     params
 }
 
-/// Convert parsed commands to audio commands with timing
-pub fn commands_to_audio(
-    parsed: &[ParsedCommand],
-    bpm: f32,
-) -> Vec<(f32, AudioCommand)> {
-    let mut result = Vec::new();
-    let mut time_offset = 0.0f32;
-    let mut current_bpm = bpm;
-    let mut beat_duration = 60.0 / current_bpm;
-    let mut current_reverb = 0.0f32;
-    let mut current_delay_time = 0.0f32;
-    let mut current_delay_feedback = 0.0f32;
-    let mut current_distortion = 0.0f32;
-    let mut current_lpf = 20000.0f32;
-    let mut current_hpf = 20.0f32;
-
-    for cmd in parsed {
-        match cmd {
-            ParsedCommand::PlayNote {
-                synth_type,
-                frequency,
-                amplitude,
-                duration,
-                pan,
-                envelope,
-                params,
-            } => {
-                if *frequency > 0.0 {
-                    let total_dur = duration + envelope.attack + envelope.decay + envelope.release;
-                    result.push((
-                        time_offset,
-                        AudioCommand::PlayNote {
-                            synth_type: *synth_type,
-                            frequency: *frequency,
-                            amplitude: *amplitude,
-                            duration_secs: total_dur,
-                            envelope: *envelope,
-                            pan: *pan,
-                            params: params.clone(),
-                        },
-                    ));
-                }
-            }
-            ParsedCommand::PlaySample {
-                name: _name,
-                rate,
-                amplitude,
-                pan,
-            } => {
-                result.push((
-                    time_offset,
-                    AudioCommand::PlaySample {
-                        samples: Vec::new(), // Will be filled by the caller
-                        sample_rate: 44100,
-                        amplitude: *amplitude,
-                        rate: *rate,
-                        pan: *pan,
-                    },
-                ));
-            }
-            ParsedCommand::Sleep(beats) => {
-                time_offset += beats * beat_duration;
-            }
-            ParsedCommand::SetBpm(bpm_val) => {
-                current_bpm = *bpm_val;
-                beat_duration = 60.0 / current_bpm;
-                result.push((time_offset, AudioCommand::SetBpm(*bpm_val)));
-            }
-            ParsedCommand::SetVolume(vol) => {
-                result.push((time_offset, AudioCommand::SetMasterVolume(*vol)));
-            }
-            ParsedCommand::WithFx {
-                fx_type,
-                params,
-                commands,
-            } => {
-                // Emit FxStart — the SC engine will allocate a private audio bus,
-                // create the FX synth on it, and route subsequent synths through it.
-                // The cpal engine falls back to global SetEffect.
-                result.push((
-                    time_offset,
-                    AudioCommand::FxStart {
-                        fx_type: fx_type.clone(),
-                        params: params.clone(),
-                    },
-                ));
-
-                // Also emit SetEffect for the cpal engine path (fallback)
-                // Save FX state before block so we can restore it after (scoped FX)
-                let saved_reverb = current_reverb;
-                let saved_delay_time = current_delay_time;
-                let saved_delay_feedback = current_delay_feedback;
-                let saved_distortion = current_distortion;
-                let saved_lpf = current_lpf;
-                let saved_hpf = current_hpf;
-
-                match fx_type.as_str() {
-                    "reverb" | "gverb" | "krush" => {
-                        current_reverb = params.iter().find(|(n, _)| n == "mix").map(|(_, v)| *v).unwrap_or(0.5);
-                    }
-                    "echo" | "delay" => {
-                        current_delay_time = params.iter().find(|(n, _)| n == "phase" || n == "time").map(|(_, v)| *v).unwrap_or(0.25);
-                        current_delay_feedback = params.iter().find(|(n, _)| n == "feedback" || n == "decay").map(|(_, v)| *v).unwrap_or(0.5);
-                    }
-                    "distortion" | "tanh" => {
-                        current_distortion = params.iter().find(|(n, _)| n == "distort" || n == "mix").map(|(_, v)| *v).unwrap_or(0.5);
-                    }
-                    "lpf" | "rlpf" | "nrlpf" => {
-                        current_lpf = params.iter().find(|(n, _)| n == "cutoff").map(|(_, v)| *v).unwrap_or(1000.0);
-                    }
-                    "hpf" | "rhpf" | "nrhpf" => {
-                        current_hpf = params.iter().find(|(n, _)| n == "cutoff").map(|(_, v)| *v).unwrap_or(500.0);
-                    }
-                    _ => {}
-                }
-
-                result.push((
-                    time_offset,
-                    AudioCommand::SetEffect {
-                        reverb_mix: current_reverb,
-                        delay_time: current_delay_time,
-                        delay_feedback: current_delay_feedback,
-                        distortion: current_distortion,
-                        lpf_cutoff: current_lpf,
-                        hpf_cutoff: current_hpf,
-                    },
-                ));
-
-                // Process inner commands
-                let inner = commands_to_audio(commands, current_bpm);
-                for (t, c) in inner {
-                    result.push((time_offset + t, c));
-                }
+/// `*_slide` times in a param list. `note_slide` becomes `freq_slide`, to
+/// match the `freq` the engines are given instead of `note`.
+fn extract_slide_params(line: &str, ctx: &ParseContext) -> Vec<(String, f32)> {
+    let mut params = Vec::new();
+    for piece in line.split(',') {
+        let Some((key, _)) = piece.split_once(':') else {
+            continue;
+        };
+        let key = key.trim();
+        let Some(base) = key.strip_suffix("_slide") else {
+            continue;
+        };
+        if let Some(val) = extract_param(line, key, ctx) {
+            let base = if base == "note" { "freq" } else { base };
+            params.push((format!("{}_slide", base), val));
+        }
+    }
+    params
+}
 
-                // Update time offset from inner commands
-                let inner_duration = commands_to_duration(commands, current_bpm);
-                time_offset += inner_duration;
-
-                // Emit FxEnd — SC engine will free the FX synth and restore bus
-                result.push((time_offset, AudioCommand::FxEnd));
-
-                // Restore FX state after block (with_fx is scoped in Sonic Pi)
-                current_reverb = saved_reverb;
-                current_delay_time = saved_delay_time;
-                current_delay_feedback = saved_delay_feedback;
-                current_distortion = saved_distortion;
-                current_lpf = saved_lpf;
-                current_hpf = saved_hpf;
-
-                // Emit a SetEffect to restore the previous FX state (cpal fallback)
-                result.push((
-                    time_offset,
-                    AudioCommand::SetEffect {
-                        reverb_mix: current_reverb,
-                        delay_time: current_delay_time,
-                        delay_feedback: current_delay_feedback,
-                        distortion: current_distortion,
-                        lpf_cutoff: current_lpf,
-                        hpf_cutoff: current_hpf,
-                    },
-                ));
-            }
-            ParsedCommand::Loop { commands, name, parallel } => {
-                // Check if the loop body contains a Stop command at the top level
-                let has_stop = commands.iter().any(|c| matches!(c, ParsedCommand::Stop));
-                // If the body has 'stop', it's a one-shot section — run just once
-                // Otherwise repeat up to 500 times for indefinite loops
-                let loop_iterations = if has_stop { 1 } else { 500 };
-                eprintln!("[parser] live_loop :{} → {} iteration(s), stop={}, parallel={}", name, loop_iterations, has_stop, parallel);
-                
-                let loop_start_offset = time_offset;
-                let mut loop_time = loop_start_offset;
-                for iter in 0..loop_iterations {
-                    let inner = commands_to_audio(commands, current_bpm);
-                    let inner_duration = commands_to_duration(commands, current_bpm);
-                    for (t, c) in inner {
-                        result.push((loop_time + t, c));
-                    }
-                    loop_time += inner_duration;
-                    // Safety: cap at 100k commands to prevent blocking
-                    if result.len() > 100_000 {
-                        eprintln!("[parser] WARNING: command limit reached in live_loop :{} at iteration {}", name, iter);
-                        break;
-                    }
-                }
-                
-                if *parallel {
-                    // Parallel loops (live_loop, in_thread) do NOT advance the
-                    // parent time offset — they run concurrently with subsequent code.
-                    // time_offset stays unchanged.
-                } else {
-                    // Sequential loops advance time normally
-                    time_offset = loop_time;
-                }
-            }
-            ParsedCommand::TimesLoop { count, commands } => {
-                // Repeat commands N times
-                for iter in 0..*count {
-                    let inner = commands_to_audio(commands, current_bpm);
-                    let inner_duration = commands_to_duration(commands, current_bpm);
-                    for (t, c) in inner {
-                        result.push((time_offset + t, c));
-                    }
-                    time_offset += inner_duration;
-                    // Safety: cap at 100k commands
-                    if result.len() > 100_000 {
-                        eprintln!("[parser] WARNING: command limit reached in {}.times at iteration {}", count, iter);
-                        break;
-                    }
-                }
-            }
-            ParsedCommand::Stop => {
-                // Stop this sequence - break out
-                break;
+/// Params of a `control` line: `note` is converted to `freq`, other
+/// numeric params are passed on by name
+fn extract_control_params(params_str: &str, ctx: &ParseContext) -> Vec<(String, f32)> {
+    let mut params = extract_slide_params(params_str, ctx);
+    for piece in params_str.split(',') {
+        let Some((key, _)) = piece.split_once(':') else {
+            continue;
+        };
+        let key = key.trim();
+        if key.is_empty() || key.ends_with("_slide") || !key.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            continue;
+        }
+        if key == "note" {
+            if let Some(freq) = extract_note_param(params_str, "note") {
+                params.push(("freq".to_string(), freq));
             }
-            ParsedCommand::SetSynth(_) | ParsedCommand::Comment(_) | ParsedCommand::Log(_) => {}
+        } else if let Some(val) = extract_param(params_str, key, ctx) {
+            params.push((key.to_string(), val));
         }
     }
+    params
+}
 
-    result
+/// True for `play ...` / `synth ...`, whose value is a node `control` can change
+pub fn is_note_command(expr: &str) -> bool {
+    let word = expr.split_whitespace().next().unwrap_or("");
+    word == "play" || word == "synth"
 }
 
-/// Calculate the total duration of a sequence of parsed commands in seconds
-fn commands_to_duration(parsed: &[ParsedCommand], bpm: f32) -> f32 {
-    let mut current_bpm = bpm;
-    let mut beat_duration = 60.0 / current_bpm;
-    let mut dur = 0.0f32;
-    for cmd in parsed {
-        match cmd {
-            ParsedCommand::Sleep(beats) => {
-                dur += beats * beat_duration;
-            }
-            ParsedCommand::SetBpm(bpm_val) => {
-                current_bpm = *bpm_val;
-                beat_duration = 60.0 / current_bpm;
-            }
-            ParsedCommand::TimesLoop { count, commands } => {
-                dur += *count as f32 * commands_to_duration(commands, current_bpm);
-            }
-            ParsedCommand::Loop { commands, parallel, .. } => {
-                if *parallel {
-                    // Parallel loops don't contribute to sequential duration
-                    // (they run concurrently)
-                } else {
-                    let has_stop = commands.iter().any(|c| matches!(c, ParsedCommand::Stop));
-                    let iters = if has_stop { 1.0 } else { 500.0 };
-                    dur += iters * commands_to_duration(commands, current_bpm);
-                }
-            }
-            ParsedCommand::WithFx { commands, .. } => {
-                dur += commands_to_duration(commands, current_bpm);
-            }
-            ParsedCommand::Stop => break,
-            _ => {}
+/// Value stored in a variable that holds a running note (`s = play ...`)
+pub fn node_ref(node: u32) -> String {
+    format!("#<SynthNode {}>", node)
+}
+
+/// Node id back out of a `node_ref` value
+pub fn parse_node_ref(value: &str) -> Option<u32> {
+    value.trim().strip_prefix("#<SynthNode ")?.strip_suffix('>')?.parse().ok()
+}
+
+/// Turn a `PlayNote` into the engine command. Rests (frequency 0) and other
+/// commands give `None`.
+pub fn note_to_audio(cmd: &ParsedCommand) -> Option<AudioCommand> {
+    match cmd {
+        ParsedCommand::PlayNote {
+            synth_type,
+            frequency,
+            amplitude,
+            pan,
+            envelope,
+            params,
+        } if *frequency > 0.0 => {
+            Some(AudioCommand::PlayNote {
+                synth_type: *synth_type,
+                frequency: *frequency,
+                amplitude: *amplitude,
+                envelope: *envelope,
+                pan: *pan,
+                params: params.clone(),
+                node: None,
+                fx: None,
+            })
         }
+        _ => None,
     }
-    dur
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::audio::interpreter::{Event, Interpreter};
+
+    /// What the runtime interpreter plays for `code` in its first `secs` seconds
+    fn run_code(code: &str, bpm: f32, secs: f64) -> Vec<(f64, Event)> {
+        Interpreter::from_code(code, bpm).unwrap().run_until(secs)
+    }
+
+    fn note_amps(events: &[(f64, Event)]) -> Vec<f32> {
+        events
+            .iter()
+            .filter_map(|(_, e)| match e {
+                Event::Audio(AudioCommand::PlayNote { amplitude, .. }) => Some(*amplitude),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn sample_times(events: &[(f64, Event)]) -> Vec<f64> {
+        events
+            .iter()
+            .filter_map(|(t, e)| matches!(e, Event::Sample { .. }).then_some(*t))
+            .collect()
+    }
 
     #[test]
     fn test_sample_path_variable_resolution() {
@@ -2869,7 +2884,7 @@ sample :perc_snap, rate: 2, amp: 0.7
     }
 
     #[test]
-    fn test_commands_to_audio_sample_count() {
+    fn test_live_loop_sample_count() {
         let code = r#"
 sample_path = "C:/test/"
 live_loop :test do
@@ -2880,51 +2895,12 @@ live_loop :test do
   stop
 end
 "#;
-        let parsed = parse_code(code).unwrap();
-        let timed = commands_to_audio(&parsed, 120.0);
-        let sample_cmds: Vec<_> = timed.iter()
-            .filter(|(_, c)| matches!(c, AudioCommand::PlaySample { .. }))
-            .collect();
-        eprintln!("Timed sample commands: {:?}", sample_cmds.len());
-        assert_eq!(sample_cmds.len(), 2, "Should have 2 sample commands");
-    }
-
-    /// Mirrored from lib.rs to test index matching
-    fn collect_sample_names_test(parsed: &[ParsedCommand]) -> Vec<String> {
-        let mut names = Vec::new();
-        collect_names_recursive(parsed, &mut names);
-        names
-    }
-    fn collect_names_recursive(parsed: &[ParsedCommand], names: &mut Vec<String>) {
-        for cmd in parsed {
-            match cmd {
-                ParsedCommand::PlaySample { name, .. } => {
-                    names.push(name.clone());
-                }
-                ParsedCommand::Loop { commands, .. } => {
-                    let has_stop = commands.iter().any(|c| matches!(c, ParsedCommand::Stop));
-                    let iters = if has_stop { 1 } else { 500 };
-                    for _ in 0..iters {
-                        collect_names_recursive(commands, names);
-                        if names.len() > 100_000 { return; }
-                    }
-                }
-                ParsedCommand::TimesLoop { count, commands } => {
-                    for _ in 0..*count {
-                        collect_names_recursive(commands, names);
-                    }
-                }
-                ParsedCommand::WithFx { commands, .. } => {
-                    collect_names_recursive(commands, names);
-                }
-                ParsedCommand::Stop => { return; }
-                _ => {}
-            }
-        }
+        let played = sample_times(&run_code(code, 120.0, 10.0));
+        assert_eq!(played.len(), 2, "Should play 2 samples");
     }
 
     #[test]
-    fn test_sample_index_matching_full() {
+    fn test_finite_loops_play_every_sample() {
         let code = r#"
 use_bpm 123
 use_synth :fm
@@ -2973,28 +2949,11 @@ live_loop :breakdown do
   stop
 end
 "#;
-        let parsed = parse_code(code).unwrap();
-        
-        // Count PlaySample commands in timed_commands
-        let timed = commands_to_audio(&parsed, 123.0);
-        let play_sample_count = timed.iter()
-            .filter(|(_, c)| matches!(c, AudioCommand::PlaySample { .. }))
-            .count();
-        
-        // Count sample names from collect_sample_names
-        let sample_names = collect_sample_names_test(&parsed);
-        
-        eprintln!("PlaySample commands in timed_commands: {}", play_sample_count);
-        eprintln!("Sample names collected: {}", sample_names.len());
-        for (i, name) in sample_names.iter().enumerate() {
-            eprintln!("  [{}] {}", i, name);
-        }
-        
-        assert_eq!(
-            play_sample_count, sample_names.len(),
-            "PlaySample count in commands_to_audio ({}) must match collect_sample_names count ({})",
-            play_sample_count, sample_names.len()
-        );
+        let mut interp = Interpreter::from_code(code, 123.0).unwrap();
+        let played = sample_times(&interp.run_until(600.0));
+        assert!(interp.is_finished(), "every live_loop ends in stop");
+        // 8 perc + 16 * 2 drums + 2 * 6 vocals + 2 breakdown
+        assert_eq!(played.len(), 54, "every sample line should play once per pass");
     }
 
     #[test]
@@ -3024,15 +2983,8 @@ live_loop :c do
   stop
 end
 "#;
-        let parsed = parse_code(code).unwrap();
-        let timed = commands_to_audio(&parsed, 120.0);
-        
-        let sample_times: Vec<f32> = timed.iter()
-            .filter_map(|(t, c)| {
-                if matches!(c, AudioCommand::PlaySample { .. }) { Some(*t) } else { None }
-            })
-            .collect();
-        
+        let sample_times = sample_times(&run_code(code, 120.0, 10.0));
+
         eprintln!("Sample times: {:?}", sample_times);
         assert_eq!(sample_times.len(), 3, "Should have 3 samples");
         // Loop :a and :b are consecutive live_loops → both at t=0
@@ -3075,22 +3027,16 @@ dark_drums
   guitar_riff
 end
 "#;
-        let parsed = parse_code(code).unwrap();
-
-        // Check that we got PlayNote commands from guitar_riff expansion
-        let timed = commands_to_audio(&parsed, 120.0);
-        let note_count = timed.iter()
-            .filter(|(_, c)| matches!(c, AudioCommand::PlayNote { .. }))
-            .count();
+        // Check that we got notes from calling guitar_riff
+        let events = run_code(code, 120.0, 20.0);
+        let note_count = note_amps(&events).len();
         eprintln!("Note commands from define expansion: {}", note_count);
         // guitar_riff called once directly + 2 times in loop = 3 calls
         // Each call has 3 notes = 9 total
         assert_eq!(note_count, 9, "Should have 9 notes (3 calls x 3 notes)");
 
         // Check that dark_drums produced sample commands (live_loop inside define)
-        let sample_count = timed.iter()
-            .filter(|(_, c)| matches!(c, AudioCommand::PlaySample { .. }))
-            .count();
+        let sample_count = sample_times(&events).len();
         eprintln!("Sample commands from define expansion: {}", sample_count);
         assert!(sample_count >= 2, "Should have at least 2 samples from dark_drums");
     }
@@ -3300,6 +3246,25 @@ sleep 1
         assert!((release - 2.0).abs() < 0.01, "Release should be 2.0 from defaults, got {}", release);
     }
 
+    #[test]
+    fn test_envelope_levels_and_curve() {
+        let code = "play :c4, attack: 0.5, sustain: 2, release: 1, attack_level: 0.8, sustain_level: 0.4, env_curve: 3";
+        let parsed = parse_code(code).unwrap();
+        let envelope = parsed.iter().find_map(|c| match c {
+            ParsedCommand::PlayNote { envelope, .. } => Some(*envelope),
+            _ => None,
+        });
+        let envelope = envelope.expect("Should have a note");
+        assert_eq!(envelope.sustain, 2.0, "sustain is the hold time");
+        assert_eq!(envelope.attack_level, 0.8);
+        assert_eq!(envelope.decay_level, 0.4, "decay_level follows sustain_level");
+        assert_eq!(envelope.curve, EnvCurve::Sine);
+        assert!((envelope.duration() - 3.6).abs() < 1e-6);
+        assert!((envelope.level(0.5) - 0.8).abs() < 1e-3, "peak at the end of the attack");
+        assert!((envelope.level(1.0) - 0.4).abs() < 1e-3, "sustain_level while sustaining");
+        assert_eq!(envelope.level(3.6), 0.0);
+    }
+
     #[test]
     fn test_with_synth_block() {
         let code = r#"
@@ -3454,13 +3419,9 @@ end
         let parsed = parse_code(code).unwrap();
         assert!(!parsed.is_empty(), "Should parse comprehensive code without errors");
 
-        let timed = commands_to_audio(&parsed, 120.0);
-        let note_count = timed.iter()
-            .filter(|(_, c)| matches!(c, AudioCommand::PlayNote { .. }))
-            .count();
-        let sample_count = timed.iter()
-            .filter(|(_, c)| matches!(c, AudioCommand::PlaySample { .. }))
-            .count();
+        let events = run_code(code, 120.0, 20.0);
+        let note_count = note_amps(&events).len();
+        let sample_count = sample_times(&events).len();
         eprintln!("Comprehensive test: {} notes, {} samples", note_count, sample_count);
         assert!(note_count > 0, "Should have notes");
         assert!(sample_count > 0, "Should have samples");
@@ -3495,16 +3456,12 @@ end
         eprintln!("Parsed commands: {:#?}", parsed);
         assert!(!parsed.is_empty(), "Should parse the code without errors");
 
-        let timed = commands_to_audio(&parsed, 135.0);
-        let note_count = timed.iter()
-            .filter(|(_, c)| matches!(c, AudioCommand::PlayNote { .. }))
-            .count();
-        eprintln!("Def block test: {} notes from timed commands", note_count);
-        assert!(note_count > 0, "Should produce notes from play_pattern_timed inside live_loop");
+        let amps = note_amps(&run_code(code, 135.0, 10.0));
+        eprintln!("Def block test: {} notes played", amps.len());
+        assert!(!amps.is_empty(), "Should produce notes from play_pattern_timed inside live_loop");
 
         // Verify that the amplitude is 3.0 (from amp: 3)
-        let first_note = timed.iter()
-            .find_map(|(_, c)| if let AudioCommand::PlayNote { amplitude, .. } = c { Some(*amplitude) } else { None });
+        let first_note = amps.first().copied();
         assert!(first_note.is_some(), "Should have a note");
         assert!((first_note.unwrap() - 3.0).abs() < 0.01, "Amplitude should be 3.0, got {}", first_note.unwrap());
     }
@@ -3517,17 +3474,11 @@ end
         eprintln!("Preprocessed:\n{}", preprocessed);
         assert!(!preprocessed.contains("\n  release:"), "Continuation line should be joined");
 
-        let parsed = parse_code(code).unwrap();
-        let timed = commands_to_audio(&parsed, 120.0);
-        let note_count = timed.iter()
-            .filter(|(_, c)| matches!(c, AudioCommand::PlayNote { .. }))
-            .count();
-        assert_eq!(note_count, 3, "Should have 3 notes from play_pattern_timed");
+        let amps = note_amps(&run_code(code, 120.0, 10.0));
+        assert_eq!(amps.len(), 3, "Should have 3 notes from play_pattern_timed");
 
         // Verify amplitude
-        let amp = timed.iter()
-            .find_map(|(_, c)| if let AudioCommand::PlayNote { amplitude, .. } = c { Some(*amplitude) } else { None });
-        assert!((amp.unwrap() - 2.0).abs() < 0.01, "Amplitude should be 2.0 from joined line");
+        assert!((amps[0] - 2.0).abs() < 0.01, "Amplitude should be 2.0 from joined line");
     }
 
     #[test]
@@ -3542,11 +3493,23 @@ end
 
 my_riff
 "#;
-        let parsed = parse_code(code).unwrap();
-        let timed = commands_to_audio(&parsed, 120.0);
-        let note_count = timed.iter()
-            .filter(|(_, c)| matches!(c, AudioCommand::PlayNote { .. }))
-            .count();
+        let note_count = note_amps(&run_code(code, 120.0, 10.0)).len();
         assert_eq!(note_count, 2, "Should have 2 notes from def function call");
     }
+
+    #[test]
+    fn test_variables_substituted_in_commands() {
+        let mut ctx = ParseContext::new();
+        ctx.variables.insert("amp".to_string(), "0.25".to_string());
+        ctx.variables.insert("n".to_string(), ":e4".to_string());
+        ctx.variables.insert("msg".to_string(), "hello".to_string());
+        assert_eq!(substitute_vars("play n, amp: amp", &ctx), "play :e4, amp: 0.25");
+        assert_eq!(substitute_vars("sample :amp, rate: amp.abs", &ctx), "sample :amp, rate: amp.abs");
+        assert_eq!(substitute_vars("puts \"n\", msg", &ctx), "puts \"n\", msg");
+
+        match parse_line("sleep amp", &mut ctx) {
+            Some(ParsedCommand::Sleep(beats)) => assert_eq!(beats, 0.25),
+            other => panic!("expected sleep, got {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,133 @@
+//! Seedable random streams with Sonic Pi semantics.
+//!
+//! A stream is a seed and a position in the sequence that seed produces, so
+//! the same seed always gives the same values. `rand_reset` goes back to the
+//! start of the sequence and `rand_skip` / `rand_back` move along it without
+//! drawing. Every value is a pure function of (seed, position), which keeps
+//! streams cheap to copy into new threads.
+//!
+//! The source shapes the sequence: `white` is uniform noise, `pink` and
+//! `light_pink` average in slower-changing layers so neighbouring values are
+//! related, and `perlin` drifts smoothly from one value to the next.
+
+/// Shape of the values a stream produces
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RandomSource {
+    #[default]
+    White,
+    Pink,
+    LightPink,
+    Perlin,
+}
+
+impl RandomSource {
+    /// Source for a `use_random_source` name (with or without the colon)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().trim_start_matches(':') {
+            "white" => Some(Self::White),
+            "pink" => Some(Self::Pink),
+            "light_pink" => Some(Self::LightPink),
+            "perlin" => Some(Self::Perlin),
+            _ => None,
+        }
+    }
+}
+
+/// A position in a seeded random sequence
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RandomStream {
+    seed: u64,
+    index: u64,
+    source: RandomSource,
+}
+
+impl Default for RandomStream {
+    /// Sonic Pi starts every run from seed 0
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl RandomStream {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, index: 0, source: RandomSource::White }
+    }
+
+    /// Stream for a thread called `name`, derived from this stream's seed so
+    /// the thread sounds the same on every run
+    pub fn derive(&self, name: &str) -> Self {
+        let name_hash = name
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325u64, |h, b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3));
+        Self { seed: mix(self.seed ^ name_hash), index: 0, source: self.source }
+    }
+
+    /// `use_random_seed`: start the sequence for `seed` from the beginning
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.index = 0;
+    }
+
+    /// `rand_reset`
+    pub fn reset(&mut self) {
+        self.index = 0;
+    }
+
+    /// `rand_skip` / `rand_back`
+    pub fn skip(&mut self, steps: i64) {
+        self.index = self.index.wrapping_add_signed(steps);
+    }
+
+    pub fn set_source(&mut self, source: RandomSource) {
+        self.source = source;
+    }
+
+    /// Next value in `0.0..1.0`
+    pub fn next_f32(&mut self) -> f32 {
+        let value = self.value_at(self.index);
+        self.index = self.index.wrapping_add(1);
+        value
+    }
+
+    fn value_at(&self, index: u64) -> f32 {
+        match self.source {
+            RandomSource::White => white(self.seed, index),
+            RandomSource::Pink => layered(self.seed, index, 6),
+            RandomSource::LightPink => layered(self.seed, index, 3),
+            RandomSource::Perlin => perlin(self.seed, index),
+        }
+    }
+}
+
+/// SplitMix64 finaliser
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+fn white(seed: u64, index: u64) -> f32 {
+    let bits = mix(mix(seed) ^ index) >> 40;
+    bits as f32 / (1u64 << 24) as f32
+}
+
+/// Average of `layers` white noise layers, layer k changing every 2^k steps
+fn layered(seed: u64, index: u64, layers: u32) -> f32 {
+    let sum: f32 = (0..layers)
+        .map(|k| white(seed.wrapping_add(k as u64 + 1), index >> k))
+        .sum();
+    (sum / layers as f32).min(0.999_999)
+}
+
+/// 1D gradient noise, one lattice point every 8 steps
+fn perlin(seed: u64, index: u64) -> f32 {
+    const STEPS: u64 = 8;
+    let cell = index / STEPS;
+    let t = (index % STEPS) as f32 / STEPS as f32;
+    let g0 = white(seed, cell) * 2.0 - 1.0;
+    let g1 = white(seed, cell + 1) * 2.0 - 1.0;
+    let fade = t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let n = g0 * t + (g1 * (t - 1.0) - g0 * t) * fade;
+    (0.5 + n).clamp(0.0, 0.999_999)
+}
@@ -1,80 +1,426 @@
+//! Records the master output to a stereo WAV file. The audio thread copies
+//! each buffer into a block from a pool allocated up front and hands it to a
+//! background writer over a channel; the writer sends it back once it is on
+//! disk. So the audio thread never allocates or takes a lock, nothing is
+//! kept in memory and a long recording costs only disk space. The file
+//! always has the sample rate of the output device.
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
 use parking_lot::Mutex;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::random::RandomStream;
+
+/// Blocks in the pool: how far the writer may fall behind before audio is
+/// dropped, several seconds at any usual buffer size
+const POOL_BLOCKS: usize = 512;
+/// Interleaved samples a block holds; longer buffers take several blocks
+const BLOCK_SAMPLES: usize = 2048;
+/// How long the writer waits for a block before checking whether the
+/// recording has stopped
+const WRITER_POLL: Duration = Duration::from_millis(20);
+/// How often the writer updates the WAV header, so a crash still leaves a
+/// playable file
+const FLUSH_SECS: u32 = 2;
+
+/// Sample format of a recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordFormat {
+    /// 16-bit PCM with TPDF dither
+    Pcm16,
+    /// 24-bit PCM with TPDF dither
+    Pcm24,
+    /// 32-bit float, undithered
+    #[default]
+    Float32,
+}
+
+impl RecordFormat {
+    fn bits(self) -> u16 {
+        match self {
+            RecordFormat::Pcm16 => 16,
+            RecordFormat::Pcm24 => 24,
+            RecordFormat::Float32 => 32,
+        }
+    }
+}
+
+/// Interleaved samples, with the id of the take they were recorded for
+type Block = (u64, Vec<f32>);
+
+/// A recording, from `start` until `stop` collects it
+struct Take {
+    id: u64,
+    path: PathBuf,
+    writer: JoinHandle<Result<u64, String>>,
+}
 
 #[derive(Clone)]
 pub struct Recorder {
-    buffer: Arc<Mutex<Vec<f32>>>,
-    is_recording: Arc<Mutex<bool>>,
-    sample_rate: u32,
+    /// Output device sample rate, set by the engine that feeds the recorder
+    sample_rate: Arc<AtomicU32>,
+    /// Id of the take being recorded, 0 when not recording. The audio
+    /// thread stamps each block with it, so the writer can skip blocks left
+    /// over from an earlier take.
+    recording: Arc<AtomicU64>,
+    takes: Arc<AtomicU64>,
+    /// Filled blocks on their way to the writer, by take
+    queue: (Sender<Block>, Receiver<Block>),
+    /// Empty blocks ready for the audio thread
+    pool: (Sender<Vec<f32>>, Receiver<Vec<f32>>),
+    /// Whether the pool has been allocated, on the first recording
+    pooled: Arc<AtomicBool>,
+    take: Arc<Mutex<Option<Take>>>,
+    /// File of the last finished recording
+    last: Arc<Mutex<Option<PathBuf>>>,
+    /// Frames lost because the writer fell behind, this recording
+    dropped: Arc<AtomicU64>,
 }
 
 impl Recorder {
     pub fn new(sample_rate: u32) -> Self {
         Self {
-            buffer: Arc::new(Mutex::new(Vec::new())),
-            is_recording: Arc::new(Mutex::new(false)),
-            sample_rate,
+            sample_rate: Arc::new(AtomicU32::new(sample_rate)),
+            recording: Arc::new(AtomicU64::new(0)),
+            takes: Arc::new(AtomicU64::new(0)),
+            queue: bounded(POOL_BLOCKS),
+            pool: bounded(POOL_BLOCKS),
+            pooled: Arc::new(AtomicBool::new(false)),
+            take: Arc::new(Mutex::new(None)),
+            last: Arc::new(Mutex::new(None)),
+            dropped: Arc::new(AtomicU64::new(0)),
         }
     }
 
-    pub fn start(&self) {
-        let mut recording = self.is_recording.lock();
-        let mut buffer = self.buffer.lock();
-        buffer.clear();
-        *recording = true;
+    pub fn set_sample_rate(&self, sample_rate: u32) {
+        self.sample_rate.store(sample_rate, Ordering::Relaxed);
+    }
+
+    /// Start recording into a new WAV file at `path`, replacing it if it
+    /// exists. A recording already running, or one whose writer failed, is
+    /// finished first.
+    pub fn start(&self, path: &Path, format: RecordFormat) -> Result<(), String> {
+        if self.take.lock().is_some() {
+            let _ = self.stop();
+        }
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create '{}': {}", dir.display(), e))?;
+        }
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed);
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample: format.bits(),
+            sample_format: match format {
+                RecordFormat::Float32 => hound::SampleFormat::Float,
+                _ => hound::SampleFormat::Int,
+            },
+        };
+        let writer = hound::WavWriter::create(path, spec)
+            .map_err(|e| format!("Failed to create file: {}", e))?;
+
+        if !self.pooled.swap(true, Ordering::Relaxed) {
+            for _ in 0..POOL_BLOCKS {
+                let _ = self.pool.0.try_send(Vec::with_capacity(BLOCK_SAMPLES));
+            }
+        }
+        let id = self.takes.fetch_add(1, Ordering::Relaxed) + 1;
+        self.dropped.store(0, Ordering::Relaxed);
+        self.recording.store(id, Ordering::Release);
+        let flush_every = sample_rate as u64 * FLUSH_SECS as u64 * 2;
+        let recorder = self.clone();
+        let writer = std::thread::spawn(move || write_blocks(writer, &recorder, id, format, flush_every));
+        *self.take.lock() = Some(Take { id, path: path.to_path_buf(), writer });
+        Ok(())
     }
 
-    pub fn stop(&self) {
-        let mut recording = self.is_recording.lock();
-        *recording = false;
+    /// Finish the recording and close its file. Fails with the writer's
+    /// error if it could not write the file.
+    pub fn stop(&self) -> Result<String, String> {
+        let take = self.take.lock().take().ok_or("Not recording")?;
+        // The writer drains the queue, sees the take is over and finishes
+        let _ = self.recording.compare_exchange(take.id, 0, Ordering::AcqRel, Ordering::Relaxed);
+        let frames = take
+            .writer
+            .join()
+            .map_err(|_| "Recording writer panicked".to_string())??;
+        if frames == 0 {
+            let _ = std::fs::remove_file(&take.path);
+            return Err("No audio recorded".to_string());
+        }
+        let message = saved_message(frames, self.sample_rate.load(Ordering::Relaxed), &take.path);
+        *self.last.lock() = Some(take.path);
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            return Ok(format!("{} ({} frames lost: the disk could not keep up)", message, dropped));
+        }
+        Ok(message)
     }
 
+    /// Whether audio is being recorded: false once stopped, or as soon as
+    /// the writer has failed
     pub fn is_recording(&self) -> bool {
-        *self.is_recording.lock()
+        self.recording.load(Ordering::Relaxed) != 0
+    }
+
+    /// Take a finished recording written elsewhere (by scsynth) as the last
+    /// recording, so it can be moved and saved like one of ours
+    pub fn adopt(&self, path: PathBuf) -> String {
+        let message = match hound::WavReader::open(&path) {
+            Ok(reader) => saved_message(reader.duration() as u64, reader.spec().sample_rate, &path),
+            Err(_) => format!("Saved recording to {}", path.display()),
+        };
+        *self.last.lock() = Some(path);
+        message
     }
 
+    /// Queue interleaved stereo samples for the writer. Called from the
+    /// audio thread, so it never waits, locks or allocates: with no free
+    /// block left the samples are dropped.
     pub fn push_samples(&self, samples: &[f32]) {
-        if *self.is_recording.lock() {
-            let mut buffer = self.buffer.lock();
-            buffer.extend_from_slice(samples);
+        let take = self.recording.load(Ordering::Acquire);
+        if take == 0 {
+            return;
+        }
+        for chunk in samples.chunks(BLOCK_SAMPLES) {
+            let Ok(mut block) = self.pool.1.try_recv() else {
+                self.dropped.fetch_add(chunk.len() as u64 / 2, Ordering::Relaxed);
+                continue;
+            };
+            block.clear();
+            block.extend_from_slice(chunk);
+            if let Err(e) = self.queue.0.try_send((take, block)) {
+                let _ = self.pool.0.try_send(e.into_inner().1);
+                self.dropped.fetch_add(chunk.len() as u64 / 2, Ordering::Relaxed);
+            }
         }
     }
 
+    /// Move the last recording to `path`
+    pub fn move_to(&self, path: &str) -> Result<String, String> {
+        let mut last = self.last.lock();
+        let from = last.as_ref().ok_or("No audio recorded")?;
+        let to = PathBuf::from(path);
+        if *from != to {
+            // Renaming fails across file systems; copy there instead
+            if std::fs::rename(from, &to).is_err() {
+                std::fs::copy(from, &to).map_err(|e| format!("Failed to save: {}", e))?;
+                let _ = std::fs::remove_file(from);
+            }
+            *last = Some(to);
+        }
+        Ok(format!("Saved recording to {}", path))
+    }
+
+    /// Copy the last recording to `path`
     pub fn save_to_file(&self, path: &str) -> Result<String, String> {
-        let buffer = self.buffer.lock();
-        if buffer.is_empty() {
-            return Err("No audio recorded".to_string());
+        let last = self.last.lock();
+        let from = last.as_ref().ok_or("No audio recorded")?;
+        if from != Path::new(path) {
+            std::fs::copy(from, path).map_err(|e| format!("Failed to save: {}", e))?;
         }
+        Ok(format!("Saved recording to {}", path))
+    }
+}
 
-        let spec = hound::WavSpec {
-            channels: 1,
-            sample_rate: self.sample_rate,
-            bits_per_sample: 32,
-            sample_format: hound::SampleFormat::Float,
+fn saved_message(frames: u64, sample_rate: u32, path: &Path) -> String {
+    format!(
+        "Saved {} frames ({:.1}s) to {}",
+        frames,
+        frames as f64 / sample_rate as f64,
+        path.display()
+    )
+}
+
+/// Writer thread for take `id`: write its blocks until the take is over
+/// and the queue is drained, then finish the file. Returns the frames
+/// written. If writing fails the take ends there, so the audio thread
+/// stops recording.
+fn write_blocks(
+    writer: hound::WavWriter<BufWriter<File>>,
+    recorder: &Recorder,
+    id: u64,
+    format: RecordFormat,
+    flush_every: u64,
+) -> Result<u64, String> {
+    let result = write_take(writer, recorder, id, format, flush_every);
+    if result.is_err() {
+        let _ = recorder.recording.compare_exchange(id, 0, Ordering::AcqRel, Ordering::Relaxed);
+    }
+    result
+}
+
+fn write_take(
+    mut writer: hound::WavWriter<BufWriter<File>>,
+    recorder: &Recorder,
+    id: u64,
+    format: RecordFormat,
+    flush_every: u64,
+) -> Result<u64, String> {
+    let mut dither = RandomStream::new(0x5eed);
+    let mut written = 0u64;
+    let mut since_flush = 0u64;
+    loop {
+        let (take, block) = match recorder.queue.1.recv_timeout(WRITER_POLL) {
+            Ok(queued) => queued,
+            Err(RecvTimeoutError::Timeout) if recorder.recording.load(Ordering::Acquire) == id => continue,
+            Err(_) => break,
         };
+        // Blocks left over from an earlier take only go back to the pool
+        let samples = if take == id { block.len() as u64 } else { 0 };
+        let result = if take == id { write_block(&mut writer, &block, format, &mut dither) } else { Ok(()) };
+        let _ = recorder.pool.0.try_send(block);
+        result?;
+        written += samples;
+        since_flush += samples;
+        if since_flush >= flush_every {
+            since_flush = 0;
+            writer.flush().map_err(|e| format!("Failed to write: {}", e))?;
+        }
+    }
+    writer
+        .finalize()
+        .map_err(|e| format!("Failed to finalize: {}", e))?;
+    Ok(written / 2)
+}
 
-        let mut writer = hound::WavWriter::create(path, spec)
-            .map_err(|e| format!("Failed to create file: {}", e))?;
+fn write_block(
+    writer: &mut hound::WavWriter<BufWriter<File>>,
+    block: &[f32],
+    format: RecordFormat,
+    dither: &mut RandomStream,
+) -> Result<(), String> {
+    for &sample in block {
+        let sample = sample.clamp(-1.0, 1.0);
+        let result = match format {
+            RecordFormat::Float32 => writer.write_sample(sample),
+            RecordFormat::Pcm16 => writer.write_sample(dithered(sample, 16, dither) as i16),
+            RecordFormat::Pcm24 => writer.write_sample(dithered(sample, 24, dither)),
+        };
+        result.map_err(|e| format!("Failed to write: {}", e))?;
+    }
+    Ok(())
+}
+
+/// `sample` as a `bits`-bit integer, with triangular (TPDF) dither of one
+/// LSB either way so quiet passages fade into noise instead of distortion
+fn dithered(sample: f32, bits: u32, dither: &mut RandomStream) -> i32 {
+    let max = ((1i64 << (bits - 1)) - 1) as f64;
+    let noise = dither.next_f32() as f64 - dither.next_f32() as f64;
+    (sample as f64 * max + noise).round().clamp(-max - 1.0, max) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        for &sample in buffer.iter() {
-            writer
-                .write_sample(sample)
-                .map_err(|e| format!("Failed to write: {}", e))?;
+    /// A fresh path under the system temp dir
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("sonic-recorder-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_dither_stays_in_range() {
+        let mut dither = RandomStream::new(1);
+        for _ in 0..10_000 {
+            assert!((32766..=32767).contains(&dithered(1.0, 16, &mut dither)));
+            assert!((-32768..=-32766).contains(&dithered(-1.0, 16, &mut dither)));
+            assert!((-1..=1).contains(&dithered(0.0, 16, &mut dither)));
+            assert!((8_388_606..=8_388_607).contains(&dithered(1.0, 24, &mut dither)));
         }
+    }
 
-        writer
-            .finalize()
-            .map_err(|e| format!("Failed to finalize: {}", e))?;
+    #[test]
+    fn test_dither_averages_out() {
+        let mut dither = RandomStream::new(1);
+        let sum: i64 = (0..10_000).map(|_| dithered(0.5 / 32767.0, 16, &mut dither) as i64).sum();
+        assert!((sum as f64 / 10_000.0 - 0.5).abs() < 0.05);
+    }
 
-        Ok(format!(
-            "Saved {} samples ({:.1}s) to {}",
-            buffer.len(),
-            buffer.len() as f32 / self.sample_rate as f32,
-            path
-        ))
+    #[test]
+    fn test_recording_round_trip() {
+        let input: Vec<f32> = (0..5000).map(|i| ((i as f32 * 0.01).sin() * 1.2).clamp(-1.5, 1.5)).collect();
+        let formats = [
+            (RecordFormat::Pcm16, 1.0 / 32767.0),
+            (RecordFormat::Pcm24, 1.0 / 8_388_607.0),
+            (RecordFormat::Float32, 0.0),
+        ];
+        for (format, lsb) in formats {
+            let path = temp_path(&format!("{:?}.wav", format));
+            let recorder = Recorder::new(8000);
+            recorder.push_samples(&input);
+            recorder.start(&path, format).unwrap();
+            assert!(recorder.is_recording());
+            // More than a block at a time is split over several
+            recorder.push_samples(&input[..4000]);
+            recorder.push_samples(&input[4000..]);
+            let message = recorder.stop().unwrap();
+            assert!(message.starts_with("Saved 2500 frames"), "{}", message);
+            assert!(!recorder.is_recording());
+
+            let mut reader = hound::WavReader::open(&path).unwrap();
+            let spec = reader.spec();
+            assert_eq!((spec.channels, spec.sample_rate, spec.bits_per_sample), (2, 8000, format.bits()));
+            let output: Vec<f32> = match format {
+                RecordFormat::Float32 => reader.samples::<f32>().map(Result::unwrap).collect(),
+                _ => reader.samples::<i32>().map(|s| s.unwrap() as f32 * lsb).collect(),
+            };
+            assert_eq!(output.len(), input.len());
+            for (out, x) in output.iter().zip(&input) {
+                assert!((out - x.clamp(-1.0, 1.0)).abs() <= 2.0 * lsb + 1e-7, "{:?}: {} vs {}", format, out, x);
+            }
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_takes_do_not_mix() {
+        let recorder = Recorder::new(8000);
+        let first = temp_path("first.wav");
+        let second = temp_path("second.wav");
+        recorder.start(&first, RecordFormat::Float32).unwrap();
+        recorder.push_samples(&[0.25; 200]);
+        // Starting again finishes the first take
+        recorder.start(&second, RecordFormat::Float32).unwrap();
+        recorder.push_samples(&[0.5; 100]);
+        assert!(recorder.stop().unwrap().starts_with("Saved 50 frames"));
+        assert_eq!(hound::WavReader::open(&first).unwrap().duration(), 100);
+        assert!(recorder.stop().is_err());
+        std::fs::remove_file(first).unwrap();
+        std::fs::remove_file(second).unwrap();
     }
 
-    pub fn get_buffer(&self) -> Vec<f32> {
-        self.buffer.lock().clone()
+    #[test]
+    fn test_empty_recording_is_removed() {
+        let recorder = Recorder::new(8000);
+        let path = temp_path("empty.wav");
+        recorder.start(&path, RecordFormat::Pcm16).unwrap();
+        assert_eq!(recorder.stop(), Err("No audio recorded".to_string()));
+        assert!(!path.exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_writer_failure_stops_the_recording() {
+        let recorder = Recorder::new(8000);
+        recorder.start(Path::new("/dev/full"), RecordFormat::Float32).unwrap();
+        recorder.push_samples(&[0.5; 20_000]);
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        while recorder.is_recording() && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!recorder.is_recording());
+        assert!(recorder.stop().unwrap_err().starts_with("Failed to write"));
     }
 }
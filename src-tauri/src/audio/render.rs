@@ -0,0 +1,502 @@
+//! Offline rendering: run code through the built-in engine's mixing and FX
+//! with no audio device, as fast as the CPU allows, and save the result as
+//! stereo WAV files. Used for bouncing tracks, exporting a stem per
+//! live_loop, and checking that code still sounds the same.
+//!
+//! The code runs once up front into a score of timed events, so every stem
+//! of an export hears exactly the same notes and they line up to the sample.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use super::engine::{AudioCommand, OfflineEngine};
+use super::interpreter::{parse_program, Event, Interpreter};
+use super::parser::{parse_code, ParsedCommand};
+use super::sample::SampleData;
+
+/// Sample rate renders use unless told otherwise
+pub const DEFAULT_RENDER_SAMPLE_RATE: u32 = 44100;
+/// Longest render accepted, so a typo can't fill the disk
+pub const MAX_RENDER_SECS: f64 = 3600.0;
+/// How far the interpreter runs ahead per step, and the length of each
+/// rendered block
+const RENDER_CHUNK_SECS: f64 = 0.5;
+const BEATS_PER_BAR: f64 = 4.0;
+/// Stem holding the code outside any live_loop
+const MAIN_STEM: &str = "main";
+/// File name of the master mix next to the stems
+const MIX_STEM: &str = "mix";
+
+/// How much of the code to render
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RenderLength {
+    Seconds(f64),
+    /// Bars of four beats at the code's BPM
+    Bars(f64),
+}
+
+impl RenderLength {
+    pub fn secs(self, bpm: f32) -> f64 {
+        match self {
+            RenderLength::Seconds(secs) => secs,
+            RenderLength::Bars(bars) => bars * BEATS_PER_BAR * 60.0 / bpm as f64,
+        }
+    }
+}
+
+/// Audio rendered from code, and what the code printed along the way
+pub struct Render {
+    /// Interleaved left and right samples
+    pub audio: Vec<f32>,
+    pub sample_rate: u32,
+    pub bpm: f32,
+    /// `puts` / `log` output
+    pub logs: Vec<String>,
+    /// Runtime problems and samples that failed to load
+    pub errors: Vec<String>,
+}
+
+impl Render {
+    pub fn duration_secs(&self) -> f64 {
+        self.audio.len() as f64 / 2.0 / self.sample_rate as f64
+    }
+}
+
+/// A render written straight to a WAV file
+#[derive(Debug, Clone, Serialize)]
+pub struct RenderedFile {
+    pub path: PathBuf,
+    pub duration_secs: f64,
+    pub bpm: f32,
+    pub logs: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+/// Files written by a stem export
+#[derive(Debug, Clone, Serialize)]
+pub struct StemExport {
+    /// One per stem: the main code first, then each live_loop in the order
+    /// it started
+    pub stems: Vec<PathBuf>,
+    /// Master mix of every stem, when asked for
+    pub mix: Option<PathBuf>,
+    pub duration_secs: f64,
+    pub bpm: f32,
+    pub logs: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+/// Which notes and samples a render plays. FX, BPM and volume changes reach
+/// every part, so each stem goes through its own copy of the effects.
+#[derive(Debug, Clone, Copy)]
+enum Part<'a> {
+    All,
+    /// Sound from one live_loop, or from outside any (`None`)
+    Stem(Option<&'a str>),
+}
+
+/// The code run to the end of the render, ready to be played into any
+/// number of engines
+struct Score {
+    /// Events by output frame, with the live_loop that produced them
+    events: Vec<(u64, Option<String>, Event)>,
+    /// Sample data by name, for every sample that loaded
+    samples: HashMap<String, SampleData>,
+    /// live_loops in the order they started
+    loops: Vec<String>,
+    sample_rate: u32,
+    total_frames: u64,
+    bpm: f32,
+    logs: Vec<String>,
+    errors: Vec<String>,
+}
+
+impl Score {
+    fn new(
+        code: &str,
+        length: RenderLength,
+        sample_rate: u32,
+        bpm: f32,
+        mut load_sample: impl FnMut(&str) -> Result<SampleData, String>,
+    ) -> Result<Self, String> {
+        let program = parse_program(code).map_err(|e| format!("Parse error: {}", e))?;
+        let bpm = parse_code(code)
+            .ok()
+            .and_then(|parsed| {
+                parsed.iter().find_map(|cmd| match cmd {
+                    ParsedCommand::SetBpm(b) => Some(*b),
+                    _ => None,
+                })
+            })
+            .unwrap_or(bpm);
+        let secs = length.secs(bpm);
+        if !secs.is_finite() || secs <= 0.0 {
+            return Err("Render length must be more than zero".to_string());
+        }
+        if secs > MAX_RENDER_SECS {
+            return Err(format!("Render length is limited to {} seconds", MAX_RENDER_SECS));
+        }
+        if sample_rate == 0 {
+            return Err("Sample rate must be more than zero".to_string());
+        }
+
+        let mut score = Score {
+            events: Vec::new(),
+            samples: HashMap::new(),
+            loops: Vec::new(),
+            sample_rate,
+            total_frames: (secs * sample_rate as f64).round() as u64,
+            bpm,
+            logs: Vec::new(),
+            errors: Vec::new(),
+        };
+        let mut interpreter = Interpreter::new(program, bpm);
+        let mut failed = Vec::new();
+        let mut horizon = 0.0f64;
+        while horizon < secs {
+            horizon = (horizon + RENDER_CHUNK_SECS).min(secs);
+            for (time, live_loop, event) in interpreter.run_until_by_loop(horizon) {
+                let frame = (time.max(0.0) * sample_rate as f64).round() as u64;
+                match event {
+                    Event::Log(msg) => score.logs.push(msg),
+                    Event::Error(msg) => score.errors.push(msg),
+                    Event::Sample { ref name, .. } => {
+                        if !score.samples.contains_key(name) && !failed.contains(name) {
+                            match load_sample(name) {
+                                Ok(data) => {
+                                    score.samples.insert(name.clone(), data);
+                                }
+                                Err(e) => {
+                                    score.errors.push(e);
+                                    failed.push(name.clone());
+                                }
+                            }
+                        }
+                        score.events.push((frame, live_loop, event));
+                    }
+                    Event::Audio(_) => score.events.push((frame, live_loop, event)),
+                }
+            }
+            if interpreter.is_finished() {
+                break;
+            }
+        }
+        score.loops = interpreter.loops().into_iter().map(|status| status.name).collect();
+        Ok(score)
+    }
+
+    fn duration_secs(&self) -> f64 {
+        self.total_frames as f64 / self.sample_rate as f64
+    }
+
+    /// Play `part` of the score into a fresh engine, handing each rendered
+    /// block of interleaved stereo to `sink`
+    fn render(&self, part: Part, mut sink: impl FnMut(&[f32]) -> Result<(), String>) -> Result<(), String> {
+        let mut engine = OfflineEngine::new(self.sample_rate);
+        let chunk_frames = ((RENDER_CHUNK_SECS * self.sample_rate as f64) as u64).max(1);
+        let mut block = vec![0.0f32; chunk_frames as usize * 2];
+        let mut events = self.events.iter().peekable();
+        while engine.position() < self.total_frames {
+            let end = (engine.position() + chunk_frames).min(self.total_frames);
+            while let Some((frame, live_loop, event)) = events.next_if(|(frame, _, _)| *frame < end) {
+                let heard = match part {
+                    Part::All => true,
+                    Part::Stem(stem) => live_loop.as_deref() == stem,
+                };
+                match event {
+                    Event::Audio(cmd @ AudioCommand::PlayNote { .. }) if heard => {
+                        engine.queue(cmd.clone(), *frame);
+                    }
+                    Event::Audio(AudioCommand::PlayNote { .. }) => {}
+                    Event::Audio(cmd) => engine.queue(cmd.clone(), *frame),
+                    Event::Sample { name, rate, amplitude, pan, fx } if heard => {
+                        if let Some(data) = self.samples.get(name) {
+                            let cmd = AudioCommand::PlaySample {
+                                samples: data.samples.clone(),
+                                channels: data.channels,
+                                sample_rate: data.sample_rate,
+                                amplitude: *amplitude,
+                                rate: *rate,
+                                pan: *pan,
+                                fx: *fx,
+                            };
+                            engine.queue(cmd, *frame);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            let block = &mut block[..(end - engine.position()) as usize * 2];
+            engine.render(block);
+            sink(block)?;
+        }
+        Ok(())
+    }
+}
+
+fn create_wav(path: &Path, sample_rate: u32) -> Result<hound::WavWriter<BufWriter<File>>, String> {
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    hound::WavWriter::create(path, spec).map_err(|e| format!("Failed to create file: {}", e))
+}
+
+fn write_wav(writer: &mut hound::WavWriter<BufWriter<File>>, audio: &[f32]) -> Result<(), String> {
+    for &sample in audio {
+        writer
+            .write_sample(sample)
+            .map_err(|e| format!("Failed to write: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Render `part` of `score` straight to a WAV file at `path`
+fn render_to_wav(score: &Score, part: Part, path: &Path) -> Result<(), String> {
+    let mut writer = create_wav(path, score.sample_rate)?;
+    score.render(part, |block| write_wav(&mut writer, block))?;
+    writer
+        .finalize()
+        .map_err(|e| format!("Failed to finalize: {}", e))
+}
+
+/// Render `length` of `code` at `sample_rate`. `bpm` is used when the code
+/// doesn't `use_bpm`. `load_sample` turns a sample name into its audio;
+/// each name is loaded once per render.
+pub fn render_code(
+    code: &str,
+    length: RenderLength,
+    sample_rate: u32,
+    bpm: f32,
+    load_sample: impl FnMut(&str) -> Result<SampleData, String>,
+) -> Result<Render, String> {
+    let score = Score::new(code, length, sample_rate, bpm, load_sample)?;
+    let mut audio = Vec::with_capacity(score.total_frames as usize * 2);
+    score.render(Part::All, |block| {
+        audio.extend_from_slice(block);
+        Ok(())
+    })?;
+    Ok(Render {
+        audio,
+        sample_rate,
+        bpm: score.bpm,
+        logs: score.logs,
+        errors: score.errors,
+    })
+}
+
+/// Render `length` of `code` to a 32-bit float stereo WAV file at `path`,
+/// writing each block as it is rendered so long renders don't pile up in
+/// memory. Takes the same arguments as `render_code`.
+pub fn render_file(
+    code: &str,
+    length: RenderLength,
+    sample_rate: u32,
+    bpm: f32,
+    path: &Path,
+    load_sample: impl FnMut(&str) -> Result<SampleData, String>,
+) -> Result<RenderedFile, String> {
+    let score = Score::new(code, length, sample_rate, bpm, load_sample)?;
+    render_to_wav(&score, Part::All, path)?;
+    Ok(RenderedFile {
+        path: path.to_path_buf(),
+        duration_secs: score.duration_secs(),
+        bpm: score.bpm,
+        logs: score.logs,
+        errors: score.errors,
+    })
+}
+
+/// Render `length` of `code` into `dir` as one stereo WAV per live_loop,
+/// plus `main.wav` for the code outside them and, with `with_mix`, `mix.wav`
+/// with everything. All files start together and have the same length.
+/// Each stem has its own master limiter, so the stems only add up to the mix
+/// while it isn't limiting.
+pub fn export_stems(
+    code: &str,
+    length: RenderLength,
+    sample_rate: u32,
+    bpm: f32,
+    dir: &Path,
+    with_mix: bool,
+    load_sample: impl FnMut(&str) -> Result<SampleData, String>,
+) -> Result<StemExport, String> {
+    let score = Score::new(code, length, sample_rate, bpm, load_sample)?;
+    std::fs::create_dir_all(dir)
+        .map_err(|e| format!("Failed to create '{}': {}", dir.display(), e))?;
+
+    let mut taken: Vec<String> = Vec::new();
+    let mut file_for = |name: &str| {
+        let mut stem = stem_file_name(name);
+        let base = stem.clone();
+        let mut n = 2;
+        while taken.contains(&stem) {
+            stem = format!("{}_{}", base, n);
+            n += 1;
+        }
+        taken.push(stem.clone());
+        dir.join(format!("{}.wav", stem))
+    };
+
+    let mix = with_mix.then(|| file_for(MIX_STEM));
+    let mut stems = Vec::new();
+    let parts = std::iter::once((MAIN_STEM, None))
+        .chain(score.loops.iter().map(|name| (name.as_str(), Some(name.as_str()))));
+    for (name, live_loop) in parts {
+        let path = file_for(name);
+        render_to_wav(&score, Part::Stem(live_loop), &path)?;
+        stems.push(path);
+    }
+    if let Some(path) = &mix {
+        render_to_wav(&score, Part::All, path)?;
+    }
+
+    Ok(StemExport {
+        stems,
+        mix,
+        duration_secs: score.duration_secs(),
+        bpm: score.bpm,
+        logs: score.logs,
+        errors: score.errors,
+    })
+}
+
+/// A live_loop name made safe to use as a file name
+fn stem_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    if name.is_empty() { "loop".to_string() } else { name }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SR: u32 = 8000;
+
+    fn no_samples(name: &str) -> Result<SampleData, String> {
+        Err(format!("no sample '{}'", name))
+    }
+
+    fn rendered(code: &str, secs: f64) -> Render {
+        render_code(code, RenderLength::Seconds(secs), SR, 60.0, no_samples).unwrap()
+    }
+
+    /// A fresh directory under the system temp dir
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sonic-render-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read_wav(path: &Path) -> (hound::WavSpec, Vec<f32>) {
+        let mut reader = hound::WavReader::open(path).unwrap();
+        let samples = reader.samples::<f32>().map(Result::unwrap).collect();
+        (reader.spec(), samples)
+    }
+
+    #[test]
+    fn test_render_has_the_asked_length() {
+        let render = rendered("play :c4", 1.5);
+        assert_eq!(render.audio.len(), 2 * 12000);
+        assert_eq!(render.duration_secs(), 1.5);
+        // A bar is four beats at the code's BPM
+        let render = render_code("use_bpm 120\nplay :c4", RenderLength::Bars(1.0), SR, 60.0, no_samples).unwrap();
+        assert_eq!(render.bpm, 120.0);
+        assert_eq!(render.duration_secs(), 2.0);
+    }
+
+    #[test]
+    fn test_render_pads_with_silence_after_the_code_ends() {
+        let render = rendered("play :c4, release: 0.2", 4.0);
+        let peak = |audio: &[f32]| audio.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+        assert!(peak(&render.audio[..2 * SR as usize / 4]) > 0.01);
+        assert!(peak(&render.audio[2 * 3 * SR as usize..]) < 1e-3);
+        assert_eq!(rendered("sleep 1", 2.0).audio, vec![0.0; 2 * 2 * SR as usize]);
+    }
+
+    #[test]
+    fn test_render_is_deterministic() {
+        let code = "use_synth :tb303\nwith_fx :echo do\n  4.times do\n    play 60 + rrand(0, 10)\n    play [:c3, :g3].choose\n    sleep 0.25\n  end\nend";
+        let first = rendered(code, 2.0);
+        let second = rendered(code, 2.0);
+        assert!(first.audio.iter().any(|&x| x != 0.0));
+        assert_eq!(first.audio, second.audio);
+    }
+
+    #[test]
+    fn test_render_keeps_logs_and_errors() {
+        let render = rendered("puts \"hi\"\nsample :bd_haus", 0.5);
+        assert_eq!(render.logs, vec!["hi".to_string()]);
+        assert_eq!(render.errors, vec!["no sample 'bd_haus'".to_string()]);
+        assert!(render_code("play :c4", RenderLength::Seconds(0.0), SR, 60.0, no_samples).is_err());
+    }
+
+    #[test]
+    fn test_render_file_matches_the_render() {
+        let dir = temp_dir("file");
+        let path = dir.join("out.wav");
+        let code = "play :c4\nsleep 0.5\nplay :e4";
+        let file = render_file(code, RenderLength::Seconds(1.25), SR, 60.0, &path, no_samples).unwrap();
+        assert_eq!(file.duration_secs, 1.25);
+        let (spec, samples) = read_wav(&path);
+        assert_eq!((spec.channels, spec.sample_rate), (2, SR));
+        assert_eq!(samples, rendered(code, 1.25).audio);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_stems_are_aligned_and_add_up_to_the_mix() {
+        let dir = temp_dir("stems");
+        let code = "play :c3, amp: 0.2\n\
+                    live_loop :drums do\n  play :c5, amp: 0.2, release: 0.1\n  sleep 0.5\nend\n\
+                    live_loop :mix do\n  play :g4, amp: 0.2\n  sleep 1\nend";
+        let export = export_stems(code, RenderLength::Seconds(2.0), SR, 60.0, &dir, true, no_samples).unwrap();
+
+        // A loop called `mix` doesn't overwrite the master mix
+        let names: Vec<_> = export.stems.iter().map(|path| path.file_name().unwrap().to_owned()).collect();
+        assert_eq!(names, ["main.wav", "drums.wav", "mix_2.wav"]);
+        assert_eq!(export.mix, Some(dir.join("mix.wav")));
+        assert_eq!(export.duration_secs, 2.0);
+
+        let (_, mix) = read_wav(&dir.join("mix.wav"));
+        assert_eq!(mix, rendered(code, 2.0).audio);
+        let stems: Vec<Vec<f32>> = export.stems.iter().map(|path| read_wav(path).1).collect();
+        for stem in &stems {
+            assert_eq!(stem.len(), mix.len());
+            assert!(stem.iter().any(|&x| x != 0.0));
+        }
+        for (i, &x) in mix.iter().enumerate() {
+            let sum: f32 = stems.iter().map(|stem| stem[i]).sum();
+            assert!((sum - x).abs() < 1e-4, "sample {}: {} vs {}", i, sum, x);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_stems_without_a_mix() {
+        let dir = temp_dir("no-mix");
+        let export = export_stems("play :c4", RenderLength::Seconds(0.5), SR, 60.0, &dir, false, no_samples).unwrap();
+        assert_eq!(export.stems, vec![dir.join("main.wav")]);
+        assert_eq!(export.mix, None);
+        assert!(!dir.join("mix.wav").exists());
+        assert_eq!(read_wav(&export.stems[0]).1.len(), 2 * SR as usize / 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_stem_file_names_are_safe() {
+        assert_eq!(stem_file_name("bass line/2"), "bass_line_2");
+        assert_eq!(stem_file_name("drums-1"), "drums-1");
+        assert_eq!(stem_file_name(""), "loop");
+    }
+}
@@ -4,8 +4,39 @@ use walkdir::WalkDir;
 
 // ─────────────────────── Audio File I/O ───────────────────────
 
-/// Load audio file (WAV or MP3) and return mono f32 samples + sample rate
-pub fn load_wav(path: &str) -> Result<(Vec<f32>, u32), String> {
+/// Decoded audio, mono or stereo
+#[derive(Debug, Clone)]
+pub struct SampleData {
+    /// Interleaved samples, one frame after another
+    pub samples: Vec<f32>,
+    /// 1 or 2
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+impl SampleData {
+    pub fn mono(samples: Vec<f32>, sample_rate: u32) -> Self {
+        Self { samples, channels: 1, sample_rate }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+
+    /// The channels averaged together, for analysis
+    pub fn to_mono(&self) -> Vec<f32> {
+        if self.channels == 1 {
+            return self.samples.clone();
+        }
+        self.samples
+            .chunks(self.channels as usize)
+            .map(|frame| frame.iter().sum::<f32>() / self.channels as f32)
+            .collect()
+    }
+}
+
+/// Load audio file (WAV or MP3), keeping stereo as stereo
+pub fn load_wav(path: &str) -> Result<SampleData, String> {
     let path_lower = path.to_lowercase();
     
     if path_lower.ends_with(".mp3") {
@@ -15,8 +46,33 @@ pub fn load_wav(path: &str) -> Result<(Vec<f32>, u32), String> {
     }
 }
 
-/// Load WAV file and return mono f32 samples + sample rate
-fn load_wav_file(path: &str) -> Result<(Vec<f32>, u32), String> {
+/// Channels in the audio file at `path`, read from its header
+pub fn channel_count(path: &str) -> Option<u16> {
+    if path.to_lowercase().ends_with(".mp3") {
+        let data = std::fs::read(path).ok()?;
+        let frame = minimp3::Decoder::new(&data[..]).next_frame().ok()?;
+        Some(frame.channels as u16)
+    } else {
+        let reader = hound::WavReader::open(path).ok()?;
+        Some(reader.spec().channels)
+    }
+}
+
+/// Interleaved `samples` with `channels` channels as mono or stereo. Past
+/// two channels only the first two (front left and right) are kept.
+fn to_sample_data(samples: Vec<f32>, channels: usize, sample_rate: u32) -> SampleData {
+    if channels <= 2 {
+        return SampleData { samples, channels: channels.max(1) as u16, sample_rate };
+    }
+    let samples = samples
+        .chunks_exact(channels)
+        .flat_map(|frame| [frame[0], frame[1]])
+        .collect();
+    SampleData { samples, channels: 2, sample_rate }
+}
+
+/// Load WAV file as mono or stereo f32 samples
+fn load_wav_file(path: &str) -> Result<SampleData, String> {
     let reader = hound::WavReader::open(path)
         .map_err(|e| format!("Failed to open WAV file '{}': {}", path, e))?;
 
@@ -41,20 +97,11 @@ fn load_wav_file(path: &str) -> Result<(Vec<f32>, u32), String> {
         }
     };
 
-    let mono: Vec<f32> = if channels > 1 {
-        samples
-            .chunks(channels)
-            .map(|chunk| chunk.iter().sum::<f32>() / channels as f32)
-            .collect()
-    } else {
-        samples
-    };
-
-    Ok((mono, sample_rate))
+    Ok(to_sample_data(samples, channels, sample_rate))
 }
 
-/// Load MP3 file and return mono f32 samples + sample rate
-fn load_mp3(path: &str) -> Result<(Vec<f32>, u32), String> {
+/// Load MP3 file as mono or stereo f32 samples
+fn load_mp3(path: &str) -> Result<SampleData, String> {
     let data = std::fs::read(path)
         .map_err(|e| format!("Failed to read MP3 file '{}': {}", path, e))?;
     
@@ -79,17 +126,7 @@ fn load_mp3(path: &str) -> Result<(Vec<f32>, u32), String> {
         }
     }
     
-    // Convert to mono if stereo
-    let mono: Vec<f32> = if channels > 1 {
-        all_samples
-            .chunks(channels)
-            .map(|chunk| chunk.iter().sum::<f32>() / channels as f32)
-            .collect()
-    } else {
-        all_samples
-    };
-    
-    Ok((mono, sample_rate))
+    Ok(to_sample_data(all_samples, channels, sample_rate))
 }
 
 fn write_wav(path: &Path, samples: &[f32], sample_rate: u32) -> Result<(), String> {
@@ -12,17 +12,21 @@
 /// 2. **System mode**: Falls back to a system-installed SuperCollider
 ///    if the bundle is not found.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::UdpSocket;
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
 use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
-use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use parking_lot::Mutex;
-use rosc::{decoder, encoder, OscMessage, OscPacket, OscType};
+use rosc::{decoder, encoder, OscBundle, OscMessage, OscPacket, OscTime, OscType};
 
 use super::engine::AudioCommand;
+use super::master::{Meter, MeterReading, LIMITER_CEILING, LIMITER_LOOKAHEAD_SECS};
+use super::recorder::RecordFormat;
+use super::sample;
 use super::sc_synthdefs;
 use super::synth::OscillatorType;
 
@@ -34,6 +38,24 @@ const CLIENT_PORT: u16 = 57120;
 /// SuperCollider node add actions
 const ADD_TO_HEAD: i32 = 0;
 const ADD_TO_TAIL: i32 = 1;
+const ADD_BEFORE: i32 = 2;
+
+/// Interval between `/n_set` steps while a parameter glides
+const GLIDE_STEP: Duration = Duration::from_millis(10);
+
+/// How far ahead of their time (seconds) events are sent to scsynth by default
+const DEFAULT_SCHED_AHEAD_TIME: f64 = 0.2;
+
+/// How long a finished `with_fx` block's synth keeps running so reverb and
+/// echo tails ring out before it is freed
+const FX_TAIL: Duration = Duration::from_secs(4);
+
+/// Replies a second from the `sonic_meter` synth, each covering the audio
+/// since the one before
+const METER_RATE: f64 = 30.0;
+
+/// Frames of the buffer `DiskOut` streams a recording through
+const RECORD_BUFFER_FRAMES: i32 = 65536;
 
 /// Group IDs
 const ROOT_GROUP: i32 = 0;
@@ -48,6 +70,8 @@ pub struct ScEngineState {
     pub master_volume: f32,
     pub bpm: f32,
     pub sample_rate: u32,
+    /// Master bus levels from the `sonic_meter` synth's replies
+    pub meter: Meter,
 }
 
 impl Default for ScEngineState {
@@ -58,10 +82,38 @@ impl Default for ScEngineState {
             master_volume: 1.0,
             bpm: 120.0,
             sample_rate: 44100,
+            meter: Meter::new(44100),
         }
     }
 }
 
+/// A note `control` can still reach: its scsynth node and the values last
+/// sent to it, so a glide knows where to start
+struct ControlledNode {
+    node_id: i32,
+    values: HashMap<String, f32>,
+    /// `*_slide` times in seconds, by param name
+    slides: HashMap<String, f32>,
+    /// Bumped per param on every change so an older glide gives way
+    glides: Arc<Mutex<HashMap<String, u64>>>,
+    /// When the note's envelope has finished and scsynth frees the node
+    expires: Instant,
+}
+
+/// A `with_fx` block's private bus and the FX synth reading from it
+struct FxBlock {
+    bus: i32,
+    node_id: i32,
+}
+
+/// A recording scsynth is writing: the `sonic_recorder` node and the
+/// buffer holding its file open
+struct ScRecording {
+    node_id: i32,
+    buf_id: i32,
+    path: PathBuf,
+}
+
 /// SuperCollider engine — manages scsynth process and OSC communication
 pub struct ScEngine {
     /// UDP socket for sending/receiving OSC messages
@@ -78,12 +130,22 @@ pub struct ScEngine {
     next_bus_id: AtomicI32,
     /// Map from sample file path to SC buffer number
     pub loaded_buffers: Mutex<HashMap<String, i32>>,
+    /// Stereo sample buffers, which play through `sonic_playbuf2`
+    stereo_buffers: Mutex<HashSet<i32>>,
     /// Currently active FX node IDs
     active_fx_nodes: Mutex<Vec<i32>>,
-    /// FX bus stack: (bus_id, fx_node_id, fx_group_node_id)
-    /// When a with_fx block is entered, a private bus + FX synth are pushed.
-    /// Synths/samples inside the block output to the top-of-stack bus.
-    fx_bus_stack: Mutex<Vec<(i32, i32, i32)>>,
+    /// Running `with_fx` blocks by interpreter block id. Each block gets a
+    /// private bus and one FX synth for as long as it can run; notes name
+    /// the block they play into.
+    fx_blocks: Mutex<HashMap<u32, FxBlock>>,
+    /// Buses of freed blocks, reusable once their FX synth is gone
+    free_buses: Mutex<Vec<(i32, Instant)>>,
+    /// Notes kept in a variable (`s = play ...`), by interpreter node handle
+    controlled_nodes: Mutex<HashMap<u32, ControlledNode>>,
+    /// When set, outgoing messages are bundled with this time as their timetag
+    bundle_time: Mutex<Option<Instant>>,
+    /// Seconds ahead of their time that scheduled events are sent
+    sched_ahead_time: Mutex<f64>,
     /// Whether scsynth has booted and is ready
     is_booted: AtomicBool,
     /// Path to scsynth executable
@@ -98,6 +160,8 @@ pub struct ScEngine {
     use_bundled: bool,
     /// Buffer for waveform scope (SC buffer ID)
     scope_buffer_id: i32,
+    /// Recording in progress, if any
+    recording: Mutex<Option<ScRecording>>,
     /// Shared engine state
     pub state: Mutex<ScEngineState>,
 }
@@ -156,16 +220,35 @@ impl ScEngine {
             .set_nonblocking(false)
             .map_err(|e| format!("Socket config error: {}", e))?;
 
-        Ok(Self {
+        Ok(Self::with_socket(socket, SC_PORT, scsynth_path, sclang_path, synthdefs_dir, plugins_dir, use_bundled))
+    }
+
+    /// An engine for the scsynth at `scsynth_path`, which will listen on
+    /// `sc_port`, talking to it over `socket`
+    fn with_socket(
+        socket: UdpSocket,
+        sc_port: u16,
+        scsynth_path: PathBuf,
+        sclang_path: Option<PathBuf>,
+        synthdefs_dir: PathBuf,
+        plugins_dir: Option<PathBuf>,
+        use_bundled: bool,
+    ) -> Self {
+        Self {
             socket,
             scsynth_process: Mutex::new(None),
-            sc_port: SC_PORT,
+            sc_port,
             next_node_id: AtomicI32::new(2000), // Start above our group IDs
             next_buffer_id: AtomicI32::new(1),   // Buffer 0 reserved for scope
             next_bus_id: AtomicI32::new(16),     // Private buses start at 16 (after hardware)
             loaded_buffers: Mutex::new(HashMap::new()),
+            stereo_buffers: Mutex::new(HashSet::new()),
             active_fx_nodes: Mutex::new(Vec::new()),
-            fx_bus_stack: Mutex::new(Vec::new()),
+            fx_blocks: Mutex::new(HashMap::new()),
+            free_buses: Mutex::new(Vec::new()),
+            controlled_nodes: Mutex::new(HashMap::new()),
+            bundle_time: Mutex::new(None),
+            sched_ahead_time: Mutex::new(DEFAULT_SCHED_AHEAD_TIME),
             is_booted: AtomicBool::new(false),
             scsynth_path,
             sclang_path,
@@ -173,8 +256,9 @@ impl ScEngine {
             plugins_dir,
             use_bundled,
             scope_buffer_id: 0,
+            recording: Mutex::new(None),
             state: Mutex::new(ScEngineState::default()),
-        })
+        }
     }
 
     /// Boot the SuperCollider server: start scsynth, load SynthDefs
@@ -232,6 +316,11 @@ impl ScEngine {
     pub fn shutdown(&self) {
         eprintln!("[SC] Shutting down SuperCollider server...");
 
+        // Close a recording's file before the server goes away
+        if self.is_recording() {
+            let _ = self.stop_recording();
+        }
+
         // Send /quit to scsynth
         let _ = self.send_osc_msg("/quit", vec![]);
 
@@ -260,19 +349,23 @@ impl ScEngine {
                 synth_type,
                 frequency,
                 amplitude,
-                duration_secs,
                 envelope,
                 pan,
                 params,
+                node,
+                fx,
             } => {
-                self.play_note(synth_type, frequency, amplitude, duration_secs, &envelope, pan, &params)
+                self.play_note(synth_type, frequency, amplitude, &envelope, pan, &params, node, fx)
             }
+            AudioCommand::ControlNote { node, params } => self.control_node(node, &params),
             AudioCommand::PlaySample {
                 samples: _,
+                channels: _,
                 sample_rate: _,
                 amplitude: _,
                 rate: _,
                 pan: _,
+                fx: _,
             } => {
                 // For raw sample data, we can't easily send to SC.
                 // Samples should be loaded via load_sample_buffer() instead.
@@ -288,6 +381,8 @@ impl ScEngine {
                 self.state.lock().master_volume = vol;
                 Ok(())
             }
+            // scsynth has its own limit on running nodes
+            AudioCommand::SetMaxVoices(_) => Ok(()),
             AudioCommand::Stop => self.stop_all(),
             AudioCommand::SetEffect {
                 reverb_mix,
@@ -304,35 +399,61 @@ impl ScEngine {
                 lpf_cutoff,
                 hpf_cutoff,
             ),
-            AudioCommand::FxStart { fx_type, params } => {
-                self.push_fx_bus(&fx_type, &params)
-            }
-            AudioCommand::FxEnd => {
-                self.pop_fx_bus()
+            AudioCommand::FxStart { id, parent, fx_type, params } => {
+                self.start_fx_block(id, parent, &fx_type, &params)
             }
+            AudioCommand::FxFree { id } => self.free_fx_block(id),
         }
     }
 
-    /// Play a note using a SuperCollider synth
+    /// Send an AudioCommand for scsynth to run at `at` rather than on arrival
+    pub fn send_command_at(&self, cmd: AudioCommand, at: Instant) -> Result<(), String> {
+        self.scheduled(at, |sc| sc.send_command(cmd))
+    }
+
+    /// Run `f` with every OSC message it sends wrapped in a bundle
+    /// timestamped `at`, so scsynth starts it sample-accurately
+    pub fn scheduled<T>(&self, at: Instant, f: impl FnOnce(&Self) -> T) -> T {
+        let outer = self.bundle_time.lock().replace(at);
+        let result = f(self);
+        *self.bundle_time.lock() = outer;
+        result
+    }
+
+    /// Seconds ahead of their time that events should be sent, like Sonic
+    /// Pi's `sched_ahead_time`
+    pub fn sched_ahead_time(&self) -> f64 {
+        *self.sched_ahead_time.lock()
+    }
+
+    pub fn set_sched_ahead_time(&self, secs: f64) {
+        *self.sched_ahead_time.lock() = secs.max(0.0);
+    }
+
+    /// When the messages being sent take effect: the bundle time, or now
+    fn event_time(&self) -> Instant {
+        self.bundle_time.lock().unwrap_or_else(Instant::now)
+    }
+
+    /// Play a note using a SuperCollider synth, into the bus of FX block
+    /// `fx` if given. With a `node` handle the note is remembered for
+    /// `control_node`.
+    #[allow(clippy::too_many_arguments)]
     pub fn play_note(
         &self,
         synth_type: OscillatorType,
         frequency: f32,
         amplitude: f32,
-        duration_secs: f32,
         envelope: &super::synth::Envelope,
         pan: f32,
         params: &[(String, f32)],
+        node: Option<u32>,
+        fx: Option<u32>,
     ) -> Result<(), String> {
         let node_id = self.alloc_node_id();
         let def_name = sc_synthdefs::synthdef_name(&synth_type);
         let master_vol = self.state.lock().master_volume;
-
-        // Determine output bus: if inside a with_fx block, route to the FX bus
-        let out_bus = self.current_out_bus();
-
-        // Compute sustain (hold time) from total duration minus envelope times
-        let sustain_time = (duration_secs - envelope.attack - envelope.decay - envelope.release).max(0.0);
+        let out_bus = self.out_bus(fx);
 
         let mut args = vec![
             OscType::String(def_name.to_string()),
@@ -353,11 +474,17 @@ impl ScEngine {
             OscType::String("decay".to_string()),
             OscType::Float(envelope.decay),
             OscType::String("sustain".to_string()),
-            OscType::Float(sustain_time),
+            OscType::Float(envelope.sustain),
             OscType::String("release".to_string()),
             OscType::Float(envelope.release),
+            OscType::String("attack_level".to_string()),
+            OscType::Float(envelope.attack_level),
+            OscType::String("decay_level".to_string()),
+            OscType::Float(envelope.decay_level),
             OscType::String("sustain_level".to_string()),
-            OscType::Float(envelope.sustain), // sustain field = sustain_level
+            OscType::Float(envelope.sustain_level),
+            OscType::String("env_curve".to_string()),
+            OscType::Float(envelope.curve.number()),
         ];
 
         // Forward synth-specific params (cutoff, res, detune, etc.)
@@ -368,25 +495,133 @@ impl ScEngine {
 
         self.send_osc_msg("/s_new", args)?;
         self.state.lock().is_playing = true;
+
+        if let Some(node) = node {
+            let mut values: HashMap<String, f32> = params
+                .iter()
+                .filter(|(name, _)| !name.ends_with("_slide"))
+                .cloned()
+                .collect();
+            values.insert("freq".to_string(), frequency);
+            values.insert("amp".to_string(), amplitude * master_vol);
+            values.insert("pan".to_string(), pan);
+            let slides = params
+                .iter()
+                .filter_map(|(name, val)| name.strip_suffix("_slide").map(|base| (base.to_string(), *val)))
+                .collect();
+
+            let starts = self.event_time();
+            let mut nodes = self.controlled_nodes.lock();
+            nodes.retain(|_, n| n.expires > Instant::now());
+            nodes.insert(node, ControlledNode {
+                node_id,
+                values,
+                slides,
+                glides: Arc::new(Mutex::new(HashMap::new())),
+                expires: starts + Duration::from_secs_f32(envelope.duration()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Change parameters of a note started with a node handle via `/n_set`.
+    /// Params with a `*_slide` time glide there in small steps on a helper
+    /// thread, since the SynthDefs have no lag controls of their own; the
+    /// glide starts at the bundle time when the change is scheduled.
+    pub fn control_node(&self, node: u32, params: &[(String, f32)]) -> Result<(), String> {
+        let starts = self.event_time();
+        let master_vol = self.state.lock().master_volume;
+        let mut nodes = self.controlled_nodes.lock();
+        let Some(n) = nodes.get_mut(&node) else {
+            // The note has already finished
+            return Ok(());
+        };
+
+        for (name, val) in params {
+            if let Some(base) = name.strip_suffix("_slide") {
+                n.slides.insert(base.to_string(), *val);
+            }
+        }
+
+        let mut args = vec![OscType::Int(n.node_id)];
+        let mut glides = Vec::new();
+        for (name, val) in params.iter().filter(|(name, _)| !name.ends_with("_slide")) {
+            let target = if name == "amp" { val * master_vol } else { *val };
+            let from = n.values.insert(name.clone(), target);
+            let slide = n.slides.get(name).copied().unwrap_or(0.0);
+            let generation = {
+                let mut gens = n.glides.lock();
+                let generation = gens.entry(name.clone()).or_insert(0);
+                *generation += 1;
+                *generation
+            };
+            match from {
+                Some(from) if slide > 0.0 => glides.push((name.clone(), from, target, slide, generation)),
+                _ => {
+                    args.push(OscType::String(name.clone()));
+                    args.push(OscType::Float(target));
+                }
+            }
+        }
+
+        if args.len() > 1 {
+            self.send_osc_msg("/n_set", args)?;
+        }
+        if !glides.is_empty() {
+            let socket = self.socket.try_clone().map_err(|e| format!("Socket clone error: {}", e))?;
+            let sc_port = self.sc_port;
+            let node_id = n.node_id;
+            let gens = Arc::clone(&n.glides);
+            std::thread::spawn(move || {
+                std::thread::sleep(starts.saturating_duration_since(Instant::now()));
+                while !glides.is_empty() {
+                    std::thread::sleep(GLIDE_STEP);
+                    let elapsed = starts.elapsed().as_secs_f32();
+                    let mut args = vec![OscType::Int(node_id)];
+                    {
+                        let current = gens.lock();
+                        // Drop glides a newer change to the same param replaced
+                        glides.retain(|(name, _, _, _, generation)| current.get(name) == Some(generation));
+                    }
+                    for (name, from, to, secs, _) in &glides {
+                        let progress = (elapsed / secs).min(1.0);
+                        args.push(OscType::String(name.clone()));
+                        args.push(OscType::Float(from + (to - from) * progress));
+                    }
+                    glides.retain(|(_, _, _, secs, _)| elapsed < *secs);
+                    if args.len() > 1 && send_osc(&socket, sc_port, "/n_set", args).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
         Ok(())
     }
 
-    /// Play a sample that has been loaded into a SC buffer
+    /// Play a sample that has been loaded into a SC buffer, into the bus of
+    /// FX block `fx` if given
     pub fn play_sample_buffer(
         &self,
         buffer_id: i32,
         amplitude: f32,
         rate: f32,
         pan: f32,
+        fx: Option<u32>,
     ) -> Result<(), String> {
         let node_id = self.alloc_node_id();
         let master_vol = self.state.lock().master_volume;
-        let out_bus = self.current_out_bus();
+        let out_bus = self.out_bus(fx);
+        // Stereo buffers keep their image, with pan as a balance control
+        let player = if self.stereo_buffers.lock().contains(&buffer_id) {
+            "sonic_playbuf2"
+        } else {
+            "sonic_playbuf"
+        };
 
         self.send_osc_msg(
             "/s_new",
             vec![
-                OscType::String("sonic_playbuf".to_string()),
+                OscType::String(player.to_string()),
                 OscType::Int(node_id),
                 OscType::Int(ADD_TO_HEAD),
                 OscType::Int(SOURCE_GROUP),
@@ -428,31 +663,124 @@ impl ScEngine {
         let sc_path = file_path.replace('\\', "/");
 
         // /b_allocRead: [buf_num, file_path, start_frame, num_frames]
-        // 0 frames = read entire file
-        self.send_osc_msg(
-            "/b_allocRead",
-            vec![
-                OscType::Int(buf_id),
-                OscType::String(sc_path),
-                OscType::Int(0),
-                OscType::Int(0), // 0 = entire file
-            ],
-        )?;
+        // 0 frames = read entire file. Past stereo, only the first two
+        // channels (front left and right) are read, as the built-in engine does.
+        let channels = sample::channel_count(file_path).unwrap_or(1);
+        let mut args = vec![
+            OscType::Int(buf_id),
+            OscType::String(sc_path),
+            OscType::Int(0),
+            OscType::Int(0), // 0 = entire file
+        ];
+        let command = if channels > 2 {
+            args.extend([OscType::Int(0), OscType::Int(1)]);
+            "/b_allocReadChannel"
+        } else {
+            "/b_allocRead"
+        };
+        self.send_osc_msg(command, args)?;
 
         // Wait for /done response
-        self.wait_for_done("/b_allocRead", Duration::from_secs(5))?;
+        self.wait_for_done(command, Duration::from_secs(5))?;
 
         // Cache the buffer ID
         self.loaded_buffers
             .lock()
             .insert(file_path.to_string(), buf_id);
+        if channels >= 2 {
+            self.stereo_buffers.lock().insert(buf_id);
+        }
 
         eprintln!("[SC] Sample loaded into buffer {}", buf_id);
         Ok(buf_id)
     }
 
+    /// Start recording the master output to a WAV file at `path`, replacing
+    /// it if it exists. scsynth writes the file itself, from a
+    /// `sonic_recorder` node at the end of the monitor group; it does not
+    /// dither PCM formats.
+    pub fn start_recording(&self, path: &Path, format: RecordFormat) -> Result<(), String> {
+        if self.is_recording() {
+            let _ = self.stop_recording();
+        }
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create '{}': {}", dir.display(), e))?;
+        }
+        let sample_format = match format {
+            RecordFormat::Pcm16 => "int16",
+            RecordFormat::Pcm24 => "int24",
+            RecordFormat::Float32 => "float",
+        };
+
+        let buf_id = self.alloc_buffer_id();
+        self.send_osc_msg(
+            "/b_alloc",
+            vec![
+                OscType::Int(buf_id),
+                OscType::Int(RECORD_BUFFER_FRAMES),
+                OscType::Int(2),
+            ],
+        )?;
+        self.wait_for_done("/b_alloc", Duration::from_secs(5))?;
+
+        // /b_write: [buf_num, path, header, sample format, frames, start
+        // frame, leave open]
+        self.send_osc_msg(
+            "/b_write",
+            vec![
+                OscType::Int(buf_id),
+                OscType::String(path.to_string_lossy().replace('\\', "/")),
+                OscType::String("wav".to_string()),
+                OscType::String(sample_format.to_string()),
+                OscType::Int(0),
+                OscType::Int(0),
+                OscType::Int(1),
+            ],
+        )?;
+        if let Err(e) = self.wait_for_done("/b_write", Duration::from_secs(5)) {
+            let _ = self.send_osc_msg("/b_free", vec![OscType::Int(buf_id)]);
+            return Err(e);
+        }
+
+        // After the limiter at the head of the group, so it records what goes out
+        let node_id = self.alloc_node_id();
+        self.send_osc_msg(
+            "/s_new",
+            vec![
+                OscType::String("sonic_recorder".to_string()),
+                OscType::Int(node_id),
+                OscType::Int(ADD_TO_TAIL),
+                OscType::Int(MONITOR_GROUP),
+                OscType::String("buf".to_string()),
+                OscType::Int(buf_id),
+            ],
+        )?;
+        eprintln!("[SC] Recording to '{}' (buffer {})", path.display(), buf_id);
+        *self.recording.lock() = Some(ScRecording { node_id, buf_id, path: path.to_path_buf() });
+        Ok(())
+    }
+
+    /// Finish the recording, close its file and return where it is
+    pub fn stop_recording(&self) -> Result<PathBuf, String> {
+        let recording = self.recording.lock().take().ok_or("Not recording")?;
+        self.free_node(recording.node_id)?;
+        self.send_osc_msg("/b_close", vec![OscType::Int(recording.buf_id)])?;
+        self.wait_for_done("/b_close", Duration::from_secs(5))?;
+        self.send_osc_msg("/b_free", vec![OscType::Int(recording.buf_id)])?;
+        eprintln!("[SC] Recording saved to '{}'", recording.path.display());
+        Ok(recording.path)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.lock().is_some()
+    }
+
     /// Stop all audio and reset all state for a clean restart
     pub fn stop_all(&self) -> Result<(), String> {
+        // Drop bundles sent ahead that have not played yet
+        self.send_osc_msg("/clearSched", vec![])?;
+
         // Free all nodes in the source group
         self.send_osc_msg(
             "/g_freeAll",
@@ -466,8 +794,10 @@ impl ScEngine {
         )?;
 
         self.active_fx_nodes.lock().clear();
-        // Reset the FX bus stack so the next run starts clean
-        self.fx_bus_stack.lock().clear();
+        self.controlled_nodes.lock().clear();
+        // Forget FX blocks and their buses so the next run starts clean
+        self.fx_blocks.lock().clear();
+        self.free_buses.lock().clear();
         // Reset bus allocator back to 16 (first private bus)
         self.next_bus_id.store(16, Ordering::Relaxed);
         self.state.lock().is_playing = false;
@@ -480,6 +810,10 @@ impl ScEngine {
         self.state.lock().waveform_buffer.clone()
     }
 
+    pub fn get_meter(&self) -> MeterReading {
+        self.state.lock().meter.reading()
+    }
+
     /// Get state snapshot: (is_playing, master_volume, bpm)
     pub fn get_state_snapshot(&self) -> (bool, f32, f32) {
         let s = self.state.lock();
@@ -625,48 +959,6 @@ impl ScEngine {
         Ok(())
     }
 
-    /// Create an FX node for a with_fx block and return the node ID.
-    /// The FX is placed at the tail of the source group so it processes
-    /// all synths inside the same group.
-    pub fn create_fx_node(
-        &self,
-        fx_type: &str,
-        params: &[(String, f32)],
-    ) -> Result<i32, String> {
-        let node_id = self.alloc_node_id();
-        let def_name = match fx_type {
-            "reverb" | "gverb" => "sonic_fx_reverb",
-            "echo" | "delay" => "sonic_fx_echo",
-            "distortion" | "tanh" => "sonic_fx_distortion",
-            "slicer" => "sonic_fx_slicer",
-            "lpf" | "rlpf" | "nrlpf" => "sonic_fx_lpf",
-            "hpf" | "rhpf" | "nrhpf" => "sonic_fx_hpf",
-            "flanger" => "sonic_fx_flanger",
-            "compressor" => "sonic_fx_compressor",
-            _ => {
-                eprintln!("[SC] Unknown FX type '{}', using reverb", fx_type);
-                "sonic_fx_reverb"
-            }
-        };
-
-        let mut args = vec![
-            OscType::String(def_name.to_string()),
-            OscType::Int(node_id),
-            OscType::Int(ADD_TO_TAIL),
-            OscType::Int(SOURCE_GROUP),
-            OscType::String("out".to_string()),
-            OscType::Int(0),
-        ];
-
-        for (name, val) in params {
-            args.push(OscType::String(name.clone()));
-            args.push(OscType::Float(*val));
-        }
-
-        self.send_osc_msg("/s_new", args)?;
-        Ok(node_id)
-    }
-
     /// Free a specific node (e.g., an FX node when a with_fx block ends)
     pub fn free_node(&self, node_id: i32) -> Result<(), String> {
         self.send_osc_msg("/n_free", vec![OscType::Int(node_id)])
@@ -690,54 +982,62 @@ impl ScEngine {
         self.next_bus_id.fetch_add(2, Ordering::Relaxed)
     }
 
-    /// Return the current output bus: top of the FX bus stack, or 0 (hardware out).
-    fn current_out_bus(&self) -> i32 {
-        let stack = self.fx_bus_stack.lock();
-        if let Some(&(bus_id, _, _)) = stack.last() {
-            bus_id
-        } else {
-            0 // hardware output
-        }
+    /// Bus a note or sample in FX block `fx` writes to: the block's private
+    /// bus, or 0 (hardware out) outside any block or once it has been freed
+    fn out_bus(&self, fx: Option<u32>) -> i32 {
+        fx.and_then(|id| self.fx_blocks.lock().get(&id).map(|block| block.bus))
+            .unwrap_or(0)
     }
 
-    /// Push a new FX bus onto the stack.
-    ///
-    /// Allocates a private audio bus, creates an FX synth that reads from
-    /// that bus and writes to the *previous* output bus (or hardware 0).
-    /// Subsequent synths/samples will route their output to this new bus.
-    pub fn push_fx_bus(&self, fx_type: &str, params: &[(String, f32)]) -> Result<(), String> {
-        let new_bus = self.alloc_audio_bus();
-        let parent_bus = self.current_out_bus();
+    /// Enter FX block `id`. The first time, allocate a private bus and create
+    /// an FX synth that reads it and writes to the parent block's bus (or
+    /// hardware 0). Later entries only update the synth's params.
+    pub fn start_fx_block(
+        &self,
+        id: u32,
+        parent: Option<u32>,
+        fx_type: &str,
+        params: &[(String, f32)],
+    ) -> Result<(), String> {
+        let existing = self.fx_blocks.lock().get(&id).map(|block| block.node_id);
+        if let Some(node_id) = existing {
+            if params.is_empty() {
+                return Ok(());
+            }
+            let mut args = vec![OscType::Int(node_id)];
+            for (name, val) in params {
+                args.push(OscType::String(name.clone()));
+                args.push(OscType::Float(*val));
+            }
+            return self.send_osc_msg("/n_set", args);
+        }
+
+        let new_bus = self.reuse_or_alloc_bus();
+        let parent_block = parent.and_then(|p| {
+            self.fx_blocks.lock().get(&p).map(|block| (block.bus, block.node_id))
+        });
+        let parent_bus = parent_block.map_or(0, |(bus, _)| bus);
 
         let fx_node_id = self.alloc_node_id();
-        let def_name = match fx_type {
-            "reverb" | "gverb" => "sonic_fx_reverb",
-            "echo" | "delay" => "sonic_fx_echo",
-            "distortion" | "tanh" => "sonic_fx_distortion",
-            "slicer" => "sonic_fx_slicer",
-            "lpf" | "rlpf" | "nrlpf" => "sonic_fx_lpf",
-            "hpf" | "rhpf" | "nrhpf" => "sonic_fx_hpf",
-            "flanger" => "sonic_fx_flanger",
-            "compressor" => "sonic_fx_compressor",
-            "bitcrusher" => "sonic_fx_bitcrusher",
-            "pan" => "sonic_fx_pan",
-            "wobble" => "sonic_fx_wobble",
-            "tremolo" => "sonic_fx_tremolo",
-            _ => {
-                eprintln!("[SC] Unknown FX type '{}', using reverb", fx_type);
-                "sonic_fx_reverb"
-            }
-        };
+        let def_name = fx_synthdef_name(fx_type).unwrap_or_else(|| {
+            eprintln!("[SC] Unknown FX type '{}', using reverb", fx_type);
+            "sonic_fx_reverb"
+        });
 
         // FX synths use an insert-effect pattern:
         //   in_bus = new_bus  (reads source audio from here)
         //   out    = parent_bus (writes processed audio to parent)
-        // Placed at tail of FX_GROUP so they process audio after sources.
+        // A nested FX runs just before its parent's, so the parent hears it
+        // in the same control cycle; outer blocks go at the tail of FX_GROUP.
+        let (add_action, target) = match parent_block {
+            Some((_, parent_node)) => (ADD_BEFORE, parent_node),
+            None => (ADD_TO_TAIL, FX_GROUP),
+        };
         let mut args = vec![
             OscType::String(def_name.to_string()),
             OscType::Int(fx_node_id),
-            OscType::Int(ADD_TO_TAIL),
-            OscType::Int(FX_GROUP),
+            OscType::Int(add_action),
+            OscType::Int(target),
             OscType::String("in_bus".to_string()),
             OscType::Int(new_bus),
             OscType::String("out".to_string()),
@@ -752,27 +1052,36 @@ impl ScEngine {
         self.send_osc_msg("/s_new", args)?;
 
         eprintln!(
-            "[SC] Pushed FX bus: type={}, bus={}, parent_bus={}, node={}",
-            fx_type, new_bus, parent_bus, fx_node_id
+            "[SC] Started FX block {}: type={}, bus={}, parent_bus={}, node={}",
+            id, fx_type, new_bus, parent_bus, fx_node_id
         );
 
-        self.fx_bus_stack.lock().push((new_bus, fx_node_id, 0));
+        self.fx_blocks.lock().insert(id, FxBlock { bus: new_bus, node_id: fx_node_id });
         Ok(())
     }
 
-    /// Pop the top FX bus from the stack, freeing the FX synth node.
-    pub fn pop_fx_bus(&self) -> Result<(), String> {
-        let entry = self.fx_bus_stack.lock().pop();
-        if let Some((_bus_id, fx_node_id, _)) = entry {
-            // Free the FX synth node — it will stop processing
-            let _ = self.send_osc_msg("/n_free", vec![OscType::Int(fx_node_id)]);
-            eprintln!("[SC] Popped FX bus: node={}", fx_node_id);
-        } else {
-            eprintln!("[SC] Warning: pop_fx_bus called with empty stack");
-        }
+    /// Free FX block `id` after its tail, and hand its bus back for reuse
+    pub fn free_fx_block(&self, id: u32) -> Result<(), String> {
+        let Some(block) = self.fx_blocks.lock().remove(&id) else {
+            return Ok(());
+        };
+        let free_at = self.event_time() + FX_TAIL;
+        self.scheduled(free_at, |sc| sc.send_osc_msg("/n_free", vec![OscType::Int(block.node_id)]))?;
+        self.free_buses.lock().push((block.bus, free_at));
+        eprintln!("[SC] Freeing FX block {}: node={}", id, block.node_id);
         Ok(())
     }
 
+    /// A bus whose previous block's synth has been freed, or a new one
+    fn reuse_or_alloc_bus(&self) -> i32 {
+        let now = self.event_time();
+        let mut free = self.free_buses.lock();
+        match free.iter().position(|(_, free_at)| *free_at <= now) {
+            Some(index) => free.swap_remove(index).0,
+            None => self.alloc_audio_bus(),
+        }
+    }
+
     /// Start the scsynth subprocess
     fn start_scsynth(&self) -> Result<(), String> {
         // Check if scsynth is already running (we might be connecting to an existing instance)
@@ -968,8 +1277,26 @@ impl ScEngine {
         Ok(())
     }
 
-    /// Set up the scope buffer and meter node for waveform visualization
+    /// Set up the master limiter, and the scope buffer and meter node for
+    /// waveform visualization and levels
     fn setup_scope(&self) -> Result<(), String> {
+        // Limit the master bus ahead of the scope and meter, so they see
+        // what goes out
+        let limiter_node = self.alloc_node_id();
+        self.send_osc_msg(
+            "/s_new",
+            vec![
+                OscType::String("sonic_limiter".to_string()),
+                OscType::Int(limiter_node),
+                OscType::Int(ADD_TO_HEAD),
+                OscType::Int(MONITOR_GROUP),
+                OscType::String("ceiling".to_string()),
+                OscType::Float(LIMITER_CEILING),
+                OscType::String("lookahead".to_string()),
+                OscType::Float(LIMITER_LOOKAHEAD_SECS),
+            ],
+        )?;
+
         // Allocate a buffer for waveform data (2048 frames, 1 channel)
         self.send_osc_msg(
             "/b_alloc",
@@ -1009,53 +1336,10 @@ impl ScEngine {
             ],
         )?;
 
-        eprintln!("[SC] Scope and meter nodes created");
+        eprintln!("[SC] Limiter, scope and meter nodes created");
         Ok(())
     }
 
-    /// Poll the scope buffer to update the waveform display
-    pub fn poll_waveform(&self) {
-        // Request buffer data from scsynth
-        // /b_getn [buf_num, start_index, num_samples]
-        if self.send_osc_msg(
-            "/b_getn",
-            vec![
-                OscType::Int(self.scope_buffer_id),
-                OscType::Int(0),
-                OscType::Int(2048),
-            ],
-        ).is_err() {
-            return;
-        }
-
-        // Try to receive the response
-        if let Ok(packet) = self.recv_osc() {
-            if let OscPacket::Message(msg) = packet {
-                if msg.addr == "/b_setn" {
-                    // Extract float values from the response
-                    let mut waveform = Vec::with_capacity(2048);
-                    // Skip first 3 args (buf_num, start, count)
-                    for arg in msg.args.iter().skip(3) {
-                        if let OscType::Float(v) = arg {
-                            waveform.push(*v);
-                        }
-                    }
-                    if !waveform.is_empty() {
-                        let mut state = self.state.lock();
-                        state.waveform_buffer = waveform;
-                    }
-                }
-                // Check for meter data (is_playing indicator)
-                if msg.addr == "/sonic/meter" {
-                    let mut state = self.state.lock();
-                    if let Some(OscType::Float(amp)) = msg.args.get(2) {
-                        state.is_playing = *amp > 0.001;
-                    }
-                }
-            }
-        }
-    }
-
     /// Process any pending OSC messages from scsynth (e.g., meter updates)
     pub fn process_incoming(&self) {
         // Non-blocking receive of any pending messages
@@ -1065,15 +1349,7 @@ impl ScEngine {
                 Ok(packet) => {
                     if let OscPacket::Message(msg) = &packet {
                         if msg.addr == "/sonic/meter" {
-                            // Update is_playing based on amplitude
-                            if msg.args.len() >= 4 {
-                                if let (Some(OscType::Float(l)), Some(OscType::Float(r))) =
-                                    (msg.args.get(2), msg.args.get(3))
-                                {
-                                    let amp = (*l + *r) * 0.5;
-                                    self.state.lock().is_playing = amp > 0.001;
-                                }
-                            }
+                            self.handle_meter(msg);
                         }
                     }
                 }
@@ -1084,25 +1360,47 @@ impl ScEngine {
         let _ = self.socket.set_read_timeout(Some(Duration::from_millis(500)));
     }
 
+    /// A `/sonic/meter` reply: node, reply id, then peak, mean square and
+    /// K-weighted mean square, each for left and right
+    fn handle_meter(&self, msg: &OscMessage) {
+        let values: Vec<f32> = msg
+            .args
+            .iter()
+            .skip(2)
+            .filter_map(|arg| if let OscType::Float(v) = arg { Some(*v) } else { None })
+            .collect();
+        let [peak_l, peak_r, square_l, square_r, k_square_l, k_square_r] = values[..] else {
+            return;
+        };
+        let peak = peak_l.max(peak_r);
+        let mut state = self.state.lock();
+        state.is_playing = peak > 0.001;
+        state.meter.push_measured(
+            1.0 / METER_RATE,
+            peak,
+            (square_l + square_r) as f64,
+            (k_square_l + k_square_r) as f64,
+        );
+    }
+
     // ================================================================
     // OSC COMMUNICATION
     // ================================================================
 
-    /// Send an OSC message to scsynth
+    /// Send an OSC message to scsynth, bundled for later inside `scheduled`
     fn send_osc_msg(&self, addr: &str, args: Vec<OscType>) -> Result<(), String> {
         let msg = OscMessage {
             addr: addr.to_string(),
             args,
         };
-        let packet = OscPacket::Message(msg);
-        let buf = encoder::encode(&packet)
-            .map_err(|e| format!("OSC encode error: {}", e))?;
-
-        self.socket
-            .send_to(&buf, format!("127.0.0.1:{}", self.sc_port))
-            .map_err(|e| format!("OSC send error: {}", e))?;
-
-        Ok(())
+        let packet = match *self.bundle_time.lock() {
+            Some(at) => OscPacket::Bundle(OscBundle {
+                timetag: osc_time(at)?,
+                content: vec![OscPacket::Message(msg)],
+            }),
+            None => OscPacket::Message(msg),
+        };
+        send_osc_packet(&self.socket, self.sc_port, &packet)
     }
 
     /// Receive an OSC packet from scsynth (blocking with timeout)
@@ -1177,6 +1475,56 @@ impl Drop for ScEngine {
 // HELPER FUNCTIONS
 // ================================================================
 
+/// Send an OSC message to scsynth over `socket`
+fn send_osc(socket: &UdpSocket, sc_port: u16, addr: &str, args: Vec<OscType>) -> Result<(), String> {
+    let msg = OscMessage {
+        addr: addr.to_string(),
+        args,
+    };
+    send_osc_packet(socket, sc_port, &OscPacket::Message(msg))
+}
+
+fn send_osc_packet(socket: &UdpSocket, sc_port: u16, packet: &OscPacket) -> Result<(), String> {
+    let buf = encoder::encode(packet)
+        .map_err(|e| format!("OSC encode error: {}", e))?;
+
+    socket
+        .send_to(&buf, format!("127.0.0.1:{}", sc_port))
+        .map_err(|e| format!("OSC send error: {}", e))?;
+
+    Ok(())
+}
+
+/// OSC timetag for `at`, through the system clock scsynth schedules by
+fn osc_time(at: Instant) -> Result<OscTime, String> {
+    let now = Instant::now();
+    let system = if at >= now {
+        SystemTime::now() + at.duration_since(now)
+    } else {
+        SystemTime::now() - now.duration_since(at)
+    };
+    OscTime::try_from(system).map_err(|e| format!("OSC timetag error: {:?}", e))
+}
+
+/// SynthDef implementing a Sonic Pi FX name, or `None` if it has no SC version
+pub fn fx_synthdef_name(fx_type: &str) -> Option<&'static str> {
+    match fx_type {
+        "reverb" | "gverb" => Some("sonic_fx_reverb"),
+        "echo" | "delay" => Some("sonic_fx_echo"),
+        "distortion" | "tanh" => Some("sonic_fx_distortion"),
+        "slicer" => Some("sonic_fx_slicer"),
+        "lpf" | "rlpf" | "nrlpf" => Some("sonic_fx_lpf"),
+        "hpf" | "rhpf" | "nrhpf" => Some("sonic_fx_hpf"),
+        "flanger" => Some("sonic_fx_flanger"),
+        "compressor" => Some("sonic_fx_compressor"),
+        "bitcrusher" => Some("sonic_fx_bitcrusher"),
+        "pan" => Some("sonic_fx_pan"),
+        "wobble" => Some("sonic_fx_wobble"),
+        "tremolo" => Some("sonic_fx_tremolo"),
+        _ => None,
+    }
+}
+
 /// Find scsynth in a bundled sc-bundle directory.
 /// Returns (scsynth_path, plugins_dir, synthdefs_dir) if found.
 fn find_bundled_scsynth(bundle_dir: &std::path::Path) -> Option<(PathBuf, PathBuf, PathBuf)> {
@@ -1464,3 +1812,211 @@ fn bind_udp_socket(start_port: u16, end_port: u16) -> Result<UdpSocket, String>
         start_port, end_port
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A booted engine talking to a local socket that stands in for scsynth
+    fn engine_and_server() -> (ScEngine, UdpSocket) {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let engine = ScEngine::with_socket(socket, port, PathBuf::new(), None, std::env::temp_dir(), None, false);
+        engine.is_booted.store(true, Ordering::Relaxed);
+        (engine, server)
+    }
+
+    fn recv(server: &UdpSocket) -> OscPacket {
+        let mut buf = [0u8; 65536];
+        let (size, _) = server.recv_from(&mut buf).unwrap();
+        decoder::decode_udp(&buf[..size]).unwrap().1
+    }
+
+    fn secs(time: OscTime) -> f64 {
+        time.seconds as f64 + time.fractional as f64 / 2f64.powi(32)
+    }
+
+    /// The timetag and message of a bundle holding one message
+    fn bundled(packet: OscPacket) -> (OscTime, OscMessage) {
+        match packet {
+            OscPacket::Bundle(OscBundle { timetag, content }) => match &content[..] {
+                [OscPacket::Message(msg)] => (timetag, msg.clone()),
+                other => panic!("expected one message, got {:?}", other),
+            },
+            other => panic!("expected a bundle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_scheduled_messages_are_bundled_at_their_time() {
+        let (sc, server) = engine_and_server();
+        let at = Instant::now() + Duration::from_millis(300);
+        sc.scheduled(at, |sc| sc.free_node(2001)).unwrap();
+        let (timetag, msg) = bundled(recv(&server));
+        assert_eq!(msg.addr, "/n_free");
+        assert!((secs(timetag) - secs(osc_time(at).unwrap())).abs() < 0.001);
+
+        // Outside `scheduled`, messages go out to run on arrival
+        sc.free_node(2002).unwrap();
+        assert!(matches!(recv(&server), OscPacket::Message(msg) if msg.addr == "/n_free"));
+    }
+
+    #[test]
+    fn test_timed_note_is_one_bundle_at_its_time() {
+        let (sc, server) = engine_and_server();
+        let at = Instant::now() + Duration::from_millis(200);
+        let note = AudioCommand::PlayNote {
+            synth_type: OscillatorType::Sine,
+            frequency: 440.0,
+            amplitude: 1.0,
+            envelope: Default::default(),
+            pan: 0.0,
+            params: Vec::new(),
+            node: None,
+            fx: None,
+        };
+        sc.send_command_at(note, at).unwrap();
+        let (timetag, msg) = bundled(recv(&server));
+        assert_eq!(msg.addr, "/s_new");
+        assert!((secs(timetag) - secs(osc_time(at).unwrap())).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_nested_schedule_restores_the_outer_time() {
+        let (sc, server) = engine_and_server();
+        let outer = Instant::now() + Duration::from_millis(100);
+        let inner = outer + Duration::from_secs(4);
+        sc.scheduled(outer, |sc| {
+            sc.scheduled(inner, |sc| sc.free_node(1))?;
+            sc.free_node(2)
+        })
+        .unwrap();
+        let (first, _) = bundled(recv(&server));
+        let (second, _) = bundled(recv(&server));
+        assert!((secs(first) - secs(osc_time(inner).unwrap())).abs() < 0.001);
+        assert!((secs(second) - secs(osc_time(outer).unwrap())).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_fx_bus_is_reused_once_its_synth_is_freed() {
+        let (sc, server) = engine_and_server();
+        let now = Instant::now();
+        sc.scheduled(now, |sc| sc.start_fx_block(1, None, "reverb", &[])).unwrap();
+        let bus = sc.fx_blocks.lock()[&1].bus;
+        sc.scheduled(now, |sc| sc.free_fx_block(1)).unwrap();
+        // The freed block's tail still plays on its bus
+        sc.scheduled(now, |sc| sc.start_fx_block(2, None, "echo", &[])).unwrap();
+        assert_ne!(sc.fx_blocks.lock()[&2].bus, bus);
+        // Once its synth is gone, the next block takes the bus over
+        sc.scheduled(now + FX_TAIL, |sc| sc.start_fx_block(3, None, "echo", &[])).unwrap();
+        assert_eq!(sc.fx_blocks.lock()[&3].bus, bus);
+
+        let sent: Vec<OscPacket> = (0..4).map(|_| recv(&server)).collect();
+        let (timetag, msg) = bundled(sent[1].clone());
+        assert_eq!(msg.addr, "/n_free");
+        assert!((secs(timetag) - secs(osc_time(now + FX_TAIL).unwrap())).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_meter_replies_reach_get_meter() {
+        let (sc, server) = engine_and_server();
+        let reply = OscMessage {
+            addr: "/sonic/meter".to_string(),
+            args: [OscType::Int(1003), OscType::Int(-1)]
+                .into_iter()
+                .chain([0.5, 0.25, 0.1, 0.1, 0.1, 0.1].map(OscType::Float))
+                .collect(),
+        };
+        let packet = encoder::encode(&OscPacket::Message(reply)).unwrap();
+        server.send_to(&packet, sc.socket.local_addr().unwrap()).unwrap();
+        sc.process_incoming();
+        let reading = sc.get_meter();
+        assert!((reading.peak_db - 20.0 * 0.5f32.log10()).abs() < 1e-4);
+        assert!(reading.rms_db > MeterReading::default().rms_db);
+        assert!(sc.get_state_snapshot().0);
+    }
+
+    /// Answer buffer commands on `server` the way scsynth does, with
+    /// `/done`, or `/fail` for `fail`, until it goes quiet. Returns every
+    /// message it got.
+    fn serve_buffers(server: UdpSocket, fail: Option<&'static str>) -> std::thread::JoinHandle<Vec<OscMessage>> {
+        std::thread::spawn(move || {
+            server.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0u8; 65536];
+            while let Ok((size, from)) = server.recv_from(&mut buf) {
+                let Ok((_, OscPacket::Message(msg))) = decoder::decode_udp(&buf[..size]) else {
+                    continue;
+                };
+                if matches!(msg.addr.as_str(), "/b_alloc" | "/b_write" | "/b_close") {
+                    let reply = OscMessage {
+                        addr: if fail == Some(msg.addr.as_str()) { "/fail" } else { "/done" }.to_string(),
+                        args: vec![OscType::String(msg.addr.clone())],
+                    };
+                    let packet = encoder::encode(&OscPacket::Message(reply)).unwrap();
+                    server.send_to(&packet, from).unwrap();
+                }
+                received.push(msg);
+            }
+            received
+        })
+    }
+
+    #[test]
+    fn test_recording_writes_through_a_buffer() {
+        let (sc, server) = engine_and_server();
+        let server = serve_buffers(server, None);
+        let path = std::env::temp_dir().join("sonic-sc-recording.wav");
+        for format in [RecordFormat::Pcm16, RecordFormat::Pcm24, RecordFormat::Float32] {
+            sc.start_recording(&path, format).unwrap();
+            assert!(sc.is_recording());
+            assert_eq!(sc.stop_recording().unwrap(), path);
+            assert!(!sc.is_recording());
+        }
+        assert!(sc.stop_recording().is_err());
+
+        let received = server.join().unwrap();
+        let addrs: Vec<&str> = received.iter().map(|msg| msg.addr.as_str()).collect();
+        let take = ["/b_alloc", "/b_write", "/s_new", "/n_free", "/b_close", "/b_free"];
+        assert_eq!(addrs, take.repeat(3));
+        for (messages, sample_format) in received.chunks(6).zip(["int16", "int24", "float"]) {
+            let buf = messages[0].args[0].clone();
+            assert_eq!(messages[0].args[1..], [OscType::Int(RECORD_BUFFER_FRAMES), OscType::Int(2)]);
+            assert_eq!(
+                messages[1].args,
+                [
+                    buf.clone(),
+                    OscType::String(path.to_string_lossy().replace('\\', "/")),
+                    OscType::String("wav".to_string()),
+                    OscType::String(sample_format.to_string()),
+                    OscType::Int(0),
+                    OscType::Int(0),
+                    OscType::Int(1),
+                ]
+            );
+            // The recorder synth streams the buffer, and is freed before the file is closed
+            assert_eq!(messages[2].args[0], OscType::String("sonic_recorder".to_string()));
+            assert_eq!(messages[2].args[4..], [OscType::String("buf".to_string()), buf.clone()]);
+            assert_eq!(messages[3].args, [messages[2].args[1].clone()]);
+            assert_eq!(messages[4].args, messages[5].args);
+            assert_eq!(messages[5].args, [buf]);
+        }
+    }
+
+    #[test]
+    fn test_recording_fails_when_the_file_cannot_be_opened() {
+        let (sc, server) = engine_and_server();
+        let server = serve_buffers(server, Some("/b_write"));
+        let path = std::env::temp_dir().join("sonic-sc-recording-fail.wav");
+        let error = sc.start_recording(&path, RecordFormat::Float32).unwrap_err();
+        assert!(error.starts_with("SC server error"), "{}", error);
+        assert!(!sc.is_recording());
+        drop(sc);
+        // The buffer goes back, and no recorder synth starts
+        let addrs: Vec<String> = server.join().unwrap().into_iter().map(|msg| msg.addr).collect();
+        assert_eq!(addrs, ["/b_alloc", "/b_write", "/b_free", "/quit"]);
+    }
+}
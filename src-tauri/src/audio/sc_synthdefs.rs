@@ -60,102 +60,115 @@ pub fn generate_synthdef_script(output_dir: &Path) -> String {
         r#"(
 var dir = "{dir}";
 
+// Sonic Pi's note envelope. The shape of every segment is the env_curve
+// control (EnvGen's shape numbers: 1 linear, 2 exponential, 3 sine, 4 welch,
+// 6 squared, 7 cubed), so it is written out in EnvGen's raw array format;
+// exponential segments start and end at 0.0001, since they can't reach 0.
+var sonicEnv = {{ |attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve|
+    var zero = BinaryOpUGen('==', env_curve.round, 2) * 0.0001;
+    EnvGen.kr([zero, 4, -99, -99,
+        attack_level, attack, env_curve, 0,
+        decay_level, decay, env_curve, 0,
+        sustain_level, sustain, env_curve, 0,
+        zero, release, env_curve, 0], doneAction: 2);
+}};
+
 // ============================================================
 // SYNTH DEFINITIONS - Matching Sonic Pi's built-in synths
 // ============================================================
 
 // Beep / Sine
-SynthDef(\sonic_beep, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, sustain_level=1|
+SynthDef(\sonic_beep, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1|
     var sig = SinOsc.ar(freq);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Saw
-SynthDef(\sonic_saw, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, sustain_level=1, cutoff=100, res=0.3|
+SynthDef(\sonic_saw, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, cutoff=100, res=0.3|
     var sig = Saw.ar(freq);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Square
-SynthDef(\sonic_square, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, sustain_level=1, cutoff=100, res=0.3|
+SynthDef(\sonic_square, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, cutoff=100, res=0.3|
     var sig = Pulse.ar(freq, 0.5);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Triangle
-SynthDef(\sonic_tri, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, sustain_level=1|
+SynthDef(\sonic_tri, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, attack_level=1, decay_level=1, sustain_level=1, env_curve=1|
     var sig = LFTri.ar(freq);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Noise
-SynthDef(\sonic_noise, {{ |out=0, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=1, sustain_level=1, cutoff=110, freq=0|
+SynthDef(\sonic_noise, {{ |out=0, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, cutoff=110, freq=0|
     var sig = WhiteNoise.ar;
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), 0.3);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Pulse (variable width)
-SynthDef(\sonic_pulse, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, sustain_level=1, pulse_width=0.5, cutoff=100, res=0.3|
+SynthDef(\sonic_pulse, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, pulse_width=0.5, cutoff=100, res=0.3|
     var sig = Pulse.ar(freq, pulse_width);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Super Saw (7 detuned saws)
-SynthDef(\sonic_supersaw, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, sustain_level=1, cutoff=130, res=0.7|
+SynthDef(\sonic_supersaw, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, cutoff=130, res=0.7|
     var sigs = Array.fill(7, {{ |i|
         var dt = (i - 3) * 0.12;
         Saw.ar(freq * (1 + (dt * 0.01)));
     }});
     var sig = Mix.ar(sigs) / 3;
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Detuned Saw
-SynthDef(\sonic_dsaw, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, sustain_level=1, detune=0.1, cutoff=100, res=0.3|
+SynthDef(\sonic_dsaw, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, detune=0.1, cutoff=100, res=0.3|
     var sig = Mix.ar([Saw.ar(freq), Saw.ar(freq * (1 + (detune * 0.01)))]) * 0.5;
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Detuned Pulse
-SynthDef(\sonic_dpulse, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, sustain_level=1, detune=0.1, cutoff=100, res=0.3|
+SynthDef(\sonic_dpulse, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, detune=0.1, cutoff=100, res=0.3|
     var sig = Mix.ar([Pulse.ar(freq, 0.5), Pulse.ar(freq * (1 + (detune * 0.01)), 0.5)]) * 0.5;
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Detuned Tri
-SynthDef(\sonic_dtri, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, sustain_level=1, detune=0.1|
+SynthDef(\sonic_dtri, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, detune=0.1|
     var sig = Mix.ar([LFTri.ar(freq), LFTri.ar(freq * (1 + (detune * 0.01)))]) * 0.5;
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // FM Synthesis
-SynthDef(\sonic_fm, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, sustain_level=1, divisor=2, depth=1|
+SynthDef(\sonic_fm, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, divisor=2, depth=1|
     var modFreq = freq / divisor;
     var modulator = SinOsc.ar(modFreq) * depth * modFreq;
     var sig = SinOsc.ar(freq + modulator);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Mod FM
-SynthDef(\sonic_mod_fm, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, sustain_level=1, mod_phase=1, mod_range=5, mod_pulse_width=0.5, mod_phase_offset=0, mod_invert_wave=0, mod_wave=0, divisor=2, depth=1|
+SynthDef(\sonic_mod_fm, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, mod_phase=1, mod_range=5, mod_pulse_width=0.5, mod_phase_offset=0, mod_invert_wave=0, mod_wave=0, divisor=2, depth=1|
     var modFreq = freq / divisor;
     var lfo = Select.kr(mod_wave, [
         SinOsc.kr(mod_phase, mod_phase_offset),
@@ -166,12 +179,12 @@ SynthDef(\sonic_mod_fm, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=
     lfo = lfo.linlin(-1, 1, freq, freq * mod_range);
     var modulator = SinOsc.ar(modFreq) * depth * modFreq;
     var sig = SinOsc.ar(lfo + modulator);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Mod Sine
-SynthDef(\sonic_mod_sine, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, sustain_level=1, mod_phase=1, mod_range=5, mod_pulse_width=0.5, mod_phase_offset=0, mod_wave=0|
+SynthDef(\sonic_mod_sine, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, mod_phase=1, mod_range=5, mod_pulse_width=0.5, mod_phase_offset=0, mod_wave=0|
     var lfo = Select.kr(mod_wave, [
         SinOsc.kr(mod_phase, mod_phase_offset),
         LFSaw.kr(mod_phase, mod_phase_offset),
@@ -180,12 +193,12 @@ SynthDef(\sonic_mod_sine, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, deca
     ]);
     var modulated_freq = freq * (1 + (lfo * mod_range * 0.01));
     var sig = SinOsc.ar(modulated_freq);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Mod Saw
-SynthDef(\sonic_mod_saw, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, sustain_level=1, mod_phase=1, mod_range=5, mod_pulse_width=0.5, mod_phase_offset=0, mod_wave=0, cutoff=100, res=0.3|
+SynthDef(\sonic_mod_saw, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, mod_phase=1, mod_range=5, mod_pulse_width=0.5, mod_phase_offset=0, mod_wave=0, cutoff=100, res=0.3|
     var lfo = Select.kr(mod_wave, [
         SinOsc.kr(mod_phase, mod_phase_offset),
         LFSaw.kr(mod_phase, mod_phase_offset),
@@ -194,13 +207,13 @@ SynthDef(\sonic_mod_saw, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay
     ]);
     var modulated_freq = freq * (1 + (lfo * mod_range * 0.01));
     var sig = Saw.ar(modulated_freq);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Mod DSaw
-SynthDef(\sonic_mod_dsaw, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, sustain_level=1, mod_phase=1, mod_range=5, mod_pulse_width=0.5, mod_phase_offset=0, mod_wave=0, detune=0.1, cutoff=100, res=0.3|
+SynthDef(\sonic_mod_dsaw, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, mod_phase=1, mod_range=5, mod_pulse_width=0.5, mod_phase_offset=0, mod_wave=0, detune=0.1, cutoff=100, res=0.3|
     var lfo = Select.kr(mod_wave, [
         SinOsc.kr(mod_phase, mod_phase_offset),
         LFSaw.kr(mod_phase, mod_phase_offset),
@@ -209,13 +222,13 @@ SynthDef(\sonic_mod_dsaw, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, deca
     ]);
     var modulated_freq = freq * (1 + (lfo * mod_range * 0.01));
     var sig = Mix.ar([Saw.ar(modulated_freq), Saw.ar(modulated_freq * (1 + (detune * 0.01)))]) * 0.5;
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Mod Tri
-SynthDef(\sonic_mod_tri, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, sustain_level=1, mod_phase=1, mod_range=5, mod_pulse_width=0.5, mod_phase_offset=0, mod_wave=0|
+SynthDef(\sonic_mod_tri, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, mod_phase=1, mod_range=5, mod_pulse_width=0.5, mod_phase_offset=0, mod_wave=0|
     var lfo = Select.kr(mod_wave, [
         SinOsc.kr(mod_phase, mod_phase_offset),
         LFSaw.kr(mod_phase, mod_phase_offset),
@@ -224,12 +237,12 @@ SynthDef(\sonic_mod_tri, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay
     ]);
     var modulated_freq = freq * (1 + (lfo * mod_range * 0.01));
     var sig = LFTri.ar(modulated_freq);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Mod Pulse
-SynthDef(\sonic_mod_pulse, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, sustain_level=1, mod_phase=1, mod_range=5, mod_pulse_width=0.5, mod_phase_offset=0, mod_wave=0, cutoff=100, res=0.3|
+SynthDef(\sonic_mod_pulse, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, mod_phase=1, mod_range=5, mod_pulse_width=0.5, mod_phase_offset=0, mod_wave=0, cutoff=100, res=0.3|
     var lfo = Select.kr(mod_wave, [
         SinOsc.kr(mod_phase, mod_phase_offset),
         LFSaw.kr(mod_phase, mod_phase_offset),
@@ -238,34 +251,34 @@ SynthDef(\sonic_mod_pulse, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, dec
     ]);
     var modulated_freq = freq * (1 + (lfo * mod_range * 0.01));
     var sig = Pulse.ar(modulated_freq, mod_pulse_width);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // TB-303 (acid bass)
-SynthDef(\sonic_tb303, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, sustain_level=1, cutoff=100, res=0.8, wave=0|
+SynthDef(\sonic_tb303, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, cutoff=100, res=0.8, wave=0|
     var sig = Select.ar(wave, [Saw.ar(freq), Pulse.ar(freq, 0.5)]);
     var fenv = EnvGen.kr(Env.perc(0.001, release * 2), 1, cutoff.midicps * 2, cutoff.midicps * 0.5);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, fenv.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Prophet (detuned saws + pulse)
-SynthDef(\sonic_prophet, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, sustain_level=1, cutoff=110, res=0.7|
+SynthDef(\sonic_prophet, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, cutoff=110, res=0.7|
     var sig = Mix.ar([
         Saw.ar(freq, 0.5),
         Pulse.ar(freq * 1.002, 0.4, 0.4),
         Pulse.ar(freq * 0.998, 0.6, 0.3)
     ]);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Zawa (phase modulation synth)
-SynthDef(\sonic_zawa, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, sustain_level=1, cutoff=100, res=0.9, phase=1, wave=3|
+SynthDef(\sonic_zawa, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, cutoff=100, res=0.9, phase=1, wave=3|
     var modulator = SinOsc.ar(freq * phase) * 2pi;
     var sig = Select.ar(wave, [
         SinOsc.ar(freq, modulator),
@@ -273,174 +286,174 @@ SynthDef(\sonic_zawa, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0,
         Pulse.ar(freq, 0.5),
         LFTri.ar(freq)
     ]);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Blade (thick detuned saws)
-SynthDef(\sonic_blade, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, sustain_level=1, cutoff=100, res=0.5|
+SynthDef(\sonic_blade, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, cutoff=100, res=0.5|
     var sig = Mix.ar(Array.fill(8, {{ |i|
         var detune = (i - 3.5) * 0.007;
         Saw.ar(freq * (1 + detune));
     }})) / 4;
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Tech Saws (5 layered saws)
-SynthDef(\sonic_tech_saws, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, sustain_level=1, cutoff=130, res=0.3|
+SynthDef(\sonic_tech_saws, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, cutoff=130, res=0.3|
     var sig = Mix.ar(Array.fill(5, {{ |i|
         Saw.ar(freq * (1 + (i * 0.01)));
     }})) / 3;
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Hoover (classic rave synth)
-SynthDef(\sonic_hoover, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.05, decay=0, sustain=0, release=1, sustain_level=1, cutoff=130|
+SynthDef(\sonic_hoover, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.05, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, cutoff=130|
     var sig = Mix.ar([
         Saw.ar(freq, 0.3),
         Saw.ar(freq * 1.01, 0.3),
         Saw.ar(freq * 0.99, 0.3),
         Pulse.ar(freq * 0.5, 0.5, 0.2)
     ]);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), 0.5);
     sig = FreeVerb.ar(sig, 0.3, 0.5, 0.5);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Pluck (Karplus-Strong)
-SynthDef(\sonic_pluck, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=1, sustain_level=1, coef=0.3|
+SynthDef(\sonic_pluck, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, coef=0.3|
     var sig = Pluck.ar(WhiteNoise.ar, 1, 0.2, freq.reciprocal, release * 5, coef);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Piano (additive harmonics)
-SynthDef(\sonic_piano, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.5, sustain_level=1, vel=0.8|
+SynthDef(\sonic_piano, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.5, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, vel=0.8|
     var sig = Mix.ar(Array.fill(8, {{ |i|
         var partial = i + 1;
         SinOsc.ar(freq * partial, 0, 1.0 / (partial * partial));
     }}));
-    var env = EnvGen.kr(Env.perc(attack, release), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = sig * env * vel * amp;
     Out.ar(out, Pan2.ar(sig, pan));
 }}).writeDefFile(dir);
 
 // Pretty Bell (inharmonic partials)
-SynthDef(\sonic_pretty_bell, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1.5, sustain_level=1|
+SynthDef(\sonic_pretty_bell, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1.5, attack_level=1, decay_level=1, sustain_level=1, env_curve=1|
     var partials = [1, 2.4, 3.1, 4.7, 6.2];
     var sig = Mix.ar(partials.collect({{ |p|
         SinOsc.ar(freq * p, 0, 1.0 / p);
     }}));
-    var env = EnvGen.kr(Env.perc(attack, release), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Dull Bell
-SynthDef(\sonic_dull_bell, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1.5, sustain_level=1|
+SynthDef(\sonic_dull_bell, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1.5, attack_level=1, decay_level=1, sustain_level=1, env_curve=1|
     var partials = [1, 2.0, 2.5, 3.2, 4.0];
     var sig = Mix.ar(partials.collect({{ |p|
         SinOsc.ar(freq * p, 0, 1.0 / (p * p));
     }}));
-    var env = EnvGen.kr(Env.perc(attack, release), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = LPF.ar(sig, 2000);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Hollow (band-pass filtered)
-SynthDef(\sonic_hollow, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, sustain_level=1, cutoff=90, res=0.99|
+SynthDef(\sonic_hollow, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, cutoff=90, res=0.99|
     var sig = Mix.ar([SinOsc.ar(freq), PinkNoise.ar(0.3)]);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = BPF.ar(sig, freq, 1 - res.clip(0.01, 0.99));
     sig = sig * 4;
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Dark Ambience (atmospheric pad)
-SynthDef(\sonic_dark_ambience, {{ |out=0, freq=52, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, sustain_level=1, cutoff=90, res=0.7, detune=12, noise=0, room=70, reverb_time=100|
+SynthDef(\sonic_dark_ambience, {{ |out=0, freq=52, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, cutoff=90, res=0.7, detune=12, noise=0, room=70, reverb_time=100|
     var sig = Mix.ar([
         Saw.ar(freq * (1 + (detune * 0.001)), 0.3),
         Saw.ar(freq * (1 - (detune * 0.001)), 0.3),
         SinOsc.ar(freq * 0.5, 0, 0.2),
         PinkNoise.ar(0.08 + (noise * 0.1))
     ]);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     sig = FreeVerb.ar(sig, 0.7, room / 100, 0.5);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Growl (ring modulated)
-SynthDef(\sonic_growl, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.1, decay=0, sustain=0, release=1, sustain_level=1, cutoff=130|
+SynthDef(\sonic_growl, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.1, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, cutoff=130|
     var mod = SinOsc.ar(freq * 0.5);
     var sig = SinOsc.ar(freq) * mod;
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), 0.3);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Chip Lead
-SynthDef(\sonic_chip_lead, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=0.3, sustain_level=1, width=0|
+SynthDef(\sonic_chip_lead, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=0.3, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, width=0|
     var sig = Pulse.ar(freq, (width * 0.5) + 0.5);
     sig = sig.round(0.125);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Chip Bass
-SynthDef(\sonic_chip_bass, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=0.3, sustain_level=1|
+SynthDef(\sonic_chip_bass, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=0.3, attack_level=1, decay_level=1, sustain_level=1, env_curve=1|
     var sig = Pulse.ar(freq, 0.5) + Pulse.ar(freq * 0.5, 0.5);
     sig = sig.round(0.125) * 0.5;
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Chip Noise
-SynthDef(\sonic_chip_noise, {{ |out=0, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=0.3, sustain_level=1, freq=440|
+SynthDef(\sonic_chip_noise, {{ |out=0, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=0.3, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, freq=440|
     var sig = LFNoise0.ar(freq * 4);
     sig = sig.round(0.125);
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Brown Noise
-SynthDef(\sonic_bnoise, {{ |out=0, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=1, sustain_level=1, freq=0|
+SynthDef(\sonic_bnoise, {{ |out=0, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, freq=0|
     var sig = BrownNoise.ar;
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Pink Noise
-SynthDef(\sonic_pnoise, {{ |out=0, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=1, sustain_level=1, freq=0|
+SynthDef(\sonic_pnoise, {{ |out=0, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, freq=0|
     var sig = PinkNoise.ar;
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Grey Noise
-SynthDef(\sonic_gnoise, {{ |out=0, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=1, sustain_level=1, freq=0|
+SynthDef(\sonic_gnoise, {{ |out=0, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, freq=0|
     var sig = GrayNoise.ar;
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Clip Noise
-SynthDef(\sonic_cnoise, {{ |out=0, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=1, sustain_level=1, freq=0|
+SynthDef(\sonic_cnoise, {{ |out=0, amp=0.5, pan=0, attack=0, decay=0, sustain=0, release=1, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, freq=0|
     var sig = ClipNoise.ar;
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
 
 // Sub Pulse
-SynthDef(\sonic_subpulse, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, sustain_level=1, cutoff=100, res=0.3|
+SynthDef(\sonic_subpulse, {{ |out=0, freq=440, amp=0.5, pan=0, attack=0.01, decay=0, sustain=0, release=0.3, attack_level=1, decay_level=1, sustain_level=1, env_curve=1, cutoff=100, res=0.3|
     var sig = Pulse.ar(freq, 0.5) + SinOsc.ar(freq * 0.5, 0, 0.6);
     sig = sig * 0.5;
-    var env = EnvGen.kr(Env([0, 1, sustain_level, sustain_level, 0], [attack, decay, sustain, release], \lin), doneAction: 2);
+    var env = sonicEnv.(attack, decay, sustain, release, attack_level, decay_level, sustain_level, env_curve);
     sig = RLPF.ar(sig, cutoff.midicps.min(SampleRate.ir * 0.45), res.clip(0.01, 0.99));
     Out.ar(out, Pan2.ar(sig * env * amp, pan));
 }}).writeDefFile(dir);
@@ -578,12 +591,29 @@ SynthDef(\sonic_scope, {{ |out=0, buf=0|
     BufWr.ar(sig, buf, Phasor.ar(0, 1, 0, BufFrames.kr(buf)));
 }}).writeDefFile(dir);
 
-// Amplitude monitor - sends amplitude back via OSC for is_playing detection
+// Master limiter: brickwall at `ceiling` with `lookahead` seconds of
+// look-ahead, replacing the output bus before the scope and meter read it
+SynthDef(\sonic_limiter, {{ |ceiling=0.891, lookahead=0.005|
+    ReplaceOut.ar(0, Limiter.ar(In.ar(0, 2), ceiling, lookahead));
+}}).writeDefFile(dir);
+
+// Level meter - sends, 30 times a second, each channel's peak, mean square
+// and K-weighted (ITU-R BS.1770) mean square since the last reply
 SynthDef(\sonic_meter, {{ |out=0|
     var sig = In.ar(0, 2);
-    var amp_l = Amplitude.kr(sig[0], 0.01, 0.1);
-    var amp_r = Amplitude.kr(sig[1], 0.01, 0.1);
-    SendReply.kr(Impulse.kr(30), '/sonic/meter', [amp_l, amp_r]);
+    var trig = Impulse.kr(30);
+    var frames = (SampleRate.ir / 30).round;
+    var k = HPF.ar(BHiShelf.ar(sig, 1681.97, 1, 4), 38.14);
+    var peak = RunningMax.ar(sig.abs, Delay1.kr(trig));
+    var square = RunningSum.ar(sig.squared, frames) / frames;
+    var k_square = RunningSum.ar(k.squared, frames) / frames;
+    SendReply.kr(trig, '/sonic/meter', peak ++ square ++ k_square);
+}}).writeDefFile(dir);
+
+// Recorder - streams the master output, after the limiter, to the sound
+// file left open on `buf`
+SynthDef(\sonic_recorder, {{ |buf=0|
+    DiskOut.ar(buf, In.ar(0, 2));
 }}).writeDefFile(dir);
 
 
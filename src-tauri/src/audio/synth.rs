@@ -53,12 +53,83 @@ pub enum OscillatorType {
     SubPulse,
 }
 
+/// Shape of each envelope segment (`env_curve`), numbered as Sonic Pi and
+/// SuperCollider's `EnvGen` number them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+pub enum EnvCurve {
+    #[default]
+    Linear,
+    Exponential,
+    Sine,
+    Welch,
+    Squared,
+    Cubed,
+}
+
+impl EnvCurve {
+    /// Curve for an `env_curve:` value; unknown numbers are linear
+    pub fn from_number(n: f32) -> Self {
+        match n.round() as i32 {
+            2 => Self::Exponential,
+            3 => Self::Sine,
+            4 => Self::Welch,
+            6 => Self::Squared,
+            7 => Self::Cubed,
+            _ => Self::Linear,
+        }
+    }
+
+    /// `EnvGen` shape number
+    pub fn number(self) -> f32 {
+        match self {
+            Self::Linear => 1.0,
+            Self::Exponential => 2.0,
+            Self::Sine => 3.0,
+            Self::Welch => 4.0,
+            Self::Squared => 6.0,
+            Self::Cubed => 7.0,
+        }
+    }
+
+    /// Level `pos` (0..1) of the way from `from` to `to`, as `EnvGen` moves
+    fn interpolate(self, from: f32, to: f32, pos: f32) -> f32 {
+        match self {
+            Self::Linear => from + (to - from) * pos,
+            Self::Exponential => {
+                let (from, to) = (from.max(EXP_FLOOR), to.max(EXP_FLOOR));
+                from * (to / from).powf(pos)
+            }
+            Self::Sine => from + (to - from) * (0.5 - 0.5 * (PI * pos).cos()),
+            Self::Welch if from < to => from + (to - from) * (0.5 * PI * pos).sin(),
+            Self::Welch => to - (to - from) * (0.5 * PI * (1.0 - pos)).sin(),
+            Self::Squared => {
+                let (a, b) = (from.max(0.0).sqrt(), to.max(0.0).sqrt());
+                (a + (b - a) * pos).powi(2)
+            }
+            Self::Cubed => {
+                let (a, b) = (from.cbrt(), to.cbrt());
+                (a + (b - a) * pos).powi(3)
+            }
+        }
+    }
+}
+
+/// Lowest level an exponential segment moves from or to; zero is unreachable
+const EXP_FLOOR: f32 = 0.0001;
+
+/// Sonic Pi's note envelope: from 0 up to `attack_level` over `attack`, to
+/// `decay_level` over `decay`, to `sustain_level` over `sustain`, then back
+/// to 0 over `release`. Every time is in seconds.
 #[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
 pub struct Envelope {
     pub attack: f32,
     pub decay: f32,
     pub sustain: f32,
     pub release: f32,
+    pub attack_level: f32,
+    pub decay_level: f32,
+    pub sustain_level: f32,
+    pub curve: EnvCurve,
 }
 
 impl Default for Envelope {
@@ -66,10 +137,178 @@ impl Default for Envelope {
         Self {
             attack: 0.01,
             decay: 0.1,
-            sustain: 0.7,
+            sustain: 0.5,
             release: 0.3,
+            attack_level: 1.0,
+            decay_level: 0.7,
+            sustain_level: 0.7,
+            curve: EnvCurve::Linear,
+        }
+    }
+}
+
+impl Envelope {
+    /// How long the note sounds, all four phases together
+    pub fn duration(&self) -> f32 {
+        self.attack.max(0.0) + self.decay.max(0.0) + self.sustain.max(0.0) + self.release.max(0.0)
+    }
+
+    /// Level `t` seconds into the note
+    pub fn level(&self, t: f32) -> f32 {
+        let segments = [
+            (self.attack, self.attack_level),
+            (self.decay, self.decay_level),
+            (self.sustain, self.sustain_level),
+            (self.release, 0.0),
+        ];
+        let mut from = 0.0;
+        let mut start = 0.0;
+        for (time, to) in segments {
+            let time = time.max(0.0);
+            if t < start + time {
+                return self.curve.interpolate(from, to, (t - start) / time);
+            }
+            start += time;
+            from = to;
+        }
+        0.0
+    }
+}
+
+/// A parameter that moves to a new value over its slide time instead of
+/// jumping, like Sonic Pi's `*_slide` options
+#[derive(Debug, Clone, Copy)]
+pub struct Slide {
+    value: f32,
+    target: f32,
+    step: f32,
+    /// Glide time in seconds for the next change
+    time: f32,
+}
+
+impl Slide {
+    pub fn new(value: f32) -> Self {
+        Self { value, target: value, step: 0.0, time: 0.0 }
+    }
+
+    /// Move towards `target` over the current slide time
+    pub fn set(&mut self, target: f32, sample_rate: f32) {
+        let samples = self.time * sample_rate;
+        self.target = target;
+        if samples < 1.0 {
+            self.value = target;
+            self.step = 0.0;
+        } else {
+            self.step = (target - self.value) / samples;
+        }
+    }
+
+    /// Pick up `name` and `name_slide` from control params
+    pub fn apply(&mut self, name: &str, params: &[(String, f32)], sample_rate: f32) {
+        let slide_name = format!("{}_slide", name);
+        if let Some((_, time)) = params.iter().find(|(n, _)| *n == slide_name) {
+            self.time = time.max(0.0);
+        }
+        if let Some((_, value)) = params.iter().find(|(n, _)| n == name) {
+            self.set(*value, sample_rate);
+        }
+    }
+
+    /// Advance one sample and return the current value
+    pub fn next(&mut self) -> f32 {
+        if self.step != 0.0 {
+            self.value += self.step;
+            if (self.step > 0.0 && self.value >= self.target) || (self.step < 0.0 && self.value <= self.target) {
+                self.value = self.target;
+                self.step = 0.0;
+            }
+        }
+        self.value
+    }
+}
+
+/// Named synth params (besides note, amp, pan and the envelope), with the
+/// defaults of the matching `sonic_*` SynthDef. A synth ignores the ones
+/// its SynthDef does not have.
+#[derive(Debug, Clone, Copy)]
+struct SynthParams {
+    detune: f32,
+    pulse_width: f32,
+    depth: f32,
+    divisor: f32,
+    mod_phase: f32,
+    mod_range: f32,
+    mod_pulse_width: f32,
+    mod_phase_offset: f32,
+    mod_wave: f32,
+    wave: f32,
+    phase: f32,
+    coef: f32,
+    vel: f32,
+    width: f32,
+    noise: f32,
+}
+
+impl SynthParams {
+    fn new(osc_type: OscillatorType) -> Self {
+        Self {
+            detune: if matches!(osc_type, OscillatorType::DarkAmbience) { 12.0 } else { 0.1 },
+            pulse_width: 0.5,
+            depth: 1.0,
+            divisor: 2.0,
+            mod_phase: 1.0,
+            mod_range: 5.0,
+            mod_pulse_width: 0.5,
+            mod_phase_offset: 0.0,
+            mod_wave: 0.0,
+            wave: if matches!(osc_type, OscillatorType::Zawa) { 3.0 } else { 0.0 },
+            phase: 1.0,
+            coef: 0.3,
+            vel: 0.8,
+            width: 0.0,
+            noise: 0.0,
         }
     }
+
+    /// Set `name` if it is one of these params
+    fn set(&mut self, name: &str, value: f32) {
+        let field = match name {
+            "detune" => &mut self.detune,
+            "pulse_width" => &mut self.pulse_width,
+            "depth" => &mut self.depth,
+            "divisor" => &mut self.divisor,
+            "mod_phase" => &mut self.mod_phase,
+            "mod_range" => &mut self.mod_range,
+            "mod_pulse_width" => &mut self.mod_pulse_width,
+            "mod_phase_offset" => &mut self.mod_phase_offset,
+            "mod_wave" => &mut self.mod_wave,
+            "wave" => &mut self.wave,
+            "phase" => &mut self.phase,
+            "coef" => &mut self.coef,
+            "vel" => &mut self.vel,
+            "width" => &mut self.width,
+            "noise" => &mut self.noise,
+            _ => return,
+        };
+        *field = value;
+    }
+}
+
+/// Default `cutoff` (MIDI note) and `res` of each synth's SynthDef
+fn filter_defaults(osc_type: OscillatorType) -> (f32, f32) {
+    use OscillatorType::*;
+    match osc_type {
+        Noise => (110.0, 0.3),
+        SuperSaw => (130.0, 0.7),
+        TB303 => (100.0, 0.8),
+        Prophet => (110.0, 0.7),
+        Zawa => (100.0, 0.9),
+        Blade => (100.0, 0.5),
+        TechSaws | Hoover | Growl => (130.0, 0.3),
+        Hollow => (90.0, 0.99),
+        DarkAmbience => (90.0, 0.7),
+        _ => (100.0, 0.3),
+    }
 }
 
 pub struct SynthVoice {
@@ -86,21 +325,19 @@ pub struct SynthVoice {
     phase2: f32,
     // Noise state
     noise_state: u32,
-    // Pulse width
-    pulse_width: f32,
-    // FM modulation index & ratio
-    mod_index: f32,
-    mod_ratio: f32,
+    // Named params from `play` / `control`
+    params: SynthParams,
+    // FM modulator phase
     mod_phase: f32,
-    // Filter state (for TB303, Blade, etc.)
+    // Resonant filter: cutoff in Hz, `res` as the SynthDefs take it (the
+    // filter's rq), and the filter's state
     filter_cutoff: f32,
     filter_resonance: f32,
-    filter_lp: f32,
-    filter_bp: f32,
-    filter_hp: f32,
-    // Pluck / Karplus-Strong buffer
+    filter_state: [f32; 2],
+    // Pluck / Karplus-Strong buffer and its damping filter
     pluck_buffer: Vec<f32>,
     pluck_pos: usize,
+    pluck_lp: f32,
     // Brown noise accumulator
     brown_acc: f32,
     // Pink noise state (Voss-McCartney)
@@ -112,6 +349,11 @@ pub struct SynthVoice {
     lfo_rate: f32,
     // Sample counter for time-dependent synthesis
     sample_count: u64,
+    // Values changed by `control`, gliding per their `*_slide` times
+    freq_slide: Slide,
+    amp_slide: Slide,
+    cutoff_slide: Option<Slide>,
+    res_slide: Option<Slide>,
 }
 
 impl SynthVoice {
@@ -140,35 +382,13 @@ impl SynthVoice {
             *s = (rng as f32 / u32::MAX as f32) * 2.0 - 1.0;
         }
 
-        // Determine FM parameters based on synth type
-        let (mod_index, mod_ratio) = match osc_type {
-            OscillatorType::FM => (5.0, 1.0),
-            OscillatorType::ModFM => (8.0, 2.0),
-            _ => (1.0, 1.0),
-        };
-
         let lfo_rate = match osc_type {
-            OscillatorType::ModSine | OscillatorType::ModSaw | OscillatorType::ModTri
-            | OscillatorType::ModPulse | OscillatorType::ModDSaw => 6.0,
-            OscillatorType::Zawa => 1.0,
             OscillatorType::Growl => 8.0,
             _ => 5.0,
         };
 
-        // Filter cutoff varies by synth type
-        let (filter_cutoff, filter_resonance) = match osc_type {
-            OscillatorType::TB303 => (800.0, 0.8),
-            OscillatorType::Blade => (2000.0, 0.3),
-            OscillatorType::Hollow => (600.0, 0.9),
-            OscillatorType::DarkAmbience => (300.0, 0.5),
-            _ => (5000.0, 0.0),
-        };
-
-        let pulse_width = match osc_type {
-            OscillatorType::SubPulse => 0.5,
-            OscillatorType::Prophet => 0.3,
-            _ => 0.5,
-        };
+        let (cutoff, res) = filter_defaults(osc_type);
+        let filter_cutoff = Self::cutoff_hz(cutoff, sample_rate);
 
         Self {
             osc_type,
@@ -181,17 +401,14 @@ impl SynthVoice {
             detune_amounts,
             phase2: 0.0,
             noise_state: 12345,
-            pulse_width,
-            mod_index,
-            mod_ratio,
+            params: SynthParams::new(osc_type),
             mod_phase: 0.0,
             filter_cutoff,
-            filter_resonance,
-            filter_lp: 0.0,
-            filter_bp: 0.0,
-            filter_hp: 0.0,
+            filter_resonance: res,
+            filter_state: [0.0; 2],
             pluck_buffer,
             pluck_pos: 0,
+            pluck_lp: 0.0,
             brown_acc: 0.0,
             pink_rows: [0.0; 16],
             pink_index: 0,
@@ -199,11 +416,63 @@ impl SynthVoice {
             lfo_phase: 0.0,
             lfo_rate,
             sample_count: 0,
+            freq_slide: Slide::new(frequency),
+            amp_slide: Slide::new(amplitude),
+            cutoff_slide: None,
+            res_slide: None,
+        }
+    }
+
+    /// Set the synth's named params (`play :c2, cutoff: 70, res: 0.9`) as
+    /// the matching SynthDef takes them: `cutoff` is a MIDI note. Params the
+    /// synth does not have, and `*_slide` times, are ignored.
+    pub fn set_params(&mut self, params: &[(String, f32)]) {
+        for (name, value) in params {
+            match name.as_str() {
+                "cutoff" => self.filter_cutoff = Self::cutoff_hz(*value, self.sample_rate),
+                "res" => self.filter_resonance = *value,
+                name => self.params.set(name, *value),
+            }
+        }
+    }
+
+    /// Change parameters of the sounding voice (`control`). `freq`, `amp`,
+    /// `cutoff` (MIDI note) and `res` glide over their `*_slide` time; other
+    /// synth params change at once.
+    pub fn control(&mut self, params: &[(String, f32)]) {
+        let sr = self.sample_rate;
+        for (name, value) in params {
+            if !matches!(name.as_str(), "freq" | "amp" | "cutoff" | "res") && !name.ends_with("_slide") {
+                self.params.set(name, *value);
+            }
+        }
+        self.freq_slide.apply("freq", params, sr);
+        self.amp_slide.apply("amp", params, sr);
+        if params.iter().any(|(n, _)| n.starts_with("cutoff")) {
+            let cutoff = self.cutoff_slide.get_or_insert(Slide::new(self.filter_cutoff));
+            let hz: Vec<(String, f32)> = params
+                .iter()
+                .map(|(n, v)| if n == "cutoff" { (n.clone(), Self::cutoff_hz(*v, sr)) } else { (n.clone(), *v) })
+                .collect();
+            cutoff.apply("cutoff", &hz, sr);
+        }
+        if params.iter().any(|(n, _)| n.starts_with("res")) {
+            let res = self.res_slide.get_or_insert(Slide::new(self.filter_resonance));
+            res.apply("res", params, sr);
         }
     }
 
     pub fn next_sample(&mut self) -> f32 {
         self.sample_count += 1;
+        self.frequency = self.freq_slide.next();
+        self.amplitude = self.amp_slide.next();
+        if let Some(cutoff) = &mut self.cutoff_slide {
+            self.filter_cutoff = cutoff.next();
+        }
+        if let Some(res) = &mut self.res_slide {
+            self.filter_resonance = res.next();
+        }
+        self.modulate_frequency();
         let sample = match self.osc_type {
             OscillatorType::Sine => self.sine(),
             OscillatorType::Saw => self.saw(),
@@ -215,13 +484,12 @@ impl SynthVoice {
             OscillatorType::DSaw => self.detuned_saw(),
             OscillatorType::DPulse => self.detuned_pulse(),
             OscillatorType::DTri => self.detuned_tri(),
-            OscillatorType::FM => self.fm_synth(),
-            OscillatorType::ModFM => self.fm_synth(),
-            OscillatorType::ModSine => self.mod_sine(),
-            OscillatorType::ModSaw => self.mod_saw(),
-            OscillatorType::ModDSaw => self.mod_dsaw(),
-            OscillatorType::ModTri => self.mod_tri(),
-            OscillatorType::ModPulse => self.mod_pulse(),
+            OscillatorType::FM | OscillatorType::ModFM => self.fm_synth(),
+            OscillatorType::ModSine => self.sine(),
+            OscillatorType::ModSaw => self.saw(),
+            OscillatorType::ModDSaw => self.detuned_saw(),
+            OscillatorType::ModTri => self.triangle(),
+            OscillatorType::ModPulse => self.pulse_wave(self.params.mod_pulse_width),
             OscillatorType::TB303 => self.tb303(),
             OscillatorType::Prophet => self.prophet(),
             OscillatorType::Zawa => self.zawa(),
@@ -244,27 +512,78 @@ impl SynthVoice {
             OscillatorType::CNoise => self.clip_noise(),
             OscillatorType::SubPulse => self.sub_pulse(),
         };
+        let sample = match self.lowpass_rq() {
+            Some(rq) => self.svf(sample, self.filter_cutoff, rq).0,
+            None => sample,
+        };
         sample * self.amplitude
     }
 
-    pub fn envelope_value(&self, samples_elapsed: u64, total_samples: u64) -> f32 {
-        let t = samples_elapsed as f32 / self.sample_rate;
-        let total_t = total_samples as f32 / self.sample_rate;
-        let release_start = total_t - self.envelope.release;
+    /// `rq` of the `RLPF` the synth's SynthDef puts after its oscillators,
+    /// if it has one
+    fn lowpass_rq(&self) -> Option<f32> {
+        use OscillatorType::*;
+        match self.osc_type {
+            Saw | Square | Pulse | SuperSaw | DSaw | DPulse | ModSaw | ModDSaw | ModPulse | Prophet | Zawa
+            | Blade | TechSaws | DarkAmbience | SubPulse => Some(self.filter_resonance.clamp(0.01, 0.99)),
+            Noise | Growl => Some(0.3),
+            Hoover => Some(0.5),
+            _ => None,
+        }
+    }
+
+    /// `cutoff.midicps`, kept below Nyquist as the SynthDefs do
+    fn cutoff_hz(cutoff: f32, sample_rate: f32) -> f32 {
+        midi_to_freq_f32(cutoff).min(sample_rate * 0.45)
+    }
 
-        if t < self.envelope.attack {
-            t / self.envelope.attack
-        } else if t < self.envelope.attack + self.envelope.decay {
-            let decay_t = (t - self.envelope.attack) / self.envelope.decay;
-            1.0 - (1.0 - self.envelope.sustain) * decay_t
-        } else if t < release_start {
-            self.envelope.sustain
-        } else {
-            let release_t = (t - release_start) / self.envelope.release;
-            self.envelope.sustain * (1.0 - release_t).max(0.0)
+    /// The mod_* synths' LFO: `mod_wave` 0 sine, 1 saw, 2 pulse, 3 triangle,
+    /// `mod_phase` cycles per second, in -1..1
+    fn mod_lfo(&mut self) -> f32 {
+        let phase = self.lfo_phase;
+        let offset = self.params.mod_phase_offset;
+        let v = match self.params.mod_wave.max(0.0) as usize {
+            0 => ((phase + offset / (2.0 * PI)) * 2.0 * PI).sin(),
+            1 => (phase + offset * 0.5).fract() * 2.0 - 1.0,
+            2 => if phase < self.params.mod_pulse_width { 1.0 } else { -1.0 },
+            _ => {
+                let p = (phase + offset * 0.25).fract();
+                if p < 0.25 { p * 4.0 } else if p < 0.75 { 2.0 - p * 4.0 } else { p * 4.0 - 4.0 }
+            }
+        };
+        self.lfo_phase += self.params.mod_phase / self.sample_rate;
+        if self.lfo_phase >= 1.0 {
+            self.lfo_phase -= 1.0;
+        }
+        v
+    }
+
+    /// Pitch modulation of the mod_* synths for this sample
+    fn modulate_frequency(&mut self) {
+        use OscillatorType::*;
+        match self.osc_type {
+            ModSine | ModSaw | ModDSaw | ModTri | ModPulse => {
+                let lfo = self.mod_lfo();
+                self.frequency *= 1.0 + lfo * self.params.mod_range * 0.01;
+            }
+            ModFM => {
+                let lfo = self.mod_lfo();
+                self.frequency *= 1.0 + (lfo + 1.0) * 0.5 * (self.params.mod_range - 1.0);
+            }
+            _ => {}
         }
     }
 
+    /// Current amplitude, after any `amp` slide
+    pub fn amplitude(&self) -> f32 {
+        self.amplitude
+    }
+
+    /// Envelope level after `samples_elapsed` samples
+    pub fn envelope_value(&self, samples_elapsed: u64) -> f32 {
+        self.envelope.level(samples_elapsed as f32 / self.sample_rate)
+    }
+
     // ──────────────── Helpers ────────────────
 
     fn advance_phase(&mut self) {
@@ -298,13 +617,20 @@ impl SynthVoice {
         prev + alpha * (input - prev)
     }
 
-    /// State-variable filter (LP/BP/HP) - updates internal state
-    fn svf_tick(&mut self, input: f32) {
-        let f = 2.0 * (PI * self.filter_cutoff / self.sample_rate).sin();
-        let q = 1.0 - self.filter_resonance.min(0.99);
-        self.filter_lp += f * self.filter_bp;
-        self.filter_hp = input - self.filter_lp - q * self.filter_bp;
-        self.filter_bp += f * self.filter_hp;
+    /// Resonant state-variable filter (trapezoidal, stable up to Nyquist).
+    /// `rq` is the reciprocal of Q, as `RLPF` / `BPF` take it. Returns the
+    /// low-pass and the unity-peak band-pass outputs.
+    fn svf(&mut self, input: f32, cutoff: f32, rq: f32) -> (f32, f32) {
+        let g = (PI * cutoff.min(self.sample_rate * 0.49) / self.sample_rate).tan();
+        let a1 = 1.0 / (1.0 + g * (g + rq));
+        let a2 = g * a1;
+        let a3 = g * a2;
+        let [ic1, ic2] = self.filter_state;
+        let v3 = input - ic2;
+        let v1 = a1 * ic1 + a2 * v3;
+        let v2 = ic2 + a2 * ic1 + a3 * v3;
+        self.filter_state = [2.0 * v1 - ic1, 2.0 * v2 - ic2];
+        (v2, v1 * rq)
     }
 
     // ──────────────── PolyBLEP Anti-aliasing ────────────────
@@ -367,10 +693,14 @@ impl SynthVoice {
     }
 
     fn pulse(&mut self) -> f32 {
+        self.pulse_wave(self.params.pulse_width)
+    }
+
+    fn pulse_wave(&mut self, width: f32) -> f32 {
         let dt = self.frequency / self.sample_rate;
-        let mut s = if self.phase < self.pulse_width { 1.0 } else { -1.0 };
+        let mut s = if self.phase < width { 1.0 } else { -1.0 };
         s += Self::poly_blep(self.phase, dt);
-        s -= Self::poly_blep((self.phase + (1.0 - self.pulse_width)) % 1.0, dt);
+        s -= Self::poly_blep((self.phase + (1.0 - width)) % 1.0, dt);
         self.advance_phase();
         s
     }
@@ -394,10 +724,15 @@ impl SynthVoice {
 
     // ──────────────── Detuned Oscillators ────────────────
 
+    /// Frequency ratio of the second oscillator of the d* synths
+    fn detune_ratio(&self) -> f32 {
+        1.0 + self.params.detune * 0.01
+    }
+
     /// :dsaw - two detuned saw oscillators (band-limited)
     fn detuned_saw(&mut self) -> f32 {
         let dt1 = self.frequency / self.sample_rate;
-        let dt2 = self.frequency * 1.005 / self.sample_rate;
+        let dt2 = self.frequency * self.detune_ratio() / self.sample_rate;
         let mut s1 = 2.0 * self.phase - 1.0;
         s1 -= Self::poly_blep(self.phase, dt1);
         let mut s2 = 2.0 * self.phase2 - 1.0;
@@ -411,13 +746,13 @@ impl SynthVoice {
     /// :dpulse - two detuned pulse oscillators (band-limited)
     fn detuned_pulse(&mut self) -> f32 {
         let dt1 = self.frequency / self.sample_rate;
-        let dt2 = self.frequency * 1.005 / self.sample_rate;
-        let mut s1 = if self.phase < self.pulse_width { 1.0 } else { -1.0 };
+        let dt2 = self.frequency * self.detune_ratio() / self.sample_rate;
+        let mut s1 = if self.phase < 0.5 { 1.0 } else { -1.0 };
         s1 += Self::poly_blep(self.phase, dt1);
-        s1 -= Self::poly_blep((self.phase + (1.0 - self.pulse_width)) % 1.0, dt1);
-        let mut s2 = if self.phase2 < self.pulse_width { 1.0 } else { -1.0 };
+        s1 -= Self::poly_blep((self.phase + 0.5) % 1.0, dt1);
+        let mut s2 = if self.phase2 < 0.5 { 1.0 } else { -1.0 };
         s2 += Self::poly_blep(self.phase2, dt2);
-        s2 -= Self::poly_blep((self.phase2 + (1.0 - self.pulse_width)) % 1.0, dt2);
+        s2 -= Self::poly_blep((self.phase2 + 0.5) % 1.0, dt2);
         self.advance_phase();
         self.phase2 += dt2;
         if self.phase2 >= 1.0 { self.phase2 -= 1.0; }
@@ -430,87 +765,52 @@ impl SynthVoice {
         let s1 = tri(self.phase);
         let s2 = tri(self.phase2);
         self.advance_phase();
-        self.phase2 += self.frequency * 1.005 / self.sample_rate;
+        self.phase2 += self.frequency * self.detune_ratio() / self.sample_rate;
         if self.phase2 >= 1.0 { self.phase2 -= 1.0; }
         (s1 + s2) * 0.5
     }
 
     // ──────────────── FM Synthesis ────────────────
 
-    /// :fm / :mod_fm - basic FM synthesis
+    /// :fm / :mod_fm - a sine carrier whose frequency swings by `depth`
+    /// times the modulator's, the modulator at `freq / divisor`
     fn fm_synth(&mut self) -> f32 {
-        let modulator = (self.mod_phase * 2.0 * PI).sin();
-        let carrier_phase = self.phase + self.mod_index * modulator;
-        let s = (carrier_phase * 2.0 * PI).sin();
-        self.advance_phase();
-        self.mod_phase += self.frequency * self.mod_ratio / self.sample_rate;
-        if self.mod_phase >= 1.0 { self.mod_phase -= 1.0; }
-        s
-    }
-
-    // ──────────────── Modulated Oscillators ────────────────
-
-    /// :mod_sine - sine with tremolo LFO
-    fn mod_sine(&mut self) -> f32 {
-        let lfo = self.advance_lfo();
+        let mod_freq = self.frequency / self.params.divisor.max(0.001);
+        let modulator = (self.mod_phase * 2.0 * PI).sin() * self.params.depth * mod_freq;
         let s = (self.phase * 2.0 * PI).sin();
-        self.advance_phase();
-        s * (0.7 + 0.3 * lfo)
-    }
-
-    /// :mod_saw - band-limited saw with tremolo LFO
-    fn mod_saw(&mut self) -> f32 {
-        let lfo = self.advance_lfo();
-        let dt = self.frequency / self.sample_rate;
-        let mut s = 2.0 * self.phase - 1.0;
-        s -= Self::poly_blep(self.phase, dt);
-        self.advance_phase();
-        s * (0.7 + 0.3 * lfo)
-    }
-
-    /// :mod_dsaw - detuned saw with tremolo
-    fn mod_dsaw(&mut self) -> f32 {
-        let lfo = self.advance_lfo();
-        let s = self.detuned_saw();
-        s * (0.7 + 0.3 * lfo)
-    }
-
-    /// :mod_tri - triangle with tremolo
-    fn mod_tri(&mut self) -> f32 {
-        let lfo = self.advance_lfo();
-        let tri = if self.phase < 0.5 { 4.0 * self.phase - 1.0 } else { 3.0 - 4.0 * self.phase };
-        self.advance_phase();
-        tri * (0.7 + 0.3 * lfo)
-    }
-
-    /// :mod_pulse - band-limited pulse with PWM via LFO
-    fn mod_pulse(&mut self) -> f32 {
-        let lfo = self.advance_lfo();
-        let pw = 0.5 + 0.3 * lfo; // modulate pulse width
-        let dt = self.frequency / self.sample_rate;
-        let mut s = if self.phase < pw { 1.0 } else { -1.0 };
-        s += Self::poly_blep(self.phase, dt);
-        s -= Self::poly_blep((self.phase + (1.0 - pw)) % 1.0, dt);
-        self.advance_phase();
+        self.phase = (self.phase + (self.frequency + modulator) / self.sample_rate).rem_euclid(1.0);
+        self.mod_phase += mod_freq / self.sample_rate;
+        if self.mod_phase >= 1.0 { self.mod_phase -= 1.0; }
         s
     }
 
     // ──────────────── Classic Synths ────────────────
 
-    /// :tb303 - acid bass: band-limited saw through resonant low-pass filter
+    /// :tb303 - acid bass: saw (`wave: 0`) or square (`wave: 1`) through a
+    /// resonant low-pass that falls from twice to half the cutoff
     fn tb303(&mut self) -> f32 {
-        // Envelope modulates filter cutoff
-        let t = self.sample_count as f32 / self.sample_rate;
-        let env_mod = (-t * 4.0).exp();
-        let cutoff = self.filter_cutoff + 3000.0 * env_mod;
-        self.filter_cutoff = cutoff.min(18000.0);
-
         let dt = self.frequency / self.sample_rate;
-        let mut raw = 2.0 * self.phase - 1.0; // saw
-        raw -= Self::poly_blep(self.phase, dt);
+        let raw = if self.params.wave >= 1.0 {
+            let mut s = if self.phase < 0.5 { 1.0 } else { -1.0 };
+            s += Self::poly_blep(self.phase, dt);
+            s - Self::poly_blep((self.phase + 0.5) % 1.0, dt)
+        } else {
+            2.0 * self.phase - 1.0 - Self::poly_blep(self.phase, dt)
+        };
         self.advance_phase();
-        self.svf_tick(raw);
-        self.filter_lp
+
+        // Env.perc(0.001, release * 2) scaled from cutoff * 2 down to cutoff * 0.5
+        let t = self.sample_count as f32 / self.sample_rate;
+        let fall = (self.envelope.release * 2.0).max(0.001);
+        let env = if t < 0.001 {
+            t / 0.001
+        } else {
+            let x = ((t - 0.001) / fall).min(1.0);
+            1.0 - (1.0 - (-4.0 * x).exp()) / (1.0 - (-4.0f32).exp())
+        };
+        let cutoff = self.filter_cutoff * (0.5 + 2.0 * env);
+        let rq = self.filter_resonance.clamp(0.01, 0.99);
+        self.svf(raw, cutoff, rq).0
     }
 
     /// :prophet - rich poly synth: band-limited detuned saw + pulse, mixed
@@ -521,24 +821,35 @@ impl SynthVoice {
         saw1 -= Self::poly_blep(self.phase, dt1);
         let mut saw2 = 2.0 * self.phase2 - 1.0;
         saw2 -= Self::poly_blep(self.phase2, dt2);
-        let mut pulse_val = if self.phase < self.pulse_width { 1.0 } else { -1.0 };
+        let mut pulse_val = if self.phase < 0.4 { 1.0 } else { -1.0 };
         pulse_val += Self::poly_blep(self.phase, dt1);
-        pulse_val -= Self::poly_blep((self.phase + (1.0 - self.pulse_width)) % 1.0, dt1);
+        pulse_val -= Self::poly_blep((self.phase + 0.6) % 1.0, dt1);
         self.advance_phase();
         self.phase2 += dt2;
         if self.phase2 >= 1.0 { self.phase2 -= 1.0; }
         saw1 * 0.4 + saw2 * 0.3 + pulse_val * 0.3
     }
 
-    /// :zawa - slowly evolving phase-modulated synth
+    /// :zawa - `wave` 0 is a sine phase-modulated at `phase` times the note
+    /// frequency; 1 saw, 2 square and 3 (the default) triangle
     fn zawa(&mut self) -> f32 {
-        let lfo = self.advance_lfo();
-        let mod_depth = 2.0 + 2.0 * lfo;
-        let modulator = (self.mod_phase * 2.0 * PI).sin();
-        let s = ((self.phase + mod_depth * modulator) * 2.0 * PI).sin();
+        let dt = self.frequency / self.sample_rate;
+        let s = match self.params.wave.max(0.0) as usize {
+            0 => {
+                let modulator = (self.mod_phase * 2.0 * PI).sin();
+                self.mod_phase += self.frequency * self.params.phase / self.sample_rate;
+                self.mod_phase -= self.mod_phase.floor();
+                (self.phase * 2.0 * PI + modulator * 2.0 * PI).sin()
+            }
+            1 => 2.0 * self.phase - 1.0 - Self::poly_blep(self.phase, dt),
+            2 => {
+                let mut s = if self.phase < 0.5 { 1.0 } else { -1.0 };
+                s += Self::poly_blep(self.phase, dt);
+                s - Self::poly_blep((self.phase + 0.5) % 1.0, dt)
+            }
+            _ => if self.phase < 0.5 { 4.0 * self.phase - 1.0 } else { 3.0 - 4.0 * self.phase },
+        };
         self.advance_phase();
-        self.mod_phase += self.frequency * 0.5 / self.sample_rate;
-        if self.mod_phase >= 1.0 { self.mod_phase -= 1.0; }
         s
     }
 
@@ -558,9 +869,7 @@ impl SynthVoice {
             sum += s;
         }
         self.advance_phase();
-        let raw = sum / 3.0;
-        self.svf_tick(raw);
-        self.filter_lp * 0.7 + self.filter_bp * 0.3
+        sum / 3.0
     }
 
     /// :tech_saws - multiple detuned band-limited saws for trance/tech leads
@@ -603,18 +912,20 @@ impl SynthVoice {
 
     // ──────────────── Plucked / Percussive ────────────────
 
-    /// :pluck - Karplus-Strong plucked string
+    /// :pluck - Karplus-Strong plucked string. `coef` sets the damping
+    /// filter in the loop; the string rings for `release * 5` seconds.
     fn pluck(&mut self) -> f32 {
         if self.pluck_buffer.is_empty() {
             return 0.0;
         }
         let len = self.pluck_buffer.len();
         let out = self.pluck_buffer[self.pluck_pos];
-        let next_pos = (self.pluck_pos + 1) % len;
-        // Averaging filter for decay
-        let avg = (self.pluck_buffer[self.pluck_pos] + self.pluck_buffer[next_pos]) * 0.499;
-        self.pluck_buffer[self.pluck_pos] = avg;
-        self.pluck_pos = next_pos;
+        let coef = self.params.coef.clamp(-0.999, 0.999);
+        self.pluck_lp = (1.0 - coef.abs()) * out + coef * self.pluck_lp;
+        let decay = (self.envelope.release * 5.0).max(0.001);
+        let feedback = 0.001f32.powf(len as f32 / self.sample_rate / decay);
+        self.pluck_buffer[self.pluck_pos] = self.pluck_lp * feedback;
+        self.pluck_pos = (self.pluck_pos + 1) % len;
         out
     }
 
@@ -639,7 +950,7 @@ impl SynthVoice {
             let _ = phase_inc; // phase advance handled below
         }
         self.advance_phase();
-        s
+        s * self.params.vel
     }
 
     /// :pretty_bell - bright bell with inharmonic partials
@@ -682,29 +993,33 @@ impl SynthVoice {
 
     // ──────────────── Pads / Ambient ────────────────
 
-    /// :hollow - hollow pad: bandpass filtered mix of sine + noise
+    /// :hollow - hollow pad: sine and pink noise through a band-pass at the
+    /// note, narrower as `res` goes up
     fn hollow(&mut self) -> f32 {
-        let lfo = self.advance_lfo();
         let sine_part = (self.phase * 2.0 * PI).sin();
-        let noise_part = self.xorshift();
+        let noise_part = self.pink_noise();
         self.advance_phase();
-        let raw = sine_part * 0.6 + noise_part * 0.15;
-        // Modulate cutoff slightly with LFO
-        self.filter_cutoff = 600.0 + 200.0 * lfo;
-        self.svf_tick(raw);
-        self.filter_bp
+        let raw = sine_part + noise_part * 0.3;
+        let rq = 1.0 - self.filter_resonance.clamp(0.01, 0.99);
+        self.svf(raw, self.frequency, rq).1 * 4.0
     }
 
-    /// :dark_ambience - dark ambient pad: filtered noise + sub sine
+    /// :dark_ambience - dark ambient pad: saws `detune` apart, a sub sine
+    /// and pink noise (`noise` adds more)
     fn dark_ambience(&mut self) -> f32 {
-        let lfo = self.advance_lfo();
-        let noise_part = self.xorshift();
-        let sub = (self.phase * 2.0 * PI).sin();
+        let spread = self.params.detune * 0.001;
+        let dt1 = self.frequency * (1.0 + spread) / self.sample_rate;
+        let dt2 = self.frequency * (1.0 - spread) / self.sample_rate;
+        self.phases[0] = (self.phases[0] + dt1).fract();
+        self.phases[1] = (self.phases[1] + dt2).fract();
+        let saws = (2.0 * self.phases[0] - 1.0 - Self::poly_blep(self.phases[0], dt1))
+            + (2.0 * self.phases[1] - 1.0 - Self::poly_blep(self.phases[1], dt2));
+        let sub = (self.phase2 * 2.0 * PI).sin();
+        self.phase2 = (self.phase2 + self.frequency * 0.5 / self.sample_rate).fract();
+        let noise_level = 0.08 + self.params.noise * 0.1;
+        let noise = self.pink_noise();
         self.advance_phase();
-        let raw = noise_part * 0.4 + sub * 0.5;
-        self.filter_cutoff = 300.0 + 100.0 * lfo;
-        self.svf_tick(raw);
-        self.filter_lp * 0.8
+        saws * 0.3 + sub * 0.2 + noise * noise_level
     }
 
     /// :growl - growling bass: band-limited saw modulated by LFO at audio rate
@@ -726,11 +1041,7 @@ impl SynthVoice {
 
     /// :chiplead - quantized band-limited square wave (lo-fi chiptune lead)
     fn chip_lead(&mut self) -> f32 {
-        let dt = self.frequency / self.sample_rate;
-        let mut raw = if self.phase < 0.5 { 1.0f32 } else { -1.0 };
-        raw += Self::poly_blep(self.phase, dt);
-        raw -= Self::poly_blep((self.phase + 0.5) % 1.0, dt);
-        self.advance_phase();
+        let raw = self.pulse_wave(self.params.width * 0.5 + 0.5);
         // Quantize to 4-bit
         (raw * 8.0).round() / 8.0
     }
@@ -803,9 +1114,9 @@ impl SynthVoice {
     /// :subpulse - band-limited pulse wave with sub-octave added
     fn sub_pulse(&mut self) -> f32 {
         let dt = self.frequency / self.sample_rate;
-        let mut main_pulse = if self.phase < self.pulse_width { 1.0 } else { -1.0 };
+        let mut main_pulse = if self.phase < 0.5 { 1.0 } else { -1.0 };
         main_pulse += Self::poly_blep(self.phase, dt);
-        main_pulse -= Self::poly_blep((self.phase + (1.0 - self.pulse_width)) % 1.0, dt);
+        main_pulse -= Self::poly_blep((self.phase + 0.5) % 1.0, dt);
         let sub = (self.phase2 * 2.0 * PI).sin();
         self.advance_phase();
         self.phase2 += (self.frequency * 0.5) / self.sample_rate;
@@ -819,6 +1130,11 @@ pub fn midi_to_freq(note: u8) -> f32 {
     440.0 * 2.0f32.powf((note as f32 - 69.0) / 12.0)
 }
 
+/// Convert a fractional MIDI note to frequency
+pub fn midi_to_freq_f32(note: f32) -> f32 {
+    440.0 * 2.0f32.powf((note - 69.0) / 12.0)
+}
+
 /// Convert note name to MIDI number  
 pub fn note_name_to_midi(name: &str) -> Option<u8> {
     let name = name.trim().to_uppercase();
@@ -858,3 +1174,96 @@ pub fn note_name_to_midi(name: &str) -> Option<u8> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SR: f32 = 44100.0;
+
+    fn voice(osc_type: OscillatorType, freq: f32, params: &[(&str, f32)]) -> SynthVoice {
+        let mut voice = SynthVoice::new(osc_type, freq, 1.0, SR, Envelope::default());
+        voice.set_params(&owned(params));
+        voice
+    }
+
+    fn owned(params: &[(&str, f32)]) -> Vec<(String, f32)> {
+        params.iter().map(|&(name, value)| (name.to_string(), value)).collect()
+    }
+
+    fn run(voice: &mut SynthVoice, frames: usize) -> Vec<f32> {
+        (0..frames).map(|_| voice.next_sample()).collect()
+    }
+
+    /// Mean size of the second difference: mostly the high end
+    fn brightness(samples: &[f32]) -> f32 {
+        samples.windows(3).map(|w| (w[2] - 2.0 * w[1] + w[0]).abs()).sum::<f32>() / samples.len() as f32
+    }
+
+    #[test]
+    fn test_cutoff_is_a_midi_note() {
+        let v = voice(OscillatorType::TB303, 65.0, &[("cutoff", 69.0), ("res", 0.9)]);
+        assert!((v.filter_cutoff - 440.0).abs() < 1e-3);
+        assert_eq!(v.filter_resonance, 0.9);
+        // Never above Nyquist
+        let v = voice(OscillatorType::TB303, 65.0, &[("cutoff", 200.0)]);
+        assert_eq!(v.filter_cutoff, SR * 0.45);
+    }
+
+    #[test]
+    fn test_lower_cutoff_is_darker() {
+        let open = brightness(&run(&mut voice(OscillatorType::Saw, 220.0, &[]), 4410));
+        let closed = brightness(&run(&mut voice(OscillatorType::Saw, 220.0, &[("cutoff", 60.0)]), 4410));
+        assert!(closed < open * 0.5, "{} vs {}", closed, open);
+    }
+
+    #[test]
+    fn test_pulse_width_sets_the_duty_cycle() {
+        let out = run(&mut voice(OscillatorType::Pulse, 441.0, &[("pulse_width", 0.25)]), 44100);
+        let mean = out.iter().sum::<f32>() / out.len() as f32;
+        assert!((mean + 0.5).abs() < 0.05, "mean {}", mean);
+    }
+
+    #[test]
+    fn test_dsaw_without_detune_is_a_saw() {
+        let saw = run(&mut voice(OscillatorType::Saw, 220.0, &[]), 4410);
+        let dsaw = run(&mut voice(OscillatorType::DSaw, 220.0, &[("detune", 0.0)]), 4410);
+        assert!(saw.iter().zip(&dsaw).all(|(a, b)| (a - b).abs() < 1e-4));
+    }
+
+    #[test]
+    fn test_params_the_synth_lacks_are_ignored() {
+        let plain = run(&mut voice(OscillatorType::Prophet, 220.0, &[]), 4410);
+        let other = run(&mut voice(OscillatorType::Prophet, 220.0, &[("room", 1.0), ("cutoff_slide", 2.0)]), 4410);
+        assert_eq!(plain, other);
+    }
+
+    #[test]
+    fn test_cutoff_glides_over_its_slide_time() {
+        let mut v = voice(OscillatorType::TB303, 65.0, &[]);
+        let from = v.filter_cutoff;
+        v.control(&owned(&[("cutoff", 60.0), ("cutoff_slide", 1.0)]));
+        let to = midi_to_freq_f32(60.0);
+        run(&mut v, 22050);
+        assert!((v.filter_cutoff - (from + to) / 2.0).abs() < 1.0, "{}", v.filter_cutoff);
+        run(&mut v, 22050);
+        assert_eq!(v.filter_cutoff, to);
+    }
+
+    #[test]
+    fn test_every_synth_stays_finite() {
+        use OscillatorType::*;
+        let synths = [
+            Sine, Saw, Square, Triangle, Noise, Pulse, SuperSaw, DSaw, DPulse, DTri, FM, ModFM, ModSine, ModSaw,
+            ModDSaw, ModTri, ModPulse, TB303, Prophet, Zawa, Blade, TechSaws, Hoover, Pluck, Piano, PrettyBell,
+            DullBell, Hollow, DarkAmbience, Growl, ChipLead, ChipBass, ChipNoise, BNoise, PNoise, GNoise, CNoise,
+            SubPulse,
+        ];
+        for synth in synths {
+            for freq in [20.0, 440.0, 12_000.0] {
+                let out = run(&mut voice(synth, freq, &[("res", 0.99)]), 4410);
+                assert!(out.iter().all(|x| x.is_finite() && x.abs() < 10.0), "{:?} at {} Hz", synth, freq);
+            }
+        }
+    }
+}
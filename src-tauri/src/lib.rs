@@ -1,12 +1,19 @@
 mod audio;
 
-use audio::engine::{AudioCommand, AudioEngine};
-use audio::parser::{commands_to_audio, parse_code, ParsedCommand};
-use audio::recorder::Recorder;
+use audio::diagnostics::{check_code, Diagnostic, Severity};
+use audio::engine::{AudioCommand, AudioEngine, BeatClock};
+use audio::interpreter::{parse_program, Event, Interpreter, LoopControl, LoopStatus, Stmt};
+use audio::master::MeterReading;
+use audio::parser::{parse_code, ParsedCommand};
+use audio::recorder::{RecordFormat, Recorder};
 use audio::sample::{self, SampleInfo};
 use audio::synth::{Envelope, OscillatorType};
 use audio::sc_engine::{ScEngine, find_sc_bundle_dir};
 
+pub use audio::render;
+pub use audio::sample::{load_wav, SampleData};
+use audio::render::{RenderLength, StemExport, DEFAULT_RENDER_SAMPLE_RATE};
+
 
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
@@ -16,6 +23,15 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+/// How much of the program (seconds) the scheduler evaluates at a time. It
+/// starts on a chunk as soon as playback is within the dispatch lead of it.
+const SCHEDULE_CHUNK_SECS: f64 = 0.5;
+/// How early (seconds) events are handed to the built-in engine, which then
+/// starts each on its exact frame. Must cover an audio buffer or two.
+const CPAL_DISPATCH_AHEAD_SECS: f64 = 0.1;
+/// Beats a re-run is quantised to unless `run_code` is told otherwise
+const DEFAULT_LAUNCH_QUANTUM: f64 = 1.0;
+
 // Windows high-resolution timer (1ms precision for scheduler thread)
 #[cfg(target_os = "windows")]
 #[link(name = "winmm")]
@@ -31,12 +47,47 @@ struct AppState {
     sc_bundle_dir: Mutex<Option<PathBuf>>,
     recorder: Recorder,
     samples_dir: PathBuf,
-    loaded_samples: Mutex<HashMap<String, (Vec<f32>, u32)>>,
+    loaded_samples: Mutex<HashMap<String, SampleData>>,
     session_id: Mutex<u64>,
+    /// Scheduler currently playing, which re-runs hand their code to
+    live_run: Mutex<Option<LiveRun>>,
     log_messages: Mutex<Vec<LogEntry>>,
     user_samples_dir: Mutex<Option<PathBuf>>,
 }
 
+/// A playing session that accepts edited code without restarting
+struct LiveRun {
+    session: u64,
+    using_sc: bool,
+    /// When playback started, so the playhead carries on across re-runs
+    started: Instant,
+    /// Scheduler time (seconds) evaluated up to, counting the chunk being
+    /// evaluated now; re-run code can land no earlier
+    generated: f64,
+    /// Code from a re-run waiting to be swapped in
+    pending: Option<PendingCode>,
+    /// Transport actions on single live_loops, applied at the next chunk
+    controls: Vec<(String, LoopControl)>,
+    /// live_loops as of the last chunk
+    loops: Vec<LoopStatus>,
+}
+
+/// Re-run code and where it lands
+struct PendingCode {
+    program: Vec<Stmt>,
+    bpm: f32,
+    /// Scheduler time (seconds since playback started) to swap it in at
+    at: f64,
+}
+
+/// Per-run options from the frontend
+#[derive(Debug, Clone, Default, Deserialize)]
+struct RunOptions {
+    /// Beats to quantise the start of code run while something is playing:
+    /// 1 = next beat, 4 = next bar, 0 = as soon as possible
+    quantum: Option<f64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct LogEntry {
     timestamp: f64,
@@ -50,6 +101,10 @@ struct EngineStatus {
     master_volume: f32,
     bpm: f32,
     is_recording: bool,
+    /// Notes and samples sounding in the built-in engine
+    voice_count: usize,
+    /// Notes and samples the built-in engine cut short at its voice limit
+    stolen_voices: u64,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -57,9 +112,13 @@ struct RunResult {
     success: bool,
     message: String,
     logs: Vec<LogEntry>,
-    duration_estimate: f32,
     effective_bpm: f32,
+    /// Time from receiving the code to starting the scheduler (parse, checks, sample preload)
     setup_time_ms: f64,
+    /// How long playback had been running when this result was built, for playhead sync
+    playback_offset_ms: f64,
+    /// Problems found in the code, for the editor to mark
+    diagnostics: Vec<Diagnostic>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -77,8 +136,13 @@ pub struct UserSampleInfo {
 }
 
 #[tauri::command]
-fn run_code(code: String, state: tauri::State<Arc<AppState>>) -> Result<RunResult, String> {
+fn run_code(
+    code: String,
+    options: Option<RunOptions>,
+    state: tauri::State<Arc<AppState>>,
+) -> Result<RunResult, String> {
     let start = Instant::now();
+    let quantum = options.unwrap_or_default().quantum.unwrap_or(DEFAULT_LAUNCH_QUANTUM);
     let mut logs = Vec::new();
 
     // Log the code size
@@ -151,49 +215,49 @@ fn run_code(code: String, state: tauri::State<Arc<AppState>>) -> Result<RunResul
     let effective_bpm = parsed.iter()
         .find_map(|cmd| if let ParsedCommand::SetBpm(b) = cmd { Some(*b) } else { None })
         .unwrap_or(engine_bpm);
-    eprintln!("[run_code] Converting to audio commands at {} BPM (engine: {})...", effective_bpm, engine_bpm);
-    
-    // Convert to audio commands using the effective BPM
-    let convert_start = Instant::now();
-    let timed_commands = commands_to_audio(&parsed, effective_bpm);
-    let convert_elapsed = convert_start.elapsed();
-    eprintln!("[run_code] Generated {} timed commands in {:.1}ms",
-        timed_commands.len(), convert_elapsed.as_secs_f64() * 1000.0);
-
-    if timed_commands.len() > 50_000 {
-        let warn = format!("WARNING: {} commands generated - this may be slow. Consider adding 'stop' to live_loops.", timed_commands.len());
-        eprintln!("[run_code] {}", warn);
+    eprintln!("[run_code] Building interpreter at {} BPM (engine: {})...", effective_bpm, engine_bpm);
+
+    // Build the runtime program — loop bodies are evaluated fresh every iteration
+    let program = match parse_program(&code) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("[run_code] Interpreter error: {}", e);
+            logs.push(LogEntry {
+                timestamp: start.elapsed().as_secs_f64(),
+                level: "error".to_string(),
+                message: format!("Parse error: {}", e),
+            });
+            let mut log_store = state.log_messages.lock();
+            log_store.extend(logs.clone());
+            return Err(format!("Parse error: {}", e));
+        }
+    };
+
+    // Collect log messages from parsed commands
+    collect_logs(&parsed, &mut logs);
+
+    // Report code that is ignored or approximated instead of dropping it silently
+    let diagnostics = check_code(&code);
+    for diag in &diagnostics {
+        let level = match diag.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Info => "info",
+        };
         logs.push(LogEntry {
             timestamp: start.elapsed().as_secs_f64(),
-            level: "warning".to_string(),
-            message: warn,
+            level: level.to_string(),
+            message: format!("line {}:{}: {} ({})", diag.line, diag.column, diag.hint, diag.text),
         });
     }
-    
-    // Collect log messages from parsed commands
-    collect_logs(&parsed, &mut logs);
-
-    // Calculate total duration estimate (cap at 10 minutes)
-    let max_time = timed_commands.iter()
-        .map(|(t, _)| *t)
-        .filter(|t| *t <= 600.0)
-        .fold(0.0f32, f32::max);
-
-    // Start a new playback session by incrementing the session ID
-    // This invalidates all old scheduled threads from previous buffers
-    let current_session = {
-        let mut session = state.session_id.lock();
-        *session = session.wrapping_add(1);
-        *session
-    };
 
     // Check if we should use SuperCollider engine
     let using_sc = state.use_sc.load(Ordering::Relaxed);
 
-    // Track when the scheduler starts — used for playhead sync
-    // Updated right before spawning the scheduler thread so the frontend
-    // can offset the playhead by the time elapsed since scheduling began
-    let mut scheduler_started = start; // default to function start
+    // Code that is already playing on this engine is hot-swapped, not restarted
+    let hot_swap = state.live_run.lock().as_ref().is_some_and(|run| {
+        run.using_sc == using_sc && run.session == *state.session_id.lock()
+    });
 
     if using_sc {
         // ============================================================
@@ -208,7 +272,7 @@ fn run_code(code: String, state: tauri::State<Arc<AppState>>) -> Result<RunResul
 
         // Stop any previous playback before starting new code
         // This ensures clean state when switching buffers
-        {
+        if !hot_swap {
             let sc_stop = state.sc_engine.lock();
             if let Some(ref sc) = *sc_stop {
                 let _ = sc.stop_all();
@@ -238,226 +302,11 @@ fn run_code(code: String, state: tauri::State<Arc<AppState>>) -> Result<RunResul
                 return Err(format!("SC sample load error: {}", e));
             }
         }
-
-        // Schedule commands via SuperCollider OSC
-        eprintln!("[run_code] Scheduling {} commands via SuperCollider...", timed_commands.len());
-        let max_schedule_time = 600.0f32;
-        let mut scheduled_count = 0u32;
-
-        // Build sample name → buffer ID map for this run
-        let sample_names = collect_sample_names(&parsed);
-        let mut sample_idx = 0usize;
-
-        // Pre-process ALL events into a sorted schedule
-        // All events go through the single scheduler thread for consistent timing
-        enum ScEvent {
-            PlaySample { buf_id: i32, amp: f32, rate: f32, pan: f32 },
-            PlayNote { synth_type: OscillatorType, freq: f32, amp: f32, dur: f32, env: Envelope, pan: f32, params: Vec<(String, f32)> },
-            SetEffect { rm: f32, dt: f32, df: f32, dist: f32, lpf: f32, hpf: f32 },
-            FxStart { fx_type: String, params: Vec<(String, f32)> },
-            FxEnd,
-            SetBpm(f32),
-            SetVolume(f32),
-            Stop,
-        }
-
-        let mut all_events: Vec<(f32, ScEvent)> = Vec::new();
-
-        for (time_offset, cmd) in &timed_commands {
-            if *time_offset > max_schedule_time {
-                if let AudioCommand::PlaySample { .. } = cmd {
-                    sample_idx += 1;
-                }
-                continue;
-            }
-
-            match cmd {
-                AudioCommand::PlaySample { amplitude, rate, pan, .. } => {
-                    if sample_idx < sample_names.len() {
-                        let name = &sample_names[sample_idx];
-                        sample_idx += 1;
-                        let path = resolve_sample_path(name, &state.samples_dir);
-                        let path_str = path.to_string_lossy().to_string();
-
-                        let buf_id = {
-                            let loaded = sc.loaded_buffers.lock();
-                            loaded.get(&path_str).copied()
-                        };
-
-                        if let Some(buf_id) = buf_id {
-                            all_events.push((*time_offset, ScEvent::PlaySample {
-                                buf_id,
-                                amp: *amplitude,
-                                rate: *rate,
-                                pan: *pan,
-                            }));
-                            scheduled_count += 1;
-                        } else {
-                            eprintln!("[SC schedule] No buffer for sample '{}'", name);
-                        }
-                    }
-                }
-                AudioCommand::PlayNote { synth_type, frequency, amplitude, duration_secs, envelope, pan, ref params } => {
-                    all_events.push((*time_offset, ScEvent::PlayNote {
-                        synth_type: *synth_type,
-                        freq: *frequency,
-                        amp: *amplitude,
-                        dur: *duration_secs,
-                        env: *envelope,
-                        pan: *pan,
-                        params: params.clone(),
-                    }));
-                    scheduled_count += 1;
-                }
-                AudioCommand::SetEffect { reverb_mix, delay_time, delay_feedback, distortion, lpf_cutoff, hpf_cutoff } => {
-                    all_events.push((*time_offset, ScEvent::SetEffect {
-                        rm: *reverb_mix,
-                        dt: *delay_time,
-                        df: *delay_feedback,
-                        dist: *distortion,
-                        lpf: *lpf_cutoff,
-                        hpf: *hpf_cutoff,
-                    }));
-                    scheduled_count += 1;
-                }
-                AudioCommand::SetBpm(bpm_val) => {
-                    all_events.push((*time_offset, ScEvent::SetBpm(*bpm_val)));
-                    scheduled_count += 1;
-                }
-                AudioCommand::SetMasterVolume(vol) => {
-                    all_events.push((*time_offset, ScEvent::SetVolume(*vol)));
-                    scheduled_count += 1;
-                }
-                AudioCommand::FxStart { ref fx_type, ref params } => {
-                    all_events.push((*time_offset, ScEvent::FxStart {
-                        fx_type: fx_type.clone(),
-                        params: params.clone(),
-                    }));
-                    scheduled_count += 1;
-                }
-                AudioCommand::FxEnd => {
-                    all_events.push((*time_offset, ScEvent::FxEnd));
-                    scheduled_count += 1;
-                }
-                AudioCommand::Stop => {
-                    all_events.push((*time_offset, ScEvent::Stop));
-                    scheduled_count += 1;
-                }
-            }
-        }
-
-        // Drop the SC lock before spawning the scheduler thread
-        drop(sc_guard);
-
-        // Sort all events by time offset for sequential processing
-        all_events.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
-
-        let event_count = all_events.len();
-        eprintln!("[run_code] Scheduling {} SC events in single scheduler thread", event_count);
-
-        // Spawn a SINGLE scheduler thread for ALL events (including t=0)
-        // This ensures consistent timing — all events use the same time reference
-        if !all_events.is_empty() {
-            let state_clone = Arc::clone(&*state);
-            // Capture the reference time BEFORE spawning — pass it to the thread
-            // so both the thread and the setup_time_ms use the same reference point
-            let schedule_ref = Instant::now();
-            scheduler_started = schedule_ref;
-            std::thread::spawn(move || {
-                // Set Windows timer resolution to 1ms for precise scheduling
-                #[cfg(target_os = "windows")]
-                unsafe {
-                    timeBeginPeriod(1);
-                }
-
-                let start_time = schedule_ref;
-
-                for (target_time, evt) in all_events {
-                    // Check if session is still valid
-                    if *state_clone.session_id.lock() != current_session {
-                        eprintln!("[SC scheduler] Session cancelled, stopping scheduler");
-                        #[cfg(target_os = "windows")]
-                        unsafe { timeEndPeriod(1); }
-                        return;
-                    }
-
-                    // Wait until the target time using high-precision timing
-                    let elapsed = start_time.elapsed().as_secs_f64();
-                    let target = target_time as f64;
-                    let wait = target - elapsed;
-                    if wait > 0.0005 {
-                        // Windows thread::sleep has ~15.6ms granularity by default.
-                        // Use coarse sleep + spin-wait for precision.
-                        if wait > 0.020 {
-                            // Sleep for most of the time, leaving 18ms margin for spin-wait
-                            let coarse = Duration::from_secs_f64((wait - 0.018).max(0.0));
-                            std::thread::sleep(coarse);
-                        }
-                        // Spin-wait for the remaining time (up to ~18ms on Windows)
-                        while (start_time.elapsed().as_secs_f64()) < target {
-                            std::hint::spin_loop();
-                        }
-                    }
-
-                    // Re-check session after sleeping
-                    if *state_clone.session_id.lock() != current_session {
-                        #[cfg(target_os = "windows")]
-                        unsafe { timeEndPeriod(1); }
-                        return;
-                    }
-
-                    // Execute the event
-                    let sc_lock = state_clone.sc_engine.lock();
-                    if let Some(ref sc) = *sc_lock {
-                        match evt {
-                            ScEvent::PlaySample { buf_id, amp, rate, pan } => {
-                                if let Err(e) = sc.play_sample_buffer(buf_id, amp, rate, pan) {
-                                    eprintln!("[SC scheduler] sample play failed: {}", e);
-                                }
-                            }
-                            ScEvent::PlayNote { synth_type, freq, amp, dur, env, pan, ref params } => {
-                                if let Err(e) = sc.play_note(synth_type, freq, amp, dur, &env, pan, params) {
-                                    eprintln!("[SC scheduler] note play failed: {}", e);
-                                }
-                            }
-                            ScEvent::SetEffect { rm, dt, df, dist, lpf, hpf } => {
-                                let _ = sc.set_global_effects(rm, dt, df, dist, lpf, hpf);
-                            }
-                            ScEvent::SetBpm(bpm_val) => {
-                                sc.state.lock().bpm = bpm_val;
-                            }
-                            ScEvent::SetVolume(vol) => {
-                                sc.state.lock().master_volume = vol;
-                            }
-                            ScEvent::FxStart { ref fx_type, ref params } => {
-                                if let Err(e) = sc.push_fx_bus(fx_type, params) {
-                                    eprintln!("[SC scheduler] FxStart failed: {}", e);
-                                }
-                            }
-                            ScEvent::FxEnd => {
-                                if let Err(e) = sc.pop_fx_bus() {
-                                    eprintln!("[SC scheduler] FxEnd failed: {}", e);
-                                }
-                            }
-                            ScEvent::Stop => {
-                                let _ = sc.stop_all();
-                            }
-                        }
-                    }
-                    drop(sc_lock);
-                }
-                eprintln!("[SC scheduler] All {} events played", event_count);
-
-                // Restore default Windows timer resolution
-                #[cfg(target_os = "windows")]
-                unsafe { timeEndPeriod(1); }
-            });
-        }
     } else {
         // ============================================================
         // CPAL ENGINE PATH (original)
         // ============================================================
-        // First, load all samples from the parsed commands
+        // Load the samples we can see statically; others load on first use
         eprintln!("[run_code] Preloading samples...");
         let preload_start = Instant::now();
         match preload_samples(&parsed, &state) {
@@ -476,53 +325,75 @@ fn run_code(code: String, state: tauri::State<Arc<AppState>>) -> Result<RunResul
                 return Err(format!("Sample load error: {}", e));
             }
         }
+    }
 
-        // Now schedule all commands with proper timing
-        eprintln!("[run_code] Scheduling {} commands...", timed_commands.len());
-        let mut scheduled_count = 0u32;
-        let max_schedule_time = 600.0f32; // Cap at 10 minutes
-        let engine = &state.engine;
-        for (time_offset, cmd) in &timed_commands {
-            // Skip commands scheduled beyond the max time
-            if *time_offset > max_schedule_time {
-                continue;
-            }
-            let cmd_to_send = match cmd {
-                AudioCommand::PlaySample { .. } => {
-                    continue;
-                }
-                other => other.clone(),
-            };
-
-            if *time_offset < 0.001 {
-                engine.send_command(cmd_to_send)?;
-            } else {
-                // Schedule for later
-                let cmd_clone = cmd_to_send.clone();
-                let delay = Duration::from_secs_f32(*time_offset);
-                let tx = state.engine.command_tx_clone();
-                let state_clone = Arc::clone(&*state);
-                std::thread::spawn(move || {
-                    std::thread::sleep(delay);
-                    // Only send if this session is still active
-                    if *state_clone.session_id.lock() == current_session {
-                        if let Err(e) = tx.try_send(cmd_clone) {
-                            eprintln!("[schedule] NOTE command send failed: {}", e);
-                        }
-                    }
+    // Hand the code to the running scheduler; it swaps it in at the next chunk
+    let mut program = Some(program);
+    let swapped_into = {
+        let mut live = state.live_run.lock();
+        let current = *state.session_id.lock();
+        match live.as_mut().filter(|run| run.using_sc == using_sc && run.session == current) {
+            Some(run) => {
+                // Land on the shared beat clock's next quantum past what the
+                // scheduler has already generated
+                let mut clock = state.engine.clock.lock();
+                let lands = swap_landing(&clock, run.started, run.generated, Instant::now(), quantum);
+                let beat = clock.beat_at(lands);
+                clock.set_bpm(effective_bpm, lands);
+                run.pending = program.take().map(|program| PendingCode {
+                    program,
+                    bpm: effective_bpm,
+                    at: lands.duration_since(run.started).as_secs_f64(),
                 });
+                Some((run.started, lands, beat))
             }
-            scheduled_count += 1;
+            None => None,
         }
-        eprintln!("[run_code] Scheduled {} non-sample commands", scheduled_count);
-
-        // Schedule all sample playbacks with proper timing
-        eprintln!("[run_code] Scheduling sample playbacks...");
-        schedule_samples_with_timing(&parsed, &timed_commands, &state, current_session)?;
-    }
+    };
 
-    let total_elapsed = start.elapsed();
-    eprintln!("[run_code] Total setup completed in {:.1}ms", total_elapsed.as_secs_f64() * 1000.0);
+    // Track when the scheduler starts — used for playhead sync
+    // The frontend offsets the playhead by the time elapsed since scheduling began
+    let scheduler_started = match swapped_into {
+        Some((started, lands, beat)) => {
+            logs.push(LogEntry {
+                timestamp: start.elapsed().as_secs_f64(),
+                level: "info".to_string(),
+                message: format!(
+                    "Updating running live_loops on beat {} (in {:.0}ms)",
+                    beat.round(),
+                    lands.saturating_duration_since(Instant::now()).as_secs_f64() * 1000.0
+                ),
+            });
+            started
+        }
+        None => {
+            // Start a new playback session by incrementing the session ID
+            // This invalidates all old scheduled threads from previous buffers
+            let current_session = {
+                let mut session = state.session_id.lock();
+                *session = session.wrapping_add(1);
+                *session
+            };
+            // Nothing is playing, so start right away and count beats from here
+            let started = Instant::now();
+            state.engine.clock.lock().restart(started, effective_bpm);
+            *state.live_run.lock() = Some(LiveRun {
+                session: current_session,
+                using_sc,
+                started,
+                generated: 0.0,
+                pending: None,
+                controls: Vec::new(),
+                loops: Vec::new(),
+            });
+            let program = program.take().expect("program not handed to a running scheduler");
+            let interpreter = Interpreter::new(program, effective_bpm);
+            spawn_scheduler(Arc::clone(&*state), interpreter, current_session, using_sc, started);
+            started
+        }
+    };
+    let setup_time_ms = start.elapsed().as_secs_f64() * 1000.0;
+    eprintln!("[run_code] Total setup completed in {:.1}ms", setup_time_ms);
 
     // Store logs
     {
@@ -538,178 +409,334 @@ fn run_code(code: String, state: tauri::State<Arc<AppState>>) -> Result<RunResul
     Ok(RunResult {
         success: true,
         message: format!(
-            "Code executed in {:.1}ms{}",
-            start.elapsed().as_secs_f64() * 1000.0,
+            "Code started in {:.1}ms{}",
+            setup_time_ms,
             if using_sc { " (SuperCollider)" } else { "" }
         ),
         logs,
-        duration_estimate: max_time + 1.0,
         effective_bpm,
-        setup_time_ms: scheduler_started.elapsed().as_secs_f64() * 1000.0,
+        setup_time_ms,
+        playback_offset_ms: scheduler_started.elapsed().as_secs_f64() * 1000.0,
+        diagnostics,
     })
 }
 
-/// Preload all samples referenced in the parsed commands without playing them
-fn preload_samples(parsed: &[ParsedCommand], state: &Arc<AppState>) -> Result<(), String> {
-    for cmd in parsed {
-        match cmd {
-            ParsedCommand::PlaySample { name, .. } => {
-                let mut loaded = state.loaded_samples.lock();
-                let path = resolve_sample_path(name, &state.samples_dir);
-                let path_str = path.to_string_lossy().to_string();
-                eprintln!("[preload] sample '{}' -> resolved path '{}'", name, path_str);
-                
-                if !loaded.contains_key(&path_str) {
-                    if path.exists() {
-                        match sample::load_wav(&path_str) {
-                            Ok((samples, sr)) => {
-                                eprintln!("[preload] Loaded '{}': {} samples @ {}Hz", path_str, samples.len(), sr);
-                                loaded.insert(path_str.clone(), (samples, sr));
-                            }
-                            Err(e) => {
-                                eprintln!("[preload] ERROR loading '{}': {}", path_str, e);
-                                return Err(format!("Failed to load sample '{}': {}", name, e));
-                            }
-                        }
-                    } else {
-                        eprintln!("[preload] WARNING: file not found '{}', using placeholder", path_str);
-                        // Generate a simple placeholder beep for missing samples
-                        let sr = 44100u32;
-                        let dur = 0.2;
-                        let n = (sr as f32 * dur) as usize;
-                        let samples: Vec<f32> = (0..n)
-                            .map(|i| {
-                                let t = i as f32 / sr as f32;
-                                (t * 440.0 * 2.0 * std::f32::consts::PI).sin()
-                                    * (-t * 20.0).exp()
-                            })
-                            .collect();
-                        loaded.insert(path_str.clone(), (samples, sr));
+/// Where re-run code lands: the first multiple of `quantum` beats on the
+/// shared clock at or after both `now` and the end of what the scheduler has
+/// `generated` (seconds after `started`), which can no longer change
+fn swap_landing(clock: &BeatClock, started: Instant, generated: f64, now: Instant, quantum: f64) -> Instant {
+    let earliest = now.max(started + Duration::from_secs_f64(generated.max(0.0)));
+    clock.next_boundary(earliest, quantum)
+}
+
+/// Run the interpreter on a single scheduler thread, generating events a
+/// chunk ahead of the playhead and dispatching each at its target time.
+/// Runs until the program finishes or the session changes (stop or a fresh
+/// start); code from a re-run is swapped in between chunks.
+fn spawn_scheduler(
+    state: Arc<AppState>,
+    interpreter: Interpreter,
+    session: u64,
+    using_sc: bool,
+    start_time: Instant,
+) {
+    std::thread::spawn(move || {
+        // Set Windows timer resolution to 1ms for precise scheduling
+        #[cfg(target_os = "windows")]
+        unsafe {
+            timeBeginPeriod(1);
+        }
+
+        let mut scheduler = ChunkScheduler::new(interpreter, session, start_time);
+        let mut sample_paths: HashMap<String, PathBuf> = HashMap::new();
+        let mut dispatched = 0usize;
+
+        'schedule: loop {
+            // Both engines get events early, stamped with when to play them
+            let lead = if using_sc {
+                state.sc_engine.lock().as_ref().map_or(0.0, |sc| sc.sched_ahead_time())
+            } else {
+                CPAL_DISPATCH_AHEAD_SECS
+            };
+            let mut errors = Vec::new();
+            let chunk = scheduler.next_chunk(&state.session_id, &state.live_run, lead, &mut errors);
+            for e in errors {
+                push_runtime_log(&state, "error", e);
+            }
+            let Some(events) = chunk else {
+                break 'schedule;
+            };
+            for (target_time, event) in events {
+                // Check if session is still valid
+                if *state.session_id.lock() != session {
+                    eprintln!("[scheduler] Session cancelled, stopping scheduler");
+                    break 'schedule;
+                }
+
+                wait_until(start_time, target_time - lead);
+
+                // Re-check session after sleeping
+                if *state.session_id.lock() != session {
+                    break 'schedule;
+                }
+
+                let at = start_time + Duration::from_secs_f64(target_time.max(0.0));
+                if using_sc {
+                    dispatch_sc_event(&state, event, at, &mut sample_paths);
+                } else {
+                    dispatch_cpal_event(&state, event, at, &mut sample_paths);
+                }
+                dispatched += 1;
+            }
+        }
+        eprintln!("[scheduler] Dispatched {} events", dispatched);
+
+        // Restore default Windows timer resolution
+        #[cfg(target_os = "windows")]
+        unsafe { timeEndPeriod(1); }
+    });
+}
+
+/// The scheduler's pacing, apart from dispatch: waits for playback to come
+/// within the dispatch lead of the next chunk, applies re-run code and loop
+/// controls, then evaluates the chunk. Generation never runs more than one
+/// chunk plus the lead ahead of playback, however sparse the events.
+struct ChunkScheduler {
+    interpreter: Interpreter,
+    session: u64,
+    start_time: Instant,
+    /// Scheduler time (seconds) evaluated up to
+    horizon: f64,
+}
+
+impl ChunkScheduler {
+    fn new(interpreter: Interpreter, session: u64, start_time: Instant) -> Self {
+        Self { interpreter, session, start_time, horizon: 0.0 }
+    }
+
+    /// Events of the next chunk, sorted by time, or `None` once the session
+    /// has changed or the program has finished with nothing left to swap in.
+    /// Errors from loop controls are added to `errors`.
+    fn next_chunk(
+        &mut self,
+        session_id: &Mutex<u64>,
+        live_run: &Mutex<Option<LiveRun>>,
+        lead: f64,
+        errors: &mut Vec<String>,
+    ) -> Option<Vec<(f64, Event)>> {
+        wait_until(self.start_time, self.horizon - lead);
+        if *session_id.lock() != self.session {
+            return None;
+        }
+        let until = self.horizon + SCHEDULE_CHUNK_SECS;
+        {
+            let mut live = live_run.lock();
+            if let Some(run) = live.as_mut().filter(|run| run.session == self.session) {
+                // Swap re-run code in during the chunk it lands in. It was
+                // placed past `generated`, so it starts exactly where planned.
+                if let Some(code) = run.pending.take_if(|code| code.at < until) {
+                    self.interpreter.hot_swap(code.program, code.bpm, code.at.max(self.horizon));
+                }
+                for (name, control) in std::mem::take(&mut run.controls) {
+                    if let Err(e) = self.interpreter.control_loop(&name, control, self.horizon) {
+                        errors.push(e);
                     }
                 }
+                run.loops = self.interpreter.loops();
+                // Set while still holding the lock, so a re-run never lands
+                // inside the chunk about to be evaluated
+                run.generated = until;
             }
-            ParsedCommand::Loop { commands, .. }
-            | ParsedCommand::WithFx { commands, .. }
-            | ParsedCommand::TimesLoop { commands, .. } => {
-                preload_samples(commands, state)?;
+            let run = live.as_ref().filter(|run| run.session == self.session);
+            if self.interpreter.is_finished() && run.is_none_or(|run| run.pending.is_none()) {
+                // Nothing left to swap code into: the next run starts afresh
+                if run.is_some() {
+                    *live = None;
+                }
+                return None;
             }
-            _ => {}
         }
+        self.horizon = until;
+        Some(self.interpreter.run_until(until))
     }
-    Ok(())
 }
 
-/// Schedule sample playbacks according to the timed commands
-fn schedule_samples_with_timing(
-    parsed: &[ParsedCommand],
-    timed_commands: &[(f32, AudioCommand)],
+/// Sleep until `target` seconds after `start`. Events carry their own
+/// timestamps, so waking a few milliseconds late costs nothing audible.
+fn wait_until(start: Instant, target: f64) {
+    let wait = target - start.elapsed().as_secs_f64();
+    if wait > 0.0 {
+        std::thread::sleep(Duration::from_secs_f64(wait));
+    }
+}
+
+/// Resolve a sample name once per run (resolution may walk the samples dir)
+fn cached_sample_path(
+    name: &str,
+    samples_dir: &std::path::Path,
+    cache: &mut HashMap<String, PathBuf>,
+) -> PathBuf {
+    cache
+        .entry(name.to_string())
+        .or_insert_with(|| resolve_sample_path(name, samples_dir))
+        .clone()
+}
+
+/// Record a runtime log or error from the interpreter
+fn push_runtime_log(state: &Arc<AppState>, level: &str, message: String) {
+    let mut log_store = state.log_messages.lock();
+    log_store.push(LogEntry {
+        timestamp: 0.0,
+        level: level.to_string(),
+        message,
+    });
+    if log_store.len() > 1000 {
+        let drain = log_store.len() - 1000;
+        log_store.drain(0..drain);
+    }
+}
+
+/// Hand an event to the built-in engine, to start exactly at `at`
+fn dispatch_cpal_event(
     state: &Arc<AppState>,
-    current_session: u64,
-) -> Result<(), String> {
-    // Build a list of sample names from parsed commands in order
-    let sample_names = collect_sample_names(parsed);
-    eprintln!("[schedule_samples] Collected {} sample names", sample_names.len());
-    
-    let max_schedule_time = 600.0f32; // Cap at 10 minutes
-    let mut scheduled = 0u32;
-    
-    // Match them with PlaySample commands in timed_commands
-    let mut sample_idx = 0;
-    for (time_offset, cmd) in timed_commands {
-        if let AudioCommand::PlaySample { amplitude, rate, pan, .. } = cmd {
-            if sample_idx < sample_names.len() {
-                let name = &sample_names[sample_idx];
-                sample_idx += 1;
-                
-                // Skip commands beyond max time
-                if *time_offset > max_schedule_time {
-                    continue;
+    event: Event,
+    at: Instant,
+    sample_paths: &mut HashMap<String, PathBuf>,
+) {
+    match event {
+        Event::Audio(cmd) => {
+            if let Err(e) = state.engine.send_command_at(cmd, at) {
+                eprintln!("[scheduler] command send failed: {}", e);
+            }
+        }
+        Event::Sample { name, rate, amplitude, pan, fx } => {
+            let path = cached_sample_path(&name, &state.samples_dir, sample_paths);
+            let path_str = match ensure_sample_loaded(&name, &path, state) {
+                Ok(p) => p,
+                Err(e) => {
+                    eprintln!("[scheduler] {}", e);
+                    return;
                 }
-                
-                // Load the sample data
+            };
+            let cmd = {
                 let loaded = state.loaded_samples.lock();
-                let path = resolve_sample_path(name, &state.samples_dir);
-                let path_str = path.to_string_lossy().to_string();
-                
-                if let Some((samples, sr)) = loaded.get(&path_str) {
-                    eprintln!("[schedule_samples] #{} t={:.2}s '{}' -> scheduling ({} samples)", sample_idx - 1, time_offset, name, samples.len());
-                    let cmd_to_send = AudioCommand::PlaySample {
-                        samples: samples.clone(),
-                        sample_rate: *sr,
-                        amplitude: *amplitude,
-                        rate: *rate,
-                        pan: *pan,
-                    };
-                    
-                    if *time_offset < 0.001 {
-                        state.engine.send_command(cmd_to_send)?;
-                    } else {
-                        // Schedule for later
-                        let delay = Duration::from_secs_f32(*time_offset);
-                        let tx = state.engine.command_tx_clone();
-                        let state_clone = Arc::clone(&*state);
-                        std::thread::spawn(move || {
-                            std::thread::sleep(delay);
-                            // Only send if this session is still active
-                            if *state_clone.session_id.lock() == current_session {
-                                if let Err(e) = tx.try_send(cmd_to_send) {
-                                    eprintln!("[schedule_samples] SAMPLE command send failed: {}", e);
-                                }
-                            }
-                        });
-                    }
-                    scheduled += 1;
-                } else {
-                    eprintln!("[schedule_samples] #{} MISS: '{}' not in loaded cache (resolved path: '{}')", sample_idx - 1, name, path_str);
+                loaded.get(&path_str).map(|data| AudioCommand::PlaySample {
+                    samples: data.samples.clone(),
+                    channels: data.channels,
+                    sample_rate: data.sample_rate,
+                    amplitude,
+                    rate,
+                    pan,
+                    fx,
+                })
+            };
+            if let Some(cmd) = cmd {
+                if let Err(e) = state.engine.send_command_at(cmd, at) {
+                    eprintln!("[scheduler] sample send failed: {}", e);
                 }
             }
         }
+        Event::Log(msg) => push_runtime_log(state, "info", msg),
+        Event::Error(msg) => push_runtime_log(state, "error", msg),
     }
-    eprintln!("[schedule_samples] Scheduled {} sample playbacks", scheduled);
-    Ok(())
 }
 
-/// Collect all sample names from parsed commands in execution order
-fn collect_sample_names(parsed: &[ParsedCommand]) -> Vec<String> {
-    let mut names = Vec::new();
-    collect_sample_names_recursive(parsed, &mut names, 1);
-    names
+/// Hand an event to scsynth as a bundle timestamped `at`
+fn dispatch_sc_event(
+    state: &Arc<AppState>,
+    event: Event,
+    at: Instant,
+    sample_paths: &mut HashMap<String, PathBuf>,
+) {
+    match event {
+        Event::Log(msg) => push_runtime_log(state, "info", msg),
+        Event::Error(msg) => push_runtime_log(state, "error", msg),
+        Event::Audio(cmd) => {
+            let sc_lock = state.sc_engine.lock();
+            if let Some(ref sc) = *sc_lock {
+                if let Err(e) = sc.send_command_at(cmd, at) {
+                    eprintln!("[SC scheduler] command failed: {}", e);
+                }
+            }
+        }
+        Event::Sample { name, rate, amplitude, pan, fx } => {
+            let path = cached_sample_path(&name, &state.samples_dir, sample_paths);
+            if !path.exists() {
+                eprintln!("[SC scheduler] No buffer for sample '{}'", name);
+                return;
+            }
+            let sc_lock = state.sc_engine.lock();
+            if let Some(ref sc) = *sc_lock {
+                // Cached after the first load, so this only blocks for new samples
+                match sc.load_sample_buffer(&path.to_string_lossy()) {
+                    Ok(buf_id) => {
+                        if let Err(e) = sc.scheduled(at, |sc| sc.play_sample_buffer(buf_id, amplitude, rate, pan, fx)) {
+                            eprintln!("[SC scheduler] sample play failed: {}", e);
+                        }
+                    }
+                    Err(e) => eprintln!("[SC scheduler] sample load failed: {}", e),
+                }
+            }
+        }
+    }
 }
 
-fn collect_sample_names_recursive(parsed: &[ParsedCommand], names: &mut Vec<String>, _loop_count: usize) {
+/// Preload all samples referenced in the parsed commands without playing them
+fn preload_samples(parsed: &[ParsedCommand], state: &Arc<AppState>) -> Result<(), String> {
     for cmd in parsed {
         match cmd {
             ParsedCommand::PlaySample { name, .. } => {
-                names.push(name.clone());
+                let path = resolve_sample_path(name, &state.samples_dir);
+                eprintln!("[preload] sample '{}' -> resolved path '{}'", name, path.display());
+                ensure_sample_loaded(name, &path, state)?;
             }
-            ParsedCommand::Loop { commands, .. } => {
-                // Check if body contains stop — if so, only expand once
-                let has_stop = commands.iter().any(|c| matches!(c, ParsedCommand::Stop));
-                let iters = if has_stop { 1 } else { 500 };
-                for _ in 0..iters {
-                    collect_sample_names_recursive(commands, names, 1);
-                    // Safety cap
-                    if names.len() > 100_000 {
-                        eprintln!("[run_code] WARNING: sample name collection capped at 100k");
-                        return;
-                    }
-                }
+            ParsedCommand::Loop { commands, .. }
+            | ParsedCommand::WithFx { commands, .. }
+            | ParsedCommand::TimesLoop { commands, .. } => {
+                preload_samples(commands, state)?;
             }
-            ParsedCommand::TimesLoop { count, commands } => {
-                for _ in 0..*count {
-                    collect_sample_names_recursive(commands, names, 1);
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Load the sample at `path` into the cache if it isn't there yet and return
+/// its cache key. Missing files get a placeholder beep.
+fn ensure_sample_loaded(name: &str, path: &std::path::Path, state: &Arc<AppState>) -> Result<String, String> {
+    let mut loaded = state.loaded_samples.lock();
+    let path_str = path.to_string_lossy().to_string();
+
+    if !loaded.contains_key(&path_str) {
+        if path.exists() {
+            match sample::load_wav(&path_str) {
+                Ok(data) => {
+                    eprintln!("[preload] Loaded '{}': {} frames x {} channels @ {}Hz",
+                        path_str, data.frames(), data.channels, data.sample_rate);
+                    loaded.insert(path_str.clone(), data);
+                }
+                Err(e) => {
+                    eprintln!("[preload] ERROR loading '{}': {}", path_str, e);
+                    return Err(format!("Failed to load sample '{}': {}", name, e));
                 }
             }
-            ParsedCommand::WithFx { commands, .. } => {
-                collect_sample_names_recursive(commands, names, 1);
-            }
-            ParsedCommand::Stop => {
-                // Stop means we don't continue collecting from subsequent commands
-                return;
-            }
-            _ => {}
+        } else {
+            eprintln!("[preload] WARNING: file not found '{}', using placeholder", path_str);
+            // Generate a simple placeholder beep for missing samples
+            let sr = 44100u32;
+            let dur = 0.2;
+            let n = (sr as f32 * dur) as usize;
+            let samples: Vec<f32> = (0..n)
+                .map(|i| {
+                    let t = i as f32 / sr as f32;
+                    (t * 440.0 * 2.0 * std::f32::consts::PI).sin()
+                        * (-t * 20.0).exp()
+                })
+                .collect();
+            loaded.insert(path_str.clone(), SampleData::mono(samples, sr));
         }
     }
+    Ok(path_str)
 }
 
 fn process_sample_command(cmd: &ParsedCommand, state: &Arc<AppState>) -> Result<(), String> {
@@ -729,8 +756,8 @@ fn process_sample_command(cmd: &ParsedCommand, state: &Arc<AppState>) -> Result<
             if !loaded.contains_key(&path_str) {
                 if path.exists() {
                     match sample::load_wav(&path_str) {
-                        Ok((samples, sr)) => {
-                            loaded.insert(path_str.clone(), (samples, sr));
+                        Ok(data) => {
+                            loaded.insert(path_str.clone(), data);
                         }
                         Err(e) => {
                             return Err(format!("Failed to load sample '{}': {}", name, e));
@@ -748,17 +775,19 @@ fn process_sample_command(cmd: &ParsedCommand, state: &Arc<AppState>) -> Result<
                                 * (-t * 20.0).exp()
                         })
                         .collect();
-                    loaded.insert(path_str.clone(), (samples, sr));
+                    loaded.insert(path_str.clone(), SampleData::mono(samples, sr));
                 }
             }
 
-            if let Some((samples, sr)) = loaded.get(&path_str) {
+            if let Some(data) = loaded.get(&path_str) {
                 state.engine.send_command(AudioCommand::PlaySample {
-                    samples: samples.clone(),
-                    sample_rate: *sr,
+                    samples: data.samples.clone(),
+                    channels: data.channels,
+                    sample_rate: data.sample_rate,
                     amplitude: *amplitude,
                     rate: *rate,
                     pan: *pan,
+                    fx: None,
                 })?;
             }
         }
@@ -859,6 +888,51 @@ fn stop_audio(state: tauri::State<Arc<AppState>>) -> Result<String, String> {
     Ok("Stopped".to_string())
 }
 
+/// live_loops of the code that is playing, with their iteration counts
+#[tauri::command]
+fn list_loops(state: tauri::State<Arc<AppState>>) -> Vec<LoopStatus> {
+    let current = *state.session_id.lock();
+    state.live_run.lock().as_ref()
+        .filter(|run| run.session == current)
+        .map(|run| run.loops.clone())
+        .unwrap_or_default()
+}
+
+#[tauri::command]
+fn stop_loop(name: String, state: tauri::State<Arc<AppState>>) -> Result<(), String> {
+    queue_loop_control(&state, &name, LoopControl::Stop)
+}
+
+#[tauri::command]
+fn restart_loop(name: String, state: tauri::State<Arc<AppState>>) -> Result<(), String> {
+    queue_loop_control(&state, &name, LoopControl::Restart)
+}
+
+#[tauri::command]
+fn mute_loop(name: String, muted: bool, state: tauri::State<Arc<AppState>>) -> Result<(), String> {
+    queue_loop_control(&state, &name, LoopControl::Mute(muted))
+}
+
+#[tauri::command]
+fn solo_loop(name: String, soloed: bool, state: tauri::State<Arc<AppState>>) -> Result<(), String> {
+    queue_loop_control(&state, &name, LoopControl::Solo(soloed))
+}
+
+/// Hand a transport action on one live_loop to the running scheduler
+fn queue_loop_control(state: &AppState, name: &str, control: LoopControl) -> Result<(), String> {
+    let name = name.trim().trim_start_matches(':');
+    let current = *state.session_id.lock();
+    let mut live = state.live_run.lock();
+    let run = live.as_mut()
+        .filter(|run| run.session == current)
+        .ok_or("Nothing is playing")?;
+    if !run.loops.iter().any(|l| l.name == name) {
+        return Err(format!("No live_loop named :{}", name));
+    }
+    run.controls.push((name.to_string(), control));
+    Ok(())
+}
+
 #[tauri::command]
 fn get_waveform(state: tauri::State<Arc<AppState>>) -> Vec<f32> {
     if state.use_sc.load(Ordering::Relaxed) {
@@ -872,6 +946,7 @@ fn get_waveform(state: tauri::State<Arc<AppState>>) -> Vec<f32> {
 
 #[tauri::command]
 fn get_status(state: tauri::State<Arc<AppState>>) -> EngineStatus {
+    let (voice_count, stolen_voices) = state.engine.get_voice_stats();
     if state.use_sc.load(Ordering::Relaxed) {
         if let Some(ref sc) = *state.sc_engine.lock() {
             sc.process_incoming();
@@ -880,17 +955,41 @@ fn get_status(state: tauri::State<Arc<AppState>>) -> EngineStatus {
                 is_playing,
                 master_volume,
                 bpm,
-                is_recording: state.recorder.is_recording(),
+                is_recording: state.recorder.is_recording() || sc.is_recording(),
+                voice_count,
+                stolen_voices,
             };
         }
     }
     let (is_playing, master_volume, bpm) = state.engine.get_state_snapshot();
+    // SuperCollider may still be recording after switching engines
+    let sc_recording = state.sc_engine.lock().as_ref().is_some_and(|sc| sc.is_recording());
     EngineStatus {
         is_playing,
         master_volume,
         bpm,
-        is_recording: state.recorder.is_recording(),
+        is_recording: state.recorder.is_recording() || sc_recording,
+        voice_count,
+        stolen_voices,
+    }
+}
+
+/// Master bus peak, RMS, short-term loudness and limiter gain reduction
+#[tauri::command]
+fn get_meter(state: tauri::State<Arc<AppState>>) -> MeterReading {
+    if state.use_sc.load(Ordering::Relaxed) {
+        if let Some(ref sc) = *state.sc_engine.lock() {
+            sc.process_incoming();
+            return sc.get_meter();
+        }
     }
+    state.engine.get_meter()
+}
+
+/// Set how many notes and samples the built-in engine plays at once
+#[tauri::command]
+fn set_max_voices(max_voices: usize, state: tauri::State<Arc<AppState>>) -> Result<(), String> {
+    state.engine.send_command(AudioCommand::SetMaxVoices(max_voices))
 }
 
 #[tauri::command]
@@ -902,25 +1001,55 @@ fn set_volume(volume: f32, state: tauri::State<Arc<AppState>>) -> Result<(), Str
 
 #[tauri::command]
 fn set_bpm(bpm: f32, state: tauri::State<Arc<AppState>>) -> Result<(), String> {
+    state.engine.clock.lock().set_bpm(bpm, Instant::now());
     state.engine.send_command(AudioCommand::SetBpm(bpm))
 }
 
+/// Start recording the output of the engine in use to `path` (default:
+/// sonic_daw_recording.wav in the Music folder), written to disk as it plays
 #[tauri::command]
-fn start_recording(state: tauri::State<Arc<AppState>>) -> Result<String, String> {
-    state.recorder.start();
-    Ok("Recording started".to_string())
+fn start_recording(
+    path: Option<String>,
+    format: Option<RecordFormat>,
+    state: tauri::State<Arc<AppState>>,
+) -> Result<String, String> {
+    let path = path.map(PathBuf::from).unwrap_or_else(default_recording_path);
+    let format = format.unwrap_or_default();
+    let sc_recording = state.use_sc.load(Ordering::Relaxed)
+        && match *state.sc_engine.lock() {
+            Some(ref sc) => {
+                sc.start_recording(&path, format)?;
+                true
+            }
+            None => false,
+        };
+    if !sc_recording {
+        state.recorder.start(&path, format)?;
+    }
+    Ok(format!("Recording to {}", path.display()))
 }
 
+/// Stop recording on whichever engine is recording, moving the file to
+/// `path` if given
 #[tauri::command]
 fn stop_recording(path: Option<String>, state: tauri::State<Arc<AppState>>) -> Result<String, String> {
-    state.recorder.stop();
-    let save_path = path.unwrap_or_else(|| {
-        let home = dirs_next().unwrap_or_else(|| PathBuf::from("."));
-        home.join("sonic_daw_recording.wav")
-            .to_string_lossy()
-            .to_string()
-    });
-    state.recorder.save_to_file(&save_path)
+    let sc_path = match *state.sc_engine.lock() {
+        Some(ref sc) if sc.is_recording() => Some(sc.stop_recording()?),
+        _ => None,
+    };
+    let saved = match sc_path {
+        Some(sc_path) => state.recorder.adopt(sc_path),
+        None => state.recorder.stop()?,
+    };
+    match path {
+        Some(path) => state.recorder.move_to(&path),
+        None => Ok(saved),
+    }
+}
+
+fn default_recording_path() -> PathBuf {
+    let home = dirs_next().unwrap_or_else(|| PathBuf::from("."));
+    home.join("sonic_daw_recording.wav")
 }
 
 fn dirs_next() -> Option<PathBuf> {
@@ -975,13 +1104,15 @@ fn set_effects(
 
 #[tauri::command]
 fn play_sample_file(path: String, state: tauri::State<Arc<AppState>>) -> Result<String, String> {
-    let (samples, sr) = sample::load_wav(&path)?;
+    let data = sample::load_wav(&path)?;
     state.engine.send_command(AudioCommand::PlaySample {
-        samples,
-        sample_rate: sr,
+        samples: data.samples,
+        channels: data.channels,
+        sample_rate: data.sample_rate,
         amplitude: 1.0,
         rate: 1.0,
         pan: 0.0,
+        fx: None,
     })?;
     Ok("Playing sample".to_string())
 }
@@ -992,18 +1123,22 @@ fn preview_synth(synth_name: String, state: tauri::State<Arc<AppState>>) -> Resu
     let envelope = Envelope {
         attack: 0.01,
         decay: 0.1,
-        sustain: 0.6,
+        sustain: 0.29,
         release: 0.2,
+        decay_level: 0.6,
+        sustain_level: 0.6,
+        ..Envelope::default()
     };
     // Play middle C (C4 = 261.63 Hz) for 0.6 seconds
     state.engine.send_command(AudioCommand::PlayNote {
         synth_type: osc,
         frequency: 261.63,
         amplitude: 0.5,
-        duration_secs: 0.6,
         envelope,
         pan: 0.0,
         params: vec![],
+        node: None,
+        fx: None,
     })?;
     Ok(format!("Previewing synth: {}", synth_name))
 }
@@ -1053,6 +1188,85 @@ fn parse_synth_name_for_preview(name: &str) -> OscillatorType {
     }
 }
 
+/// Render code offline with the built-in engine, faster than real time and
+/// without a sound card, and save it as a stereo WAV file
+#[tauri::command(async)]
+fn render_to_file(
+    code: String,
+    path: String,
+    length: RenderLength,
+    sample_rate: Option<u32>,
+    state: tauri::State<Arc<AppState>>,
+) -> Result<String, String> {
+    let state = Arc::clone(&*state);
+    let (_, _, engine_bpm) = state.engine.get_state_snapshot();
+    let start = Instant::now();
+    let mut sample_paths = HashMap::new();
+    let render = render::render_file(
+        &code,
+        length,
+        sample_rate.unwrap_or(DEFAULT_RENDER_SAMPLE_RATE),
+        engine_bpm,
+        std::path::Path::new(&path),
+        |name| render_sample(name, &state, &mut sample_paths),
+    )?;
+    push_render_logs(&state, &render.logs, &render.errors);
+    Ok(format!(
+        "Rendered {:.1}s in {:.1}s to {}",
+        render.duration_secs,
+        start.elapsed().as_secs_f64(),
+        path
+    ))
+}
+
+/// Render code offline with the built-in engine into `dir`: a stereo WAV
+/// per live_loop, one for the code outside them and, with `with_mix`, the
+/// master mix, all sample-aligned
+#[tauri::command(async)]
+fn export_stems(
+    code: String,
+    dir: String,
+    length: RenderLength,
+    with_mix: Option<bool>,
+    sample_rate: Option<u32>,
+    state: tauri::State<Arc<AppState>>,
+) -> Result<StemExport, String> {
+    let state = Arc::clone(&*state);
+    let (_, _, engine_bpm) = state.engine.get_state_snapshot();
+    let mut sample_paths = HashMap::new();
+    let export = render::export_stems(
+        &code,
+        length,
+        sample_rate.unwrap_or(DEFAULT_RENDER_SAMPLE_RATE),
+        engine_bpm,
+        std::path::Path::new(&dir),
+        with_mix.unwrap_or(true),
+        |name| render_sample(name, &state, &mut sample_paths),
+    )?;
+    push_render_logs(&state, &export.logs, &export.errors);
+    Ok(export)
+}
+
+/// Sample data for an offline render, from the same cache playback uses
+fn render_sample(
+    name: &str,
+    state: &Arc<AppState>,
+    sample_paths: &mut HashMap<String, PathBuf>,
+) -> Result<SampleData, String> {
+    let path = cached_sample_path(name, &state.samples_dir, sample_paths);
+    let key = ensure_sample_loaded(name, &path, state)?;
+    state.loaded_samples.lock().get(&key).cloned().ok_or_else(|| format!("Sample '{}' not loaded", name))
+}
+
+fn push_render_logs(state: &Arc<AppState>, logs: &[String], errors: &[String]) {
+    for msg in logs {
+        push_runtime_log(state, "info", msg.clone());
+    }
+    for msg in errors {
+        push_runtime_log(state, "error", msg.clone());
+    }
+}
+
 #[tauri::command]
 fn save_recording(path: String, state: tauri::State<Arc<AppState>>) -> Result<String, String> {
     state.recorder.save_to_file(&path)
@@ -1140,7 +1354,8 @@ fn analyze_audio_file(path: &std::path::Path, root: &std::path::Path) -> Result<
         .unwrap_or_default();
     
     // Load audio data for analysis
-    let (samples, sample_rate) = sample::load_wav(&path_str)?;
+    let data = sample::load_wav(&path_str)?;
+    let (samples, sample_rate) = (data.to_mono(), data.sample_rate);
     
     let duration_secs = if sample_rate > 0 {
         samples.len() as f32 / sample_rate as f32
@@ -1602,6 +1817,16 @@ fn toggle_sc_engine(enabled: bool, state: tauri::State<Arc<AppState>>) -> Result
     }
 }
 
+/// Set how far ahead (seconds) events are sent to SuperCollider. Larger
+/// values survive more scheduling hiccups; smaller ones react faster.
+#[tauri::command]
+fn set_sched_ahead_time(seconds: f64, state: tauri::State<Arc<AppState>>) -> Result<(), String> {
+    let sc = state.sc_engine.lock();
+    let sc = sc.as_ref().ok_or("SuperCollider engine not initialized")?;
+    sc.set_sched_ahead_time(seconds);
+    Ok(())
+}
+
 /// Preload samples into SuperCollider buffers
 fn preload_samples_sc(
     parsed: &[ParsedCommand],
@@ -1633,30 +1858,9 @@ fn preload_samples_sc(
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    // Create recorder first (we'll get sample rate from it)
-    let recorder = Recorder::new(44100); // Default, will be updated
-    
-    // Create engine with recorder
+    // The engine sets the recorder to the output device's sample rate
+    let recorder = Recorder::new(44100);
     let engine = AudioEngine::new(recorder.clone()).expect("Failed to initialize audio engine");
-    
-    let sample_rate = {
-        let s = engine.state.lock();
-        s.sample_rate
-    };
-
-    // Update recorder with correct sample rate if needed
-    let recorder = if sample_rate != 44100 {
-        Recorder::new(sample_rate)
-    } else {
-        recorder
-    };
-
-    // Recreate engine with correct sample rate recorder if needed
-    let engine = if sample_rate != 44100 {
-        AudioEngine::new(recorder.clone()).expect("Failed to initialize audio engine")
-    } else {
-        engine
-    };
 
     // Set up samples directory
     let samples_dir = sample::get_samples_dir();
@@ -1700,6 +1904,7 @@ pub fn run() {
         samples_dir,
         loaded_samples: Mutex::new(HashMap::new()),
         session_id: Mutex::new(0),
+        live_run: Mutex::new(None),
         log_messages: Mutex::new(Vec::new()),
         user_samples_dir: Mutex::new(None),
     });
@@ -1749,6 +1954,11 @@ pub fn run() {
         .invoke_handler(tauri::generate_handler![
             run_code,
             stop_audio,
+            list_loops,
+            stop_loop,
+            restart_loop,
+            mute_loop,
+            solo_loop,
             get_waveform,
             get_status,
             set_volume,
@@ -1762,10 +1972,15 @@ pub fn run() {
             play_sample_file,
             preview_synth,
             save_recording,
+            render_to_file,
+            export_stems,
             get_env_var,
             init_supercollider,
             sc_status,
             toggle_sc_engine,
+            set_sched_ahead_time,
+            set_max_voices,
+            get_meter,
             set_user_samples_dir,
             get_user_samples_dir,
             scan_user_samples,
@@ -1773,3 +1988,131 @@ pub fn run() {
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn live_run(session: u64, started: Instant) -> Mutex<Option<LiveRun>> {
+        Mutex::new(Some(LiveRun {
+            session,
+            using_sc: false,
+            started,
+            generated: 0.0,
+            pending: None,
+            controls: Vec::new(),
+            loops: Vec::new(),
+        }))
+    }
+
+    fn note_times(events: &[(f64, Event)]) -> Vec<f64> {
+        events
+            .iter()
+            .filter_map(|(t, e)| matches!(e, Event::Audio(AudioCommand::PlayNote { .. })).then_some(*t))
+            .collect()
+    }
+
+    #[test]
+    fn test_swap_lands_past_generated_chunk() {
+        let started = Instant::now();
+        let mut clock = BeatClock::new(100.0);
+        clock.restart(started, 100.0);
+        // 100 BPM: beats every 0.6s. Run at 1.01s with 2.0s already
+        // generated, the next beat that can still change is 2.4s (beat 4).
+        let now = started + Duration::from_secs_f64(1.01);
+        let lands = swap_landing(&clock, started, 2.0, now, 1.0);
+        assert!((lands.duration_since(started).as_secs_f64() - 2.4).abs() < 1e-6);
+        assert!((clock.beat_at(lands) - 4.0).abs() < 1e-6);
+        // Nothing generated past now: the next beat after now
+        let lands = swap_landing(&clock, started, 0.5, now, 1.0);
+        assert!((lands.duration_since(started).as_secs_f64() - 1.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_swap_starts_exactly_on_the_boundary() {
+        let code = "live_loop :a do\n  play :c4\n  sleep 1\nend\n";
+        let program = parse_program("live_loop :a do\n  play :c5\n  sleep 1\nend\n").unwrap();
+        let started = Instant::now() - Duration::from_secs(10);
+        let session = Mutex::new(1);
+        let live = live_run(1, started);
+        let mut clock = BeatClock::new(100.0);
+        clock.restart(started, 100.0);
+        let mut scheduler = ChunkScheduler::new(Interpreter::from_code(code, 100.0).unwrap(), 1, started);
+        let mut errors = Vec::new();
+        for _ in 0..4 {
+            scheduler.next_chunk(&session, &live, 0.0, &mut errors).unwrap();
+        }
+
+        // Queue a re-run the way run_code does, while 2.0s is generated
+        let lands = {
+            let mut live = live.lock();
+            let run = live.as_mut().unwrap();
+            assert_eq!(run.generated, 2.0);
+            let lands = swap_landing(&clock, started, run.generated, started, 1.0);
+            run.pending = Some(PendingCode {
+                program,
+                bpm: 100.0,
+                at: lands.duration_since(started).as_secs_f64(),
+            });
+            lands.duration_since(started).as_secs_f64()
+        };
+        assert!((lands - 2.4).abs() < 1e-6);
+
+        let mut events = Vec::new();
+        while scheduler.horizon < 4.0 {
+            events.extend(scheduler.next_chunk(&session, &live, 0.0, &mut errors).unwrap());
+        }
+        let new_notes: Vec<f64> = events
+            .iter()
+            .filter_map(|(t, e)| match e {
+                Event::Audio(AudioCommand::PlayNote { frequency, .. }) if *frequency > 400.0 => Some(*t),
+                _ => None,
+            })
+            .collect();
+        assert!((new_notes[0] - lands).abs() < 1e-6, "swapped in at {:?}, not {}", new_notes, lands);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_scheduler_stays_one_chunk_ahead_of_playback() {
+        // Nothing to play for long stretches: the scheduler must still wait
+        let code = "live_loop :a do\n  play :c4\n  sleep 8\nend\n";
+        let started = Instant::now();
+        let session = Mutex::new(1);
+        let live = live_run(1, started);
+        let mut scheduler = ChunkScheduler::new(Interpreter::from_code(code, 60.0).unwrap(), 1, started);
+        let lead = 0.05;
+        let mut played = Vec::new();
+        for _ in 0..4 {
+            played.extend(note_times(&scheduler.next_chunk(&session, &live, lead, &mut Vec::new()).unwrap()));
+            let ahead = scheduler.horizon - started.elapsed().as_secs_f64();
+            assert!(ahead <= SCHEDULE_CHUNK_SECS + lead + 1e-3, "{}s ahead of playback", ahead);
+        }
+        assert_eq!(played, vec![0.0]);
+        assert_eq!(live.lock().as_ref().unwrap().generated, scheduler.horizon);
+    }
+
+    #[test]
+    fn test_scheduler_stops_when_session_changes() {
+        let code = "live_loop :a do\n  sleep 1\nend\n";
+        let started = Instant::now();
+        let session = Mutex::new(1);
+        let live = live_run(1, started);
+        let mut scheduler = ChunkScheduler::new(Interpreter::from_code(code, 60.0).unwrap(), 1, started);
+        assert!(scheduler.next_chunk(&session, &live, 0.0, &mut Vec::new()).is_some());
+        *session.lock() = 2;
+        assert!(scheduler.next_chunk(&session, &live, 0.0, &mut Vec::new()).is_none());
+    }
+
+    #[test]
+    fn test_scheduler_ends_finished_program() {
+        let started = Instant::now();
+        let session = Mutex::new(1);
+        let live = live_run(1, started);
+        let mut scheduler = ChunkScheduler::new(Interpreter::from_code("play :c4\n", 60.0).unwrap(), 1, started);
+        let first = scheduler.next_chunk(&session, &live, 0.0, &mut Vec::new()).unwrap();
+        assert_eq!(note_times(&first), vec![0.0]);
+        assert!(scheduler.next_chunk(&session, &live, 0.0, &mut Vec::new()).is_none());
+        assert!(live.lock().is_none(), "a finished run takes no more re-runs");
+    }
+}
@@ -37,7 +37,7 @@ const SAMPLE_NAMES = [
 ];
 
 const CodeEditor: React.FC = () => {
-  const { buffers, activeBufferId, updateBufferCode, theme } = useStore();
+  const { buffers, activeBufferId, updateBufferCode, theme, diagnostics } = useStore();
   const activeBuffer = buffers.find(b => b.id === activeBufferId);
   const editorRef = useRef<any>(null);
   const monacoRef = useRef<any>(null);
@@ -266,6 +266,26 @@ const CodeEditor: React.FC = () => {
     }
   }, [theme]);
 
+  // Underline problems reported by the last run
+  useEffect(() => {
+    const monaco = monacoRef.current;
+    const model = editorRef.current?.getModel();
+    if (!monaco || !model) return;
+    const severityMap: Record<string, number> = {
+      error: monaco.MarkerSeverity.Error,
+      warning: monaco.MarkerSeverity.Warning,
+      info: monaco.MarkerSeverity.Info,
+    };
+    monaco.editor.setModelMarkers(model, 'pibeat', diagnostics.map((d) => ({
+      severity: severityMap[d.severity] ?? monaco.MarkerSeverity.Warning,
+      startLineNumber: d.line,
+      startColumn: d.column,
+      endLineNumber: d.line,
+      endColumn: d.column + d.text.length,
+      message: d.hint,
+    })));
+  }, [diagnostics]);
+
   const editorTheme = theme === 'sonicpi' ? 'sonicPiClassic' : theme === 'amber' ? 'amberTheme' : 'sonicDark';
 
   return (
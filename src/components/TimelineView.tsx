@@ -748,7 +748,7 @@ const TrackLane: React.FC<{
 // ─── Main TimelineView ──────────────────────────────────────────
 
 const TimelineView: React.FC = () => {
-  const { buffers, bpm, isPlaying, updateBufferCode, activeBufferId, setupTimeMs } = useStore();
+  const { buffers, bpm, isPlaying, updateBufferCode, activeBufferId, playbackOffsetMs } = useStore();
   const [pixelsPerBeat, setPixelsPerBeat] = useState(24);
   const [playheadBeat, setPlayheadBeat] = useState(0);
   const scrollContainerRef = useRef<HTMLDivElement>(null);
@@ -806,8 +806,8 @@ const TimelineView: React.FC = () => {
   // Animate playhead
   useEffect(() => {
     if (isPlaying) {
-      // Offset playhead by setup time so it aligns with when audio actually started
-      playStartTimeRef.current = Date.now() - setupTimeMs;
+      // Offset playhead by the time playback had already run so it aligns with when audio actually started
+      playStartTimeRef.current = Date.now() - playbackOffsetMs;
       const animate = () => {
         if (!playStartTimeRef.current || isDraggingPlayhead.current) {
           requestAnimationFrame(animate);
@@ -34,15 +34,26 @@ export interface EngineStatus {
   master_volume: number;
   bpm: number;
   is_recording: boolean;
+  voice_count: number;
+  stolen_voices: number;
+}
+
+export interface Diagnostic {
+  severity: 'error' | 'warning' | 'info';
+  line: number;
+  column: number;
+  text: string;
+  hint: string;
 }
 
 export interface RunResult {
   success: boolean;
   message: string;
   logs: LogEntry[];
-  duration_estimate: number;
   effective_bpm: number;
   setup_time_ms: number;
+  playback_offset_ms: number;
+  diagnostics: Diagnostic[];
 }
 
 export interface ScStatus {
@@ -89,7 +100,7 @@ interface AppStore {
   isRecording: boolean;
   masterVolume: number;
   bpm: number;
-  setupTimeMs: number;
+  playbackOffsetMs: number;
   
   // SuperCollider status
   scStatus: ScStatus;
@@ -99,6 +110,9 @@ interface AppStore {
   
   // Logs
   logs: LogEntry[];
+
+  // Problems found in the last run code
+  diagnostics: Diagnostic[];
   
   // Samples
   samples: SampleInfo[];
@@ -246,10 +260,11 @@ export const useStore = create<AppStore>((set, get) => ({
   isRecording: false,
   masterVolume: 1.0,
   bpm: 120,
-  setupTimeMs: 0,
+  playbackOffsetMs: 0,
   scStatus: { available: false, booted: false, enabled: false, message: 'Not initialized' },
   waveform: new Array(2048).fill(0),
   logs: [],
+  diagnostics: [],
   samples: [],
   effects: {
     reverb_mix: 0.0,
@@ -315,17 +330,18 @@ export const useStore = create<AppStore>((set, get) => ({
 
     try {
       const result = await invoke<RunResult>('run_code', { code: buffer.code });
-      set({ isPlaying: true, bpm: result.effective_bpm || get().bpm, setupTimeMs: result.setup_time_ms || 0 });
+      set({
+        isPlaying: true,
+        bpm: result.effective_bpm || get().bpm,
+        playbackOffsetMs: result.playback_offset_ms || 0,
+        diagnostics: result.diagnostics || [],
+      });
       if (result.logs.length > 0) {
         set((s) => ({
           logs: [...s.logs, ...result.logs].slice(-500),
         }));
       }
       get().addLog('info', result.message);
-      // Log duration estimate
-      if (result.duration_estimate > 0) {
-        get().addLog('info', `Estimated duration: ${result.duration_estimate.toFixed(1)}s`);
-      }
     } catch (e: any) {
       const errorMsg = typeof e === 'string' ? e : e?.message || JSON.stringify(e);
       get().addLog('error', `Code error: ${errorMsg}`);